@@ -88,6 +88,68 @@ fn bcst_to_code_rec(
     }
 }
 
+fn map_leaves<'a, F: FnMut(&Data<'a>) -> Data<'a>>((t, th): &Twh<'a>, f: &mut F) -> Twh<'a> {
+    match t.as_ref() {
+        BCSTree::Leaf(x) if x == &DATA_NIL => (t.clone(), *th),
+        BCSTree::Leaf(x) => (Rc::new(BCSTree::Leaf(f(x))), *th),
+        BCSTree::Node(m, x, y) => {
+            let x = map_leaves(x, f);
+            let y = map_leaves(y, f);
+
+            (Rc::new(BCSTree::Node(*m, x, y)), *th)
+        }
+    }
+}
+
+/// The tree with the position of each leaf relative to the end of the
+/// previous leaf, and the end of each leaf relative to its start.
+/// Adding or removing lines only moves the leaves right after them, so
+/// that diffs of relative trees are local and merge cleanly.  Byte
+/// ranges only keep the length of the leaves.
+pub(crate) fn to_relative<'a>(t: &Twh<'a>) -> Twh<'a> {
+    let mut prev = (0, 0);
+
+    map_leaves(t, &mut |x| {
+        let ((r, c), (er, ec)) = (x.range.start, x.range.end);
+        let (pr, pc) = prev;
+
+        prev = (er, ec);
+
+        Data {
+            range: (
+                r.saturating_sub(pr),
+                if r == pr { c.saturating_sub(pc) } else { c },
+            )
+                ..(
+                    er.saturating_sub(r),
+                    if er == r { ec.saturating_sub(c) } else { ec },
+                ),
+            byte_range: 0..x.byte_range.len(),
+            ..x.clone()
+        }
+    })
+}
+
+/// Inverse of `to_relative`, byte ranges aside.
+pub(crate) fn to_absolute<'a>(t: &Twh<'a>) -> Twh<'a> {
+    let mut prev = (0, 0);
+
+    map_leaves(t, &mut |x| {
+        let ((dr, dc), (der, dec)) = (x.range.start, x.range.end);
+        let (pr, pc) = prev;
+
+        let start = (pr + dr, if dr == 0 { pc + dc } else { dc });
+        let end = (start.0 + der, if der == 0 { start.1 + dec } else { dec });
+
+        prev = end;
+
+        Data {
+            range: start..end,
+            ..x.clone()
+        }
+    })
+}
+
 #[derive(Copy, Clone, Hash, Debug, PartialEq, Eq)]
 pub(crate) enum FlatDiff {
     Eps,
@@ -516,9 +578,9 @@ mod test {
 
     use std::rc::Rc;
 
-    use crate::backend::diff::ered;
+    use crate::backend::{diff::ered, merge::merge};
 
-    use super::{diff_wrapper, patch, BCSTree, RCSTree};
+    use super::{diff_wrapper, patch, to_absolute, to_relative, BCSTree, RCSTree};
 
     #[test]
     fn no_difference() {
@@ -735,4 +797,43 @@ mod test {
 
         assert_eq!(patch0, patch1)
     }
+
+    #[test]
+    fn relative_positions() {
+        let base = "fn foo() {\n    1\n}\n\nfn bar() {\n    2\n}";
+        let left = "fn foo() {\n    let x = 0;\n    1\n}\n\nfn bar() {\n    2\n}";
+        let right = "fn foo() {\n    1\n}\n\nfn bar() {\n    3\n}";
+        let res = "fn foo() {\n    let x = 0;\n    1\n}\n\nfn bar() {\n    3\n}";
+
+        let mut parser = Parser::new();
+
+        parser
+            .set_language(&tree_sitter_rust::LANGUAGE.into())
+            .unwrap();
+
+        let trees = [base, left, right].map(|code| (code, parser.parse(code, None).unwrap()));
+        let [b, l, r] = trees.each_ref().map(|(code, tree)| {
+            let (t, th): (BCSTree, usize) = RCSTree::from(tree.root_node(), code).into();
+
+            to_relative(&(Rc::new(t), th))
+        });
+
+        assert_eq!(super::bcst_to_code(to_absolute(&l).0), left);
+
+        /* the lines added to `foo` only move the first leaf after them,
+         * so the change to `bar` still applies
+         */
+        let mut conflicts = Vec::new();
+        let d = merge(
+            ered(diff_wrapper(b.clone(), l)),
+            ered(diff_wrapper(b.clone(), r)),
+            &mut conflicts,
+        );
+
+        assert!(conflicts.is_empty());
+        assert_eq!(
+            super::bcst_to_code(to_absolute(&patch(b, d).unwrap()).0),
+            res
+        );
+    }
 }
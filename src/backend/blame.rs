@@ -0,0 +1,232 @@
+//! Attribution of the leaves and lines of a file to the versions which
+//! introduced them
+use imara_diff::{Algorithm, Diff as TokenDiff, InternedInput};
+
+use std::rc::Rc;
+
+use crate::backend::{
+    bcst::{patch, BCSTree},
+    data::{Data, DATA_NIL},
+    diff::Diff,
+    linear::LinDiff,
+};
+
+/// Carry the labels of the leaves of `t` (in order) over the diff `d`.
+/// Returns the labels of the leaves of `patch(t, d)`, the leaves
+/// introduced by `d` being labelled `new`, or `None` if `d` does not
+/// apply to `t`.
+///
+/// A leaf which only moves keeps its label: re-indenting code does
+/// not make it new.  Neither does a change of the shape of the subtree
+/// holding it, such as code added around it or a delimiter which the
+/// diff pairs with another leaf (see `carry_leaves`).
+pub(crate) fn carry_tree<L: Copy>(t: &BCSTree, d: &Diff, labels: &[L], new: L) -> Option<Vec<L>> {
+    let mut out = Vec::with_capacity(labels.len());
+
+    carry_tree_rec(t, d, labels, new, &mut out).then_some(out)
+}
+
+/// Whether `d` replaces, adds or removes subtrees, rather than
+/// changing leaves in place.
+fn reshapes(d: &Diff) -> bool {
+    matches!(
+        d,
+        Diff::Mod(_, _) | Diff::AddL(_, _, _) | Diff::AddR(_, _, _) | Diff::DelL(_) | Diff::DelR(_)
+    )
+}
+
+fn carry_tree_rec<L: Copy>(t: &BCSTree, d: &Diff, labels: &[L], new: L, out: &mut Vec<L>) -> bool {
+    match (t, d) {
+        (_, Diff::Eps) => out.extend_from_slice(labels),
+        (BCSTree::Leaf(x), Diff::RMod(_, _, _, text)) => {
+            out.push(if x.text == *text { labels[0] } else { new })
+        }
+        (BCSTree::Node(_, (x, _), (y, _)), Diff::TEps(_, dx, dy))
+        | (BCSTree::Node(_, (x, _), (y, _)), Diff::TMod(_, _, dx, dy))
+            if !reshapes(dx) && !reshapes(dy) =>
+        {
+            let (lx, ly) = labels.split_at(x.size());
+
+            return carry_tree_rec(x, dx, lx, new, out) && carry_tree_rec(y, dy, ly, new, out);
+        }
+        _ => match patch((Rc::new(t.clone()), 0), Rc::new(d.clone())) {
+            Ok((y, _)) => out.extend(carry_leaves(t, labels, &y, new)),
+            Err(_) => return false,
+        },
+    }
+
+    true
+}
+
+/// Carry the labels of the leaves of `x` over to the leaves of `y`
+/// which replace them: the leaves common to both, by text, keep their
+/// label, the others are labelled `new`.
+///
+/// The diff of two trees may pair a leaf with another one, and add the
+/// same leaf again next to it: the closing brace of a block which
+/// grows, for instance.  Comparing the leaves of the whole subtree
+/// keeps the label of such a leaf.
+fn carry_leaves<L: Copy>(x: &BCSTree, labels: &[L], y: &BCSTree, new: L) -> Vec<L> {
+    let mut input = InternedInput::default();
+
+    input.update_before(leaves(x).into_iter().map(|l| l.text));
+    input.update_after(leaves(y).into_iter().map(|l| l.text));
+
+    let diff = TokenDiff::compute(Algorithm::Myers, &input);
+    let mut i = 0;
+
+    (0..input.after.len() as u32)
+        .map(|j| {
+            if diff.is_added(j) {
+                return new;
+            }
+
+            while diff.is_removed(i) {
+                i += 1;
+            }
+
+            i += 1;
+
+            labels[i as usize - 1]
+        })
+        .collect()
+}
+
+/// Carry the labels of the lines of a file over a linear diff.
+pub(crate) fn carry_lines<L: Copy>(d: &[LinDiff], labels: &[L], new: L) -> Vec<L> {
+    let mut out = Vec::with_capacity(labels.len());
+    let mut i = 0;
+
+    for x in d {
+        match x {
+            LinDiff::Eps => {
+                out.push(labels[i]);
+                i += 1;
+            }
+            LinDiff::Del => i += 1,
+            LinDiff::Add(_) => out.push(new),
+        }
+    }
+
+    out
+}
+
+/// The leaves of `t`, in order.
+pub(crate) fn leaves<'t, 'a>(t: &'t BCSTree<'a>) -> Vec<&'t Data<'a>> {
+    fn rec<'t, 'a>(t: &'t BCSTree<'a>, out: &mut Vec<&'t Data<'a>>) {
+        match t {
+            BCSTree::Leaf(x) => out.push(x),
+            BCSTree::Node(_, (x, _), (y, _)) => {
+                rec(x, out);
+                rec(y, out);
+            }
+        }
+    }
+
+    let mut out = Vec::new();
+
+    rec(t, &mut out);
+
+    out
+}
+
+/// Label each line with the greatest label of the leaves it holds.
+/// Lines without leaves (blank lines...) keep their label in `lines`.
+pub(crate) fn label_lines<L: Copy + Ord>(t: &BCSTree, labels: &[L], lines: &mut [L]) {
+    let mut seen = vec![false; lines.len()];
+
+    for (x, l) in leaves(t).into_iter().zip(labels) {
+        if *x == DATA_NIL {
+            continue;
+        }
+
+        for row in x.range.start.0..=x.range.end.0 {
+            if row < lines.len() {
+                lines[row] = if seen[row] { lines[row].max(*l) } else { *l };
+                seen[row] = true;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tree_sitter::Parser;
+
+    use std::rc::Rc;
+
+    use crate::backend::{
+        bcst::{diff_wrapper, BCSTree},
+        diff::ered,
+        linear,
+        rcst::RCSTree,
+    };
+
+    use super::{carry_lines, carry_tree, label_lines, leaves};
+
+    fn blame(left: &str, right: &str) -> Vec<usize> {
+        let mut parser = Parser::new();
+
+        parser
+            .set_language(&tree_sitter_rust::LANGUAGE.into())
+            .unwrap();
+
+        let lt = parser.parse(left, None).unwrap();
+        let rt = parser.parse(right, None).unwrap();
+
+        let (lb, lbh): (BCSTree, usize) = RCSTree::from(lt.root_node(), left).into();
+        let (rb, rbh): (BCSTree, usize) = RCSTree::from(rt.root_node(), right).into();
+
+        let labels = vec![0; leaves(&lb).len()];
+        let d = ered(diff_wrapper(
+            (Rc::new(lb.clone()), lbh),
+            (Rc::new(rb.clone()), rbh),
+        ));
+        let labels = carry_tree(&lb, &d, &labels, 1).unwrap();
+
+        assert_eq!(labels.len(), leaves(&rb).len());
+
+        let mut lines = vec![1; right.lines().count()];
+
+        label_lines(&rb, &labels, &mut lines);
+
+        lines
+    }
+
+    #[test]
+    fn modified_line() {
+        let left = "fn foo() {\n    let x = 1;\n    x\n}";
+        let right = "fn foo() {\n    let x = 2;\n    x\n}";
+
+        assert_eq!(blame(left, right), vec![0, 1, 0, 0]);
+    }
+
+    #[test]
+    fn reindented() {
+        let left = "fn foo() {\n    let x = 1;\n    x\n}";
+        let right = "fn foo() {\n        let x = 1;\n\n        x\n}";
+
+        assert_eq!(blame(left, right), vec![0, 0, 1, 0, 0]);
+    }
+
+    #[test]
+    fn edited_block() {
+        let left = "fn foo() {\n    1\n}\nfn bar() {\n    2\n}";
+        let right = "fn foo() {\n    let x = 0;\n    1 + x\n}\nfn bar() {\n    3\n}";
+
+        assert_eq!(blame(left, right), vec![0, 1, 1, 0, 0, 1, 0]);
+    }
+
+    #[test]
+    fn lines() {
+        let left = "a\nb\nc\n";
+        let right = "a\nc\nd\n";
+
+        let ll = left.split_terminator('\n').collect::<Vec<_>>();
+        let rl = right.split_terminator('\n').collect::<Vec<_>>();
+
+        let d = linear::diff(left, right, &ll, &rl);
+
+        assert_eq!(carry_lines(&d, &[0, 1, 2], 3), vec![0, 2, 3]);
+    }
+}
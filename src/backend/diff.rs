@@ -1,5 +1,9 @@
 //! Tree differences datatype
-use crate::backend::{bcst::Twh, merge::MergeConflict, metadata::Metadata};
+use crate::backend::{
+    bcst::{patch, BCSTree, Twh},
+    merge::MergeConflict,
+    metadata::Metadata,
+};
 use std::{cmp::Ordering, ops::Range, rc::Rc};
 
 #[derive(Debug, Hash, Clone, PartialEq, Eq)]
@@ -101,6 +105,102 @@ pub(crate) fn ered<'a>(d: Rc<Diff<'a>>) -> Rc<Diff<'a>> {
     }
 }
 
+/// The diff undoing `d`, which applies to `patch(t, d)`.  Returns
+/// `None` if `d` does not apply to `t`.
+pub(crate) fn invert<'a>((t, th): Twh<'a>, d: Rc<Diff<'a>>) -> Option<Rc<Diff<'a>>> {
+    let inverse = match (t.as_ref(), d.as_ref()) {
+        (_, Diff::Err(_)) => return None,
+        (_, Diff::Eps) => Diff::Eps,
+        (BCSTree::Leaf(x), Diff::RMod(_, _, _, _)) => {
+            Diff::RMod(x.node_type, x.range.clone(), x.byte_range.clone(), x.text)
+        }
+        (_, Diff::Mod(x, y)) if t == x.0 => Diff::Mod(y.clone(), x.clone()),
+        (BCSTree::Node(m, x, y), Diff::TEps(md, dx, dy)) if m == md => Diff::TEps(
+            *m,
+            invert(x.clone(), dx.clone())?,
+            invert(y.clone(), dy.clone())?,
+        ),
+        (_, Diff::AddL(_, _, dy)) => Diff::DelL(invert((t, th), dy.clone())?),
+        (_, Diff::AddR(_, dx, _)) => Diff::DelR(invert((t, th), dx.clone())?),
+        (BCSTree::Node(m, x, y), Diff::DelL(dy)) => {
+            Diff::AddL(*m, x.clone(), invert(y.clone(), dy.clone())?)
+        }
+        (BCSTree::Node(m, x, y), Diff::DelR(dx)) => {
+            Diff::AddR(*m, invert(x.clone(), dx.clone())?, y.clone())
+        }
+        (BCSTree::Node(m, x, y), Diff::TMod(m0, m1, dx, dy)) if m == m0 => Diff::TMod(
+            *m1,
+            *m0,
+            invert(x.clone(), dx.clone())?,
+            invert(y.clone(), dy.clone())?,
+        ),
+        _ => return None,
+    };
+
+    Some(Rc::new(inverse))
+}
+
+/// Keep the changes of `d` to the top-level nodes of `t` which `keep`
+/// accepts, given the node before and after the change (`None` for
+/// added and deleted nodes).  A change which is not confined to a
+/// top-level node is given whole, from the first node it touches.
+/// Returns `None` if `d` does not apply to `t`.
+pub(crate) fn select<'a, F>(t: Twh<'a>, d: Rc<Diff<'a>>, keep: &mut F) -> Option<Rc<Diff<'a>>>
+where
+    F: FnMut(Option<Twh<'a>>, Option<Twh<'a>>) -> bool,
+{
+    let selected = match (t.0.as_ref(), d.as_ref()) {
+        (_, Diff::Eps) => return Some(d),
+        /* the left child of a node of the spine is a top-level node */
+        (BCSTree::Node(m, x, y), Diff::TEps(md, dx, dy)) if m == md => {
+            let dx = if dx.is_eps() {
+                dx.clone()
+            } else {
+                let after = patch(x.clone(), dx.clone()).ok()?;
+
+                if keep(Some(x.clone()), Some(after)) {
+                    dx.clone()
+                } else {
+                    Rc::new(Diff::Eps)
+                }
+            };
+
+            Diff::TEps(*m, dx, select(y.clone(), dy.clone(), keep)?)
+        }
+        (_, Diff::AddL(md, x, dy)) => {
+            let kept = keep(None, Some(x.clone()));
+            let dy = select(t, dy.clone(), keep)?;
+
+            if kept {
+                Diff::AddL(*md, x.clone(), dy)
+            } else {
+                return Some(dy);
+            }
+        }
+        (BCSTree::Node(m, x, y), Diff::DelL(dy)) => {
+            let kept = keep(Some(x.clone()), None);
+            let dy = select(y.clone(), dy.clone(), keep)?;
+
+            if kept {
+                Diff::DelL(dy)
+            } else {
+                Diff::TEps(*m, Rc::new(Diff::Eps), dy)
+            }
+        }
+        _ => {
+            let after = patch(t.clone(), d.clone()).ok()?;
+
+            return Some(if keep(Some(t), Some(after)) {
+                d
+            } else {
+                Rc::new(Diff::Eps)
+            });
+        }
+    };
+
+    Some(Rc::new(selected))
+}
+
 impl PartialOrd for Diff<'_> {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
         Some(self.cmp(other))
@@ -112,3 +212,92 @@ impl Ord for Diff<'_> {
         self.weight().cmp(&other.weight())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use tree_sitter::Parser;
+
+    use std::rc::Rc;
+
+    use crate::backend::{
+        bcst::{bcst_to_code, diff_wrapper, patch, BCSTree},
+        rcst::RCSTree,
+    };
+
+    use super::{ered, invert, select};
+
+    fn round_trip(left: &str, right: &str) {
+        let mut parser = Parser::new();
+
+        parser
+            .set_language(&tree_sitter_rust::LANGUAGE.into())
+            .unwrap();
+
+        let lt = parser.parse(left, None).unwrap();
+        let rt = parser.parse(right, None).unwrap();
+
+        let (lb, lbh): (BCSTree, usize) = RCSTree::from(lt.root_node(), left).into();
+        let (rb, rbh): (BCSTree, usize) = RCSTree::from(rt.root_node(), right).into();
+
+        let l = (Rc::new(lb), lbh);
+        let d = ered(diff_wrapper(l.clone(), (Rc::new(rb), rbh)));
+
+        let patched = patch(l.clone(), d.clone()).unwrap();
+        let inverse = invert(l.clone(), d).unwrap();
+
+        assert_eq!(bcst_to_code(patched.0.clone()), right);
+        assert_eq!(patch(patched, inverse).unwrap().0, l.0);
+    }
+
+    #[test]
+    fn invert_modification() {
+        round_trip("fn foo() { 5 + 6 }", "fn foo() { 5 - bar(6) }");
+    }
+
+    #[test]
+    fn invert_additions() {
+        round_trip(
+            "fn foo() {}",
+            "fn foo() {}\nfn bar(x: u8) -> u8 { x }\nstruct S;",
+        );
+        round_trip("fn foo() {}\nfn bar() {}\nstruct S;", "fn bar() {}");
+    }
+
+    #[test]
+    fn select_top_level() {
+        let left = "fn foo() { 1 }\nfn bar() { 2 }\nfn baz() { 3 }";
+        let right = "fn foo() { 10 }\nfn bar() { 2 }\nfn baz() { 30 }";
+
+        let mut parser = Parser::new();
+
+        parser
+            .set_language(&tree_sitter_rust::LANGUAGE.into())
+            .unwrap();
+
+        let lt = parser.parse(left, None).unwrap();
+        let rt = parser.parse(right, None).unwrap();
+
+        let (lb, lbh): (BCSTree, usize) = RCSTree::from(lt.root_node(), left).into();
+        let (rb, rbh): (BCSTree, usize) = RCSTree::from(rt.root_node(), right).into();
+
+        let l = (Rc::new(lb), lbh);
+        let d = ered(diff_wrapper(l.clone(), (Rc::new(rb), rbh)));
+
+        /* only keep the second change, to baz */
+        let mut n = 0;
+        let selected = select(l.clone(), d, &mut |before, after| {
+            n += 1;
+
+            assert!(before.is_some() && after.is_some());
+
+            n == 2
+        })
+        .unwrap();
+
+        assert_eq!(n, 2);
+        assert_eq!(
+            bcst_to_code(patch(l, selected).unwrap().0),
+            "fn foo() { 1 }\nfn bar() { 2 }\nfn baz() { 30 }"
+        );
+    }
+}
@@ -112,6 +112,34 @@ pub(crate) fn patch<'a>(
     Ok(right)
 }
 
+/// The diff undoing `d`, `left` being the lines `d` applies to.
+/// Returns `None` if `d` does not apply to `left`.
+///
+/// As in the diffs computed by `diff`, the lines of each hunk are
+/// deleted before new ones are added, which `merge` relies on.
+pub(crate) fn invert<'a>(left: &[&'a str], d: &[LinDiff<'a>]) -> Option<Vec<LinDiff<'a>>> {
+    let mut lines = left.iter();
+    let mut inverse = Vec::with_capacity(d.len());
+    let mut added = Vec::new();
+
+    for x in d {
+        match x {
+            LinDiff::Add(_) => inverse.push(LinDiff::Del),
+            LinDiff::Del => added.push(LinDiff::Add(lines.next()?)),
+            LinDiff::Eps => {
+                lines.next()?;
+
+                inverse.append(&mut added);
+                inverse.push(LinDiff::Eps);
+            }
+        }
+    }
+
+    inverse.append(&mut added);
+
+    Some(inverse)
+}
+
 pub(crate) fn merge<'a>(
     left: &[LinDiff<'a>],
     right: &[LinDiff<'a>],
@@ -231,16 +259,20 @@ pub(crate) fn serialise<'a>(dd: &[LinDiff<'a>]) -> Vec<u8> {
     out
 }
 
-pub(crate) fn deserialise<'a>(b: &'a [u8]) -> Vec<LinDiff<'a>> {
+/// Inverse of `serialise`.  Returns `None` if `b` is not a serialised
+/// diff.
+pub(crate) fn deserialise<'a>(b: &'a [u8]) -> Option<Vec<LinDiff<'a>>> {
     let mut i = ADDR_BYTES;
+    let len = usize::from_le_bytes(b.get(0..i)?.try_into().ok()?);
 
-    let mut out = Vec::with_capacity(usize::from_le_bytes(b[0..i].try_into().unwrap()));
+    /* the count is checked at the end, not trusted to size the vector */
+    let mut out = Vec::new();
 
     while i < b.len() {
         let ok = b[i];
         i += 1;
 
-        match b[i] {
+        match *b.get(i)? {
             0 => out.push((ok, LinDiff::Eps)),
             1 => out.push((ok, LinDiff::Del)),
             2 => {
@@ -250,23 +282,24 @@ pub(crate) fn deserialise<'a>(b: &'a [u8]) -> Vec<LinDiff<'a>> {
                     i += 1;
                 }
 
-                out.push((
-                    ok,
-                    LinDiff::Add(unsafe { str::from_utf8_unchecked(&b[j..i]) }),
-                ));
+                if i == b.len() {
+                    return None;
+                }
+
+                out.push((ok, LinDiff::Add(str::from_utf8(&b[j..i]).ok()?)));
             }
-            _ => unreachable!(),
+            _ => return None,
         }
 
         i += 1;
     }
 
-    rle_decode(&out)
+    (out.len() == len).then(|| rle_decode(&out))
 }
 
 #[cfg(test)]
 mod test {
-    use super::{deserialise, diff, merge, patch, serialise};
+    use super::{deserialise, diff, invert, merge, patch, serialise};
 
     #[test]
     fn diff0() {
@@ -322,6 +355,20 @@ diff should be full of `Del`s"#;
         assert_eq!(right, patch.join("\n"));
     }
 
+    #[test]
+    fn invert0() {
+        let left = "first\nsecond\nthird\nfourth";
+        let right = "first\nnew\nthird\nlast\nfourth";
+
+        let left_lines = left.lines().collect::<Vec<&str>>();
+        let right_lines = right.lines().collect::<Vec<&str>>();
+
+        let diff = diff(left, right, &left_lines, &right_lines);
+        let inverse = invert(&left_lines, &diff).unwrap();
+
+        assert_eq!(left, patch(&right_lines, &inverse).unwrap().join("\n"));
+    }
+
     #[test]
     fn merge0() {
         let base = "line one\nline two\n";
@@ -464,6 +511,13 @@ human race
 
         let diff = diff(left, right, &left_lines, &right_lines);
 
-        assert_eq!(diff, deserialise(&serialise(&diff)));
+        let ser = serialise(&diff);
+
+        assert_eq!(diff, deserialise(&ser).unwrap());
+
+        /* a truncated diff is rejected */
+        for end in 0..ser.len() {
+            assert!(deserialise(&ser[..end]).is_none());
+        }
     }
 }
@@ -1,6 +1,7 @@
 pub(crate) const ADDR_BYTES: usize = (usize::BITS / 8) as usize;
 
 pub(crate) mod bcst;
+pub(crate) mod blame;
 pub(crate) mod data;
 pub(crate) mod diff;
 #[allow(static_mut_refs)]
@@ -12,3 +13,4 @@ pub(crate) mod metadata;
 pub(crate) mod patch;
 pub(crate) mod rcst;
 pub(crate) mod serde;
+pub(crate) mod symbol;
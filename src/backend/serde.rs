@@ -227,74 +227,88 @@ fn u16_to_nt(x: u16) -> Option<u16> {
     }
 }
 
+fn read_u16(b: &[u8], i: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(b.get(i..i + 2)?.try_into().ok()?))
+}
+
+fn read_usize(b: &[u8], i: usize) -> Option<usize> {
+    Some(usize::from_le_bytes(
+        b.get(i..i + ADDR_BYTES)?.try_into().ok()?,
+    ))
+}
+
+/// Inverse of `serialise_tree`.  Returns `None` if `b` is not a
+/// serialised tree, or refers to ranges missing from `r` and `tr`.
 fn deserialise_tree<'a>(
     b: &'a [u8],
     t: &'a str,
     r: &VecRanges,
     tr: &VecTextRanges<'a>,
-) -> (Twh<'a>, &'a [u8]) {
-    match b[0] {
+) -> Option<(Twh<'a>, &'a [u8])> {
+    match *b.first()? {
         0 => {
-            let nt = u16_to_nt(u16::from_le_bytes(b[1..3].try_into().unwrap()));
-            let text = b[3] != 0;
-            let rn = usize::from_le_bytes(b[4..4 + ADDR_BYTES].try_into().unwrap());
-            let named = b[4 + ADDR_BYTES] != 0;
+            let nt = u16_to_nt(read_u16(b, 1)?);
+            let is_text = *b.get(3)? != 0;
+            let rn = read_usize(b, 4)?;
+            let named = *b.get(4 + ADDR_BYTES)? != 0;
 
-            (
+            let (range, byte_range, text) = if is_text {
+                tr.get(rn)?.clone()
+            } else {
+                let (range, byte_range) = r.get(rn)?.clone();
+                let text = t.get(byte_range.clone())?;
+
+                (range, byte_range, text)
+            };
+
+            Some((
                 (
                     Rc::new(BCSTree::Leaf(Data {
                         node_type: nt,
-                        range: if text {
-                            tr[rn].0.clone()
-                        } else {
-                            r[rn].0.clone()
-                        },
-                        byte_range: if text {
-                            tr[rn].1.clone()
-                        } else {
-                            r[rn].1.clone()
-                        },
-                        text: if text { tr[rn].2 } else { &t[r[rn].1.clone()] },
+                        range,
+                        byte_range,
+                        text,
                         named,
                     })),
                     0,
                 ),
                 &b[4 + ADDR_BYTES + 1..],
-            )
+            ))
         }
         1 => {
-            let h = usize::from_le_bytes(b[1..1 + ADDR_BYTES].try_into().unwrap());
-            let m = u16_to_nt(u16::from_le_bytes(
-                b[1 + ADDR_BYTES..1 + ADDR_BYTES + 2].try_into().unwrap(),
-            ));
+            let h = read_usize(b, 1)?;
+            let m = u16_to_nt(read_u16(b, 1 + ADDR_BYTES)?);
 
-            let (left, b) = deserialise_tree(&b[1 + ADDR_BYTES + 2..], t, r, tr);
-            let (right, b) = deserialise_tree(b, t, r, tr);
+            let (left, b) = deserialise_tree(&b[1 + ADDR_BYTES + 2..], t, r, tr)?;
+            let (right, b) = deserialise_tree(b, t, r, tr)?;
 
-            (
+            Some((
                 (
                     Rc::new(BCSTree::Node(Metadata { node_type: m }, left, right)),
                     h,
                 ),
                 b,
-            )
+            ))
         }
-        _ => unreachable!("badly serialised tree"),
+        _ => None,
     }
 }
 
+/// Inverse of `serialise`, `t` being the left file.  Returns `None` if
+/// `b` is not a serialised diff, or refers to ranges missing from `r`
+/// and `tr`.
 pub(crate) fn deserialise<'a>(
     b: &'a [u8],
     t: &'a str,
     r: &VecRanges,
     tr: &VecTextRanges<'a>,
-) -> (Diff<'a>, &'a [u8]) {
-    match b[0] {
+) -> Option<(Diff<'a>, &'a [u8])> {
+    Some(match *b.first()? {
         0 => (Diff::Eps, &b[1..]),
         1 => {
-            let t = u16::from_le_bytes(b[1..3].try_into().unwrap());
-            let ri = usize::from_le_bytes(b[3..3 + ADDR_BYTES].try_into().unwrap());
-            let (r, br, s) = tr.get(ri).unwrap();
+            let t = read_u16(b, 1)?;
+            let ri = read_usize(b, 3)?;
+            let (r, br, s) = tr.get(ri)?;
 
             (
                 Diff::RMod(u16_to_nt(t), r.clone(), br.clone(), s),
@@ -302,9 +316,9 @@ pub(crate) fn deserialise<'a>(
             )
         }
         2 => {
-            let m = u16::from_le_bytes(b[1..3].try_into().unwrap());
-            let (left, b) = deserialise(&b[3..], t, r, tr);
-            let (right, b) = deserialise(b, t, r, tr);
+            let m = read_u16(b, 1)?;
+            let (left, b) = deserialise(&b[3..], t, r, tr)?;
+            let (right, b) = deserialise(b, t, r, tr)?;
 
             (
                 Diff::TEps(
@@ -318,17 +332,17 @@ pub(crate) fn deserialise<'a>(
             )
         }
         3 => {
-            let (from, b) = deserialise_tree(&b[1..], t, r, tr);
-            let (to, b) = deserialise_tree(b, t, r, tr);
+            let (from, b) = deserialise_tree(&b[1..], t, r, tr)?;
+            let (to, b) = deserialise_tree(b, t, r, tr)?;
 
             (Diff::Mod(from, to), b)
         }
         4 => {
-            let from = u16::from_le_bytes(b[1..3].try_into().unwrap());
-            let to = u16::from_le_bytes(b[3..5].try_into().unwrap());
+            let from = read_u16(b, 1)?;
+            let to = read_u16(b, 3)?;
 
-            let (left, b) = deserialise(&b[5..], t, r, tr);
-            let (right, b) = deserialise(b, t, r, tr);
+            let (left, b) = deserialise(&b[5..], t, r, tr)?;
+            let (right, b) = deserialise(b, t, r, tr)?;
 
             (
                 Diff::TMod(
@@ -345,9 +359,9 @@ pub(crate) fn deserialise<'a>(
             )
         }
         5 => {
-            let m = u16::from_le_bytes(b[1..3].try_into().unwrap());
-            let (tree, b) = deserialise_tree(&b[3..], t, r, tr);
-            let (d, b) = deserialise(b, t, r, tr);
+            let m = read_u16(b, 1)?;
+            let (tree, b) = deserialise_tree(&b[3..], t, r, tr)?;
+            let (d, b) = deserialise(b, t, r, tr)?;
 
             (
                 Diff::AddL(
@@ -361,9 +375,9 @@ pub(crate) fn deserialise<'a>(
             )
         }
         6 => {
-            let m = u16::from_le_bytes(b[1..3].try_into().unwrap());
-            let (tree, b) = deserialise_tree(&b[3..], t, r, tr);
-            let (d, b) = deserialise(b, t, r, tr);
+            let m = read_u16(b, 1)?;
+            let (tree, b) = deserialise_tree(&b[3..], t, r, tr)?;
+            let (d, b) = deserialise(b, t, r, tr)?;
 
             (
                 Diff::AddR(
@@ -377,17 +391,17 @@ pub(crate) fn deserialise<'a>(
             )
         }
         7 => {
-            let (d, b) = deserialise(&b[1..], t, r, tr);
+            let (d, b) = deserialise(&b[1..], t, r, tr)?;
 
             (Diff::DelL(Rc::new(d)), b)
         }
         8 => {
-            let (d, b) = deserialise(&b[1..], t, r, tr);
+            let (d, b) = deserialise(&b[1..], t, r, tr)?;
 
             (Diff::DelR(Rc::new(d)), b)
         }
-        _ => unreachable!("badly serialised diff"),
-    }
+        _ => return None,
+    })
 }
 
 #[cfg(test)]
@@ -445,8 +459,13 @@ mod test {
             vtr[v] = k;
         }
 
-        let (de, _) = deserialise(&ser, left, &vr, &vtr);
+        let (de, _) = deserialise(&ser, left, &vr, &vtr).unwrap();
+
+        assert_eq!(diff.as_ref(), &de);
 
-        assert_eq!(diff.as_ref(), &de)
+        /* a truncated diff is rejected */
+        for end in 0..ser.len() {
+            assert!(deserialise(&ser[..end], left, &vr, &vtr).is_none());
+        }
     }
 }
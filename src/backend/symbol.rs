@@ -0,0 +1,154 @@
+//! Lookup of the syntax node defining a symbol (function, type...)
+use crate::backend::rcst::{List, RCSTree};
+use std::rc::Rc;
+use tree_sitter::Node;
+
+const DEFINITION_KINDS: [&str; 4] = ["definition", "declaration", "item", "specifier"];
+
+fn is_name(node: Node<'_>) -> bool {
+    node.child_count() == 0
+        && node.is_named()
+        && (node.kind().ends_with("identifier") || node.kind() == "name")
+}
+
+fn first_name(node: Node<'_>) -> Option<Node<'_>> {
+    if is_name(node) {
+        return Some(node);
+    }
+
+    let mut cursor = node.walk();
+    let children = node.named_children(&mut cursor).collect::<Vec<_>>();
+
+    children.into_iter().find_map(first_name)
+}
+
+fn find_node<'t, F: Fn(Node<'t>) -> bool>(node: Node<'t>, pred: &F) -> Option<Node<'t>> {
+    if pred(node) {
+        return Some(node);
+    }
+
+    let mut cursor = node.walk();
+    let children = node.named_children(&mut cursor).collect::<Vec<_>>();
+
+    children.into_iter().find_map(|c| find_node(c, pred))
+}
+
+/// Find the outermost node defining `name` in the tree of `src`.
+///
+/// A node defines `name` if its `name` field is `name` or, failing
+/// that, if it is a definition or declaration which first identifier
+/// is `name`.
+pub(crate) fn find_symbol<'t>(root: Node<'t>, src: &str, name: &str) -> Option<Node<'t>> {
+    let text = |n: Node<'_>| &src[n.byte_range()];
+
+    find_node(root, &|n| {
+        n.child_by_field_name("name")
+            .is_some_and(|c| text(c) == name)
+    })
+    .or_else(|| {
+        find_node(root, &|n| {
+            DEFINITION_KINDS.iter().any(|k| n.kind().ends_with(k))
+                && first_name(n).is_some_and(|c| text(c) == name)
+        })
+    })
+}
+
+fn shift<'a>(t: &RCSTree<'a>, rows: usize, bytes: usize) -> RCSTree<'a> {
+    match t {
+        RCSTree::Leaf(x) => {
+            let mut x = x.clone();
+
+            x.range =
+                (x.range.start.0 - rows, x.range.start.1)..(x.range.end.0 - rows, x.range.end.1);
+            x.byte_range = x.byte_range.start - bytes..x.byte_range.end - bytes;
+
+            RCSTree::Leaf(x)
+        }
+        RCSTree::Node(m, xs) => RCSTree::Node(*m, shift_list(xs, rows, bytes)),
+    }
+}
+
+fn shift_list<'a>(xs: &List<Rc<RCSTree<'a>>>, rows: usize, bytes: usize) -> List<Rc<RCSTree<'a>>> {
+    match xs {
+        List::Nil => List::Nil,
+        List::Cons(x, xs) => List::Cons(
+            Rc::new(shift(x, rows, bytes)),
+            Rc::new(shift_list(xs, rows, bytes)),
+        ),
+    }
+}
+
+/// The tree of `node`, with positions relative to the line it starts
+/// on, so that moving a node around the file does not change it.
+pub(crate) fn relative_tree<'a>(node: Node<'a>, src: &'a str) -> RCSTree<'a> {
+    let rows = node.start_position().row;
+    let bytes = node.start_byte() - node.start_position().column;
+
+    shift(&RCSTree::from(node, src), rows, bytes)
+}
+
+#[cfg(test)]
+mod test {
+    use tree_sitter::{Node, Parser};
+
+    use std::rc::Rc;
+
+    use crate::backend::{
+        bcst::{bcst_to_code, diff_wrapper, BCSTree},
+        diff::ered,
+    };
+
+    use super::{find_symbol, relative_tree};
+
+    fn with_tree<F: FnOnce(Node<'_>)>(code: &str, f: F) {
+        let mut parser = Parser::new();
+
+        parser
+            .set_language(&tree_sitter_rust::LANGUAGE.into())
+            .unwrap();
+
+        let tree = parser.parse(code, None).unwrap();
+
+        f(tree.root_node())
+    }
+
+    #[test]
+    fn find() {
+        let code = "fn main() {\n    foo();\n}\n\nimpl S {\n    fn foo(&self) -> u8 {\n        1\n    }\n}\n\nstruct S;\n";
+
+        with_tree(code, |root| {
+            let foo = find_symbol(root, code, "foo").unwrap();
+            let s = find_symbol(root, code, "S").unwrap();
+
+            assert_eq!(foo.kind(), "function_item");
+            assert_eq!(
+                &code[foo.byte_range()],
+                "fn foo(&self) -> u8 {\n        1\n    }"
+            );
+            assert_eq!(s.kind(), "struct_item");
+            assert!(find_symbol(root, code, "bar").is_none());
+        });
+    }
+
+    #[test]
+    fn moved() {
+        let left = "fn foo() {\n    1\n}\n";
+        let right = "fn bar() {}\n\nfn foo() {\n    1\n}\n";
+
+        with_tree(left, |lroot| {
+            with_tree(right, |rroot| {
+                let l = relative_tree(find_symbol(lroot, left, "foo").unwrap(), left);
+                let r = relative_tree(find_symbol(rroot, right, "foo").unwrap(), right);
+
+                let (lb, lbh): (BCSTree, usize) = l.into();
+                let (rb, rbh): (BCSTree, usize) = r.into();
+
+                assert_eq!(bcst_to_code(Rc::new(rb.clone())), "fn foo() {\n    1\n}");
+
+                let d = ered(diff_wrapper((Rc::new(lb), lbh), (Rc::new(rb), rbh)));
+
+                assert!(d.is_eps());
+            })
+        });
+    }
+}
@@ -1,17 +1,33 @@
 use std::{
+    collections::BTreeSet,
     env,
     fs::{self, File},
-    io::{prelude::*, BufReader},
-    path::Path,
+    io::{self, prelude::*, BufReader},
+    path::{Path, PathBuf},
 };
 
 use anyhow::Result;
 use glob::{glob, Pattern};
+use tree_sitter::{Language, Parser};
 
 use crate::{
-    commands::visit_dirs,
+    backend::{
+        bcst::{bcst_to_code, diff_wrapper, patch, to_absolute, Twh},
+        diff::{ered, select},
+        linear,
+        linguist::{get_ts_language, LinguistState},
+    },
+    commands::{guess_contents_language, matches_pathspec, visit_dirs},
     debug,
-    vcs::{config::Config, find_repo, index::Object},
+    vcs::{
+        commit::{fetch_head, get_branch},
+        config::Config,
+        find_repo,
+        index::{get_indexed_files, Object},
+        merge::{apply_hunks, hunks, relative, restore_layout},
+        snapshot::Snapshot,
+        INDEX_DIR, LAST_DIR,
+    },
     NotificationLevel,
 };
 
@@ -72,3 +88,298 @@ pub(crate) fn add(paths: &[String], _cfg: &Config, lvl: NotificationLevel) -> Re
 
     Ok(())
 }
+
+/// The answers of the user to `dcg add --patch`.
+#[derive(Default)]
+struct Prompt {
+    /// the answer for the changes left in the current file
+    rest: Option<bool>,
+    /// stop asking: the changes left are not staged
+    quit: bool,
+}
+
+impl Prompt {
+    /// Whether to stage the change just printed.
+    fn ask(&mut self) -> bool {
+        if self.quit {
+            return false;
+        }
+
+        if let Some(answer) = self.rest {
+            return answer;
+        }
+
+        let stdin = io::stdin();
+
+        loop {
+            print!("stage this change [y,n,a,d,q,?]? ");
+
+            let mut line = String::new();
+
+            if io::stdout().flush().is_err() || stdin.lock().read_line(&mut line).unwrap_or(0) == 0
+            {
+                println!();
+                self.quit = true;
+
+                return false;
+            }
+
+            match line.trim() {
+                "y" => return true,
+                "n" => return false,
+                "a" => {
+                    self.rest = Some(true);
+                    return true;
+                }
+                "d" => {
+                    self.rest = Some(false);
+                    return false;
+                }
+                "q" => {
+                    self.quit = true;
+                    return false;
+                }
+                _ => println!(
+                    "y - stage this change\n\
+                     n - do not stage this change\n\
+                     a - stage this change and the next ones in the file\n\
+                     d - do not stage this change nor the next ones in the file\n\
+                     q - quit, without staging this change nor the next ones"
+                ),
+            }
+        }
+    }
+}
+
+fn print_lines(prefix: &str, colour: &str, text: &str) {
+    for l in text.lines() {
+        println!("\x1b[0;{}m{} {}\x1b[0m", colour, prefix, l);
+    }
+}
+
+/// The code of a node of a relative tree.
+fn render(t: &Twh) -> String {
+    bcst_to_code(to_absolute(t).0)
+        .trim_start_matches('\n')
+        .trim_end()
+        .to_string()
+}
+
+/// Let the user pick the changes to the top-level syntax nodes of a file
+/// to stage.  Returns `None` if the file cannot be parsed, or if the
+/// picked changes do not make valid code.
+fn pick_tree_changes(
+    language: &Language,
+    path: &Path,
+    base: &str,
+    new: &str,
+    prompt: &mut Prompt,
+) -> Result<Option<String>> {
+    let mut parser = Parser::new();
+
+    parser.set_language(language)?;
+
+    let (Some(bt), Some(nt)) = (parser.parse(base, None), parser.parse(new, None)) else {
+        return Ok(None);
+    };
+
+    if bt.root_node().has_error() || nt.root_node().has_error() {
+        return Ok(None);
+    }
+
+    let b = relative(&bt, base);
+    let d = ered(diff_wrapper(b.clone(), relative(&nt, new)));
+
+    let (mut asked, mut kept) = (0, 0);
+
+    let selected = select(b.clone(), d, &mut |before, after| {
+        println!("\x1b[0;1m{}:\x1b[0m", path.display());
+
+        if let Some(before) = before {
+            print_lines("-", "31", &render(&before));
+        }
+
+        if let Some(after) = after {
+            print_lines("+", "32", &render(&after));
+        }
+
+        asked += 1;
+
+        let answer = prompt.ask();
+
+        if answer {
+            kept += 1;
+        }
+
+        answer
+    });
+
+    let Some(selected) = selected else {
+        return Ok(None);
+    };
+
+    if kept == 0 {
+        return Ok(Some(base.to_string()));
+    } else if kept == asked {
+        return Ok(Some(new.to_string()));
+    }
+
+    let Ok(patched) = patch(b, selected) else {
+        return Ok(None);
+    };
+
+    let mut contents = restore_layout(&bcst_to_code(to_absolute(&patched).0), &[base, new]);
+
+    if new.ends_with('\n') {
+        contents.push('\n');
+    }
+
+    match parser.parse(&contents, None) {
+        Some(t) if !t.root_node().has_error() => Ok(Some(contents)),
+        _ => Ok(None),
+    }
+}
+
+/// Let the user pick the runs of changed lines of a file to stage.
+fn pick_line_changes(path: &Path, base: &str, new: &str, prompt: &mut Prompt) -> String {
+    /* see `do_diff_linear` */
+    let (base, new) = (format!("{}\n", base), format!("{}\n", new));
+
+    let bl = base.split_terminator('\n').collect::<Vec<_>>();
+    let nl = new.split_terminator('\n').collect::<Vec<_>>();
+
+    let hunks = hunks(&linear::diff(&base, &new, &bl, &nl));
+    let mut kept = Vec::new();
+
+    for h in &hunks {
+        println!("\x1b[0;1m{}:\x1b[0m line {}", path.display(), h.start + 1);
+
+        for l in &bl[h.start..h.end] {
+            print_lines("-", "31", l);
+        }
+
+        for l in &h.lines {
+            print_lines("+", "32", l);
+        }
+
+        if prompt.ask() {
+            kept.push(h);
+        }
+    }
+
+    apply_hunks(&bl, &kept, 0, bl.len()).join("\n")
+}
+
+/// Let the user pick the changes to `path` to stage.
+fn pick_changes(dd: &Path, state: LinguistState, path: &Path, prompt: &mut Prompt) -> Result<()> {
+    let staged = match Object::read(dd, INDEX_DIR, path)? {
+        Some(o) => Some(o),
+        None => Object::read(dd, LAST_DIR, path)?,
+    }
+    .map(|(_, contents)| contents);
+
+    let full_path = dd.join(path);
+    let new = if full_path.is_file() {
+        Some(fs::read(&full_path)?)
+    } else {
+        None
+    };
+
+    prompt.rest = None;
+
+    let contents = match (staged, new) {
+        (base, new) if base == new => return Ok(()),
+        (None, Some(new)) => {
+            println!("\x1b[0;1m{}:\x1b[0m file was created", path.display());
+
+            if !prompt.ask() {
+                return Ok(());
+            }
+
+            new
+        }
+        (Some(base), None) => {
+            println!("\x1b[0;1m{}:\x1b[0m file was deleted", path.display());
+
+            if prompt.ask() {
+                Object::delete(dd, path)?;
+                return Ok(());
+            }
+
+            base
+        }
+        (Some(base), Some(new)) => match (String::from_utf8(base), String::from_utf8(new)) {
+            (Ok(base), Ok(new)) => {
+                let language =
+                    get_ts_language(guess_contents_language(state, path, new.as_bytes())?);
+
+                let tree = match &language {
+                    Some(l) => pick_tree_changes(l, path, &base, &new, prompt)?,
+                    None => None,
+                };
+
+                match tree {
+                    Some(c) => c.into_bytes(),
+                    None => pick_line_changes(path, &base, &new, prompt).into_bytes(),
+                }
+            }
+            /* binary files are staged whole */
+            (_, new) => {
+                println!("\x1b[0;1m{}:\x1b[0m binary file changed", path.display());
+
+                if !prompt.ask() {
+                    return Ok(());
+                }
+
+                new.map_or_else(|e| e.into_bytes(), String::into_bytes)
+            }
+        },
+        (None, None) => unreachable!(),
+    };
+
+    Object::new(path, contents).write(dd)?;
+
+    Ok(())
+}
+
+/// Stage parts of the changes to the tracked files matching `paths`,
+/// and the new files among them, as picked by the user.
+pub(crate) fn add_patch(
+    paths: &[String],
+    state: LinguistState,
+    _cfg: &Config,
+    _lvl: NotificationLevel,
+) -> Result<()> {
+    let wd = env::current_dir().map(fs::canonicalize)??.into_boxed_path();
+    let dd = find_repo(&wd)?;
+
+    let head = fetch_head(dd, &get_branch(dd)?)?;
+
+    let mut files = Snapshot::at(dd, head)?
+        .files
+        .into_keys()
+        .collect::<BTreeSet<_>>();
+
+    files.extend(get_indexed_files(dd)?);
+    files.retain(|p| matches_pathspec(p, paths));
+
+    /* new files must be named */
+    files.extend(
+        paths
+            .iter()
+            .map(PathBuf::from)
+            .filter(|p| dd.join(p).is_file()),
+    );
+
+    let mut prompt = Prompt::default();
+
+    for path in files {
+        pick_changes(dd, state, &path, &mut prompt)?;
+
+        if prompt.quit {
+            break;
+        }
+    }
+
+    Ok(())
+}
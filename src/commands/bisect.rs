@@ -0,0 +1,407 @@
+//! Binary search of the history for the commit introducing a regression
+use std::{
+    collections::BTreeSet,
+    env,
+    fs::{self, File},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::Result;
+use clap::Subcommand;
+
+use crate::{
+    combine_paths, info,
+    vcs::{
+        checkout::{checkout, ensure_clean},
+        commit::{fetch_head, get_branch, CommitObject},
+        config::Config,
+        find_repo,
+        revision::{ancestors, resolve_revision},
+        snapshot::Snapshot,
+        DcgError, BISECT_DIR, DCG_DIR,
+    },
+    NotificationLevel,
+};
+
+/// Exit code of `bisect run` scripts for commits which cannot be
+/// tested.
+const SKIP_CODE: i32 = 125;
+
+#[derive(Subcommand)]
+pub(crate) enum BisectCommand {
+    /// start a bisection
+    Start {
+        /// the first bad revision, optionally followed by good ones
+        revisions: Vec<String>,
+    },
+    /// mark a revision (by default the one checked out) as good
+    Good { revision: Option<String> },
+    /// mark a revision (by default the one checked out) as bad
+    Bad { revision: Option<String> },
+    /// mark a revision (by default the one checked out) as
+    /// untestable
+    Skip { revision: Option<String> },
+    /// end the bisection and go back to the head of the current branch
+    Reset,
+    /// bisect automatically, using the exit code of a command: 0 if
+    /// the revision is good, 125 if it cannot be tested and anything
+    /// else below 128 if it is bad
+    Run {
+        /// the command and its arguments
+        #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+        command: Vec<String>,
+    },
+}
+
+/// The progress of a bisection, kept in `.dcg/bisect/`.
+struct Bisection {
+    dir: PathBuf,
+    /// the head of the current branch when the bisection started
+    head: Option<[u8; 32]>,
+    /// the commit in the working tree
+    current: Option<[u8; 32]>,
+    bad: Option<[u8; 32]>,
+    good: BTreeSet<[u8; 32]>,
+    skip: BTreeSet<[u8; 32]>,
+}
+
+fn read_hashes(p: &Path) -> Result<Vec<[u8; 32]>> {
+    if !p.is_file() {
+        return Ok(Vec::new());
+    }
+
+    let mut s = String::new();
+
+    File::open(p)?.read_to_string(&mut s)?;
+
+    s.split_whitespace()
+        .map(|x| match hex::decode(x)?.try_into() {
+            Ok(h) => Ok(h),
+            _ => Err(DcgError::InvalidCommit.into()),
+        })
+        .collect()
+}
+
+fn write_hashes<'a, I: IntoIterator<Item = &'a [u8; 32]>>(p: &Path, hashes: I) -> Result<()> {
+    let s = hashes
+        .into_iter()
+        .map(hex::encode)
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    File::create(p)?.write_all(s.as_bytes())?;
+
+    Ok(())
+}
+
+/// What is left to test.
+enum Step {
+    /// check out and test this commit, `n` commits being left after it
+    Test([u8; 32], usize),
+    /// the first bad commit was found
+    Found([u8; 32]),
+    /// only skipped commits are left: any of them may be the first bad
+    /// commit
+    Skipped(Vec<[u8; 32]>),
+}
+
+impl Bisection {
+    fn dir(dd: &Path) -> PathBuf {
+        combine_paths!(dd, DCG_DIR, BISECT_DIR)
+    }
+
+    fn start(dd: &Path) -> Result<Self> {
+        let dir = Self::dir(dd);
+        let head = fetch_head(dd, &get_branch(dd)?)?.ok_or(DcgError::EmptyTree)?;
+
+        fs::create_dir_all(&dir)?;
+        write_hashes(&dir.join("head"), [&head])?;
+        write_hashes(&dir.join("current"), [&head])?;
+
+        Self::read(dd)
+    }
+
+    fn read(dd: &Path) -> Result<Self> {
+        let dir = Self::dir(dd);
+
+        if !dir.is_dir() {
+            return Err(DcgError::NoBisection.into());
+        }
+
+        Ok(Self {
+            head: read_hashes(&dir.join("head"))?.first().copied(),
+            current: read_hashes(&dir.join("current"))?.first().copied(),
+            bad: read_hashes(&dir.join("bad"))?.first().copied(),
+            good: read_hashes(&dir.join("good"))?.into_iter().collect(),
+            skip: read_hashes(&dir.join("skip"))?.into_iter().collect(),
+            dir,
+        })
+    }
+
+    fn write(&self) -> Result<()> {
+        write_hashes(&self.dir.join("current"), &self.current)?;
+        write_hashes(&self.dir.join("bad"), &self.bad)?;
+        write_hashes(&self.dir.join("good"), &self.good)?;
+        write_hashes(&self.dir.join("skip"), &self.skip)
+    }
+
+    /// The commit to test next.  `None` until a bad and a good commit
+    /// are known.
+    fn next(&self, dd: &Path) -> Result<Option<Step>> {
+        let Some(bad) = self.bad else {
+            return Ok(None);
+        };
+
+        if self.good.is_empty() {
+            return Ok(None);
+        }
+
+        /* the first bad commit is an ancestor of the bad commit which
+         * is not an ancestor of any good commit
+         */
+        let mut candidates = ancestors(dd, bad)?;
+
+        for g in &self.good {
+            for h in ancestors(dd, *g)? {
+                candidates.remove(&h);
+            }
+        }
+
+        candidates.remove(&bad);
+
+        let testable = candidates
+            .iter()
+            .filter(|h| !self.skip.contains(*h))
+            .copied()
+            .collect::<Vec<_>>();
+
+        if testable.is_empty() {
+            return Ok(Some(if candidates.is_empty() {
+                Step::Found(bad)
+            } else {
+                let mut left = vec![bad];
+
+                left.extend(candidates);
+
+                Step::Skipped(left)
+            }));
+        }
+
+        /* the best commit to test halves the candidates: whether it is
+         * good or bad, as few of them as possible are left
+         */
+        let n = candidates.len() + 1;
+        let mut best = (0, testable[0]);
+
+        for h in testable {
+            let below = ancestors(dd, h)?
+                .iter()
+                .filter(|a| candidates.contains(*a))
+                .count();
+            let score = below.min(n - below);
+
+            if score > best.0 {
+                best = (score, h);
+            }
+        }
+
+        Ok(Some(Step::Test(best.1, n - best.0 - 1)))
+    }
+
+    /// Replace the working tree with the files of `h`.
+    fn switch(&mut self, dd: &Path, h: [u8; 32]) -> Result<()> {
+        let from = Snapshot::at(dd, self.current)?;
+
+        ensure_clean(dd, &from)?;
+        checkout(dd, &from, &Snapshot::at(dd, Some(h))?)?;
+
+        self.current = Some(h);
+
+        Ok(())
+    }
+
+    /// Check out the next commit to test.  Returns whether the
+    /// bisection is over.
+    fn advance(&mut self, dd: &Path, lvl: NotificationLevel) -> Result<bool> {
+        let done = match self.next(dd)? {
+            None => {
+                match (self.bad, self.good.is_empty()) {
+                    (None, true) => info!(lvl, "waiting for a good and a bad revision"),
+                    (None, false) => info!(lvl, "waiting for a bad revision"),
+                    _ => info!(lvl, "waiting for a good revision"),
+                }
+
+                false
+            }
+            Some(Step::Test(h, left)) => {
+                self.switch(dd, h)?;
+
+                let steps = usize::BITS - left.leading_zeros();
+
+                info!(
+                    lvl,
+                    "bisecting: {} revision{} left to test after this (roughly {} step{})",
+                    left,
+                    if left == 1 { "" } else { "s" },
+                    steps,
+                    if steps == 1 { "" } else { "s" }
+                );
+                info!(lvl, "{}", describe(dd, h)?);
+
+                false
+            }
+            Some(Step::Found(h)) => {
+                info!(lvl, "{} is the first bad commit", hex::encode(h));
+                info!(lvl, "{}", describe(dd, h)?);
+
+                true
+            }
+            Some(Step::Skipped(hs)) => {
+                info!(
+                    lvl,
+                    "only skipped commits are left to test. the first bad commit could be any of:"
+                );
+
+                for h in hs {
+                    info!(lvl, "{}", describe(dd, h)?);
+                }
+
+                true
+            }
+        };
+
+        self.write()?;
+
+        Ok(done)
+    }
+
+    /// Go back to the head of the branch and forget the bisection.
+    fn reset(mut self, dd: &Path) -> Result<()> {
+        if let Some(head) = self.head {
+            self.switch(dd, head)?;
+        }
+
+        fs::remove_dir_all(&self.dir)?;
+
+        Ok(())
+    }
+}
+
+/// `[<short hash>] <subject>`
+fn describe(dd: &Path, h: [u8; 32]) -> Result<String> {
+    let commit = CommitObject::read(dd, h)?;
+
+    Ok(format!(
+        "[{}] {}",
+        hex::encode(&h[..4]),
+        commit.message.lines().next().unwrap_or("")
+    ))
+}
+
+/// Resolve `revision`, the commit checked out by default.
+fn resolve(dd: &Path, bisection: &Bisection, revision: &Option<String>) -> Result<[u8; 32]> {
+    match revision {
+        Some(r) => resolve_revision(dd, r),
+        None => Ok(bisection.current.ok_or(DcgError::EmptyTree)?),
+    }
+}
+
+/// Run `command` on the commits to test until the first bad commit is
+/// found.
+fn run(dd: &Path, command: &[String], lvl: NotificationLevel) -> Result<()> {
+    loop {
+        let mut bisection = Bisection::read(dd)?;
+
+        let (Some(current), Some(_), false) =
+            (bisection.current, bisection.bad, bisection.good.is_empty())
+        else {
+            return Err(DcgError::BisectRunFailed(
+                "a good and a bad revision are needed first".to_string(),
+            )
+            .into());
+        };
+
+        info!(lvl, "running {}", command.join(" "));
+
+        let status = Command::new(&command[0])
+            .args(&command[1..])
+            .current_dir(dd)
+            .status()?;
+
+        match status.code() {
+            Some(0) => {
+                bisection.good.insert(current);
+            }
+            Some(SKIP_CODE) => {
+                bisection.skip.insert(current);
+            }
+            Some(c) if (1..128).contains(&c) => bisection.bad = Some(current),
+            _ => {
+                return Err(DcgError::BisectRunFailed(format!(
+                    "'{}' did not exit normally ({})",
+                    command.join(" "),
+                    status
+                ))
+                .into())
+            }
+        };
+
+        if bisection.advance(dd, lvl)? {
+            return Ok(());
+        }
+    }
+}
+
+pub(crate) fn bisect(command: &BisectCommand, _cfg: &Config, lvl: NotificationLevel) -> Result<()> {
+    let wd = env::current_dir().map(fs::canonicalize)??.into_boxed_path();
+    let dd = find_repo(&wd)?;
+
+    match command {
+        BisectCommand::Start { revisions } => {
+            if Bisection::dir(dd).is_dir() {
+                Bisection::read(dd)?.reset(dd)?;
+            }
+
+            let mut hashes = revisions
+                .iter()
+                .map(|r| resolve_revision(dd, r))
+                .collect::<Result<Vec<_>>>()?
+                .into_iter();
+
+            ensure_clean(dd, &Snapshot::at(dd, fetch_head(dd, &get_branch(dd)?)?)?)?;
+
+            let mut bisection = Bisection::start(dd)?;
+
+            bisection.bad = hashes.next();
+            bisection.good.extend(hashes);
+            bisection.advance(dd, lvl)?;
+        }
+        BisectCommand::Good { revision } => {
+            let mut bisection = Bisection::read(dd)?;
+            let h = resolve(dd, &bisection, revision)?;
+
+            bisection.good.insert(h);
+            bisection.advance(dd, lvl)?;
+        }
+        BisectCommand::Bad { revision } => {
+            let mut bisection = Bisection::read(dd)?;
+            let h = resolve(dd, &bisection, revision)?;
+
+            bisection.bad = Some(h);
+            bisection.advance(dd, lvl)?;
+        }
+        BisectCommand::Skip { revision } => {
+            let mut bisection = Bisection::read(dd)?;
+            let h = resolve(dd, &bisection, revision)?;
+
+            bisection.skip.insert(h);
+            bisection.advance(dd, lvl)?;
+        }
+        BisectCommand::Reset => Bisection::read(dd)?.reset(dd)?,
+        BisectCommand::Run { command } => run(dd, command, lvl)?,
+    }
+
+    Ok(())
+}
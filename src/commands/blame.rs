@@ -0,0 +1,209 @@
+use std::{env, fs, path::Path, rc::Rc};
+
+use anyhow::Result;
+use clap::ValueEnum;
+use time::format_description::parse;
+use tree_sitter::{Parser, Tree};
+
+use crate::{
+    backend::{
+        bcst::{diff_wrapper, BCSTree},
+        blame::{carry_lines, carry_tree, label_lines, leaves},
+        diff::ered,
+        linear,
+        linguist::{get_ts_language, LinguistState},
+        rcst::RCSTree,
+    },
+    commands::guess_contents_language,
+    vcs::{
+        commit::CommitObject, config::Config, find_repo, revision::resolve_revision,
+        snapshot::Snapshot, DcgError,
+    },
+    NotificationLevel,
+};
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum Granularity {
+    /// annotate each line
+    Line,
+    /// annotate each top-level syntax node (function, declaration...)
+    Node,
+}
+
+/// What a version of the file is attributed to.
+struct Origin {
+    hash: String,
+    author: String,
+    date: String,
+}
+
+impl Origin {
+    fn read(dd: &Path, h: [u8; 32]) -> Result<Self> {
+        let commit = CommitObject::read(dd, h)?;
+        let fmt = parse("[year]-[month]-[day] [hour]:[minute]:[second]")?;
+
+        Ok(Self {
+            hash: hex::encode(&h[..4]),
+            date: commit.author.datetime()?.format(&fmt)?,
+            author: commit.author.name,
+        })
+    }
+}
+
+pub(crate) fn blame(
+    file: &str,
+    revision: &Option<String>,
+    granularity: Granularity,
+    state: LinguistState,
+    _cfg: &Config,
+    _lvl: NotificationLevel,
+) -> Result<()> {
+    let wd = env::current_dir().map(fs::canonicalize)??.into_boxed_path();
+    let dd = find_repo(&wd)?;
+
+    let h = resolve_revision(dd, revision.as_deref().unwrap_or("HEAD"))?;
+    let path = Path::new(file);
+
+    let versions = Snapshot::at(dd, Some(h))?.history(path)?;
+
+    let Some((_, last)) = versions.last() else {
+        return Err(DcgError::UntrackedFile(file.to_string()).into());
+    };
+
+    let language = get_ts_language(guess_contents_language(state, path, last)?);
+
+    let texts = versions
+        .iter()
+        .map(|(_, c)| String::from_utf8_lossy(c).into_owned())
+        .collect::<Vec<_>>();
+
+    let mut parser = Parser::new();
+
+    if let Some(l) = &language {
+        parser.set_language(l)?;
+    }
+
+    let trees = texts
+        .iter()
+        .map(|s| language.as_ref().and_then(|_| parser.parse(s, None)))
+        .collect::<Vec<Option<Tree>>>();
+
+    let bcsts = trees
+        .iter()
+        .zip(&texts)
+        .map(|(t, s)| {
+            t.as_ref().map(|t| {
+                let (b, bh): (BCSTree, usize) = RCSTree::from(t.root_node(), s).into();
+
+                (Rc::new(b), bh)
+            })
+        })
+        .collect::<Vec<_>>();
+
+    /* every line and every leaf is labelled with the index of the
+     * version which introduced it
+     */
+    let mut line_labels = vec![0; texts[0].split_terminator('\n').count()];
+    let mut leaf_labels = bcsts[0].as_ref().map(|(b, _)| vec![0; b.size()]);
+
+    for i in 1..texts.len() {
+        let (left, right) = (&texts[i - 1], &texts[i]);
+
+        let ll = left.split_terminator('\n').collect::<Vec<_>>();
+        let rl = right.split_terminator('\n').collect::<Vec<_>>();
+
+        line_labels = carry_lines(&linear::diff(left, right, &ll, &rl), &line_labels, i);
+
+        leaf_labels = match (&bcsts[i - 1], &bcsts[i], &leaf_labels) {
+            (Some(l), Some(r), Some(labels)) => {
+                let d = ered(diff_wrapper(l.clone(), r.clone()));
+
+                carry_tree(&l.0, &d, labels, i)
+            }
+            _ => None,
+        };
+
+        /* without a tree to follow, fall back to the lines */
+        if leaf_labels.is_none() {
+            leaf_labels = bcsts[i].as_ref().map(|(b, _)| {
+                leaves(b)
+                    .iter()
+                    .map(|x| line_labels.get(x.range.start.0).copied().unwrap_or(i))
+                    .collect()
+            });
+        }
+    }
+
+    let origins = versions
+        .iter()
+        .map(|(h, _)| Origin::read(dd, *h))
+        .collect::<Result<Vec<_>>>()?;
+
+    let width = origins.iter().map(|o| o.author.len()).max().unwrap_or(0);
+    let text = &texts[texts.len() - 1];
+    let lines = text.split_terminator('\n').collect::<Vec<_>>();
+    let last_bcst = &bcsts[bcsts.len() - 1];
+
+    match granularity {
+        Granularity::Line => {
+            if let (Some((b, _)), Some(labels)) = (last_bcst, &leaf_labels) {
+                label_lines(b, labels, &mut line_labels);
+            }
+
+            let nw = lines.len().to_string().len();
+
+            for (n, (line, label)) in lines.iter().zip(&line_labels).enumerate() {
+                let o = &origins[*label];
+
+                println!(
+                    "{} ({:<width$} {} {:>nw$}) {}",
+                    o.hash,
+                    o.author,
+                    o.date,
+                    n + 1,
+                    line
+                );
+            }
+        }
+        Granularity::Node => {
+            let (Some(tree), Some((b, _)), Some(labels)) =
+                (&trees[trees.len() - 1], last_bcst, &leaf_labels)
+            else {
+                return Err(DcgError::NoSyntaxTree(file.to_string()).into());
+            };
+
+            let leaves = leaves(b);
+            let root = tree.root_node();
+            let mut cursor = root.walk();
+
+            for node in root.named_children(&mut cursor) {
+                let range = node.byte_range();
+
+                let label = leaves
+                    .iter()
+                    .zip(labels)
+                    .filter(|(x, _)| {
+                        x.byte_range.start >= range.start && x.byte_range.end <= range.end
+                    })
+                    .map(|(_, l)| *l)
+                    .max()
+                    .unwrap_or(0);
+
+                let o = &origins[label];
+                let (start, end) = (node.start_position().row + 1, node.end_position().row + 1);
+
+                println!(
+                    "{} ({:<width$} {} {}-{}) {}",
+                    o.hash,
+                    o.author,
+                    o.date,
+                    start,
+                    end,
+                    lines.get(start - 1).unwrap_or(&"")
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
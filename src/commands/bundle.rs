@@ -0,0 +1,64 @@
+//! Transfer of history through bundle files
+use std::{env, fs};
+
+use anyhow::Result;
+use clap::Subcommand;
+
+use crate::{
+    info,
+    vcs::{
+        bundle::{create_bundle, Bundle},
+        config::Config,
+        find_repo,
+        remote::RemoteRepository,
+    },
+    NotificationLevel,
+};
+
+#[derive(Subcommand)]
+pub(crate) enum BundleCommand {
+    /// write the commits of a range to a bundle file
+    Create {
+        /// the bundle file to write
+        file: String,
+        /// the commits to bundle: a branch or a tag for its whole
+        /// history, or '<from>..<branch>' for the commits 'from' does
+        /// not have
+        range: String,
+    },
+    /// store the commits of a bundle and list its branches and tags
+    Unbundle {
+        /// the bundle file to read
+        file: String,
+    },
+}
+
+pub(crate) fn bundle(command: &BundleCommand, _cfg: &Config, lvl: NotificationLevel) -> Result<()> {
+    let wd = env::current_dir().map(fs::canonicalize)??.into_boxed_path();
+    let dd = find_repo(&wd)?;
+
+    match command {
+        BundleCommand::Create { file, range } => {
+            let n = create_bundle(dd, &wd.join(file), range)?;
+
+            info!(lvl, "{} commits bundled in '{}'", n, file);
+        }
+        BundleCommand::Unbundle { file } => {
+            let mut bundle = Bundle::open(&wd.join(file))?;
+            let n = bundle.unbundle(dd)?;
+            let refs = bundle.refs()?;
+
+            info!(lvl, "{} new commits stored from '{}'", n, file);
+
+            for (branch, h) in &refs.branches {
+                println!("{} {}", hex::encode(h), branch);
+            }
+
+            for (tag, h) in &refs.tags {
+                println!("{} tags/{}", hex::encode(h), tag);
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,158 @@
+//! Replay of a commit on top of the current branch
+use std::{env, fs, path::Path};
+
+use anyhow::Result;
+
+use crate::{
+    backend::linguist::LinguistState,
+    commands::commit::{record, CommitOptions},
+    info,
+    vcs::{
+        checkout::ensure_clean,
+        commit::{fetch_head, get_branch, CommitObject},
+        config::Config,
+        find_repo,
+        index::{get_indexed_files, stage},
+        merge::replay,
+        pending::Pending,
+        revision::resolve_revision,
+        snapshot::Snapshot,
+        DcgError, CHERRY_PICK_DIR,
+    },
+    NotificationLevel,
+};
+
+/// `<short hash> (<subject>)`, naming the side of a picked commit in
+/// conflict markers.
+pub(crate) fn label(h: [u8; 32], commit: &CommitObject) -> String {
+    format!(
+        "{} ({})",
+        hex::encode(&h[..4]),
+        commit.message.lines().next().unwrap_or("")
+    )
+}
+
+/// Fail unless the working tree and the index match the head of the
+/// current branch, which is returned.
+pub(crate) fn clean_head(dd: &Path) -> Result<Snapshot> {
+    let head = fetch_head(dd, &get_branch(dd)?)?.ok_or(DcgError::EmptyTree)?;
+    let tip = Snapshot::at(dd, Some(head))?;
+
+    ensure_clean(dd, &tip)?;
+
+    let staged = get_indexed_files(dd)?;
+
+    if !staged.is_empty() {
+        return Err(DcgError::UncommittedChanges(staged).into());
+    }
+
+    Ok(tip)
+}
+
+fn pick(
+    dd: &Path,
+    revision: &str,
+    state: LinguistState,
+    cfg: &Config,
+    lvl: NotificationLevel,
+) -> Result<()> {
+    Pending::ensure_none(dd, "cherry-pick", CHERRY_PICK_DIR)?;
+
+    let h = resolve_revision(dd, revision)?;
+    let tip = clean_head(dd)?;
+    let commit = CommitObject::read(dd, h)?;
+
+    let replayed = replay(dd, state, h, &tip, false, Some(&label(h, &commit)))?;
+
+    if replayed.conflicts.is_empty() {
+        stage(dd, &replayed.files)?;
+        record(
+            dd,
+            &commit.message,
+            &CommitOptions {
+                origin: Some(h),
+                author: Some(commit.author.clone()),
+                action: Some("cherry-pick"),
+                ..Default::default()
+            },
+            state,
+            cfg,
+            lvl,
+        )?;
+
+        return Ok(());
+    }
+
+    let mut pending = Pending::new(dd, "cherry-pick", CHERRY_PICK_DIR);
+
+    pending.files = replayed.files;
+    pending.conflicts = replayed.conflicts.clone();
+    pending.write()?;
+    pending.write_field("commit", hex::encode(h))?;
+
+    info!(lvl, "could not apply {}", label(h, &commit));
+    info!(
+        lvl,
+        "resolve the conflicts and run 'dcg cherry-pick --continue', or 'dcg cherry-pick --abort'"
+    );
+
+    Err(DcgError::MergeConflict(replayed.conflicts).into())
+}
+
+/// Commit a cherry-pick once its conflicts are resolved.
+fn resume(dd: &Path, state: LinguistState, cfg: &Config, lvl: NotificationLevel) -> Result<()> {
+    let pending = Pending::read(dd, "cherry-pick", CHERRY_PICK_DIR)?;
+    let files = pending.resolved_files(dd)?;
+    let h = pending.read_hash("commit")?;
+    let commit = CommitObject::read(dd, h)?;
+
+    stage(dd, &files)?;
+    record(
+        dd,
+        &commit.message,
+        &CommitOptions {
+            origin: Some(h),
+            author: Some(commit.author.clone()),
+            action: Some("cherry-pick"),
+            ..Default::default()
+        },
+        state,
+        cfg,
+        lvl,
+    )?;
+
+    pending.finish()
+}
+
+/// Give up a cherry-pick, restoring the files it changed.
+fn abort(dd: &Path) -> Result<()> {
+    let pending = Pending::read(dd, "cherry-pick", CHERRY_PICK_DIR)?;
+
+    let paths = CommitObject::read(dd, pending.read_hash("commit")?)?
+        .changes
+        .into_iter()
+        .map(|c| c.path)
+        .collect::<Vec<_>>();
+
+    pending.abort(dd, &paths)
+}
+
+pub(crate) fn cherry_pick(
+    revision: &Option<String>,
+    resume_pick: bool,
+    abort_pick: bool,
+    state: LinguistState,
+    cfg: &Config,
+    lvl: NotificationLevel,
+) -> Result<()> {
+    let wd = env::current_dir().map(fs::canonicalize)??.into_boxed_path();
+    let dd = find_repo(&wd)?;
+
+    match (revision, resume_pick, abort_pick) {
+        (_, true, _) => resume(dd, state, cfg, lvl),
+        (_, _, true) => abort(dd),
+        (Some(r), _, _) => pick(dd, r, state, cfg, lvl),
+        /* clap requires one of them */
+        (None, _, _) => unreachable!(),
+    }
+}
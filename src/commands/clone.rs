@@ -0,0 +1,123 @@
+//! Creation of a repository from an existing one
+use std::{env, fs, path::Path};
+
+use anyhow::Result;
+
+use crate::{
+    combine_paths,
+    commands::init::init_repo,
+    info,
+    vcs::{
+        checkout::reset,
+        commit::make_tag,
+        remote::{add_remote, connect, is_local, local_path, update_tracking, RemoteRepository},
+        snapshot::Snapshot,
+        transfer::has_commit,
+        DcgError, DCG_DIR,
+    },
+    warning, NotificationLevel,
+};
+
+/// Copy the history of `remote` into the new repository `dst`, whose
+/// current branch is `branch`, and check out its tip.  With `depth`,
+/// only the last `depth` commits of `branch` are copied.
+fn copy_repo(
+    remote: &mut dyn RemoteRepository,
+    dst: &Path,
+    branch: &str,
+    depth: Option<usize>,
+    lvl: NotificationLevel,
+) -> Result<()> {
+    let refs = remote.refs()?;
+    let mut heads = refs.branches;
+
+    if depth.is_some() {
+        heads.retain(|b, _| b == branch);
+    }
+
+    let mut wanted = heads.values().copied().collect::<Vec<_>>();
+
+    if depth.is_none() {
+        wanted.extend(refs.tags.values());
+    }
+
+    remote.fetch(dst, &wanted, depth)?;
+
+    for (b, h) in &heads {
+        update_tracking(dst, "origin", b, Some(*h))?;
+    }
+
+    /* tags of commits left out of a shallow clone are not copied */
+    for (tag, h) in refs.tags {
+        if has_commit(dst, h) {
+            make_tag(dst, h, &tag)?;
+        }
+    }
+
+    match heads.get(branch) {
+        Some(h) => {
+            reset(dst, &Snapshot::at(dst, None)?, *h, "clone")?;
+        }
+        None => warning!(lvl, "You appear to have cloned an empty repository."),
+    }
+
+    Ok(())
+}
+
+pub(crate) fn clone(
+    source: &str,
+    directory: &Option<String>,
+    depth: Option<usize>,
+    lvl: NotificationLevel,
+) -> Result<()> {
+    let wd = env::current_dir().map(fs::canonicalize)??.into_boxed_path();
+
+    /* paths are recorded absolute, as they are relative to the
+     * repository once recorded */
+    let url = if is_local(source) {
+        local_path(&wd, source)?.to_string_lossy().to_string()
+    } else {
+        source.to_string()
+    };
+
+    let directory = match directory {
+        Some(d) => d.clone(),
+        None => url
+            .trim_end_matches('/')
+            .rsplit('/')
+            .next()
+            .map(|n| n.strip_suffix(".bundle").unwrap_or(n))
+            .filter(|n| !n.is_empty() && !n.contains(':'))
+            .map(str::to_string)
+            .ok_or(DcgError::InvalidRemote(source.to_string()))?,
+    };
+    let dst = wd.join(&directory);
+
+    let created = !dst.exists();
+
+    if !created && fs::read_dir(&dst)?.next().is_some() {
+        return Err(DcgError::DestinationExists(directory).into());
+    }
+
+    let mut remote = connect(&wd, &url, env::var("DCG_TOKEN").ok().as_deref())?;
+    let branch = remote.refs()?.head;
+
+    info!(lvl, "Cloning into '{}'...", directory);
+
+    fs::create_dir_all(&dst)?;
+
+    let cloned = init_repo(&dst, &branch, lvl)
+        .and_then(|_| add_remote(&dst, "origin", &url))
+        .and_then(|_| copy_repo(remote.as_mut(), &dst, &branch, depth, lvl));
+
+    /* leave nothing behind a failed clone */
+    if cloned.is_err() {
+        if created {
+            fs::remove_dir_all(&dst)?;
+        } else {
+            fs::remove_dir_all(combine_paths!(&dst, DCG_DIR))?;
+        }
+    }
+
+    cloned
+}
@@ -1,56 +1,100 @@
 use std::{
+    collections::BTreeSet,
     env,
     fs::{self, File},
-    io::Read,
+    io::{Read, Write},
+    path::Path,
     process::Command,
 };
 
 use anyhow::Result;
+use clap::Args;
 use mktemp::Temp;
 
 use crate::{
     backend::linguist::LinguistState,
-    error, info,
+    info,
     vcs::{
-        commit::{get_branch, Change, ChangeContent, CommitObject},
+        checkout::set_last,
+        commit::{
+            fetch_head, get_branch, get_parents, Change, ChangeContent, CommitObject, Signature,
+        },
         config::Config,
+        date::parse_commit_date,
         find_repo,
-        index::{compute_status, get_indexed_files},
+        index::{clear_index, compute_status, get_indexed_files, stage, Object},
+        snapshot::Snapshot,
         DcgError,
     },
     NotificationLevel,
 };
 
-pub(crate) fn commit(
-    message: &Option<String>,
+/// What a commit records besides its message and changes.
+#[derive(Default)]
+pub(crate) struct CommitOptions {
+    /// the commit this one was cherry-picked from
+    pub(crate) origin: Option<[u8; 32]>,
+    /// the parents of the commit, by default the head of the current
+    /// branch
+    pub(crate) parents: Option<Vec<[u8; 32]>>,
+    /// the author of the commit, by default the committer
+    pub(crate) author: Option<Signature>,
+    /// the command making the commit, as recorded in the reflog (by
+    /// default `commit`)
+    pub(crate) action: Option<&'static str>,
+}
+
+#[derive(Args)]
+pub(crate) struct CommitArgs {
+    /// the message to associate with the commit.
+    message: Option<String>,
+    /// add every tracked file of the working tree to the index
+    /// before committing.  files removed from the working tree are
+    /// deleted
+    #[arg(short, long)]
+    all: bool,
+    /// replace the last commit by one with the same parents and the
+    /// files of the index (or of the last commit, if the index is
+    /// empty)
+    #[arg(long)]
+    amend: bool,
+    /// keep the message of the amended commit instead of editing
+    /// it
+    #[arg(long, requires = "amend", conflicts_with = "message")]
+    no_edit: bool,
+    /// the author of the commit, as 'Name <email>'
+    #[arg(long)]
+    author: Option<String>,
+    /// the date of the commit, such as '2025-05-02 14:31 +0200' or
+    /// '@1746189065 +0200'.  without an offset, the local time zone
+    /// is used
+    #[arg(long)]
+    date: Option<String>,
+}
+
+/// Commit the changes in the index with `message`, printing a summary.
+pub(crate) fn record(
+    dd: &Path,
+    message: &str,
+    options: &CommitOptions,
     state: LinguistState,
     cfg: &Config,
     lvl: NotificationLevel,
-) -> Result<()> {
-    let wd = env::current_dir().map(fs::canonicalize)??.into_boxed_path();
-    let dd = find_repo(&wd)?.to_path_buf();
-
-    if message.is_none() && (cfg.commit.as_ref().and_then(|c| c.editor.as_ref())).is_none() {
-        return Err(DcgError::NoEditor.into());
-    }
-
-    if (cfg
-        .user
-        .as_ref()
-        .and_then(|u| u.name.as_ref().and(u.email.as_ref())))
-    .is_none()
-    {
-        return Err(DcgError::NoAuthor.into());
-    }
+) -> Result<[u8; 32]> {
+    let committer = Signature::now("COMMITTER", cfg)?;
+    let author = match &options.author {
+        Some(a) => a.clone(),
+        None => Signature::now("AUTHOR", cfg)?,
+    };
 
-    let files = compute_status()?.into_iter().map(|x| x.0);
+    let files = compute_status(dd)?.into_iter().map(|x| x.0);
     let mut added = 0;
     let mut modified = 0;
     let mut deleted = 0;
 
     let mut changes = Vec::new();
     for file in files {
-        let change = Change::from(state, &file, &dd)?;
+        let change = Change::from(state, &file, &dd.to_path_buf())?;
 
         if let Some(ch) = change {
             match ch.content {
@@ -63,30 +107,118 @@ pub(crate) fn commit(
         }
     }
 
-    if changes.is_empty() {
+    /* merges may not change the files of their first parent */
+    if changes.is_empty() && options.parents.as_ref().is_none_or(|p| p.len() < 2) {
         return Err(DcgError::NoChanges.into());
     }
 
-    let message = if let Some(msg) = message {
-        msg.to_string()
-    } else {
-        let mut s = String::new();
-        let tmp = Temp::new_file()?;
+    let mut commit = CommitObject::new(author, committer, message.to_string(), changes);
 
-        let tmppb = tmp.to_path_buf();
-        let fname = tmppb.as_os_str().to_str().unwrap_or("");
+    commit.origin = options.origin;
 
-        let cmd = cfg.commit.as_ref().unwrap().editor.as_ref().unwrap();
+    let action = options.action.unwrap_or("commit");
 
-        let st = Command::new(cmd).arg(fname).status()?;
+    let h = match &options.parents {
+        Some(parents) => commit.write_on(dd, parents, action)?,
+        None => commit.write(dd, action)?,
+    };
 
-        if !st.success() {
-            return Err(DcgError::FailedToWriteMessage.into());
-        }
+    info!(
+        lvl,
+        "[{} {}] {}",
+        get_branch(dd)?,
+        hex::encode(&h[..4]),
+        message.lines().next().unwrap_or("")
+    );
+    info!(
+        lvl,
+        "  {} files created, {} files deleted and {} files modified", added, deleted, modified
+    );
+
+    Ok(h)
+}
+
+/// Let the user edit `text` with the editor defined in the
+/// configuration.
+pub(crate) fn edit(cfg: &Config, text: &str) -> Result<String> {
+    let Some(cmd) = cfg.commit.as_ref().and_then(|c| c.editor.as_ref()) else {
+        return Err(DcgError::NoEditor.into());
+    };
+
+    let mut s = String::new();
+    let tmp = Temp::new_file()?;
+
+    File::create(&tmp)?.write_all(text.as_bytes())?;
+
+    let tmppb = tmp.to_path_buf();
+    let fname = tmppb.as_os_str().to_str().unwrap_or("");
+
+    let st = Command::new(cmd).arg(fname).status()?;
+
+    if !st.success() {
+        return Err(DcgError::FailedToWriteMessage.into());
+    }
+
+    File::open(tmp)?.read_to_string(&mut s)?;
+
+    Ok(s)
+}
+
+/// Stage the working tree version of every tracked file, that is every
+/// file of `tip` or of the index.  Files removed from the working tree
+/// are left out of the index, and so deleted by the next commit.
+fn stage_tracked(dd: &Path, tip: &Snapshot) -> Result<()> {
+    let mut paths = tip.files.keys().cloned().collect::<BTreeSet<_>>();
+
+    paths.extend(get_indexed_files(dd)?);
+
+    let files = paths
+        .into_iter()
+        .filter(|p| dd.join(p).is_file())
+        .collect::<Vec<_>>();
+
+    clear_index(dd)?;
+    stage(dd, &files)
+}
 
-        File::open(tmp)?.read_to_string(&mut s)?;
+/// The author of a commit: `base` or, by default, the user making the
+/// commit, with the identity and date given on the command line.
+fn author(args: &CommitArgs, base: Option<Signature>, cfg: &Config) -> Result<Signature> {
+    let mut author = match base {
+        Some(a) => a,
+        None => Signature::now("AUTHOR", cfg)?,
+    };
+
+    if let Some(a) = &args.author {
+        (author.name, author.email) = Signature::parse_identity(a)?;
+    }
+
+    if let Some(d) = &args.date {
+        (author.time, author.offset) = parse_commit_date(d)?;
+    }
+
+    Ok(author)
+}
+
+/// Replace the head of the current branch by a commit with the same
+/// parents and the files of the index or, if the index is empty, the
+/// files of the head.  The author of the head is kept.
+fn amend(
+    dd: &Path,
+    args: &CommitArgs,
+    state: LinguistState,
+    cfg: &Config,
+    lvl: NotificationLevel,
+) -> Result<()> {
+    let head = fetch_head(dd, &get_branch(dd)?)?.ok_or(DcgError::EmptyTree)?;
+    let tip = Snapshot::at(dd, Some(head))?;
+    let previous = CommitObject::read(dd, head)?;
+    let parents = get_parents(dd, head)?;
 
-        s
+    let message = match &args.message {
+        Some(msg) => msg.to_string(),
+        None if args.no_edit => previous.message.clone(),
+        None => edit(cfg, &previous.message)?,
     };
 
     if message.is_empty() {
@@ -95,21 +227,173 @@ pub(crate) fn commit(
         return Ok(());
     }
 
-    let commit = CommitObject::new(cfg.user.clone().unwrap(), message.clone(), changes)?;
+    if get_indexed_files(dd)?.is_empty() {
+        for path in tip.files.keys() {
+            let contents = tip.read(path)?.ok_or(DcgError::InvalidCommit)?;
 
-    let h = commit.write(&dd)?;
+            Object::new(path, contents).write(dd)?;
+        }
+    }
 
-    info!(
-        lvl,
-        "[{} {}] {}",
-        get_branch(dd)?,
-        hex::encode(&h[..4]),
-        message.lines().next().unwrap()
-    );
-    info!(
-        lvl,
-        "  {} files created, {} files deleted and {} files modified", added, deleted, modified
-    );
+    /* the changes are recorded against the first parent, as if the
+     * head had never been committed
+     */
+    set_last(dd, &Snapshot::at(dd, parents.first().copied())?)?;
+
+    let options = CommitOptions {
+        origin: previous.origin,
+        parents: Some(parents),
+        author: Some(author(args, Some(previous.author), cfg)?),
+        action: Some("commit (amend)"),
+    };
+
+    match record(dd, &message, &options, state, cfg, lvl) {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            set_last(dd, &tip)?;
+
+            Err(e)
+        }
+    }
+}
+
+pub(crate) fn commit(
+    args: &CommitArgs,
+    state: LinguistState,
+    cfg: &Config,
+    lvl: NotificationLevel,
+) -> Result<()> {
+    let wd = env::current_dir().map(fs::canonicalize)??.into_boxed_path();
+    let dd = find_repo(&wd)?.to_path_buf();
+
+    if args.message.is_none()
+        && !args.no_edit
+        && (cfg.commit.as_ref().and_then(|c| c.editor.as_ref())).is_none()
+    {
+        return Err(DcgError::NoEditor.into());
+    }
+
+    if args.all {
+        let tip = Snapshot::at(&dd, fetch_head(&dd, &get_branch(&dd)?)?)?;
+
+        stage_tracked(&dd, &tip)?;
+    }
+
+    if args.amend {
+        return amend(&dd, args, state, cfg, lvl);
+    }
+
+    let options = CommitOptions {
+        author: Some(author(args, None, cfg)?),
+        ..Default::default()
+    };
+
+    let message = if let Some(msg) = &args.message {
+        msg.to_string()
+    } else {
+        edit(cfg, "")?
+    };
+
+    if message.is_empty() {
+        info!(lvl, "empty commit message. aborting.");
+
+        return Ok(());
+    }
+
+    record(&dd, &message, &options, state, cfg, lvl)?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use std::{collections::BTreeMap, fs, path::Path};
+
+    use super::{amend, record, CommitArgs, CommitOptions};
+    use crate::{
+        vcs::{
+            checkout::checkout,
+            commit::{get_parents, CommitObject, Signature},
+            config::{Config, User},
+            index::stage,
+            revision::resolve_revision,
+            snapshot::Snapshot,
+            testing::{commit_and_check_out, repo},
+        },
+        NotificationLevel,
+    };
+
+    #[test]
+    fn amend_and_check_out_previous_head() {
+        let dd = repo();
+        let empty = BTreeMap::new();
+        let state = (&empty, &empty, &empty, &empty);
+        let lvl = NotificationLevel::Errors;
+        let cfg = Config {
+            user: Some(User {
+                name: Some("C O Mitter".to_string()),
+                email: Some("c@o.mi".to_string()),
+            }),
+            ..Config::default()
+        };
+        let no_edit = CommitArgs {
+            message: None,
+            all: false,
+            amend: true,
+            no_edit: true,
+            author: None,
+            date: None,
+        };
+        let author = Signature {
+            name: "A U Thor".to_string(),
+            email: "a@u.th".to_string(),
+            time: 1_600_000_000,
+            offset: 7200,
+        };
+
+        let base = commit_and_check_out(&dd, &[("a.txt", "a\n")]);
+
+        fs::write(dd.join("b.txt"), "b\n").unwrap();
+        stage(&dd, &["b.txt".into()]).unwrap();
+
+        let options = CommitOptions {
+            author: Some(author.clone()),
+            ..Default::default()
+        };
+        let head = record(&dd, "Add b.txt", &options, state, &cfg, lvl).unwrap();
+
+        /* within the same second, but with other changes */
+        fs::write(dd.join("c.txt"), "c\n").unwrap();
+        stage(&dd, &["c.txt".into()]).unwrap();
+        amend(&dd, &no_edit, state, &cfg, lvl).unwrap();
+
+        let amended = resolve_revision(&dd, "HEAD").unwrap();
+        let commit = CommitObject::read(&dd, amended).unwrap();
+
+        assert_ne!(amended, head);
+        assert_eq!(get_parents(&dd, amended).unwrap(), vec![base]);
+        assert_eq!(commit.author, author);
+        assert_eq!(commit.committer.name, "C O Mitter");
+        assert_eq!(commit.message, "Add b.txt");
+        assert_eq!(resolve_revision(&dd, "HEAD@{1}").unwrap(), head);
+
+        /* amending again without changes keeps both commits readable */
+        amend(&dd, &no_edit, state, &cfg, lvl).unwrap();
+
+        let last = resolve_revision(&dd, "HEAD").unwrap();
+        let previous = resolve_revision(&dd, "HEAD@{1}").unwrap();
+
+        assert_eq!(previous, amended);
+        assert_eq!(get_parents(&dd, last).unwrap(), vec![base]);
+        assert_eq!(CommitObject::read(&dd, last).unwrap().author, author);
+
+        /* the head before the first amend is still whole */
+        let before = Snapshot::at(&dd, Some(resolve_revision(&dd, "HEAD@{2}").unwrap())).unwrap();
+
+        checkout(&dd, &Snapshot::at(&dd, Some(last)).unwrap(), &before).unwrap();
+
+        assert_eq!(fs::read_to_string(dd.join("a.txt")).unwrap(), "a\n");
+        assert_eq!(fs::read_to_string(dd.join("b.txt")).unwrap(), "b\n");
+        assert!(!Path::new(&dd.join("c.txt")).exists());
+    }
+}
@@ -1,27 +1,59 @@
 use std::{
+    collections::BTreeSet,
     env,
     fs::{self},
-    io::Write,
-    path::Path,
+    path::{Path, PathBuf},
 };
 
 use anyhow::Result;
-use flate2::write::GzDecoder;
 use glob::glob;
+use mktemp::Temp;
 
 use crate::{
     backend::{linear, linguist::LinguistState},
-    commands::visit_dirs,
+    commands::{matches_pathspec, parse_revisions, visit_dirs, RevisionArg},
     debug,
     vcs::{
         commit::{Change, ChangeContent},
         config::Config,
-        diffs::{deserialise_everything, DiffType},
-        find_repo,
+        diffs::{deserialise_everything, do_diff, get_diff_type, split_tree_diff, DiffType},
+        find_repo, gz_decode,
+        index::{get_fname, get_indexed_files},
+        revision::merge_base,
+        snapshot::Snapshot,
+        DcgError,
     },
     NotificationLevel,
 };
 
+/// Print a diff returned by `do_diff`, given the plain contents of its
+/// left file.
+fn print_diff(dt: DiffType, left: &[u8], d: &[u8]) -> Result<()> {
+    match dt {
+        DiffType::FromBinary(_) => {
+            println!("\n{}", String::from_utf8_lossy(d));
+        }
+        DiffType::Binary => println!(" file is binary"),
+        DiffType::Linear(_, _) => {
+            let text = format!("{}\n", str::from_utf8(left)?);
+            let ll = text.split_terminator('\n').collect::<Vec<&str>>();
+
+            let d = linear::deserialise(d).ok_or(DcgError::InvalidDiff)?;
+
+            println!();
+            linear::pretty_print(&ll, &d);
+        }
+        DiffType::Tree(_) => {
+            let d = deserialise_everything(split_tree_diff(d)?.0, str::from_utf8(left)?)?;
+
+            println!();
+            println!("{:?}", d);
+        }
+    }
+
+    Ok(())
+}
+
 fn diff_file(state: LinguistState, f: &Path, dd: &Path) -> Result<()> {
     // basically what `compute_status` does but for only one file
     let ch = Change::from(state, f, dd)?;
@@ -33,46 +65,123 @@ fn diff_file(state: LinguistState, f: &Path, dd: &Path) -> Result<()> {
             ChangeContent::Addition(_) => println!(" file was created"),
             ChangeContent::Deletion => println!(" file was deleted"),
             ChangeContent::Modification(dt, _, d) => {
-                let mut writer = Vec::new();
+                let left = gz_decode(&ch.file)?;
+
+                match dt {
+                    DiffType::FromBinary(_) => print_diff(dt, &left, &gz_decode(&d)?)?,
+                    _ => print_diff(dt, &left, &d)?,
+                }
+            }
+        }
+    }
 
-                let text = if !matches!(dt, DiffType::FromBinary(_) | DiffType::Binary) {
-                    let mut decoder = GzDecoder::new(writer);
+    Ok(())
+}
 
-                    decoder.write_all(&ch.file)?;
-                    writer = decoder.finish()?;
+/// Diff the plain contents of two versions of `path`.
+fn diff_contents(state: LinguistState, path: &Path, left: &[u8], right: &[u8]) -> Result<()> {
+    /* the language is partly guessed from the file name */
+    let ld = Temp::new_dir()?;
+    let rd = Temp::new_dir()?;
 
-                    str::from_utf8(&writer)?
-                } else {
-                    ""
-                };
+    let lf = ld.join(get_fname(path));
+    let rf = rd.join(get_fname(path));
 
-                match dt {
-                    DiffType::FromBinary(_) => {
-                        println!("\n{}", String::from_utf8(d)?);
-                    }
-                    DiffType::Binary => println!(" file is binary"),
-                    DiffType::Linear(_, _) => {
-                        let mut ll = text.lines().collect::<Vec<&str>>();
-                        ll.push("");
-
-                        println!();
-                        linear::pretty_print(&ll, &linear::deserialise(&d));
-                    }
-                    DiffType::Tree(_) => {
-                        let d = deserialise_everything(&d, text)?;
-
-                        println!();
-                        println!("{:?}", d);
-                    }
-                }
+    fs::write(&lf, left)?;
+    fs::write(&rf, right)?;
+
+    let dt = get_diff_type(state, &lf, &rf)?;
+    let d = do_diff(dt, &lf, &rf, false)?;
+
+    print_diff(dt, left, &d)
+}
+
+/// Read `path` from a snapshot, or from the working tree if there is
+/// no snapshot.
+fn read_version(dd: &Path, snapshot: Option<&Snapshot>, path: &Path) -> Result<Option<Vec<u8>>> {
+    match snapshot {
+        Some(s) => s.read(path),
+        None => {
+            let full_path = dd.join(path);
+
+            if full_path.is_file() {
+                Ok(Some(fs::read(full_path)?))
+            } else {
+                Ok(None)
             }
         }
     }
+}
+
+/// Diff a commit against another commit or against the working tree.
+pub(crate) fn diff_revisions(
+    state: LinguistState,
+    dd: &Path,
+    left: &Snapshot,
+    right: Option<&Snapshot>,
+    specs: &[String],
+) -> Result<()> {
+    let mut paths = left.files.keys().cloned().collect::<BTreeSet<PathBuf>>();
+
+    match right {
+        Some(r) => paths.extend(r.files.keys().cloned()),
+        None => paths.extend(get_indexed_files(dd)?),
+    }
+
+    for path in paths.iter().filter(|p| matches_pathspec(p, specs)) {
+        if let Some(r) = right {
+            if left.files.get(path) == r.files.get(path) {
+                continue;
+            }
+        }
+
+        let l = left.read(path)?;
+        let r = read_version(dd, right, path)?;
+
+        if l == r {
+            continue;
+        }
+
+        print!("{}:", path.display());
+
+        match (l, r) {
+            (None, _) => println!(" file was created"),
+            (_, None) => println!(" file was deleted"),
+            (Some(l), Some(r)) => diff_contents(state, path, &l, &r)?,
+        }
+    }
 
     Ok(())
 }
 
+/// Commits to compare.  A `None` commit is the empty history.
+type Revisions = Vec<Option<[u8; 32]>>;
+
+/// Split the arguments of `dcg diff` into the commits to compare and
+/// the files to diff.
+fn parse_arguments(
+    dd: &Path,
+    args: &[String],
+    files: &[String],
+) -> Result<(Revisions, Vec<String>)> {
+    let (revisions, paths) = parse_revisions(dd, args, files, 2)?;
+
+    let revisions = match revisions[..] {
+        [] => vec![],
+        [RevisionArg::Single(a)] => vec![Some(a)],
+        [RevisionArg::Single(a), RevisionArg::Single(b)] | [RevisionArg::Range(a, b)] => {
+            vec![Some(a), Some(b)]
+        }
+        [RevisionArg::Symmetric(a, b)] => vec![merge_base(dd, a, b)?, Some(b)],
+        /* a range after a revision */
+        _ => return Err(DcgError::UnknownRevision(args[1].clone()).into()),
+    };
+
+    Ok((revisions, paths))
+}
+
 pub(crate) fn diff(
+    args: &[String],
     files: &[String],
     state: LinguistState,
     _cfg: &Config,
@@ -81,9 +190,44 @@ pub(crate) fn diff(
     let wd = env::current_dir().map(fs::canonicalize)??.into_boxed_path();
     let dd = find_repo(&wd)?;
 
+    let (revisions, files) = parse_arguments(dd, args, files)?;
+
+    match revisions[..] {
+        [left] => {
+            debug!(
+                lvl,
+                "comparing {:?} to the working tree",
+                left.map(hex::encode)
+            );
+
+            return diff_revisions(state, dd, &Snapshot::at(dd, left)?, None, &files);
+        }
+        [left, right] => {
+            debug!(
+                lvl,
+                "comparing {:?} to {:?}",
+                left.map(hex::encode),
+                right.map(hex::encode)
+            );
+
+            return diff_revisions(
+                state,
+                dd,
+                &Snapshot::at(dd, left)?,
+                Some(&Snapshot::at(dd, right)?),
+                &files,
+            );
+        }
+        _ => {}
+    }
+
     let all_glob = [String::from("*")];
 
-    let files = if files.is_empty() { &all_glob } else { files };
+    let files = if files.is_empty() {
+        &all_glob
+    } else {
+        &files[..]
+    };
 
     for path in files {
         for entry in glob(path)? {
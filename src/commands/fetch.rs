@@ -0,0 +1,92 @@
+//! Download of the history of a remote repository
+use std::{env, fs, path::Path};
+
+use anyhow::Result;
+
+use crate::{
+    combine_paths, info,
+    vcs::{
+        commit::make_tag,
+        config::Config,
+        find_repo,
+        remote::{connect, remote_token, remote_url, tracking_branches, update_tracking},
+        revision::ancestors,
+        DCG_DIR, TAGS_DIR,
+    },
+    NotificationLevel,
+};
+
+/// `old..new` for a branch moved forward, `old...new` otherwise.
+pub(crate) fn describe_update(dd: &Path, old: [u8; 32], new: [u8; 32]) -> Result<String> {
+    let (old_s, new_s) = (hex::encode(&old[..4]), hex::encode(&new[..4]));
+
+    Ok(if ancestors(dd, new)?.contains(&old) {
+        format!("   {}..{}", old_s, new_s)
+    } else {
+        format!(" + {}...{}", old_s, new_s)
+    })
+}
+
+/// Copy the commits of the remote `name` which are missing from the
+/// repository `dd`, and record the heads of its branches under
+/// `refs/remotes/<name>/`.  Tags which do not exist locally are copied
+/// too.
+pub(crate) fn fetch_remote(
+    dd: &Path,
+    name: &str,
+    cfg: &Config,
+    lvl: NotificationLevel,
+) -> Result<()> {
+    let url = remote_url(dd, name, cfg)?;
+    let mut remote = connect(dd, &url, remote_token(dd, name, cfg)?.as_deref())?;
+    let refs = remote.refs()?;
+
+    let wanted = refs
+        .branches
+        .values()
+        .chain(refs.tags.values())
+        .copied()
+        .collect::<Vec<_>>();
+
+    remote.fetch(dd, &wanted, None)?;
+
+    info!(lvl, "From {}", url);
+
+    let known = tracking_branches(dd, name)?;
+
+    for (branch, h) in &refs.branches {
+        let line = match known.get(branch) {
+            Some(old) if old == h => continue,
+            Some(old) => describe_update(dd, *old, *h)?,
+            None => " * [new branch]".to_string(),
+        };
+
+        update_tracking(dd, name, branch, Some(*h))?;
+
+        info!(lvl, "{}\t{} -> {}/{}", line, branch, name, branch);
+    }
+
+    /* branches deleted from the remote */
+    for branch in known.keys().filter(|b| !refs.branches.contains_key(*b)) {
+        update_tracking(dd, name, branch, None)?;
+
+        info!(lvl, " - [deleted]\t{}/{}", name, branch);
+    }
+
+    for (tag, h) in refs.tags {
+        if !combine_paths!(dd, DCG_DIR, TAGS_DIR, &tag).is_file() {
+            make_tag(dd, h, &tag)?;
+
+            info!(lvl, " * [new tag]\t{} -> {}", tag, tag);
+        }
+    }
+
+    Ok(())
+}
+
+pub(crate) fn fetch(remote: &Option<String>, cfg: &Config, lvl: NotificationLevel) -> Result<()> {
+    let wd = env::current_dir().map(fs::canonicalize)??.into_boxed_path();
+    let dd = find_repo(&wd)?;
+
+    fetch_remote(dd, remote.as_deref().unwrap_or("origin"), cfg, lvl)
+}
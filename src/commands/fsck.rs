@@ -0,0 +1,33 @@
+//! Verification of the integrity of a repository
+use std::{env, fs};
+
+use anyhow::Result;
+
+use crate::{
+    info,
+    vcs::{config::Config, find_repo, fsck::fsck as check_repository, DcgError},
+    NotificationLevel,
+};
+
+/// List what is wrong with the repository.  Dangling commits and
+/// objects are listed too, but only corrupt and missing objects make
+/// the command fail.
+pub(crate) fn fsck(_cfg: &Config, lvl: NotificationLevel) -> Result<()> {
+    let wd = env::current_dir().map(fs::canonicalize)??.into_boxed_path();
+    let dd = find_repo(&wd)?;
+
+    let problems = check_repository(dd)?;
+
+    for p in &problems {
+        println!("{}", p);
+    }
+
+    match problems.iter().filter(|p| p.is_error()).count() {
+        0 => {
+            info!(lvl, "no corrupt or missing objects");
+
+            Ok(())
+        }
+        n => Err(DcgError::DamagedRepository(n).into()),
+    }
+}
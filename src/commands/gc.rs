@@ -0,0 +1,95 @@
+//! Cleanup of the objects and contents no ref or file refers to
+use std::{env, fs};
+
+use anyhow::Result;
+
+use crate::{
+    info,
+    vcs::{
+        config::Config,
+        date::parse_date,
+        find_repo,
+        gc::{collect, find_garbage, prune as prune_garbage, Garbage},
+    },
+    NotificationLevel,
+};
+
+/// How long unreachable objects are kept by default, as they may belong
+/// to a command still running.
+const DEFAULT_EXPIRE: &str = "2 weeks ago";
+
+fn list_garbage(garbage: &Garbage) {
+    for p in garbage.loose.iter().chain(&garbage.stale) {
+        println!("would remove {}", p.display());
+    }
+
+    for p in &garbage.packed {
+        println!("would drop {} from the packs", p.display());
+    }
+}
+
+/// Remove the unreachable objects and the stale contents of the index
+/// which were last modified before `expire`, then pack all the other
+/// objects.
+pub(crate) fn gc(
+    dry_run: bool,
+    expire: &Option<String>,
+    _cfg: &Config,
+    lvl: NotificationLevel,
+) -> Result<()> {
+    let wd = env::current_dir().map(fs::canonicalize)??.into_boxed_path();
+    let dd = find_repo(&wd)?;
+
+    let garbage = find_garbage(dd, parse_date(expire.as_deref().unwrap_or(DEFAULT_EXPIRE))?)?;
+
+    if dry_run {
+        list_garbage(&garbage);
+
+        return Ok(());
+    }
+
+    let n = collect(dd, &garbage)?;
+
+    info!(
+        lvl,
+        "{} objects removed, {} objects packed",
+        garbage.loose.len() + garbage.stale.len() + garbage.packed.len(),
+        n
+    );
+
+    Ok(())
+}
+
+/// Remove the unreachable loose objects and the stale contents of the
+/// index which were last modified before `expire`.  Packs are left as
+/// they are.
+pub(crate) fn prune(
+    dry_run: bool,
+    expire: &Option<String>,
+    _cfg: &Config,
+    lvl: NotificationLevel,
+) -> Result<()> {
+    let wd = env::current_dir().map(fs::canonicalize)??.into_boxed_path();
+    let dd = find_repo(&wd)?;
+
+    let garbage = Garbage {
+        packed: Vec::new(),
+        ..find_garbage(dd, parse_date(expire.as_deref().unwrap_or(DEFAULT_EXPIRE))?)?
+    };
+
+    if dry_run {
+        list_garbage(&garbage);
+
+        return Ok(());
+    }
+
+    prune_garbage(dd, &garbage)?;
+
+    info!(
+        lvl,
+        "{} objects removed",
+        garbage.loose.len() + garbage.stale.len()
+    );
+
+    Ok(())
+}
@@ -0,0 +1,99 @@
+//! Drawing of the commit graph shown by `dcg log --graph`
+use std::slice;
+
+/// The lanes of the graph.  Commits must be placed in topological
+/// order.
+pub(crate) struct Graph {
+    /// the commit each lane leads to
+    lanes: Vec<[u8; 32]>,
+}
+
+impl Graph {
+    pub(crate) fn new() -> Self {
+        Self { lanes: Vec::new() }
+    }
+
+    fn row(&self, commit: Option<usize>) -> String {
+        (0..self.lanes.len())
+            .map(|i| if Some(i) == commit { "*" } else { "|" })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// Place commit `h` on its lane, opening a new lane if no commit
+    /// placed so far leads to it, and return its row.
+    pub(crate) fn commit(&mut self, h: [u8; 32]) -> String {
+        let col = match self.lanes.iter().position(|x| *x == h) {
+            Some(col) => col,
+            None => {
+                self.lanes.push(h);
+                self.lanes.len() - 1
+            }
+        };
+
+        self.row(Some(col))
+    }
+
+    /// The row continuing every lane, to print next to the lines
+    /// following a commit.
+    pub(crate) fn padding(&self) -> String {
+        self.row(None)
+    }
+
+    /// Make the lanes leading to `h` lead to its parents instead, and
+    /// return the rows moving the lanes to their new places.
+    pub(crate) fn advance(&mut self, h: [u8; 32], parents: &[[u8; 32]]) -> Vec<String> {
+        let mut lanes = Vec::new();
+        let mut edges = Vec::new();
+
+        for (i, lane) in self.lanes.iter().enumerate() {
+            let targets = if *lane == h {
+                parents
+            } else {
+                slice::from_ref(lane)
+            };
+
+            for t in targets {
+                let j = match lanes.iter().position(|x| x == t) {
+                    Some(j) => j,
+                    None => {
+                        lanes.push(*t);
+                        lanes.len() - 1
+                    }
+                };
+
+                edges.push((i, j));
+            }
+        }
+
+        self.lanes = lanes;
+
+        let mut rows = Vec::new();
+
+        /* every row moves each edge one lane closer to its target */
+        while edges.iter().any(|(i, j)| i != j) {
+            let width = edges
+                .iter()
+                .map(|(i, j)| 2 * i.max(j) + 1)
+                .max()
+                .unwrap_or(0);
+            let mut row = vec![' '; width];
+
+            for (i, j) in &mut edges {
+                if *i == *j {
+                    row[2 * *i] = '|';
+                } else if *i < *j {
+                    row[2 * *i + 1] = '\\';
+                    *i += 1;
+                } else {
+                    row[2 * *i - 1] = '/';
+                    *i -= 1;
+                }
+            }
+
+            rows.push(row.into_iter().collect::<String>().trim_end().to_string());
+        }
+
+        rows
+    }
+}
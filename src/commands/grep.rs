@@ -0,0 +1,297 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use regex::{Regex, RegexBuilder};
+use streaming_iterator::StreamingIterator;
+use tree_sitter::{Parser, Query, QueryCursor};
+
+use crate::{
+    backend::{
+        languages::Languages,
+        linguist::{get_ts_language, LinguistState},
+    },
+    commands::{guess_contents_language, matches_pathspec},
+    debug,
+    vcs::{
+        commit::{fetch_head, get_branch, get_parents},
+        config::Config,
+        find_repo,
+        index::get_indexed_files,
+        revision::{resolve_revision, RevWalk},
+        snapshot::Snapshot,
+    },
+    NotificationLevel,
+};
+
+/// What `dcg grep` looks for.
+enum Matcher {
+    Regex(Regex),
+    /// a tree-sitter query, compiled for each language it is run on
+    Query(String, BTreeMap<Languages, Option<Query>>),
+}
+
+impl Matcher {
+    /// The lines (counted from 0) holding a match, once per match.
+    fn find(
+        &mut self,
+        state: LinguistState,
+        lvl: NotificationLevel,
+        path: &Path,
+        text: &str,
+    ) -> Result<Vec<usize>> {
+        match self {
+            Self::Regex(re) => Ok(text
+                .split_terminator('\n')
+                .enumerate()
+                .filter(|(_, l)| re.is_match(l))
+                .map(|(i, _)| i)
+                .collect()),
+            Self::Query(source, queries) => {
+                let language = guess_contents_language(state, path, text.as_bytes())?;
+
+                let Some(ts_language) = get_ts_language(language) else {
+                    return Ok(Vec::new());
+                };
+
+                let query = queries.entry(language).or_insert_with(|| {
+                    Query::new(&ts_language, source)
+                        .inspect_err(|e| {
+                            debug!(lvl, "query does not apply to {:?}: {}", language, e)
+                        })
+                        .ok()
+                });
+
+                let Some(query) = query else {
+                    return Ok(Vec::new());
+                };
+
+                let mut parser = Parser::new();
+
+                parser.set_language(&ts_language)?;
+
+                let Some(tree) = parser.parse(text, None) else {
+                    return Ok(Vec::new());
+                };
+
+                let mut cursor = QueryCursor::new();
+                let mut matches = cursor.matches(query, tree.root_node(), text.as_bytes());
+                let mut rows = Vec::new();
+
+                while let Some(m) = matches.next() {
+                    if let Some(row) = m.captures.iter().map(|c| c.node.start_position().row).min()
+                    {
+                        rows.push(row);
+                    }
+                }
+
+                Ok(rows)
+            }
+        }
+    }
+}
+
+/// Where to search.
+enum Source {
+    WorkingTree(PathBuf),
+    Revision(String, Snapshot),
+}
+
+impl Source {
+    fn files(&self) -> Result<BTreeSet<PathBuf>> {
+        match self {
+            Self::WorkingTree(dd) => {
+                let head = fetch_head(dd, &get_branch(dd)?)?;
+
+                let mut files = Snapshot::at(dd, head)?
+                    .files
+                    .into_keys()
+                    .collect::<BTreeSet<_>>();
+
+                files.extend(get_indexed_files(dd)?);
+
+                Ok(files)
+            }
+            Self::Revision(_, s) => Ok(s.files.keys().cloned().collect()),
+        }
+    }
+
+    fn read(&self, path: &Path) -> Result<Option<Vec<u8>>> {
+        match self {
+            Self::WorkingTree(dd) => {
+                let full_path = dd.join(path);
+
+                if full_path.is_file() {
+                    Ok(Some(fs::read(full_path)?))
+                } else {
+                    Ok(None)
+                }
+            }
+            Self::Revision(_, s) => s.read(path),
+        }
+    }
+}
+
+/// Count the matches in `paths` at `snapshot`.
+fn count_matches(
+    matcher: &mut Matcher,
+    state: LinguistState,
+    lvl: NotificationLevel,
+    snapshot: &Snapshot,
+    paths: &BTreeSet<PathBuf>,
+) -> Result<usize> {
+    let mut count = 0;
+
+    for path in paths {
+        if let Some(text) = snapshot.read(path)?.and_then(|c| String::from_utf8(c).ok()) {
+            count += matcher.find(state, lvl, path, &text)?.len();
+        }
+    }
+
+    Ok(count)
+}
+
+/// List the commits changing the number of matches.
+fn log_matches(
+    matcher: &mut Matcher,
+    state: LinguistState,
+    lvl: NotificationLevel,
+    dd: &Path,
+    start: [u8; 32],
+    specs: &[String],
+) -> Result<()> {
+    for entry in RevWalk::new(dd, &[start])? {
+        let (h, commit) = entry?;
+
+        let paths = commit
+            .changes
+            .iter()
+            .map(|c| c.path.clone())
+            .filter(|p| matches_pathspec(p, specs))
+            .collect::<BTreeSet<_>>();
+
+        if paths.is_empty() {
+            continue;
+        }
+
+        let parent = get_parents(dd, h)?.first().copied();
+
+        let before = count_matches(matcher, state, lvl, &Snapshot::at(dd, parent)?, &paths)?;
+        let after = count_matches(matcher, state, lvl, &Snapshot::at(dd, Some(h))?, &paths)?;
+
+        if before != after {
+            println!(
+                "\x1b[0;33m{}\x1b[0m {} ({} -> {})",
+                hex::encode(&h[..4]),
+                commit.message.lines().next().unwrap_or(""),
+                before,
+                after
+            );
+        }
+    }
+
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn grep(
+    pattern: &str,
+    args: &[String],
+    files: &[String],
+    query: bool,
+    ignore_case: bool,
+    count: bool,
+    names_only: bool,
+    log: bool,
+    state: LinguistState,
+    _cfg: &Config,
+    lvl: NotificationLevel,
+) -> Result<()> {
+    let wd = env::current_dir().map(fs::canonicalize)??.into_boxed_path();
+    let dd = find_repo(&wd)?.to_path_buf();
+
+    let mut matcher = if query {
+        Matcher::Query(pattern.to_string(), BTreeMap::new())
+    } else {
+        Matcher::Regex(
+            RegexBuilder::new(pattern)
+                .case_insensitive(ignore_case)
+                .build()?,
+        )
+    };
+
+    /* a first argument which is not a revision is a file */
+    let (revision, specs) = match args.first().map(|r| (r, resolve_revision(&dd, r))) {
+        Some((r, Ok(h))) => (Some((r.clone(), h)), &args[1..]),
+        Some((_, Err(e))) if !files.is_empty() => return Err(e),
+        _ => (None, args),
+    };
+
+    let mut specs = specs.to_vec();
+    specs.extend_from_slice(files);
+
+    if log {
+        let start = match revision {
+            Some((_, h)) => Some(h),
+            None => fetch_head(&dd, &get_branch(&dd)?)?,
+        };
+
+        return match start {
+            Some(h) => log_matches(&mut matcher, state, lvl, &dd, h, &specs),
+            None => Ok(()),
+        };
+    }
+
+    let source = match revision {
+        Some((r, h)) => Source::Revision(r, Snapshot::at(&dd, Some(h))?),
+        None => Source::WorkingTree(dd.clone()),
+    };
+
+    let prefix = match &source {
+        Source::Revision(r, _) => format!("{}:", r),
+        Source::WorkingTree(_) => String::new(),
+    };
+
+    for path in source
+        .files()?
+        .iter()
+        .filter(|p| matches_pathspec(p, &specs))
+    {
+        let Some(text) = source.read(path)?.and_then(|c| String::from_utf8(c).ok()) else {
+            continue;
+        };
+
+        let rows = matcher.find(state, lvl, path, &text)?;
+
+        if rows.is_empty() {
+            continue;
+        }
+
+        if names_only {
+            println!("{}{}", prefix, path.display());
+            continue;
+        }
+
+        if count {
+            println!("{}{}:{}", prefix, path.display(), rows.len());
+            continue;
+        }
+
+        let lines = text.split_terminator('\n').collect::<Vec<_>>();
+
+        for row in rows.into_iter().collect::<BTreeSet<_>>() {
+            println!(
+                "{}\x1b[0;35m{}\x1b[0m:\x1b[0;32m{}\x1b[0m:{}",
+                prefix,
+                path.display(),
+                row + 1,
+                lines.get(row).unwrap_or(&"")
+            );
+        }
+    }
+
+    Ok(())
+}
@@ -10,8 +10,8 @@ use anyhow::Result;
 use crate::{
     combine_paths, debug, info,
     vcs::{
-        config::Config, BASE_DIR, BLOBS_DIR, BRANCHES_DIR, DCG_DIR, INDEX_DIR, LAST_DIR, REFS_DIR,
-        TAGS_DIR, TREE_DIR,
+        config::Config, format::write_format, BASE_DIR, BLOBS_DIR, BRANCHES_DIR, DCG_DIR,
+        INDEX_DIR, LAST_DIR, REFS_DIR, TAGS_DIR, TREE_DIR,
     },
     NotificationLevel,
 };
@@ -27,22 +27,11 @@ const REPO_DIRECTORIES: [&str; 8] = [
     TAGS_DIR,
 ];
 
-pub(crate) fn init(
-    initial_branch: &Option<String>,
-    directory: &Option<String>,
-    cfg: &Config,
-    lvl: NotificationLevel,
-) -> Result<()> {
-    let initial_branch = initial_branch
-        .as_ref()
-        .or(cfg.init.as_ref().and_then(|x| x.default_branch.as_ref()))
-        .map_or("master", String::as_str);
-    let p_directory = combine_paths!(
-        directory
-            .as_ref()
-            .map_or(env::current_dir()?, |x| Path::new(&x).to_path_buf()),
-        DCG_DIR
-    );
+/// Create the `.dcg` directory of a repository at `root`, whose
+/// current branch is `initial_branch`.  An existing one is deleted
+/// first, in which case `true` is returned.
+pub(crate) fn init_repo(root: &Path, initial_branch: &str, lvl: NotificationLevel) -> Result<bool> {
+    let p_directory = combine_paths!(root, DCG_DIR);
 
     let reinit = if !p_directory.exists() {
         false
@@ -75,6 +64,27 @@ pub(crate) fn init(
 
     debug!(lvl, "created '.dcg/{}{}'", BRANCHES_DIR, initial_branch);
 
+    write_format(root)?;
+
+    Ok(reinit)
+}
+
+pub(crate) fn init(
+    initial_branch: &Option<String>,
+    directory: &Option<String>,
+    cfg: &Config,
+    lvl: NotificationLevel,
+) -> Result<()> {
+    let initial_branch = initial_branch
+        .as_ref()
+        .or(cfg.init.as_ref().and_then(|x| x.default_branch.as_ref()))
+        .map_or("master", String::as_str);
+    let root = directory
+        .as_ref()
+        .map_or(env::current_dir()?, |x| Path::new(&x).to_path_buf());
+
+    let reinit = init_repo(&root, initial_branch, lvl)?;
+
     info!(
         lvl,
         "{} dcg repository in '{}'",
@@ -1,61 +1,458 @@
-use std::{env, fs};
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    ops::Range,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
 
 use anyhow::Result;
-use time::{format_description::parse, OffsetDateTime};
+use clap::Args;
+use regex::Regex;
+use tree_sitter::{Language, Parser, Tree};
 
 use crate::{
-    backend::linguist::LinguistState,
+    backend::{
+        bcst::{diff_wrapper, BCSTree},
+        blame::leaves,
+        diff::ered,
+        linguist::{get_ts_language, LinguistState},
+        symbol::{find_symbol, relative_tree},
+    },
+    commands::{
+        graph::Graph,
+        guess_contents_language, matches_pathspec, parse_revisions,
+        pretty::{self, Color, DateFormat, Format, Printer},
+        RevisionArg,
+    },
     vcs::{
-        commit::{fetch_head, get_branch, get_parent, CommitObject},
+        commit::{fetch_head, get_branch, get_parents},
         config::Config,
+        date::parse_date,
         find_repo,
+        revision::{list_refs, RevWalk},
+        snapshot::Snapshot,
+        DcgError,
     },
     NotificationLevel,
 };
 
+#[derive(Args)]
+pub(crate) struct LogArgs {
+    /// display each commit on one line (same as '--format=oneline')
+    #[arg(long = "oneline")]
+    one_line: bool,
+
+    /// how to display the commits: 'medium' (the default), 'oneline',
+    /// a format defined in the configuration or a template such as
+    /// '%h %an %ad %s'.  see the manual for the placeholders
+    #[arg(long, visible_alias = "pretty")]
+    format: Option<String>,
+
+    /// how to display dates
+    #[arg(long, value_enum, default_value_t = DateFormat::Default)]
+    date: DateFormat,
+
+    /// when to colour the output
+    #[arg(long, value_enum, default_value_t = Color::Auto)]
+    color: Color,
+
+    /// draw the graph of the commits next to them
+    #[arg(long)]
+    graph: bool,
+
+    /// list the commits of every branch and tag
+    #[arg(long)]
+    all: bool,
+
+    /// show the branches and tags pointing to the commits (implied by
+    /// `--graph`)
+    #[arg(long)]
+    decorate: bool,
+
+    /// only list commits which author ('name <email>') matches this
+    /// regular expression
+    #[arg(long)]
+    author: Option<String>,
+
+    /// only list commits made after this date ('YYYY-MM-DD',
+    /// 'YYYY-MM-DD HH:MM:SS', a unix timestamp or for instance
+    /// '2 weeks ago')
+    #[arg(long, visible_alias = "after")]
+    since: Option<String>,
+
+    /// only list commits made before this date
+    #[arg(long, visible_alias = "before")]
+    until: Option<String>,
+
+    /// only list commits which message matches this regular
+    /// expression
+    #[arg(long)]
+    grep: Option<String>,
+
+    /// follow the definition of a symbol (a function, a type...) in
+    /// a file, given as ':symbol:file', and show how each commit
+    /// changed it.  the symbol is looked up at the commit to start
+    /// from
+    #[arg(short = 'L', value_name = ":SYMBOL:FILE", conflicts_with_all = ["graph", "all"])]
+    symbol: Option<String>,
+
+    /// list at most this number of commits
+    #[arg(short = 'n', long = "max-count")]
+    max_count: Option<usize>,
+
+    /// the commit to start from (by default the head of the current
+    /// branch) or a range 'a..b' of the commits reachable from 'b'
+    /// but not from 'a', followed by files: only the commits changing
+    /// these files are listed.  folders are matched recursively.
+    args: Vec<String>,
+
+    /// the files whose commits to list, when they could be mistaken
+    /// for a revision
+    #[arg(last = true)]
+    paths: Vec<String>,
+}
+
+/// The definition of a symbol followed by `dcg log -L`.
+struct SymbolTrace {
+    name: String,
+    path: PathBuf,
+    language: Language,
+    /// the contents of the file before and after the commits which
+    /// changed it, read a first-parent history at a time
+    versions: BTreeMap<[u8; 32], (Option<String>, Option<String>)>,
+}
+
+impl SymbolTrace {
+    /// Parse ':symbol:file'.  The symbol must be defined in the file
+    /// at commit `start`.
+    fn new(state: LinguistState, dd: &Path, spec: &str, start: [u8; 32]) -> Result<Self> {
+        let (name, path) = spec
+            .strip_prefix(':')
+            .and_then(|s| s.rsplit_once(':'))
+            .ok_or(DcgError::UnknownSymbol(spec.to_string()))?;
+
+        let path = PathBuf::from(path);
+        let contents = Snapshot::at(dd, Some(start))?
+            .read(&path)?
+            .ok_or(DcgError::UnknownSymbol(spec.to_string()))?;
+
+        let language = get_ts_language(guess_contents_language(state, &path, &contents)?)
+            .ok_or(DcgError::NoSyntaxTree(path.display().to_string()))?;
+
+        let trace = Self {
+            name: name.to_string(),
+            path,
+            language,
+            versions: BTreeMap::new(),
+        };
+
+        if trace.find(&String::from_utf8(contents)?)?.is_none() {
+            return Err(DcgError::UnknownSymbol(spec.to_string()).into());
+        }
+
+        Ok(trace)
+    }
+
+    /// The tree of the file and the node defining the symbol in it.
+    fn find(&self, src: &str) -> Result<Option<(Tree, Range<usize>)>> {
+        let mut parser = Parser::new();
+
+        parser.set_language(&self.language)?;
+
+        Ok(parser.parse(src, None).and_then(|t| {
+            let r = find_symbol(t.root_node(), src, &self.name)?.byte_range();
+
+            Some((t, r))
+        }))
+    }
+
+    /// Record the contents of the file before and after each commit of
+    /// the first-parent history of `h` which changed it.  Returns its
+    /// contents at `h`.
+    fn load(&mut self, dd: &Path, h: Option<[u8; 32]>) -> Result<Option<String>> {
+        let mut before = None;
+
+        for (ch, contents) in Snapshot::at(dd, h)?.history(&self.path)? {
+            let after = Some(String::from_utf8(contents)?);
+
+            self.versions.entry(ch).or_insert((before, after.clone()));
+            before = after;
+        }
+
+        Ok(before)
+    }
+
+    /// The contents of the file before and after commit `h`, which
+    /// changed it.  The walk goes from children to parents, so that
+    /// the history read for the first commit holds the next ones: each
+    /// of them only costs the diff replayed for it.
+    fn versions(&mut self, dd: &Path, h: [u8; 32]) -> Result<(Option<String>, Option<String>)> {
+        if !self.versions.contains_key(&h) {
+            self.load(dd, Some(h))?;
+        }
+
+        /* `h` deleted the file */
+        if !self.versions.contains_key(&h) {
+            let before = self.load(dd, get_parents(dd, h)?.first().copied())?;
+
+            self.versions.insert(h, (before, None));
+        }
+
+        Ok(self.versions[&h].clone())
+    }
+
+    /// How commit `h` changed the symbol, or `None` if it did not.
+    fn change(&mut self, dd: &Path, h: [u8; 32]) -> Result<Option<String>> {
+        let (left, right) = self.versions(dd, h)?;
+
+        let find = |src: &Option<String>| match src {
+            Some(s) => self.find(s),
+            None => Ok(None),
+        };
+
+        Ok(match (find(&left)?, find(&right)?) {
+            (None, None) => None,
+            (Some(_), None) => Some(format!("{} was deleted", self.name)),
+            (None, Some((_, r))) => Some(format!(
+                "{} was created:\n\n{}",
+                self.name,
+                &right.as_deref().unwrap()[r]
+            )),
+            (Some((lt, lr)), Some((rt, rr))) => {
+                let (left, right) = (left.as_deref().unwrap(), right.as_deref().unwrap());
+
+                if left[lr.clone()] == right[rr.clone()] {
+                    return Ok(None);
+                }
+
+                /* the nodes are looked up again in the trees which
+                 * outlive them
+                 */
+                let ln = find_symbol(lt.root_node(), left, &self.name).unwrap();
+                let rn = find_symbol(rt.root_node(), right, &self.name).unwrap();
+
+                let (lb, lbh): (BCSTree, usize) = relative_tree(ln, left).into();
+                let (rb, rbh): (BCSTree, usize) = relative_tree(rn, right).into();
+
+                /* re-indenting is not changing */
+                let tokens = |b| leaves(b).into_iter().map(|x| (x.node_type, x.text));
+
+                if tokens(&lb).eq(tokens(&rb)) {
+                    return Ok(None);
+                }
+
+                let d = ered(diff_wrapper((Rc::new(lb), lbh), (Rc::new(rb), rbh)));
+
+                if d.is_eps() {
+                    None
+                } else {
+                    Some(format!("{:?}", d))
+                }
+            }
+        })
+    }
+}
+
+/// The commits reachable from the first commit but not from the
+/// second one.
+type CommitRange = ([u8; 32], Option<[u8; 32]>);
+
+/// Split the arguments of `dcg log` into the range of commits to list
+/// and the files to filter them with.
+fn parse_arguments(
+    dd: &Path,
+    args: &[String],
+    paths: &[String],
+) -> Result<(Option<CommitRange>, Vec<String>)> {
+    let (revisions, files) = parse_revisions(dd, args, paths, 1)?;
+
+    let range = match revisions[..] {
+        [] => None,
+        [RevisionArg::Single(h)] => Some((h, None)),
+        [RevisionArg::Range(a, b)] => Some((b, Some(a))),
+        _ => return Err(DcgError::UnknownRevision(args[0].clone()).into()),
+    };
+
+    Ok((range, files))
+}
+
 pub(crate) fn log(
+    args: &LogArgs,
     state: LinguistState,
     cfg: &Config,
-    lvl: NotificationLevel,
-    one_line: bool,
+    _lvl: NotificationLevel,
 ) -> Result<()> {
     let wd = env::current_dir().map(fs::canonicalize)??.into_boxed_path();
     let dd = find_repo(&wd)?.to_path_buf();
 
-    let branch = get_branch(&dd)?;
-    let mut head = fetch_head(&dd, &branch)?;
+    let (range, paths) = parse_arguments(&dd, &args.args, &args.paths)?;
+
+    let mut start = Vec::new();
+    let mut hidden = None;
+
+    match range {
+        Some((h, hide)) => {
+            start.push(h);
+            hidden = hide;
+        }
+        None if !args.all => start.extend(fetch_head(&dd, &get_branch(&dd)?)?),
+        None => {}
+    }
+
+    if args.all {
+        start.extend(list_refs(&dd)?.into_iter().map(|(_, h)| h));
+    }
+
+    /* the start is the head of the current branch or the revision
+     * named, as `-L` excludes `--all`
+     */
+    let mut symbol = match &args.symbol {
+        Some(spec) if !start.is_empty() => Some(SymbolTrace::new(state, &dd, spec, start[0])?),
+        _ => None,
+    };
+
+    let author = args.author.as_deref().map(Regex::new).transpose()?;
+    let grep = args.grep.as_deref().map(Regex::new).transpose()?;
+    let since = args.since.as_deref().map(parse_date).transpose()?;
+    let until = args.until.as_deref().map(parse_date).transpose()?;
+
+    let format = match (&args.format, args.one_line) {
+        (Some(f), _) => Format::parse(f, cfg)?,
+        (None, true) => Format::OneLine,
+        (None, false) => match cfg.log.as_ref().and_then(|l| l.format.as_ref()) {
+            Some(f) => Format::parse(f, cfg)?,
+            None => Format::Medium,
+        },
+    };
+
+    let decorations = if args.decorate || args.graph || format.shows_decorations() {
+        pretty::decorations(&dd)?
+    } else {
+        BTreeMap::new()
+    };
+
+    let printer = Printer::new(format, args.date, args.color.enabled(cfg));
 
-    while let Some(h) = head {
-        let commit = CommitObject::read(&dd, h)?;
+    let mut walk = RevWalk::new(&dd, &start)?;
+
+    if let Some(h) = hidden {
+        walk.hide(h)?;
+    }
+
+    let mut graph = if args.graph {
+        walk.topo_order()?;
+
+        Some(Graph::new())
+    } else {
+        None
+    };
+
+    let mut count = 0;
+
+    while let Some(entry) = walk.next() {
+        if args.max_count.is_some_and(|n| count >= n) {
+            break;
+        }
 
-        if one_line {
-            let sh = hex::encode(&h[..4]);
-            println!(
-                "\x1b[0;33m{}\x1b[0m {}",
-                sh,
-                commit.message.lines().next().unwrap()
-            );
+        let (h, commit) = entry?;
+        let date = commit.committer.time;
+
+        let mut selected = since.is_none_or(|s| date >= s) && until.is_none_or(|u| date <= u);
+
+        if let Some(re) = &author {
+            selected &= re.is_match(&format!("{} <{}>", commit.author.name, commit.author.email));
+        }
+
+        selected &= grep.as_ref().is_none_or(|re| re.is_match(&commit.message));
+        selected &= paths.is_empty()
+            || commit
+                .changes
+                .iter()
+                .any(|c| matches_pathspec(&c.path, &paths));
+
+        if let Some(trace) = &symbol {
+            selected &= commit.changes.iter().any(|c| c.path == trace.path);
+        }
+
+        let change = match &mut symbol {
+            Some(trace) if selected => trace.change(&dd, h)?,
+            _ => None,
+        };
+
+        selected &= symbol.is_none() || change.is_some();
+
+        let mut lines = if selected {
+            let decorations = decorations.get(&h).map_or(&[][..], Vec::as_slice);
+
+            count += 1;
+
+            let mut lines = printer.format(h, &commit, decorations)?;
+
+            if let Some(change) = change {
+                lines.extend(change.lines().map(str::to_string));
+                lines.push(String::new());
+            }
+
+            lines.into_iter()
         } else {
-            let date_fmt = parse(
-                "[weekday repr:short] [month repr:short] [day padding:zero] \
-		 [hour]:[minute]:[second] [year] [offset_hour sign:mandatory][offset_minute]",
-            )?;
-            println!("\x1b[0;33m{}\x1b[0m", hex::encode(&h));
-            println!(
-                "Author: {} <{}>",
-                commit.author.name.unwrap(),
-                commit.author.email.unwrap()
-            );
-            println!(
-                "Date:   {}\n",
-                OffsetDateTime::from_unix_timestamp(commit.date as i64)?.format(&date_fmt)?
-            );
+            Vec::new().into_iter()
+        };
+
+        let Some(graph) = &mut graph else {
+            lines.for_each(|l| println!("{}", l));
 
-            println!("\t{}\n", commit.message);
+            continue;
+        };
+
+        /* commits left out still move the lanes of the graph */
+        if let Some(first) = lines.next() {
+            println!("{} {}", graph.commit(h), first);
+        }
+
+        for row in graph.advance(h, &walk.parents(h)?) {
+            println!("{}", row);
         }
 
-        head = get_parent(&dd, h)?;
+        for line in lines {
+            let line = format!("{} {}", graph.padding(), line);
+
+            println!("{}", line.trim_end());
+        }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use super::parse_arguments;
+    use crate::vcs::testing::{commit, repo};
+
+    #[test]
+    fn ranges_and_paths() {
+        let dd = repo();
+        let args = |s: &[&str]| s.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+
+        let first = commit(&dd, "master", &[("a.txt", "a\n")]);
+        let second = commit(&dd, "master", &[("b.txt", "b\n")]);
+
+        fs::create_dir(dd.join("src")).unwrap();
+
+        assert_eq!(parse_arguments(&dd, &[], &[]).unwrap(), (None, vec![]));
+        assert_eq!(
+            parse_arguments(&dd, &args(&["master"]), &[]).unwrap(),
+            (Some((second, None)), vec![])
+        );
+        assert_eq!(
+            parse_arguments(&dd, &args(&["master~1..master"]), &args(&["src"])).unwrap(),
+            (Some((second, Some(first))), args(&["src"]))
+        );
+        assert_eq!(
+            parse_arguments(&dd, &args(&["master~1.."]), &[]).unwrap(),
+            (Some((second, Some(first))), vec![])
+        );
+    }
+}
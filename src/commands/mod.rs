@@ -1,14 +1,49 @@
-use std::{fs, path::Path};
+use std::{env, fs, path::Path};
 
 use anyhow::Result;
 use clap::Subcommand;
+use glob::Pattern;
+use mktemp::Temp;
+
+use crate::{
+    backend::{
+        languages::Languages,
+        linguist::{guess_language, LinguistState},
+    },
+    combine_paths, info,
+    vcs::{
+        find_repo, format, index::get_fname, journal, lock::Lock, protocol::DEFAULT_PORT,
+        revision::resolve_revision, DCG_DIR, LEGACY_DIR,
+    },
+    NotificationLevel,
+};
 
 pub(crate) mod add;
+pub(crate) mod bisect;
+pub(crate) mod blame;
+pub(crate) mod bundle;
+pub(crate) mod cherry_pick;
+pub(crate) mod clone;
 pub(crate) mod commit;
 pub(crate) mod diff;
+pub(crate) mod fetch;
+pub(crate) mod fsck;
+pub(crate) mod gc;
+pub(crate) mod graph;
+pub(crate) mod grep;
 pub(crate) mod init;
 pub(crate) mod log;
+pub(crate) mod pretty;
+pub(crate) mod pull;
+pub(crate) mod push;
+pub(crate) mod rebase;
+pub(crate) mod reflog;
+pub(crate) mod remote;
+pub(crate) mod repack;
+pub(crate) mod revert;
 pub(crate) mod rm;
+pub(crate) mod serve;
+pub(crate) mod stash;
 pub(crate) mod status;
 pub(crate) mod tag;
 
@@ -28,6 +63,95 @@ pub(crate) fn visit_dirs<F: FnMut(&Path) -> Result<()>>(dir: &Path, cb: &mut F)
     Ok(())
 }
 
+/// Whether `path`, relative to the repository root, is selected by
+/// `specs`.  A path is selected if a spec is the path itself, one
+/// of its parent directories or a glob matching it.  An empty list of
+/// specs selects every path.
+pub(crate) fn matches_pathspec(path: &Path, specs: &[String]) -> bool {
+    specs.is_empty()
+        || specs.iter().any(|s| {
+            let s = s.trim_end_matches('/');
+
+            path.starts_with(s) || Pattern::new(s).is_ok_and(|p| p.matches_path(path))
+        })
+}
+
+/// A revision given on the command line of `dcg diff` or `dcg log`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum RevisionArg {
+    /// `<rev>`
+    Single([u8; 32]),
+    /// `<a>..<b>`, either side being `HEAD` if left out
+    Range([u8; 32], [u8; 32]),
+    /// `<a>...<b>`, either side being `HEAD` if left out
+    Symmetric([u8; 32], [u8; 32]),
+}
+
+/// Split the arguments of a command into at most `max` revisions and
+/// the paths following them, `files` being the arguments given after
+/// `--`.  If there are any, every argument before `--` must be a
+/// revision.  Otherwise the paths start with the first argument which
+/// is not a revision but an existing file or a glob.  A range ends the
+/// revisions.
+pub(crate) fn parse_revisions(
+    dd: &Path,
+    args: &[String],
+    files: &[String],
+    max: usize,
+) -> Result<(Vec<RevisionArg>, Vec<String>)> {
+    let resolve = |r: &str| resolve_revision(dd, if r.is_empty() { "HEAD" } else { r });
+
+    let mut revisions = Vec::new();
+    let mut i = 0;
+
+    while i < args.len()
+        && revisions.len() < max
+        && !matches!(
+            revisions.last(),
+            Some(RevisionArg::Range(..) | RevisionArg::Symmetric(..))
+        )
+    {
+        let arg = &args[i];
+
+        let revision = if let Some((a, b)) = arg.split_once("...") {
+            RevisionArg::Symmetric(resolve(a)?, resolve(b)?)
+        } else if let Some((a, b)) = arg.split_once("..") {
+            RevisionArg::Range(resolve(a)?, resolve(b)?)
+        } else {
+            match resolve(arg) {
+                Ok(h) => RevisionArg::Single(h),
+                /* anything after `--` is a file, anything before is a revision */
+                Err(e) if !files.is_empty() => return Err(e),
+                Err(_) if Path::new(arg).exists() || arg.contains(['*', '?', '[']) => break,
+                Err(e) => return Err(e),
+            }
+        };
+
+        revisions.push(revision);
+        i += 1;
+    }
+
+    let mut paths = args[i..].to_vec();
+    paths.extend_from_slice(files);
+
+    Ok((revisions, paths))
+}
+
+/// Guess the language of `contents`, a version of the file `path`.
+pub(crate) fn guess_contents_language(
+    state: LinguistState,
+    path: &Path,
+    contents: &[u8],
+) -> Result<Languages> {
+    /* the language is partly guessed from the file name */
+    let td = Temp::new_dir()?;
+    let tf = td.join(get_fname(path));
+
+    fs::write(&tf, contents)?;
+
+    Ok(guess_language(&tf, state)?)
+}
+
 #[derive(Subcommand)]
 pub(crate) enum Commands {
     /// initialize a new dcg repository
@@ -41,12 +165,30 @@ pub(crate) enum Commands {
 
         directory: Option<String>,
     },
+    /// create a repository from an existing one, with its branches
+    /// and tags, and check out its current branch
+    Clone {
+        /// the repository to clone: the path of a repository or of a
+        /// bundle, or a URL
+        source: String,
+        /// the directory to clone into (by default the name of the
+        /// source directory)
+        directory: Option<String>,
+        /// only copy the last commits of the current branch
+        #[arg(long, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+        depth: Option<usize>,
+    },
     /// add files to the dcg index
     Add {
         /// the paths to add to the index.  these can
         /// contain globs (such as '*.rs') to add
         /// multiple files.  folders are added recursively
         paths: Vec<String>,
+        /// pick the changes to stage: the changes to each top-level
+        /// syntax node (function, declaration...) or, in files dcg
+        /// cannot parse, each run of changed lines
+        #[arg(short, long)]
+        patch: bool,
     },
     /// remove files from the dcg index
     Rm {
@@ -58,22 +200,221 @@ pub(crate) enum Commands {
     /// display the status of each file in the index
     Status,
     /// display the diff between the last commit and the index
-    /// files
+    /// files, between a commit and the working tree or between two
+    /// commits
     Diff {
-        /// the files to diff.  if empty, diff all files.  folders
-        /// are diff-ed recursively.
+        /// up to two revisions followed by the files to diff.  with
+        /// no revision, the last commit is compared to the index;
+        /// with one, the revision is compared to the working tree.
+        /// 'a..b' compares 'a' to 'b' and 'a...b' compares their
+        /// common ancestor to 'b'.  if no file is given, diff all
+        /// files.  folders are diff-ed recursively.
+        args: Vec<String>,
+        /// the files to diff, when they could be mistaken for
+        /// revisions
+        #[arg(last = true)]
         files: Vec<String>,
     },
     /// commit the changes contained in the index to the revision tree.
-    Commit {
-        /// the message to associate with the commit.
-        message: Option<String>,
-    },
+    Commit(commit::CommitArgs),
     /// list commits for the current branch
-    Log {
-        /// display each commit on one line
-        #[arg(long = "oneline")]
-        one_line: bool,
+    Log(log::LogArgs),
+    /// show the commit which last changed each line of a file
+    Blame {
+        /// the file to annotate
+        file: String,
+        /// the revision to annotate the file at (by default the head
+        /// of the current branch)
+        revision: Option<String>,
+        /// annotate each line, or each top-level syntax node
+        /// (function, declaration...)
+        #[arg(long, value_enum, default_value_t = blame::Granularity::Line)]
+        granularity: blame::Granularity,
+    },
+    /// search the tracked files for a pattern
+    Grep {
+        /// a regular expression or, with '--query', a tree-sitter
+        /// query such as '(function_item name: (identifier) @f)'
+        pattern: String,
+        /// an optional revision to search instead of the working
+        /// tree, followed by the files to search.  if no file is
+        /// given, search all tracked files
+        args: Vec<String>,
+        /// the files to search, when they could be mistaken for
+        /// revisions
+        #[arg(last = true)]
+        files: Vec<String>,
+        /// interpret the pattern as a tree-sitter query.  files in
+        /// languages the query does not apply to are skipped
+        #[arg(short, long)]
+        query: bool,
+        /// ignore case when matching a regular expression
+        #[arg(short, long)]
+        ignore_case: bool,
+        /// print the number of matches in each file
+        #[arg(short, long)]
+        count: bool,
+        /// only print the names of the files with matches
+        #[arg(short = 'l', long)]
+        files_with_matches: bool,
+        /// list the commits changing the number of matches, starting
+        /// from the revision (by default the head of the current
+        /// branch)
+        #[arg(long, conflicts_with_all = ["count", "files_with_matches"])]
+        log: bool,
+    },
+    /// create a commit undoing the changes made by an earlier one
+    Revert {
+        /// the commit to undo
+        revision: String,
+    },
+    /// apply the changes made by a commit on top of the current
+    /// branch
+    CherryPick {
+        /// the commit to apply
+        #[arg(required_unless_present_any = ["resume", "abort"])]
+        revision: Option<String>,
+        /// commit once the conflicts are resolved
+        #[arg(long = "continue", conflicts_with_all = ["revision", "abort"])]
+        resume: bool,
+        /// give up and restore the files of the current branch
+        #[arg(long, conflicts_with = "revision")]
+        abort: bool,
+    },
+    /// replay the commits of the current branch on top of another
+    /// revision
+    Rebase {
+        /// the revision to replay the commits on
+        #[arg(required_unless_present_any = ["resume", "skip", "abort"])]
+        upstream: Option<String>,
+        /// edit the list of commits to replay before starting: commits
+        /// can be reordered, dropped, reworded or squashed
+        #[arg(short, long)]
+        interactive: bool,
+        /// commit once the conflicts are resolved and go on
+        #[arg(long = "continue", conflicts_with_all = ["upstream", "skip", "abort"])]
+        resume: bool,
+        /// drop the commit stopped by conflicts and go on
+        #[arg(long, conflicts_with_all = ["upstream", "abort"])]
+        skip: bool,
+        /// give up and go back to the original branch
+        #[arg(long, conflicts_with = "upstream")]
+        abort: bool,
+    },
+    /// shelve the changes of the index and of the working tree (by
+    /// default, with 'push') to apply them later
+    Stash {
+        #[command(subcommand)]
+        command: Option<stash::StashCommand>,
+    },
+    /// manage the remote repositories (by default, list them)
+    Remote {
+        #[command(subcommand)]
+        command: Option<remote::RemoteCommand>,
+    },
+    /// download the branches and tags of a remote repository
+    Fetch {
+        /// the remote (by default 'origin')
+        remote: Option<String>,
+    },
+    /// fetch a branch of a remote repository and merge it into the
+    /// current branch
+    Pull {
+        /// the remote (by default 'origin')
+        #[arg(conflicts_with_all = ["resume", "abort"])]
+        remote: Option<String>,
+        /// the branch of the remote to merge (by default the one named
+        /// like the current branch)
+        branch: Option<String>,
+        /// commit the merge once the conflicts are resolved
+        #[arg(long = "continue", conflicts_with = "abort")]
+        resume: bool,
+        /// give up the merge and restore the files of the current
+        /// branch
+        #[arg(long)]
+        abort: bool,
+    },
+    /// upload a branch to a remote repository
+    Push {
+        /// the remote (by default 'origin')
+        remote: Option<String>,
+        /// the branch to push (by default the current branch)
+        branch: Option<String>,
+        /// replace the remote branch even if it has commits the local
+        /// branch does not have
+        #[arg(short, long)]
+        force: bool,
+    },
+    /// serve the repository to other machines, for them to fetch from
+    /// and push to it through 'dcg://' URLs, or 'http://' ones with
+    /// '--http'
+    Serve {
+        /// serve the repository over HTTP
+        #[arg(long)]
+        http: bool,
+        /// the port to listen on
+        #[arg(short, long, default_value_t = DEFAULT_PORT)]
+        port: u16,
+        /// the address to listen on
+        #[arg(long, default_value = "0.0.0.0")]
+        address: String,
+        /// the token clients must authenticate with (by default
+        /// 'serve.token' from the configuration)
+        #[arg(long)]
+        token: Option<String>,
+    },
+    /// serve a repository on the standard input and output, as run by
+    /// 'ssh://' remotes
+    #[command(hide = true)]
+    ServePack {
+        /// the repository (by default the current one)
+        directory: Option<String>,
+    },
+    /// carry history in a single file, which can be fetched from like
+    /// a remote repository
+    Bundle {
+        #[command(subcommand)]
+        command: bundle::BundleCommand,
+    },
+    /// gather the commits, base files and blobs of the repository in a
+    /// single pack file
+    Repack,
+    /// remove the commits no branch, tag, stash entry or reflog entry
+    /// leads to, with their files, then pack the rest
+    Gc {
+        /// only list what would be removed
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+        /// only remove what was written before this date (by default 2
+        /// weeks ago)
+        #[arg(long)]
+        expire: Option<String>,
+    },
+    /// list the moves of a branch or, by default, of HEAD, most recent
+    /// first
+    Reflog {
+        /// the branch, or HEAD
+        reference: Option<String>,
+    },
+    /// check the refs, commits, base files and blobs of the repository,
+    /// and list the corrupt, missing and dangling ones
+    Fsck,
+    /// remove the loose objects of the commits no branch, tag, stash
+    /// entry or reflog entry leads to
+    Prune {
+        /// only list what would be removed
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+        /// only remove what was written before this date (by default 2
+        /// weeks ago)
+        #[arg(long)]
+        expire: Option<String>,
+    },
+    /// find the commit which introduced a regression by binary
+    /// search
+    Bisect {
+        #[command(subcommand)]
+        command: bisect::BisectCommand,
     },
     /// create a tag referencing a commit
     Tag {
@@ -83,3 +424,175 @@ pub(crate) enum Commands {
         commit: Option<String>,
     },
 }
+
+impl Commands {
+    /// Whether the command changes the repository it is run in, and so
+    /// must hold its lock.  Servers only lock it while receiving a push.
+    pub(crate) fn mutates(&self) -> bool {
+        !matches!(
+            self,
+            Self::Init { .. }
+                | Self::Clone { .. }
+                | Self::Status
+                | Self::Diff { .. }
+                | Self::Log(_)
+                | Self::Blame { .. }
+                | Self::Grep { .. }
+                | Self::Serve { .. }
+                | Self::ServePack { .. }
+                | Self::Reflog { .. }
+                | Self::Fsck
+                | Self::Bundle {
+                    command: bundle::BundleCommand::Create { .. }
+                }
+                | Self::Stash {
+                    command: Some(stash::StashCommand::List | stash::StashCommand::Show { .. })
+                }
+                | Self::Remote {
+                    command: None | Some(remote::RemoteCommand::List { .. })
+                }
+                | Self::Gc { dry_run: true, .. }
+                | Self::Prune { dry_run: true, .. }
+        )
+    }
+}
+
+/// Lock the repository the current directory is in for `command`, if
+/// it changes it.  Commands which only read it lock it long enough to
+/// finish a commit an interrupted process left behind, or to upgrade a
+/// repository written by an older version of dcg.
+pub(crate) fn lock_repository(
+    command: &Commands,
+    state: LinguistState,
+    lvl: NotificationLevel,
+) -> Result<Option<Lock>> {
+    if matches!(command, Commands::Init { .. } | Commands::Clone { .. }) {
+        return Ok(None);
+    }
+
+    let wd = env::current_dir().and_then(fs::canonicalize)?;
+    let Ok(dd) = find_repo(&wd) else {
+        return Ok(None);
+    };
+
+    let outdated = format::outdated(dd)?;
+
+    if !command.mutates() && !outdated && !journal::pending(dd) {
+        return Ok(None);
+    }
+
+    let lock = Lock::acquire(dd)?;
+
+    if outdated && format::upgrade(dd, state)? {
+        info!(
+            lvl,
+            "upgraded the repository to the current format: its previous objects are kept in '{}'",
+            combine_paths!(DCG_DIR, LEGACY_DIR).display()
+        );
+    }
+
+    Ok(if command.mutates() { Some(lock) } else { None })
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, path::Path};
+
+    use clap::Parser;
+
+    use super::{matches_pathspec, parse_revisions, Commands, RevisionArg};
+    use crate::vcs::testing::{commit, repo};
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(subcommand)]
+        command: Commands,
+    }
+
+    #[test]
+    fn read_only_commands() {
+        let mutates = |args: &[&str]| {
+            Cli::parse_from(["dcg"].iter().chain(args))
+                .command
+                .mutates()
+        };
+
+        for args in [
+            &["status"][..],
+            &["log"],
+            &["stash", "list"],
+            &["stash", "show"],
+            &["remote"],
+            &["remote", "list", "-v"],
+            &["gc", "-n"],
+            &["prune", "--dry-run"],
+            &["bundle", "create", "b", "master"],
+        ] {
+            assert!(!mutates(args), "{:?}", args);
+        }
+
+        for args in [
+            &["commit", "m"][..],
+            &["stash"],
+            &["stash", "pop"],
+            &["stash", "drop"],
+            &["remote", "add", "origin", "../r"],
+            &["gc"],
+            &["prune"],
+            &["bundle", "unbundle", "b"],
+        ] {
+            assert!(mutates(args), "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn pathspecs() {
+        let specs = |s: &[&str]| s.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let path = Path::new("src/backend/merge.rs");
+
+        assert!(matches_pathspec(path, &[]));
+        assert!(matches_pathspec(path, &specs(&["src"])));
+        assert!(matches_pathspec(path, &specs(&["src/backend/"])));
+        assert!(matches_pathspec(path, &specs(&["src/backend/merge.rs"])));
+        assert!(matches_pathspec(path, &specs(&["src/*/*.rs"])));
+        assert!(matches_pathspec(path, &specs(&["doc", "src/backend"])));
+        assert!(!matches_pathspec(path, &specs(&["src/back"])));
+        assert!(!matches_pathspec(path, &specs(&["src/vcs", "*.md"])));
+    }
+
+    #[test]
+    fn revisions_and_paths() {
+        let dd = repo();
+        let args = |s: &[&str]| s.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+
+        let first = commit(&dd, "master", &[("a.txt", "a\n")]);
+        let second = commit(&dd, "master", &[("b.txt", "b\n")]);
+
+        /* paths are relative to the working directory */
+        let file = dd.join("a.txt").to_string_lossy().to_string();
+
+        fs::write(&file, "a\n").unwrap();
+
+        assert_eq!(
+            parse_revisions(&dd, &args(&["master~1", "HEAD", &file]), &[], 2).unwrap(),
+            (
+                vec![RevisionArg::Single(first), RevisionArg::Single(second)],
+                args(&[&file])
+            )
+        );
+        assert_eq!(
+            parse_revisions(&dd, &args(&["master~1...", "HEAD"]), &[], 2).unwrap(),
+            (vec![RevisionArg::Symmetric(first, second)], args(&["HEAD"]))
+        );
+        assert_eq!(
+            parse_revisions(&dd, &args(&[&file, "*.rs"]), &[], 2).unwrap(),
+            (vec![], args(&[&file, "*.rs"]))
+        );
+        assert_eq!(
+            parse_revisions(&dd, &args(&["HEAD"]), &args(&["a.txt"]), 1).unwrap(),
+            (vec![RevisionArg::Single(second)], args(&["a.txt"]))
+        );
+        assert!(parse_revisions(&dd, &args(&["a.txt"]), &args(&["b.txt"]), 1).is_err());
+        assert!(parse_revisions(&dd, &args(&["unknown"]), &[], 1).is_err());
+    }
+}
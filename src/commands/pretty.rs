@@ -0,0 +1,506 @@
+//! Formatting of the commits listed by `dcg log`
+use std::{
+    collections::BTreeMap,
+    env,
+    io::{self, IsTerminal},
+    path::Path,
+};
+
+use anyhow::Result;
+use clap::ValueEnum;
+use time::{
+    format_description::{
+        parse,
+        well_known::{Rfc2822, Rfc3339},
+    },
+    OffsetDateTime,
+};
+
+use crate::vcs::{
+    commit::{get_branch, CommitObject, Signature},
+    config::Config,
+    revision::{list_refs, Ref},
+    DcgError,
+};
+
+const DEFAULT_DATE_FORMAT: &str = "[weekday repr:short] [month repr:short] [day padding:zero] \
+				   [hour]:[minute]:[second] [year] \
+				   [offset_hour sign:mandatory][offset_minute]";
+
+/// How to print the commits.
+pub(crate) enum Format {
+    /// hash, author, date and indented message
+    Medium,
+    /// short hash and subject
+    OneLine,
+    /// a template with placeholders such as `%h` or `%an`
+    Template(String),
+}
+
+impl Format {
+    /// Parse the value of `--format`: `medium`, `oneline`, a format
+    /// defined in the `log.formats` configuration table or a template,
+    /// optionally prefixed with `format:`.
+    pub(crate) fn parse(s: &str, cfg: &Config) -> Result<Self> {
+        let presets = cfg.log.as_ref().and_then(|l| l.formats.as_ref());
+
+        match s {
+            "medium" => Ok(Self::Medium),
+            "oneline" => Ok(Self::OneLine),
+            _ => {
+                if let Some(t) = s.strip_prefix("format:") {
+                    Ok(Self::Template(t.to_string()))
+                } else if let Some(t) = presets.and_then(|p| p.get(s)) {
+                    Ok(Self::Template(
+                        t.strip_prefix("format:").unwrap_or(t).to_string(),
+                    ))
+                } else if s.contains('%') {
+                    Ok(Self::Template(s.to_string()))
+                } else {
+                    Err(DcgError::UnknownFormat(s.to_string()).into())
+                }
+            }
+        }
+    }
+
+    /// Whether the format shows branches and tags by itself.
+    pub(crate) fn shows_decorations(&self) -> bool {
+        match self {
+            Self::Template(t) => t.contains("%d") || t.contains("%D"),
+            _ => false,
+        }
+    }
+}
+
+/// How to print dates.
+#[derive(Clone, Copy, ValueEnum)]
+pub(crate) enum DateFormat {
+    /// `Sun Oct 18 21:22:15 2026 +0000`
+    Default,
+    /// `3 days ago`
+    Relative,
+    /// ISO 8601: `2026-10-18T21:22:15Z`
+    Iso,
+    /// RFC 2822: `Sun, 18 Oct 2026 21:22:15 +0000`
+    Rfc,
+    /// seconds since the unix epoch
+    Unix,
+}
+
+/// When to use ANSI escape codes.
+#[derive(Clone, Copy, ValueEnum)]
+pub(crate) enum Color {
+    /// when printing to a terminal, unless `log.color` is false or
+    /// `NO_COLOR` is set
+    Auto,
+    Always,
+    Never,
+}
+
+impl Color {
+    pub(crate) fn enabled(self, cfg: &Config) -> bool {
+        match self {
+            Self::Always => true,
+            Self::Never => false,
+            Self::Auto => {
+                cfg.log.as_ref().and_then(|l| l.color).unwrap_or(true)
+                    && env::var_os("NO_COLOR").is_none()
+                    && io::stdout().is_terminal()
+            }
+        }
+    }
+}
+
+/// A branch or tag shown next to the commit it points to.
+pub(crate) enum Decoration {
+    /// the current branch
+    Head(String),
+    Branch(String),
+    Tag(String),
+    /// a branch of a remote
+    Remote(String),
+}
+
+/// The branches and tags pointing to each commit.
+pub(crate) fn decorations(dd: &Path) -> Result<BTreeMap<[u8; 32], Vec<Decoration>>> {
+    let head = get_branch(dd)?;
+    let head = head.trim();
+
+    let mut decorations = BTreeMap::<[u8; 32], Vec<Decoration>>::new();
+
+    for (r, h) in list_refs(dd)? {
+        let d = decorations.entry(h).or_default();
+
+        match r {
+            Ref::Branch(b) if b == head => d.insert(0, Decoration::Head(b)),
+            Ref::Branch(b) => d.push(Decoration::Branch(b)),
+            Ref::Tag(t) => d.push(Decoration::Tag(t)),
+            Ref::Remote(r) => d.push(Decoration::Remote(r)),
+        }
+    }
+
+    Ok(decorations)
+}
+
+fn relative_date(t: i64, now: i64) -> String {
+    let d = now - t;
+
+    let (n, unit) = match d {
+        ..90 => (d, "second"),
+        90..5400 => (d / 60, "minute"),
+        5400..129600 => (d / 3600, "hour"),
+        129600..1209600 => (d / 86400, "day"),
+        1209600..5184000 => (d / 604800, "week"),
+        5184000..31536000 => (d / 2592000, "month"),
+        _ => (d / 31536000, "year"),
+    };
+
+    format!("{} {}{} ago", n, unit, if n == 1 { "" } else { "s" })
+}
+
+fn color_code(name: &str) -> Option<&'static str> {
+    Some(match name {
+        "reset" => "\x1b[0m",
+        "bold" => "\x1b[1m",
+        "red" => "\x1b[31m",
+        "green" => "\x1b[32m",
+        "yellow" => "\x1b[33m",
+        "blue" => "\x1b[34m",
+        "magenta" => "\x1b[35m",
+        "cyan" => "\x1b[36m",
+        _ => return None,
+    })
+}
+
+/// Formats commits to lines of text.
+pub(crate) struct Printer {
+    format: Format,
+    date: DateFormat,
+    /// whether to use ANSI escape codes
+    color: bool,
+    now: i64,
+}
+
+impl Printer {
+    pub(crate) fn new(format: Format, date: DateFormat, color: bool) -> Self {
+        Self {
+            format,
+            date,
+            color,
+            now: OffsetDateTime::now_utc().unix_timestamp(),
+        }
+    }
+
+    fn paint<'a>(&self, code: &'a str) -> &'a str {
+        if self.color {
+            code
+        } else {
+            ""
+        }
+    }
+
+    fn format_date(&self, signature: &Signature, fmt: DateFormat) -> Result<String> {
+        let dt = signature.datetime()?;
+
+        Ok(match fmt {
+            DateFormat::Default => dt.format(&parse(DEFAULT_DATE_FORMAT)?)?,
+            DateFormat::Relative => relative_date(signature.time, self.now),
+            DateFormat::Iso => dt.format(&Rfc3339)?,
+            DateFormat::Rfc => dt.format(&Rfc2822)?,
+            DateFormat::Unix => signature.time.to_string(),
+        })
+    }
+
+    /// Expand the placeholder `%<role><field>` (such as `%an` or `%cd`)
+    /// for `signature`, the author or the committer of a commit.
+    fn expand_signature(&self, signature: &Signature, field: u8) -> Result<Option<String>> {
+        Ok(Some(match field {
+            b'n' => signature.name.clone(),
+            b'e' => signature.email.clone(),
+            b'd' => self.format_date(signature, self.date)?,
+            b'r' => self.format_date(signature, DateFormat::Relative)?,
+            b'i' => self.format_date(signature, DateFormat::Iso)?,
+            b'D' => self.format_date(signature, DateFormat::Rfc)?,
+            b't' => self.format_date(signature, DateFormat::Unix)?,
+            _ => return Ok(None),
+        }))
+    }
+
+    /// `HEAD -> master, tag: v1.0`
+    fn format_decorations(&self, decorations: &[Decoration]) -> String {
+        let (reset, yellow) = (self.paint("\x1b[0m"), self.paint("\x1b[33m"));
+
+        decorations
+            .iter()
+            .map(|d| match d {
+                Decoration::Head(b) => format!(
+                    "{}HEAD -> {}{}{}{}",
+                    self.paint("\x1b[1;36m"),
+                    self.paint("\x1b[1;32m"),
+                    b,
+                    reset,
+                    yellow
+                ),
+                Decoration::Branch(b) => {
+                    format!("{}{}{}{}", self.paint("\x1b[1;32m"), b, reset, yellow)
+                }
+                Decoration::Tag(t) => format!("tag: {}", t),
+                Decoration::Remote(r) => {
+                    format!("{}{}{}{}", self.paint("\x1b[1;31m"), r, reset, yellow)
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// Expand the placeholders of a template.
+    fn expand(
+        &self,
+        template: &str,
+        h: [u8; 32],
+        commit: &CommitObject,
+        decorations: &[Decoration],
+    ) -> Result<String> {
+        let subject = commit.message.lines().next().unwrap_or("");
+        let body = commit
+            .message
+            .split_once('\n')
+            .map_or("", |(_, b)| b.trim_start_matches('\n'));
+
+        let mut out = String::new();
+        let mut rest = template;
+
+        while let Some(i) = rest.find('%') {
+            out.push_str(&rest[..i]);
+            rest = &rest[i + 1..];
+
+            let (expansion, len) = match rest.as_bytes() {
+                [b'H', ..] => (hex::encode(h), 1),
+                [b'h', ..] => (hex::encode(&h[..4]), 1),
+                [b's', ..] => (subject.to_string(), 1),
+                [b'b', ..] => (body.to_string(), 1),
+                [b'B', ..] => (commit.message.clone(), 1),
+                [b'n', ..] => ("\n".to_string(), 1),
+                [b'%', ..] => ("%".to_string(), 1),
+                [b'd', ..] if decorations.is_empty() => (String::new(), 1),
+                [b'd', ..] => (format!(" ({})", self.format_decorations(decorations)), 1),
+                [b'D', ..] => (self.format_decorations(decorations), 1),
+                [role @ (b'a' | b'c'), field, ..] => {
+                    let signature = if *role == b'a' {
+                        &commit.author
+                    } else {
+                        &commit.committer
+                    };
+
+                    match self.expand_signature(signature, *field)? {
+                        Some(e) => (e, 2),
+                        None => (String::from("%"), 0),
+                    }
+                }
+                [b'C', b'(', ..] => match rest.find(')') {
+                    Some(end) => match color_code(&rest[2..end]) {
+                        Some(c) => (self.paint(c).to_string(), end + 1),
+                        None => (String::from("%"), 0),
+                    },
+                    None => (String::from("%"), 0),
+                },
+                [b'C', ..] => match ["reset", "red", "green", "yellow", "blue"]
+                    .into_iter()
+                    .find(|c| rest[1..].starts_with(c))
+                {
+                    Some(c) => (self.paint(color_code(c).unwrap()).to_string(), c.len() + 1),
+                    None => (String::from("%"), 0),
+                },
+                /* unknown placeholders are printed as is */
+                _ => (String::from("%"), 0),
+            };
+
+            out.push_str(&expansion);
+            rest = &rest[len..];
+        }
+
+        out.push_str(rest);
+
+        Ok(out)
+    }
+
+    /// The lines describing a commit.
+    pub(crate) fn format(
+        &self,
+        h: [u8; 32],
+        commit: &CommitObject,
+        decorations: &[Decoration],
+    ) -> Result<Vec<String>> {
+        match &self.format {
+            Format::OneLine => Ok(vec![self.expand(
+                "%C(yellow)%h%d%Creset %s",
+                h,
+                commit,
+                decorations,
+            )?]),
+            Format::Medium => {
+                let header = self.expand(
+                    "%C(yellow)%H%d%Creset%nAuthor: %an <%ae>%nDate:   %ad%n",
+                    h,
+                    commit,
+                    decorations,
+                )?;
+
+                let mut lines = header.split('\n').map(str::to_string).collect::<Vec<_>>();
+
+                if let Some(origin) = commit.origin {
+                    lines.insert(3, format!("Picked: {}", hex::encode(origin)));
+                }
+
+                lines.extend(commit.message.lines().map(|l| format!("\t{}", l)));
+                lines.push(String::new());
+
+                Ok(lines)
+            }
+            Format::Template(t) => Ok(self
+                .expand(t, h, commit, decorations)?
+                .split('\n')
+                .map(str::to_string)
+                .collect()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use super::{DateFormat, Decoration, Format, Printer};
+    use crate::vcs::{
+        commit::{CommitObject, Signature},
+        config::{Config, Log},
+    };
+
+    fn commit() -> CommitObject {
+        let author = Signature {
+            name: "A U Thor".to_string(),
+            email: "a@u.th".to_string(),
+            time: 1_700_000_000,
+            offset: 3600,
+        };
+        let committer = Signature {
+            name: "C O Mitter".to_string(),
+            email: "c@o.mi".to_string(),
+            time: 1_700_003_600,
+            offset: 0,
+        };
+
+        CommitObject::new(
+            author,
+            committer,
+            "Fix the parser\n\nIt crashed on empty files.".to_string(),
+            vec![],
+        )
+    }
+
+    fn expand(template: &str, color: bool, decorations: &[Decoration]) -> String {
+        let printer = Printer::new(
+            Format::Template(template.to_string()),
+            DateFormat::Iso,
+            color,
+        );
+
+        printer
+            .expand(template, [0xab; 32], &commit(), decorations)
+            .unwrap()
+    }
+
+    #[test]
+    fn placeholders() {
+        assert_eq!(expand("%h %s", false, &[]), "abababab Fix the parser");
+        assert_eq!(expand("%H", false, &[]), "ab".repeat(32));
+        assert_eq!(expand("%b", false, &[]), "It crashed on empty files.");
+        assert_eq!(
+            expand("%B", false, &[]),
+            "Fix the parser\n\nIt crashed on empty files."
+        );
+        assert_eq!(
+            expand("%an <%ae> %cn <%ce>", false, &[]),
+            "A U Thor <a@u.th> C O Mitter <c@o.mi>"
+        );
+        assert_eq!(
+            expand("%ad|%ai|%at|%cd", false, &[]),
+            "2023-11-14T23:13:20+01:00|2023-11-14T23:13:20+01:00|1700000000|2023-11-14T23:13:20Z"
+        );
+        assert_eq!(expand("%aD", false, &[]), "Tue, 14 Nov 2023 23:13:20 +0100");
+    }
+
+    #[test]
+    fn decorations() {
+        let decorations = [
+            Decoration::Head("master".to_string()),
+            Decoration::Tag("v1.0".to_string()),
+        ];
+
+        assert_eq!(expand("%h%d", false, &[]), "abababab");
+        assert_eq!(
+            expand("%h%d", false, &decorations),
+            "abababab (HEAD -> master, tag: v1.0)"
+        );
+        assert_eq!(
+            expand("[%D]", false, &decorations),
+            "[HEAD -> master, tag: v1.0]"
+        );
+    }
+
+    #[test]
+    fn escapes_and_colors() {
+        assert_eq!(expand("100%% %s%n", false, &[]), "100% Fix the parser\n");
+        assert_eq!(expand("%Cred%h%Creset", false, &[]), "abababab");
+        assert_eq!(
+            expand("%Cred%h%Creset", true, &[]),
+            "\x1b[31mabababab\x1b[0m"
+        );
+        assert_eq!(
+            expand("%C(bold)%h%C(reset)", true, &[]),
+            "\x1b[1mabababab\x1b[0m"
+        );
+    }
+
+    #[test]
+    fn unknown_placeholders() {
+        assert_eq!(expand("%x %h", false, &[]), "%x abababab");
+        assert_eq!(expand("%az %aw", false, &[]), "%az %aw");
+        assert_eq!(expand("%C(purple)%Cpink", true, &[]), "%C(purple)%Cpink");
+        assert_eq!(expand("%C(red", true, &[]), "%C(red");
+        assert_eq!(expand("%a", false, &[]), "%a");
+        assert_eq!(expand("50%", false, &[]), "50%");
+    }
+
+    #[test]
+    fn formats() {
+        let mut formats = BTreeMap::new();
+        formats.insert("short".to_string(), "format:%h %an".to_string());
+
+        let cfg = Config {
+            log: Some(Log {
+                format: None,
+                formats: Some(formats),
+                color: None,
+            }),
+            ..Config::default()
+        };
+
+        assert!(matches!(
+            Format::parse("medium", &cfg).unwrap(),
+            Format::Medium
+        ));
+        assert!(matches!(
+            Format::parse("oneline", &cfg).unwrap(),
+            Format::OneLine
+        ));
+        assert!(
+            matches!(Format::parse("short", &cfg).unwrap(), Format::Template(t) if t == "%h %an")
+        );
+        assert!(
+            matches!(Format::parse("format:%s", &cfg).unwrap(), Format::Template(t) if t == "%s")
+        );
+        assert!(matches!(Format::parse("%h", &cfg).unwrap(), Format::Template(t) if t == "%h"));
+        assert!(Format::parse("long", &cfg).is_err());
+    }
+}
@@ -0,0 +1,191 @@
+//! Merge of a branch of a remote repository into the current branch
+use std::{env, fs, path::Path};
+
+use anyhow::Result;
+
+use crate::{
+    backend::linguist::LinguistState,
+    commands::{
+        commit::{record, CommitOptions},
+        fetch::fetch_remote,
+    },
+    info,
+    vcs::{
+        checkout::{ensure_clean, reset},
+        commit::{fetch_head, get_branch},
+        config::Config,
+        find_repo,
+        index::{get_indexed_files, stage},
+        merge::{changed_paths, merge_changes},
+        pending::Pending,
+        remote::{remote_url, tracking_branches},
+        revision::{ancestors, merge_base},
+        snapshot::Snapshot,
+        DcgError, MERGE_DIR,
+    },
+    NotificationLevel,
+};
+
+/// Merge the commit `theirs`, named `label`, into the current branch.
+/// The branch is moved to `theirs` if it is one of its ancestors;
+/// otherwise, a merge commit is made with `message`.
+fn merge(
+    dd: &Path,
+    theirs: [u8; 32],
+    label: &str,
+    message: &str,
+    state: LinguistState,
+    cfg: &Config,
+    lvl: NotificationLevel,
+) -> Result<()> {
+    let head = fetch_head(dd, &get_branch(dd)?)?;
+    let tip = Snapshot::at(dd, head)?;
+
+    if let Some(head) = head {
+        if ancestors(dd, head)?.contains(&theirs) {
+            info!(lvl, "Already up to date.");
+
+            return Ok(());
+        }
+    }
+
+    ensure_clean(dd, &tip)?;
+
+    let staged = get_indexed_files(dd)?;
+
+    if !staged.is_empty() {
+        return Err(DcgError::UncommittedChanges(staged).into());
+    }
+
+    let head = match head {
+        Some(head) if !ancestors(dd, theirs)?.contains(&head) => head,
+        _ => {
+            reset(dd, &tip, theirs, "pull: fast-forward")?;
+
+            info!(lvl, "Fast-forward to {}", hex::encode(&theirs[..4]));
+
+            return Ok(());
+        }
+    };
+
+    let before = Snapshot::at(dd, merge_base(dd, head, theirs)?)?;
+    let after = Snapshot::at(dd, Some(theirs))?;
+    let changed = changed_paths(&before, &after);
+
+    let merged = merge_changes(
+        dd,
+        state,
+        (&before, &after),
+        &changed,
+        &tip,
+        false,
+        Some(label),
+    )?;
+
+    if merged.conflicts.is_empty() {
+        stage(dd, &merged.files)?;
+        record(
+            dd,
+            message,
+            &CommitOptions {
+                parents: Some(vec![head, theirs]),
+                action: Some("pull"),
+                ..Default::default()
+            },
+            state,
+            cfg,
+            lvl,
+        )?;
+
+        return Ok(());
+    }
+
+    let mut pending = Pending::new(dd, "pull", MERGE_DIR);
+
+    pending.files = merged.files;
+    pending.conflicts = merged.conflicts.clone();
+    pending.write()?;
+    pending.write_field("commit", hex::encode(theirs))?;
+    pending.write_field("message", message)?;
+    pending.write_paths("changed", &changed)?;
+
+    info!(
+        lvl,
+        "resolve the conflicts and run 'dcg pull --continue', or 'dcg pull --abort'"
+    );
+
+    Err(DcgError::MergeConflict(merged.conflicts).into())
+}
+
+/// Commit a merge once its conflicts are resolved.
+fn resume(dd: &Path, state: LinguistState, cfg: &Config, lvl: NotificationLevel) -> Result<()> {
+    let pending = Pending::read(dd, "pull", MERGE_DIR)?;
+    let files = pending.resolved_files(dd)?;
+    let head = fetch_head(dd, &get_branch(dd)?)?.ok_or(DcgError::EmptyTree)?;
+
+    stage(dd, &files)?;
+    record(
+        dd,
+        &pending.read_string("message")?,
+        &CommitOptions {
+            parents: Some(vec![head, pending.read_hash("commit")?]),
+            action: Some("pull"),
+            ..Default::default()
+        },
+        state,
+        cfg,
+        lvl,
+    )?;
+
+    pending.finish()
+}
+
+/// Give up a merge, restoring the files it changed.
+fn abort(dd: &Path) -> Result<()> {
+    let pending = Pending::read(dd, "pull", MERGE_DIR)?;
+    let changed = pending.read_paths("changed")?;
+
+    pending.abort(dd, &changed)
+}
+
+pub(crate) fn pull(
+    remote: &Option<String>,
+    branch: &Option<String>,
+    resume_merge: bool,
+    abort_merge: bool,
+    state: LinguistState,
+    cfg: &Config,
+    lvl: NotificationLevel,
+) -> Result<()> {
+    let wd = env::current_dir().map(fs::canonicalize)??.into_boxed_path();
+    let dd = find_repo(&wd)?;
+
+    if resume_merge {
+        return resume(dd, state, cfg, lvl);
+    } else if abort_merge {
+        return abort(dd);
+    }
+
+    Pending::ensure_none(dd, "pull", MERGE_DIR)?;
+
+    let name = remote.as_deref().unwrap_or("origin");
+    let branch = match branch {
+        Some(b) => b.clone(),
+        None => get_branch(dd)?.trim().to_string(),
+    };
+
+    fetch_remote(dd, name, cfg, lvl)?;
+
+    let label = format!("{}/{}", name, branch);
+    let theirs = *tracking_branches(dd, name)?
+        .get(&branch)
+        .ok_or(DcgError::UnknownRevision(label.clone()))?;
+
+    let message = format!(
+        "Merge branch '{}' of {}",
+        branch,
+        remote_url(dd, name, cfg)?
+    );
+
+    merge(dd, theirs, &label, &message, state, cfg, lvl)
+}
@@ -0,0 +1,66 @@
+//! Upload of the history of a branch to a remote repository
+use std::{env, fs};
+
+use anyhow::Result;
+
+use crate::{
+    combine_paths,
+    commands::fetch::describe_update,
+    info,
+    vcs::{
+        commit::{fetch_head, get_branch},
+        config::Config,
+        find_repo,
+        remote::{connect, remote_token, remote_url, update_tracking},
+        transfer::has_commit,
+        DcgError, BRANCHES_DIR, DCG_DIR,
+    },
+    NotificationLevel,
+};
+
+pub(crate) fn push(
+    remote: &Option<String>,
+    branch: &Option<String>,
+    force: bool,
+    cfg: &Config,
+    lvl: NotificationLevel,
+) -> Result<()> {
+    let wd = env::current_dir().map(fs::canonicalize)??.into_boxed_path();
+    let dd = find_repo(&wd)?;
+
+    let name = remote.as_deref().unwrap_or("origin");
+    let url = remote_url(dd, name, cfg)?;
+    let mut remote = connect(dd, &url, remote_token(dd, name, cfg)?.as_deref())?;
+
+    let branch = match branch {
+        Some(b) => b.clone(),
+        None => get_branch(dd)?.trim().to_string(),
+    };
+
+    if !combine_paths!(dd, DCG_DIR, BRANCHES_DIR, &branch).is_file() {
+        return Err(DcgError::UnknownRevision(branch).into());
+    }
+
+    let h = fetch_head(dd, &branch)?.ok_or(DcgError::EmptyTree)?;
+    let old = remote.refs()?.branches.get(&branch).copied();
+
+    if old == Some(h) {
+        info!(lvl, "Everything up-to-date");
+
+        return Ok(());
+    }
+
+    remote.push(dd, &branch, h, force)?;
+    update_tracking(dd, name, &branch, Some(h))?;
+
+    let line = match old {
+        Some(old) if has_commit(dd, old) => describe_update(dd, old, h)?,
+        Some(old) => format!(" + {}...{}", hex::encode(&old[..4]), hex::encode(&h[..4])),
+        None => " * [new branch]".to_string(),
+    };
+
+    info!(lvl, "To {}", url);
+    info!(lvl, "{}\t{} -> {}", line, branch, branch);
+
+    Ok(())
+}
@@ -0,0 +1,473 @@
+//! Replay of the commits of the current branch on top of another one
+use std::{
+    env,
+    fmt::{self, Formatter},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+
+use crate::{
+    backend::linguist::LinguistState,
+    commands::{
+        cherry_pick::{clean_head, label},
+        commit::{edit, record, CommitOptions},
+    },
+    info,
+    vcs::{
+        checkout::{reset, restore, set_last},
+        commit::{fetch_head, get_branch, get_parent, get_parents, update_branch, CommitObject},
+        config::Config,
+        find_repo,
+        index::{clear_index, stage},
+        merge::replay,
+        pending::Pending,
+        revision::{ancestors, resolve_revision, RevWalk},
+        snapshot::Snapshot,
+        DcgError, REBASE_DIR,
+    },
+    NotificationLevel,
+};
+
+const TODO_HELP: &str = "
+# Commands:
+# p, pick <commit> = use commit
+# r, reword <commit> = use commit, but edit the commit message
+# s, squash <commit> = use commit, but meld into previous commit
+# f, fixup <commit> = like \"squash\", but discard this commit's message
+# d, drop <commit> = remove commit
+#
+# These lines can be re-ordered; they are executed from top to bottom.
+# If a line is removed, the commit is dropped.
+";
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Action {
+    Pick,
+    Reword,
+    Squash,
+    Fixup,
+    Drop,
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Pick => write!(f, "pick"),
+            Self::Reword => write!(f, "reword"),
+            Self::Squash => write!(f, "squash"),
+            Self::Fixup => write!(f, "fixup"),
+            Self::Drop => write!(f, "drop"),
+        }
+    }
+}
+
+/// A line of the todo list.
+#[derive(Clone, Copy)]
+struct Step {
+    action: Action,
+    commit: [u8; 32],
+}
+
+impl Step {
+    fn parse(dd: &Path, line: &str) -> Result<Self> {
+        let mut words = line.split_whitespace();
+
+        let action = match words.next() {
+            Some("p" | "pick") => Action::Pick,
+            Some("r" | "reword") => Action::Reword,
+            Some("s" | "squash") => Action::Squash,
+            Some("f" | "fixup") => Action::Fixup,
+            Some("d" | "drop") => Action::Drop,
+            _ => {
+                return Err(DcgError::InvalidTodo(format!("unknown command in '{}'", line)).into())
+            }
+        };
+
+        let Some(commit) = words.next() else {
+            return Err(DcgError::InvalidTodo(format!("missing commit in '{}'", line)).into());
+        };
+
+        Ok(Self {
+            action,
+            commit: resolve_revision(dd, commit)?,
+        })
+    }
+}
+
+/// Parse a todo list, ignoring blank lines and comments.
+fn parse_todo(dd: &Path, text: &str) -> Result<Vec<Step>> {
+    text.lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(|l| Step::parse(dd, l))
+        .collect()
+}
+
+/// The progress of a rebase, kept in `.dcg/rebase/`.
+struct Rebase {
+    /// the files to commit once the conflicts of `current` are resolved
+    pending: Pending,
+    /// the head of the branch before the rebase
+    head: [u8; 32],
+    /// the commit the branch is rebased on
+    onto: [u8; 32],
+    /// the steps left
+    todo: Vec<Step>,
+    /// the step stopped by conflicts
+    current: Option<Step>,
+}
+
+impl Rebase {
+    fn read(dd: &Path) -> Result<Self> {
+        let pending = Pending::read(dd, "rebase", REBASE_DIR)?;
+
+        Ok(Self {
+            head: pending.read_hash("head")?,
+            onto: pending.read_hash("onto")?,
+            todo: parse_todo(dd, &pending.read_string("todo")?)?,
+            current: parse_todo(dd, &pending.read_string("current")?)?
+                .first()
+                .copied(),
+            pending,
+        })
+    }
+
+    fn write(&self) -> Result<()> {
+        let todo = self
+            .todo
+            .iter()
+            .map(|s| format!("{} {}\n", s.action, hex::encode(s.commit)))
+            .collect::<String>();
+        let current = self
+            .current
+            .map(|s| format!("{} {}\n", s.action, hex::encode(s.commit)))
+            .unwrap_or_default();
+
+        self.pending.write()?;
+        self.pending.write_field("head", hex::encode(self.head))?;
+        self.pending.write_field("onto", hex::encode(self.onto))?;
+        self.pending.write_field("todo", todo)?;
+        self.pending.write_field("current", current)
+    }
+
+    /// Commit the files of `step`, which changes were applied to the
+    /// working tree.
+    fn commit(
+        &self,
+        dd: &Path,
+        step: Step,
+        files: &[PathBuf],
+        state: LinguistState,
+        cfg: &Config,
+        lvl: NotificationLevel,
+    ) -> Result<()> {
+        let commit = CommitObject::read(dd, step.commit)?;
+        let branch = get_branch(dd)?;
+
+        let mut author = commit.author.clone();
+
+        let message = match step.action {
+            Action::Pick | Action::Drop => commit.message.clone(),
+            Action::Reword => edit(cfg, &commit.message)?,
+            Action::Squash | Action::Fixup => {
+                let head = fetch_head(dd, &branch)?.ok_or(DcgError::EmptyTree)?;
+
+                if head == self.onto {
+                    return Err(DcgError::InvalidTodo(format!(
+                        "cannot {} without a previous commit",
+                        step.action
+                    ))
+                    .into());
+                }
+
+                let previous = CommitObject::read(dd, head)?;
+
+                /* the previous commit is made again with the changes of
+                 * this one, on top of its parent
+                 */
+                let parent = get_parent(dd, head)?.ok_or(DcgError::EmptyTree)?;
+
+                update_branch(dd, &branch, parent, &format!("rebase: {}", step.action))?;
+                set_last(dd, &Snapshot::at(dd, Some(parent))?)?;
+
+                author = previous.author;
+
+                if step.action == Action::Fixup {
+                    previous.message
+                } else {
+                    let message = format!("{}\n\n{}", previous.message.trim_end(), commit.message);
+
+                    match edit(cfg, &message) {
+                        Ok(m) => m,
+                        Err(e) if matches!(e.downcast_ref(), Some(DcgError::NoEditor)) => message,
+                        Err(e) => return Err(e),
+                    }
+                }
+            }
+        };
+
+        stage(dd, files)?;
+
+        let options = CommitOptions {
+            author: Some(author),
+            action: Some("rebase"),
+            ..Default::default()
+        };
+
+        match record(dd, &message, &options, state, cfg, lvl) {
+            Err(e) if matches!(e.downcast_ref(), Some(DcgError::NoChanges)) => {
+                info!(
+                    lvl,
+                    "dropping {}: its changes are already applied",
+                    label(step.commit, &commit)
+                );
+
+                clear_index(dd)
+            }
+            r => r.map(|_| ()),
+        }
+    }
+
+    /// Apply the steps left, stopping at the first conflict.
+    fn run(
+        mut self,
+        dd: &Path,
+        state: LinguistState,
+        cfg: &Config,
+        lvl: NotificationLevel,
+    ) -> Result<()> {
+        while !self.todo.is_empty() {
+            let step = self.todo.remove(0);
+
+            if step.action == Action::Drop {
+                continue;
+            }
+
+            let head = fetch_head(dd, &get_branch(dd)?)?;
+            let tip = Snapshot::at(dd, head)?;
+            let commit = CommitObject::read(dd, step.commit)?;
+
+            let replayed = replay(
+                dd,
+                state,
+                step.commit,
+                &tip,
+                false,
+                Some(&label(step.commit, &commit)),
+            )?;
+
+            if !replayed.conflicts.is_empty() {
+                self.current = Some(step);
+                self.pending.files = replayed.files;
+                self.pending.conflicts = replayed.conflicts.clone();
+                self.write()?;
+
+                info!(lvl, "could not apply {}", label(step.commit, &commit));
+                info!(
+                    lvl,
+                    "resolve the conflicts and run 'dcg rebase --continue'. 'dcg rebase --skip' drops the commit and 'dcg rebase --abort' goes back to the original branch"
+                );
+
+                return Err(DcgError::MergeConflict(replayed.conflicts).into());
+            }
+
+            self.commit(dd, step, &replayed.files, state, cfg, lvl)?;
+            self.write()?;
+        }
+
+        self.pending.finish()?;
+
+        info!(lvl, "successfully rebased {}", get_branch(dd)?);
+
+        Ok(())
+    }
+
+    /// Restore the files changed by the step stopped by conflicts.
+    fn drop_current(&mut self, dd: &Path) -> Result<()> {
+        if let Some(step) = self.current.take() {
+            let tip = Snapshot::at(dd, fetch_head(dd, &get_branch(dd)?)?)?;
+
+            let paths = CommitObject::read(dd, step.commit)?
+                .changes
+                .into_iter()
+                .map(|c| c.path)
+                .collect::<Vec<_>>();
+
+            restore(dd, &tip, &paths)?;
+            clear_index(dd)?;
+        }
+
+        self.pending.files.clear();
+        self.pending.conflicts.clear();
+
+        Ok(())
+    }
+}
+
+/// The commits of `head` which are not in `onto`, oldest first.  Merges
+/// are left out: their changes are the ones of the commits they merge.
+fn commits_to_replay(dd: &Path, head: [u8; 32], onto: [u8; 32]) -> Result<Vec<[u8; 32]>> {
+    let mut walk = RevWalk::new(dd, &[head])?;
+
+    walk.hide(onto)?;
+    walk.topo_order()?;
+
+    let mut commits = Vec::new();
+
+    for entry in walk {
+        let (h, _) = entry?;
+
+        if get_parents(dd, h)?.len() <= 1 {
+            commits.push(h);
+        }
+    }
+
+    commits.reverse();
+
+    Ok(commits)
+}
+
+fn start(
+    dd: &Path,
+    upstream: &str,
+    interactive: bool,
+    state: LinguistState,
+    cfg: &Config,
+    lvl: NotificationLevel,
+) -> Result<()> {
+    Pending::ensure_none(dd, "rebase", REBASE_DIR)?;
+
+    let onto = resolve_revision(dd, upstream)?;
+    let tip = clean_head(dd)?;
+    let head = fetch_head(dd, &get_branch(dd)?)?.ok_or(DcgError::EmptyTree)?;
+
+    if !interactive && ancestors(dd, head)?.contains(&onto) {
+        info!(lvl, "current branch {} is up to date", get_branch(dd)?);
+
+        return Ok(());
+    }
+
+    let commits = commits_to_replay(dd, head, onto)?;
+
+    let todo = if interactive {
+        let mut text = String::new();
+
+        for h in &commits {
+            let commit = CommitObject::read(dd, *h)?;
+
+            text.push_str(&format!(
+                "pick {} {}\n",
+                hex::encode(&h[..4]),
+                commit.message.lines().next().unwrap_or("")
+            ));
+        }
+
+        text.push_str(&format!(
+            "\n# Rebase {} onto {} ({} command{})\n{}",
+            get_branch(dd)?,
+            hex::encode(&onto[..4]),
+            commits.len(),
+            if commits.len() == 1 { "" } else { "s" },
+            TODO_HELP
+        ));
+
+        let todo = parse_todo(dd, &edit(cfg, &text)?)?;
+
+        if todo.is_empty() {
+            info!(lvl, "nothing to do");
+
+            return Ok(());
+        }
+
+        todo
+    } else {
+        commits
+            .into_iter()
+            .map(|commit| Step {
+                action: Action::Pick,
+                commit,
+            })
+            .collect()
+    };
+
+    let rebase = Rebase {
+        pending: Pending::new(dd, "rebase", REBASE_DIR),
+        head,
+        onto,
+        todo,
+        current: None,
+    };
+
+    rebase.write()?;
+    reset(
+        dd,
+        &tip,
+        onto,
+        &format!("rebase: start onto {}", hex::encode(onto)),
+    )?;
+
+    rebase.run(dd, state, cfg, lvl)
+}
+
+/// Commit the step stopped by conflicts once they are resolved, and go
+/// on.
+fn resume(dd: &Path, state: LinguistState, cfg: &Config, lvl: NotificationLevel) -> Result<()> {
+    let mut rebase = Rebase::read(dd)?;
+    let files = rebase.pending.resolved_files(dd)?;
+
+    if let Some(step) = rebase.current.take() {
+        rebase.commit(dd, step, &files, state, cfg, lvl)?;
+        rebase.pending.files.clear();
+        rebase.pending.conflicts.clear();
+        rebase.write()?;
+    }
+
+    rebase.run(dd, state, cfg, lvl)
+}
+
+fn skip(dd: &Path, state: LinguistState, cfg: &Config, lvl: NotificationLevel) -> Result<()> {
+    let mut rebase = Rebase::read(dd)?;
+
+    rebase.drop_current(dd)?;
+    rebase.write()?;
+    rebase.run(dd, state, cfg, lvl)
+}
+
+/// Give up the rebase and go back to the original head of the branch.
+fn abort(dd: &Path) -> Result<()> {
+    let mut rebase = Rebase::read(dd)?;
+
+    rebase.drop_current(dd)?;
+
+    let tip = Snapshot::at(dd, fetch_head(dd, &get_branch(dd)?)?)?;
+
+    reset(dd, &tip, rebase.head, "rebase: abort")?;
+
+    rebase.pending.finish()
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn rebase(
+    upstream: &Option<String>,
+    interactive: bool,
+    resume_rebase: bool,
+    skip_commit: bool,
+    abort_rebase: bool,
+    state: LinguistState,
+    cfg: &Config,
+    lvl: NotificationLevel,
+) -> Result<()> {
+    let wd = env::current_dir().map(fs::canonicalize)??.into_boxed_path();
+    let dd = find_repo(&wd)?;
+
+    match (upstream, resume_rebase, skip_commit, abort_rebase) {
+        (_, true, _, _) => resume(dd, state, cfg, lvl),
+        (_, _, true, _) => skip(dd, state, cfg, lvl),
+        (_, _, _, true) => abort(dd),
+        (Some(u), _, _, _) => start(dd, u, interactive, state, cfg, lvl),
+        /* clap requires one of them */
+        (None, _, _, _) => unreachable!(),
+    }
+}
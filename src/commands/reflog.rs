@@ -0,0 +1,38 @@
+//! Listing of the moves of a branch
+use std::{env, fs};
+
+use anyhow::Result;
+
+use crate::{
+    vcs::{config::Config, find_repo, reflog::read_reflog},
+    NotificationLevel,
+};
+
+/// List the moves of `reference`, a branch or, by default, `HEAD`,
+/// most recent first, as the revisions naming the commits they moved
+/// to.
+pub(crate) fn reflog(
+    reference: &Option<String>,
+    _cfg: &Config,
+    _lvl: NotificationLevel,
+) -> Result<()> {
+    let wd = env::current_dir().map(fs::canonicalize)??.into_boxed_path();
+    let dd = find_repo(&wd)?;
+
+    let name = reference.as_deref().unwrap_or("HEAD");
+
+    for (n, entry) in read_reflog(dd, name)?.iter().enumerate() {
+        println!(
+            "{} {}@{{{}}}: {} ({} <{}>, {})",
+            hex::encode(&entry.new[..4]),
+            name,
+            n,
+            entry.reason,
+            entry.identity.name,
+            entry.identity.email,
+            entry.identity.datetime()?.date()
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,92 @@
+//! Management of the remote repositories
+use std::{env, fs};
+
+use anyhow::Result;
+use clap::Subcommand;
+
+use crate::{
+    combine_paths, info,
+    vcs::{
+        config::{edit_repo_config, repo_config, Config},
+        find_repo,
+        remote::{add_remote, check_url},
+        DcgError, DCG_DIR, REMOTES_DIR,
+    },
+    NotificationLevel,
+};
+
+#[derive(Subcommand)]
+pub(crate) enum RemoteCommand {
+    /// add a remote repository, given by the path of a repository or
+    /// of a bundle, or by a 'file://', 'dcg://', 'ssh://' or 'http(s)://'
+    /// URL
+    Add { name: String, url: String },
+    /// remove a remote and its branches
+    Remove { name: String },
+    /// list the remotes
+    List {
+        /// show the URL of each remote
+        #[arg(short, long)]
+        verbose: bool,
+    },
+}
+
+pub(crate) fn remote(
+    command: &Option<RemoteCommand>,
+    cfg: &Config,
+    lvl: NotificationLevel,
+) -> Result<()> {
+    let wd = env::current_dir().map(fs::canonicalize)??.into_boxed_path();
+    let dd = find_repo(&wd)?;
+
+    let remotes = repo_config(dd, cfg)?.remote.unwrap_or_default();
+
+    match command {
+        Some(RemoteCommand::Add { name, url }) => {
+            if remotes.contains_key(name) {
+                return Err(DcgError::RemoteExists(name.clone()).into());
+            }
+
+            if name.is_empty() || name.contains('/') {
+                return Err(DcgError::InvalidRemoteName(name.clone()).into());
+            }
+
+            check_url(dd, url)?;
+
+            add_remote(dd, name, url)?;
+        }
+        Some(RemoteCommand::Remove { name }) => {
+            if !remotes.contains_key(name) {
+                return Err(DcgError::UnknownRemote(name.clone()).into());
+            }
+
+            edit_repo_config(dd, |t| {
+                if let Some(remote) = t.get_mut("remote").and_then(|r| r.as_table_mut()) {
+                    remote.remove(name);
+                }
+
+                Ok(())
+            })?;
+
+            let tracking = combine_paths!(dd, DCG_DIR, REMOTES_DIR, name);
+
+            if tracking.is_dir() {
+                fs::remove_dir_all(tracking)?;
+            }
+
+            info!(lvl, "removed remote '{}'", name);
+        }
+        None | Some(RemoteCommand::List { verbose: false }) => {
+            for name in remotes.keys() {
+                println!("{}", name);
+            }
+        }
+        Some(RemoteCommand::List { verbose: true }) => {
+            for (name, r) in &remotes {
+                println!("{}\t{}", name, r.url);
+            }
+        }
+    }
+
+    Ok(())
+}
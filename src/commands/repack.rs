@@ -0,0 +1,21 @@
+//! Consolidation of the objects of a repository in a pack
+use std::{env, fs};
+
+use anyhow::Result;
+
+use crate::{
+    info,
+    vcs::{config::Config, find_repo, pack::repack as write_pack},
+    NotificationLevel,
+};
+
+pub(crate) fn repack(_cfg: &Config, lvl: NotificationLevel) -> Result<()> {
+    let wd = env::current_dir().map(fs::canonicalize)??.into_boxed_path();
+    let dd = find_repo(&wd)?;
+
+    let n = write_pack(dd)?;
+
+    info!(lvl, "{} objects packed", n);
+
+    Ok(())
+}
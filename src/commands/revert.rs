@@ -0,0 +1,52 @@
+use std::{env, fs};
+
+use anyhow::Result;
+
+use crate::{
+    backend::linguist::LinguistState,
+    commands::{
+        cherry_pick::clean_head,
+        commit::{record, CommitOptions},
+    },
+    vcs::{
+        commit::CommitObject, config::Config, find_repo, index::stage, merge::replay,
+        revision::resolve_revision, DcgError,
+    },
+    NotificationLevel,
+};
+
+pub(crate) fn revert(
+    revision: &str,
+    state: LinguistState,
+    cfg: &Config,
+    lvl: NotificationLevel,
+) -> Result<()> {
+    let wd = env::current_dir().map(fs::canonicalize)??.into_boxed_path();
+    let dd = find_repo(&wd)?;
+
+    let h = resolve_revision(dd, revision)?;
+    let tip = clean_head(dd)?;
+    let replayed = replay(dd, state, h, &tip, true, None)?;
+
+    if !replayed.conflicts.is_empty() {
+        return Err(DcgError::MergeConflict(replayed.conflicts).into());
+    }
+
+    stage(dd, &replayed.files)?;
+
+    let commit = CommitObject::read(dd, h)?;
+    let message = format!(
+        "Revert \"{}\"\n\nThis reverts commit {}.\n",
+        commit.message.lines().next().unwrap_or(""),
+        hex::encode(h)
+    );
+
+    let options = CommitOptions {
+        action: Some("revert"),
+        ..Default::default()
+    };
+
+    record(dd, &message, &options, state, cfg, lvl)?;
+
+    Ok(())
+}
@@ -0,0 +1,112 @@
+//! Access to the repository from other machines
+use std::{
+    env, fs,
+    io::{self, BufReader, BufWriter},
+    net::TcpListener,
+    path::Path,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use tiny_http::Server;
+
+use crate::{
+    info,
+    vcs::{
+        config::{repo_config, Config},
+        find_repo,
+        http::serve_http,
+        protocol::serve_session,
+        DcgError,
+    },
+    warning, NotificationLevel,
+};
+
+/// How long a client may stay silent before being dropped.
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Serve the repository to clients authenticated by a token, one at a
+/// time so that their changes do not interleave.
+pub(crate) fn serve(
+    http: bool,
+    address: &str,
+    port: u16,
+    token: &Option<String>,
+    cfg: &Config,
+    lvl: NotificationLevel,
+) -> Result<()> {
+    let wd = env::current_dir().map(fs::canonicalize)??.into_boxed_path();
+    let dd = find_repo(&wd)?;
+
+    let token = match token {
+        Some(t) => t.clone(),
+        None => repo_config(dd, cfg)?
+            .serve
+            .and_then(|s| s.token)
+            .ok_or(DcgError::NoServeToken)?,
+    };
+
+    if http {
+        let server = Server::http((address, port)).map_err(|e| anyhow!(e))?;
+
+        info!(
+            lvl,
+            "Serving '{}' on http://{}",
+            dd.display(),
+            server.server_addr()
+        );
+
+        for request in server.incoming_requests() {
+            let peer = request
+                .remote_addr()
+                .map(|a| a.to_string())
+                .unwrap_or_default();
+
+            if let Err(e) = serve_http(dd, request, &token) {
+                warning!(lvl, "{}: {}", peer, e);
+            }
+        }
+
+        return Ok(());
+    }
+
+    let listener = TcpListener::bind((address, port))?;
+
+    info!(
+        lvl,
+        "Serving '{}' on {}",
+        dd.display(),
+        listener.local_addr()?
+    );
+
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let peer = stream.peer_addr()?;
+
+        stream.set_read_timeout(Some(TIMEOUT))?;
+
+        let mut r = BufReader::new(stream.try_clone()?);
+        let mut w = BufWriter::new(stream);
+
+        match serve_session(dd, &mut r, &mut w, Some(&token)) {
+            Ok(()) => info!(lvl, "served {}", peer),
+            Err(e) => warning!(lvl, "{}: {}", peer, e),
+        }
+    }
+
+    Ok(())
+}
+
+/// Serve the repository at `directory` on the standard input and
+/// output, for a client which started the command through `ssh`.
+pub(crate) fn serve_pack(directory: &Option<String>) -> Result<()> {
+    let wd = fs::canonicalize(directory.as_deref().map_or(Path::new("."), Path::new))?;
+    let dd = find_repo(&wd)?;
+
+    serve_session(
+        dd,
+        &mut BufReader::new(io::stdin().lock()),
+        &mut BufWriter::new(io::stdout().lock()),
+        None,
+    )
+}
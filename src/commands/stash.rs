@@ -0,0 +1,427 @@
+//! Shelving of the changes of the index and of the working tree
+use std::{
+    collections::BTreeSet,
+    env,
+    fs::{self, File},
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use clap::Subcommand;
+
+use crate::{
+    backend::linguist::LinguistState,
+    combine_paths,
+    commands::{cherry_pick::clean_head, diff::diff_revisions},
+    info,
+    vcs::{
+        checkout::{checkout, modified_files, set_last},
+        commit::{
+            fetch_head, get_branch, get_parent, get_parents, Change, CommitObject, Signature,
+        },
+        config::Config,
+        find_repo,
+        index::{clear_index, get_indexed_files, stage},
+        lock::write_atomic,
+        merge::replay,
+        snapshot::Snapshot,
+        DcgError, DCG_DIR, STASH_REF,
+    },
+    NotificationLevel,
+};
+
+#[derive(Subcommand)]
+pub(crate) enum StashCommand {
+    /// save the changes of the index and of the working tree, and
+    /// go back to the last commit
+    Push {
+        /// describe the stashed changes
+        #[arg(short, long)]
+        message: Option<String>,
+    },
+    /// apply a stash entry (by default the last one) and drop it
+    Pop { stash: Option<String> },
+    /// apply a stash entry (by default the last one)
+    Apply { stash: Option<String> },
+    /// list the stash entries
+    List,
+    /// forget a stash entry (by default the last one)
+    Drop { stash: Option<String> },
+    /// show the changes of a stash entry (by default the last one)
+    Show { stash: Option<String> },
+}
+
+/// The stash entries, latest first.  Each entry is a commit holding the
+/// working tree, which parents are the commit it was made on and, if
+/// files were in the index, a commit holding the index.
+fn read_stack(dd: &Path) -> Result<Vec<[u8; 32]>> {
+    let p = combine_paths!(dd, DCG_DIR, STASH_REF);
+
+    if !p.is_file() {
+        return Ok(Vec::new());
+    }
+
+    let mut s = String::new();
+
+    File::open(p)?.read_to_string(&mut s)?;
+
+    s.split_whitespace()
+        .map(|x| match hex::decode(x)?.try_into() {
+            Ok(h) => Ok(h),
+            _ => Err(DcgError::InvalidCommit.into()),
+        })
+        .collect()
+}
+
+fn write_stack(dd: &Path, stack: &[[u8; 32]]) -> Result<()> {
+    let p = combine_paths!(dd, DCG_DIR, STASH_REF);
+
+    if stack.is_empty() {
+        if p.is_file() {
+            fs::remove_file(p)?;
+        }
+
+        return Ok(());
+    }
+
+    let s = stack.iter().map(hex::encode).collect::<Vec<_>>().join("\n");
+
+    write_atomic(dd, &p, s)?;
+
+    Ok(())
+}
+
+/// The position in the stack of `stash@{n}` or `n`, 0 by default.
+fn entry(stack: &[[u8; 32]], stash: &Option<String>) -> Result<usize> {
+    let Some(s) = stash else {
+        return if stack.is_empty() {
+            Err(DcgError::NoStashEntry("stash@{0}".to_string()).into())
+        } else {
+            Ok(0)
+        };
+    };
+
+    let n = s
+        .strip_prefix("stash@{")
+        .and_then(|n| n.strip_suffix('}'))
+        .unwrap_or(s);
+
+    match n.parse::<usize>() {
+        Ok(n) if n < stack.len() => Ok(n),
+        _ => Err(DcgError::NoStashEntry(s.clone()).into()),
+    }
+}
+
+/// Commit the files of the index with `parents`, without moving the
+/// branch.
+fn commit_index(
+    dd: &Path,
+    state: LinguistState,
+    cfg: &Config,
+    message: &str,
+    paths: &BTreeSet<PathBuf>,
+    parents: &[[u8; 32]],
+) -> Result<[u8; 32]> {
+    let mut changes = Vec::new();
+
+    for path in paths {
+        if let Some(ch) = Change::from(state, path, &dd.to_path_buf())? {
+            changes.push(ch);
+        }
+    }
+
+    let author = Signature::now("AUTHOR", cfg)?;
+    let committer = Signature::now("COMMITTER", cfg)?;
+
+    CommitObject::new(author, committer, message.to_string(), changes).write_detached(dd, parents)
+}
+
+fn push(
+    dd: &Path,
+    message: &Option<String>,
+    state: LinguistState,
+    cfg: &Config,
+    lvl: NotificationLevel,
+) -> Result<()> {
+    let branch = get_branch(dd)?;
+    let head = fetch_head(dd, &branch)?.ok_or(DcgError::EmptyTree)?;
+    let tip = Snapshot::at(dd, Some(head))?;
+
+    let indexed = get_indexed_files(dd)?;
+
+    /* the files in the index are tracked, even if they were not
+     * committed yet
+     */
+    let mut paths = tip.files.keys().cloned().collect::<BTreeSet<_>>();
+    paths.extend(indexed.iter().cloned());
+
+    let added = indexed
+        .iter()
+        .any(|p| !tip.files.contains_key(p) && dd.join(p).is_file());
+
+    if indexed.is_empty() && !added && modified_files(dd, &tip)?.is_empty() {
+        return Err(DcgError::NoLocalChanges.into());
+    }
+
+    let subject = CommitObject::read(dd, head)?
+        .message
+        .lines()
+        .next()
+        .unwrap_or("")
+        .to_string();
+
+    let description = match message {
+        Some(m) => format!("On {}: {}", branch, m),
+        None => format!("WIP on {}: {} {}", branch, hex::encode(&head[..4]), subject),
+    };
+
+    let mut parents = vec![head];
+
+    if !indexed.is_empty() {
+        let index_message = format!(
+            "index on {}: {} {}",
+            branch,
+            hex::encode(&head[..4]),
+            subject
+        );
+
+        parents.push(commit_index(
+            dd,
+            state,
+            cfg,
+            &index_message,
+            &paths,
+            &[head],
+        )?);
+    }
+
+    /* the working tree is committed as if all of its tracked files
+     * were added to the index
+     */
+    let files = paths
+        .iter()
+        .filter(|p| dd.join(p).is_file())
+        .cloned()
+        .collect::<Vec<_>>();
+
+    clear_index(dd)?;
+    stage(dd, &files)?;
+
+    let h = commit_index(dd, state, cfg, &description, &paths, &parents)?;
+
+    checkout(dd, &Snapshot::at(dd, Some(h))?, &tip)?;
+    set_last(dd, &tip)?;
+    clear_index(dd)?;
+
+    let mut stack = read_stack(dd)?;
+
+    stack.insert(0, h);
+    write_stack(dd, &stack)?;
+
+    info!(
+        lvl,
+        "saved working directory and index state {}", description
+    );
+
+    Ok(())
+}
+
+/// Merge the changes of the stash entry `h` into the working tree.
+fn apply(
+    dd: &Path,
+    state: LinguistState,
+    h: [u8; 32],
+    name: &str,
+    lvl: NotificationLevel,
+) -> Result<()> {
+    let tip = clean_head(dd)?;
+    let replayed = replay(dd, state, h, &tip, false, Some(name))?;
+
+    if !replayed.conflicts.is_empty() {
+        info!(
+            lvl,
+            "{} does not apply cleanly. resolve the conflicts, then drop it with 'dcg stash drop'",
+            name
+        );
+
+        return Err(DcgError::MergeConflict(replayed.conflicts).into());
+    }
+
+    /* files which were in the index are added again */
+    if get_parents(dd, h)?.len() > 1 {
+        stage(dd, &replayed.files)?;
+    }
+
+    Ok(())
+}
+
+fn drop_entry(
+    dd: &Path,
+    stack: &mut Vec<[u8; 32]>,
+    n: usize,
+    lvl: NotificationLevel,
+) -> Result<()> {
+    let h = stack.remove(n);
+
+    write_stack(dd, stack)?;
+
+    info!(lvl, "dropped stash@{{{}}} ({})", n, hex::encode(&h[..4]));
+
+    Ok(())
+}
+
+pub(crate) fn stash(
+    command: &Option<StashCommand>,
+    state: LinguistState,
+    cfg: &Config,
+    lvl: NotificationLevel,
+) -> Result<()> {
+    let wd = env::current_dir().map(fs::canonicalize)??.into_boxed_path();
+    let dd = find_repo(&wd)?;
+
+    let mut stack = read_stack(dd)?;
+
+    match command {
+        None => push(dd, &None, state, cfg, lvl)?,
+        Some(StashCommand::Push { message }) => push(dd, message, state, cfg, lvl)?,
+        Some(StashCommand::Pop { stash }) => {
+            let n = entry(&stack, stash)?;
+
+            apply(dd, state, stack[n], &format!("stash@{{{}}}", n), lvl)?;
+            drop_entry(dd, &mut stack, n, lvl)?;
+        }
+        Some(StashCommand::Apply { stash }) => {
+            let n = entry(&stack, stash)?;
+
+            apply(dd, state, stack[n], &format!("stash@{{{}}}", n), lvl)?;
+        }
+        Some(StashCommand::List) => {
+            for (n, h) in stack.iter().enumerate() {
+                let commit = CommitObject::read(dd, *h)?;
+
+                println!(
+                    "stash@{{{}}}: {}",
+                    n,
+                    commit.message.lines().next().unwrap_or("")
+                );
+            }
+        }
+        Some(StashCommand::Drop { stash }) => {
+            let n = entry(&stack, stash)?;
+
+            drop_entry(dd, &mut stack, n, lvl)?;
+        }
+        Some(StashCommand::Show { stash }) => {
+            let h = stack[entry(&stack, stash)?];
+            let before = Snapshot::at(dd, get_parent(dd, h)?)?;
+            let after = Snapshot::at(dd, Some(h))?;
+
+            diff_revisions(state, dd, &before, Some(&after), &[])?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::BTreeMap, fs, path::Path};
+
+    use super::{apply, push, read_stack};
+    use crate::{
+        vcs::{
+            config::{Config, User},
+            index::{get_indexed_files, stage},
+            testing::{commit_and_check_out, repo},
+            DcgError,
+        },
+        NotificationLevel,
+    };
+
+    fn config() -> Config {
+        Config {
+            user: Some(User {
+                name: Some("A U Thor".to_string()),
+                email: Some("a@u.th".to_string()),
+            }),
+            ..Config::default()
+        }
+    }
+
+    fn read(dd: &Path, path: &str) -> Option<String> {
+        fs::read_to_string(dd.join(path)).ok()
+    }
+
+    #[test]
+    fn push_and_pop() {
+        let dd = repo();
+        let empty = BTreeMap::new();
+        let state = (&empty, &empty, &empty, &empty);
+        let lvl = NotificationLevel::Errors;
+
+        commit_and_check_out(&dd, &[("a.txt", "a\n"), ("b.txt", "b\n")]);
+
+        /* a staged modification and addition, and an unstaged one */
+        fs::write(dd.join("a.txt"), "a\nA\n").unwrap();
+        fs::write(dd.join("c.txt"), "c\n").unwrap();
+        stage(&dd, &["a.txt".into(), "c.txt".into()]).unwrap();
+        fs::write(dd.join("b.txt"), "b\nB\n").unwrap();
+
+        push(&dd, &None, state, &config(), lvl).unwrap();
+
+        let stack = read_stack(&dd).unwrap();
+
+        assert_eq!(stack.len(), 1);
+        assert_eq!(read(&dd, "a.txt").as_deref(), Some("a\n"));
+        assert_eq!(read(&dd, "b.txt").as_deref(), Some("b\n"));
+        assert_eq!(read(&dd, "c.txt"), None);
+        assert!(get_indexed_files(&dd).unwrap().is_empty());
+
+        /* the branch moved on meanwhile, without touching the same lines */
+        commit_and_check_out(&dd, &[("d.txt", "d\n")]);
+
+        apply(&dd, state, stack[0], "stash@{0}", lvl).unwrap();
+
+        assert_eq!(read(&dd, "a.txt").as_deref(), Some("a\nA\n"));
+        assert_eq!(read(&dd, "b.txt").as_deref(), Some("b\nB\n"));
+        assert_eq!(read(&dd, "c.txt").as_deref(), Some("c\n"));
+        assert_eq!(read(&dd, "d.txt").as_deref(), Some("d\n"));
+
+        let indexed = get_indexed_files(&dd).unwrap();
+
+        assert!(indexed.contains(&"a.txt".into()));
+        assert!(indexed.contains(&"c.txt".into()));
+    }
+
+    #[test]
+    fn pop_conflict() {
+        let dd = repo();
+        let empty = BTreeMap::new();
+        let state = (&empty, &empty, &empty, &empty);
+        let lvl = NotificationLevel::Errors;
+
+        commit_and_check_out(&dd, &[("a.txt", "a\n")]);
+
+        fs::write(dd.join("a.txt"), "stashed\n").unwrap();
+        push(&dd, &Some("edit a".to_string()), state, &config(), lvl).unwrap();
+
+        /* the same line was changed on the branch */
+        commit_and_check_out(&dd, &[("a.txt", "committed\n")]);
+
+        let stack = read_stack(&dd).unwrap();
+        let err = apply(&dd, state, stack[0], "stash@{0}", lvl).unwrap_err();
+
+        assert!(matches!(
+            err.downcast_ref::<DcgError>(),
+            Some(DcgError::MergeConflict(ps)) if ps == &vec![Path::new("a.txt").to_path_buf()]
+        ));
+
+        /* the entry is kept, and the conflict left to resolve */
+        assert_eq!(read_stack(&dd).unwrap(), stack);
+
+        let a = read(&dd, "a.txt").unwrap();
+
+        assert!(a.contains("stashed") && a.contains("committed") && a.contains("stash@{0}"));
+    }
+}
@@ -1,12 +1,20 @@
+use std::{env, fs};
+
 use anyhow::Result;
 
 use crate::{
-    vcs::index::{compute_status, ObjStatus},
+    vcs::{
+        find_repo,
+        index::{compute_status, ObjStatus},
+    },
     NotificationLevel,
 };
 
 pub(crate) fn status(_lvl: NotificationLevel) -> Result<()> {
-    let status = compute_status()?;
+    let wd = env::current_dir().map(fs::canonicalize)??.into_boxed_path();
+    let dd = find_repo(&wd)?;
+
+    let status = compute_status(dd)?;
 
     for (path, s) in status
         .into_iter()
@@ -126,17 +126,108 @@ fn try_main() -> Result<()> {
 
     let state = (&filenames, &shebang, &modelines, &heuristics);
 
+    /* held until the command returns */
+    let _lock = commands::lock_repository(&args.command, state, lvl)?;
+
     match &args.command {
         Commands::Init {
             initial_branch,
             directory,
         } => commands::init::init(initial_branch, directory, &cfg, lvl),
-        Commands::Add { paths } => commands::add::add(paths, &cfg, lvl),
+        Commands::Clone {
+            source,
+            directory,
+            depth,
+        } => commands::clone::clone(source, directory, *depth, lvl),
+        Commands::Add { paths, patch } => {
+            if *patch {
+                commands::add::add_patch(paths, state, &cfg, lvl)
+            } else {
+                commands::add::add(paths, &cfg, lvl)
+            }
+        }
         Commands::Rm { paths } => commands::rm::rm(paths, &cfg, lvl),
         Commands::Status => commands::status::status(lvl),
-        Commands::Diff { files } => commands::diff::diff(files, state, &cfg, lvl),
-        Commands::Commit { message } => commands::commit::commit(message, state, &cfg, lvl),
-        Commands::Log { one_line } => commands::log::log(state, &cfg, lvl, *one_line),
+        Commands::Diff { args, files } => commands::diff::diff(args, files, state, &cfg, lvl),
+        Commands::Commit(commit_args) => commands::commit::commit(commit_args, state, &cfg, lvl),
+        Commands::Log(log_args) => commands::log::log(log_args, state, &cfg, lvl),
+        Commands::Blame {
+            file,
+            revision,
+            granularity,
+        } => commands::blame::blame(file, revision, *granularity, state, &cfg, lvl),
+        Commands::Grep {
+            pattern,
+            args,
+            files,
+            query,
+            ignore_case,
+            count,
+            files_with_matches,
+            log,
+        } => commands::grep::grep(
+            pattern,
+            args,
+            files,
+            *query,
+            *ignore_case,
+            *count,
+            *files_with_matches,
+            *log,
+            state,
+            &cfg,
+            lvl,
+        ),
+        Commands::Revert { revision } => commands::revert::revert(revision, state, &cfg, lvl),
+        Commands::CherryPick {
+            revision,
+            resume,
+            abort,
+        } => commands::cherry_pick::cherry_pick(revision, *resume, *abort, state, &cfg, lvl),
+        Commands::Rebase {
+            upstream,
+            interactive,
+            resume,
+            skip,
+            abort,
+        } => commands::rebase::rebase(
+            upstream,
+            *interactive,
+            *resume,
+            *skip,
+            *abort,
+            state,
+            &cfg,
+            lvl,
+        ),
+        Commands::Stash { command } => commands::stash::stash(command, state, &cfg, lvl),
+        Commands::Remote { command } => commands::remote::remote(command, &cfg, lvl),
+        Commands::Bundle { command } => commands::bundle::bundle(command, &cfg, lvl),
+        Commands::Gc { dry_run, expire } => commands::gc::gc(*dry_run, expire, &cfg, lvl),
+        Commands::Prune { dry_run, expire } => commands::gc::prune(*dry_run, expire, &cfg, lvl),
+        Commands::Repack => commands::repack::repack(&cfg, lvl),
+        Commands::Fsck => commands::fsck::fsck(&cfg, lvl),
+        Commands::Reflog { reference } => commands::reflog::reflog(reference, &cfg, lvl),
+        Commands::Fetch { remote } => commands::fetch::fetch(remote, &cfg, lvl),
+        Commands::Pull {
+            remote,
+            branch,
+            resume,
+            abort,
+        } => commands::pull::pull(remote, branch, *resume, *abort, state, &cfg, lvl),
+        Commands::Push {
+            remote,
+            branch,
+            force,
+        } => commands::push::push(remote, branch, *force, &cfg, lvl),
+        Commands::Serve {
+            http,
+            port,
+            address,
+            token,
+        } => commands::serve::serve(*http, address, *port, token, &cfg, lvl),
+        Commands::ServePack { directory } => commands::serve::serve_pack(directory),
+        Commands::Bisect { command } => commands::bisect::bisect(command, &cfg, lvl),
         Commands::Tag { tag, commit } => commands::tag::tag(tag, commit, &cfg, lvl),
     }
 }
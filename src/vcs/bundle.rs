@@ -0,0 +1,293 @@
+//! Bundles: history stored in a single file, to be carried where no
+//! network reaches
+//!
+//! A bundle starts with a line naming its format, followed by the
+//! SHA-256 hash of the rest of the file, which is compressed with gzip.
+//! Once decompressed, it holds the refs of the bundle (see
+//! `protocol::write_refs`), the list of the commits the repository
+//! receiving the bundle must already have, and the commits themselves,
+//! as written by `transfer::write_commits`.
+use std::{
+    collections::BTreeSet,
+    fs::{self, File},
+    io::{Cursor, Read, Write},
+    path::Path,
+};
+
+use anyhow::Result;
+use sha2::{Digest, Sha256};
+
+use crate::combine_paths;
+
+use super::{
+    commit::{get_branch, get_parents},
+    gz_decode, gz_encode,
+    protocol::{parse_hash, read_list, read_refs, write_list, write_refs},
+    remote::{Refs, RemoteRepository},
+    revision::{ancestors, resolve_revision},
+    transfer::{has_commit, missing_commits, read_commits, write_commits},
+    DcgError, BRANCHES_DIR, DCG_DIR, TAGS_DIR,
+};
+
+const MAGIC: &[u8] = b"dcg bundle 1\n";
+
+/// Whether the file at `path` is a bundle.
+pub(crate) fn is_bundle(path: &Path) -> bool {
+    let mut magic = [0; MAGIC.len()];
+
+    path.is_file()
+        && File::open(path)
+            .and_then(|mut f| f.read_exact(&mut magic))
+            .is_ok()
+        && magic == MAGIC
+}
+
+/// The refs of a bundle of the repository `dd` made for `rev`, the end
+/// of a range: the branch or the tag it names.
+fn bundle_refs(dd: &Path, rev: &str, h: [u8; 32]) -> Result<Refs> {
+    let mut refs = Refs::default();
+
+    let name = match rev {
+        "" | "HEAD" => get_branch(dd)?.trim().to_string(),
+        _ => rev.to_string(),
+    };
+
+    if combine_paths!(dd, DCG_DIR, BRANCHES_DIR, &name).is_file() {
+        refs.branches.insert(name.clone(), h);
+        refs.head = name;
+    } else if combine_paths!(dd, DCG_DIR, TAGS_DIR, &name).is_file() {
+        refs.tags.insert(name, h);
+    } else {
+        return Err(DcgError::NotARef(rev.to_string()).into());
+    }
+
+    Ok(refs)
+}
+
+/// Write to `file` a bundle of the commits of the repository `dd` in
+/// `range`: `<rev>`, for all the history of `rev`, or `<from>..<rev>`,
+/// for the commits of `rev` which `from` does not have.  `rev` must be
+/// a branch or a tag.  Returns the number of commits bundled.
+pub(crate) fn create_bundle(dd: &Path, file: &Path, range: &str) -> Result<usize> {
+    let (from, rev) = match range.split_once("..") {
+        Some((from, rev)) => (Some(from), rev),
+        None => (None, range),
+    };
+
+    let resolve = |r: &str| resolve_revision(dd, if r.is_empty() { "HEAD" } else { r });
+
+    let h = resolve(rev)?;
+    let known = match from {
+        Some(from) => ancestors(dd, resolve(from)?)?,
+        None => BTreeSet::new(),
+    };
+
+    let commits = missing_commits(dd, &[h], |c| known.contains(&c))?;
+    let bundled = commits.iter().copied().collect::<BTreeSet<_>>();
+
+    /* the parents left out of the bundle */
+    let mut prerequisites = BTreeSet::new();
+
+    for c in &commits {
+        prerequisites.extend(
+            get_parents(dd, *c)?
+                .into_iter()
+                .filter(|p| !bundled.contains(p)),
+        );
+    }
+
+    let mut payload = Vec::new();
+
+    write_refs(&mut payload, &bundle_refs(dd, rev, h)?)?;
+    write_list(
+        &mut payload,
+        &prerequisites.iter().map(hex::encode).collect::<Vec<_>>(),
+    )?;
+    write_commits(dd, &(commits, Vec::new()), &mut payload)?;
+
+    let compressed = gz_encode(&payload)?;
+    let checksum: [u8; 32] = Sha256::digest(&compressed).into();
+
+    let mut f = File::create(file)?;
+
+    f.write_all(MAGIC)?;
+    f.write_all(&checksum)?;
+    f.write_all(&compressed)?;
+
+    Ok(bundled.len())
+}
+
+/// A bundle, read and checked.
+pub(crate) struct Bundle {
+    refs: Refs,
+    /// the commits the receiving repository must have
+    prerequisites: Vec<[u8; 32]>,
+    /// the commits, as written by `transfer::write_commits`
+    commits: Vec<u8>,
+}
+
+impl Bundle {
+    pub(crate) fn open(path: &Path) -> Result<Self> {
+        let invalid = || DcgError::InvalidBundle(path.display().to_string());
+
+        let contents = fs::read(path)?;
+        let rest = contents.strip_prefix(MAGIC).ok_or_else(invalid)?;
+
+        if rest.len() < 32 {
+            return Err(invalid().into());
+        }
+
+        let (checksum, compressed) = rest.split_at(32);
+
+        if Sha256::digest(compressed)[..] != checksum[..] {
+            return Err(DcgError::CorruptBundle(path.display().to_string()).into());
+        }
+
+        let mut payload = Cursor::new(gz_decode(compressed)?);
+
+        let refs = read_refs(&mut payload)?;
+        let prerequisites = read_list(&mut payload)?
+            .iter()
+            .map(|h| parse_hash(h))
+            .collect::<Result<Vec<_>>>()?;
+
+        let start = payload.position() as usize;
+        let mut commits = payload.into_inner();
+        commits.drain(..start);
+
+        Ok(Self {
+            refs,
+            prerequisites,
+            commits,
+        })
+    }
+
+    /// Store the commits of the bundle in the repository `dd`, which
+    /// must have its prerequisites.  Returns the number of commits
+    /// which were missing.
+    pub(crate) fn unbundle(&self, dd: &Path) -> Result<usize> {
+        let missing = self
+            .prerequisites
+            .iter()
+            .filter(|h| !has_commit(dd, **h))
+            .map(hex::encode)
+            .collect::<Vec<_>>();
+
+        if !missing.is_empty() {
+            return Err(DcgError::MissingPrerequisites(missing).into());
+        }
+
+        read_commits(dd, &mut self.commits.as_slice())
+    }
+}
+
+impl RemoteRepository for Bundle {
+    fn refs(&mut self) -> Result<Refs> {
+        Ok(self.refs.clone())
+    }
+
+    /// The whole bundle is stored, whichever commits are wanted.
+    fn fetch(&mut self, dd: &Path, _wants: &[[u8; 32]], depth: Option<usize>) -> Result<()> {
+        if depth.is_some() {
+            return Err(DcgError::NotSupported("shallow copies of a bundle".to_string()).into());
+        }
+
+        self.unbundle(dd)?;
+
+        Ok(())
+    }
+
+    fn push(&mut self, _dd: &Path, _branch: &str, _h: [u8; 32], _force: bool) -> Result<()> {
+        Err(DcgError::NotSupported("pushing to a bundle".to_string()).into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, path::Path};
+
+    use mktemp::Temp;
+
+    use super::{create_bundle, is_bundle, Bundle};
+    use crate::vcs::{
+        commit::update_branch,
+        remote::RemoteRepository,
+        snapshot::Snapshot,
+        testing::{commit, repo},
+        transfer::has_commit,
+    };
+
+    #[test]
+    fn create_and_unbundle() {
+        let (src, dst) = (repo(), repo());
+        let file = Temp::new_file().unwrap();
+
+        let first = commit(&src, "master", &[("a.txt", "a\n")]);
+        let second = commit(&src, "master", &[("b.txt", "b\n")]);
+
+        assert_eq!(create_bundle(&src, &file, "master").unwrap(), 2);
+        assert!(is_bundle(&file));
+
+        let mut bundle = Bundle::open(&file).unwrap();
+
+        assert_eq!(bundle.refs().unwrap().head, "master");
+        assert_eq!(bundle.refs().unwrap().branches.get("master"), Some(&second));
+
+        bundle.fetch(&dst, &[second], None).unwrap();
+
+        assert!(has_commit(&dst, first) && has_commit(&dst, second));
+        assert_eq!(
+            Snapshot::at(&dst, Some(second))
+                .unwrap()
+                .read(Path::new("b.txt"))
+                .unwrap(),
+            Some(b"b\n".to_vec())
+        );
+    }
+
+    #[test]
+    fn prerequisites() {
+        let (src, dst) = (repo(), repo());
+        let file = Temp::new_file().unwrap();
+
+        let first = commit(&src, "master", &[("a.txt", "a\n")]);
+        let second = commit(&src, "master", &[("b.txt", "b\n")]);
+
+        assert_eq!(
+            create_bundle(&src, &file, &format!("{}..master", hex::encode(first))).unwrap(),
+            1
+        );
+
+        let bundle = Bundle::open(&file).unwrap();
+
+        /* the first commit must be there already */
+        assert!(bundle.unbundle(&dst).is_err());
+
+        let other = Temp::new_file().unwrap();
+
+        update_branch(&src, "start", first, "branch: created").unwrap();
+        create_bundle(&src, &other, "start").unwrap();
+        Bundle::open(&other).unwrap().unbundle(&dst).unwrap();
+
+        assert_eq!(bundle.unbundle(&dst).unwrap(), 1);
+        assert!(has_commit(&dst, second));
+    }
+
+    #[test]
+    fn corrupt() {
+        let src = repo();
+        let file = Temp::new_file().unwrap();
+
+        commit(&src, "master", &[("a.txt", "a\n")]);
+        create_bundle(&src, &file, "master").unwrap();
+
+        let mut contents = fs::read(&file).unwrap();
+        let last = contents.len() - 1;
+
+        contents[last] ^= 1;
+        fs::write(&file, contents).unwrap();
+
+        assert!(Bundle::open(&file).is_err());
+        assert!(create_bundle(&src, &file, "master~0").is_err());
+    }
+}
@@ -0,0 +1,159 @@
+//! Replacement of the tracked files of the working tree by the ones of
+//! another commit
+use std::{
+    fs::{self, File},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+
+use crate::combine_paths;
+
+use super::{
+    commit::{get_branch, update_branch},
+    index::{clear_index, Object},
+    snapshot::Snapshot,
+    DcgError, DCG_DIR, LAST_DIR,
+};
+
+/// The files tracked in `snapshot` which were modified or deleted in
+/// the working tree.
+pub(crate) fn modified_files<P: AsRef<Path>>(dd: P, snapshot: &Snapshot) -> Result<Vec<PathBuf>> {
+    let dd = dd.as_ref();
+    let mut modified = Vec::new();
+
+    for (path, h) in &snapshot.files {
+        let same = dd.join(path).is_file() && Object::construct(dd, path)?.hash() == *h;
+
+        if !same {
+            modified.push(path.clone());
+        }
+    }
+
+    Ok(modified)
+}
+
+/// Fail if the working tree holds changes to the files of `snapshot`,
+/// which a checkout would lose.
+pub(crate) fn ensure_clean<P: AsRef<Path>>(dd: P, snapshot: &Snapshot) -> Result<()> {
+    let modified = modified_files(dd, snapshot)?;
+
+    if modified.is_empty() {
+        Ok(())
+    } else {
+        Err(DcgError::UncommittedChanges(modified).into())
+    }
+}
+
+/// Remove `path` and the directories it leaves empty.
+pub(crate) fn remove_file(dd: &Path, path: &Path) -> Result<()> {
+    let full_path = dd.join(path);
+
+    if full_path.is_file() {
+        fs::remove_file(&full_path)?;
+    }
+
+    let mut dir = full_path.parent();
+
+    while let Some(d) = dir.filter(|d| *d != dd) {
+        if fs::read_dir(d).map_or(true, |mut e| e.next().is_some()) {
+            break;
+        }
+
+        fs::remove_dir(d)?;
+        dir = d.parent();
+    }
+
+    Ok(())
+}
+
+/// Turn the tracked files of the working tree from the ones of `from`
+/// into the ones of `to`.  Untracked files are left alone.
+pub(crate) fn checkout<P: AsRef<Path>>(dd: P, from: &Snapshot, to: &Snapshot) -> Result<()> {
+    let dd = dd.as_ref();
+
+    for path in from.files.keys().filter(|p| !to.files.contains_key(*p)) {
+        remove_file(dd, path)?;
+    }
+
+    for (path, h) in &to.files {
+        if from.files.get(path) == Some(h) && dd.join(path).is_file() {
+            continue;
+        }
+
+        let full_path = dd.join(path);
+
+        if let Some(parent) = full_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let contents = to.read(path)?.ok_or(DcgError::InvalidCommit)?;
+
+        File::create(full_path)?.write_all(&contents)?;
+    }
+
+    Ok(())
+}
+
+/// Bring `paths` back to their version in `snapshot`, removing the ones
+/// it does not track.
+pub(crate) fn restore<P: AsRef<Path>>(dd: P, snapshot: &Snapshot, paths: &[PathBuf]) -> Result<()> {
+    let dd = dd.as_ref();
+
+    for path in paths {
+        match snapshot.read(path)? {
+            Some(contents) => {
+                let full_path = dd.join(path);
+
+                if let Some(parent) = full_path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+
+                File::create(full_path)?.write_all(&contents)?;
+            }
+            None => remove_file(dd, path)?,
+        }
+    }
+
+    Ok(())
+}
+
+/// Make the files of `snapshot` the ones of the last commit, which the
+/// index is compared to.
+pub(crate) fn set_last<P: AsRef<Path>>(dd: P, snapshot: &Snapshot) -> Result<()> {
+    let dd = dd.as_ref();
+
+    let last = combine_paths!(dd, DCG_DIR, LAST_DIR);
+
+    fs::remove_dir_all(&last)?;
+    fs::create_dir_all(&last)?;
+
+    for path in snapshot.files.keys() {
+        let contents = snapshot.read(path)?.ok_or(DcgError::InvalidCommit)?;
+
+        Object::new(path, contents).write_in(dd, LAST_DIR)?;
+    }
+
+    Ok(())
+}
+
+/// Move the head of the current branch to `h` for `reason`, replacing
+/// the tracked files of `from`, which are those of the working tree, by
+/// the ones of `h`, and empty the index.
+pub(crate) fn reset<P: AsRef<Path>>(
+    dd: P,
+    from: &Snapshot,
+    h: [u8; 32],
+    reason: &str,
+) -> Result<Snapshot> {
+    let dd = dd.as_ref();
+    let to = Snapshot::at(dd, Some(h))?;
+
+    checkout(dd, from, &to)?;
+    update_branch(dd, &get_branch(dd)?, h, reason)?;
+    set_last(dd, &to)?;
+    clear_index(dd)?;
+
+    Ok(to)
+}
@@ -1,8 +1,8 @@
 use std::{
     env,
     ffi::OsStr,
-    fs::{self, copy, create_dir_all, remove_dir_all, File},
-    io::{self, BufReader, BufWriter, Read, Write},
+    fs::{self, create_dir_all, remove_dir_all, File},
+    io::{self, BufWriter, Read, Write},
     os::unix::ffi::OsStrExt,
     path::{Path, PathBuf},
     time::{SystemTime, UNIX_EPOCH},
@@ -10,8 +10,8 @@ use std::{
 
 use anyhow::Result;
 use content_inspector::ContentType;
-use flate2::write::GzDecoder;
 use sha2::{Digest, Sha256};
+use time::{OffsetDateTime, UtcOffset};
 
 use crate::{
     backend::{linguist::LinguistState, ADDR_BYTES},
@@ -20,8 +20,19 @@ use crate::{
 };
 
 use super::{
-    config::User, diffs::DiffType, find_repo, index::get_fname, DcgError, BASE_DIR, BLOBS_DIR,
-    BRANCHES_DIR, DCG_DIR, INDEX_DIR, LAST_DIR, REFS_DIR, TAGS_DIR, TREE_DIR,
+    config::Config,
+    date::{format_offset, local_offset, parse_commit_date, parse_offset},
+    decode_hash,
+    diffs::DiffType,
+    gz_decode,
+    index::get_fname,
+    journal,
+    lock::{write_atomic, write_synced},
+    pack::{has_object, read_object},
+    reflog,
+    transfer::{finish_commit, has_commit, partial_dir},
+    DcgError, BASE_DIR, BLOBS_DIR, BRANCHES_DIR, DCG_DIR, INDEX_DIR, LAST_DIR, REFS_DIR, TAGS_DIR,
+    TREE_DIR,
 };
 
 #[derive(Debug, Clone)]
@@ -61,7 +72,7 @@ impl Change {
             let hp = laf.with_file_name(h.trim());
             File::open(&hp)?.read_to_end(&mut lb)?;
 
-            Some(hex::decode(&h)?.try_into().unwrap())
+            Some(decode_hash(&h, &laf)?)
         } else {
             None
         };
@@ -74,7 +85,7 @@ impl Change {
             let hp = inf.with_file_name(h.trim());
             File::open(&hp)?.read_to_end(&mut ib)?;
 
-            Some(hex::decode(&h)?.try_into().unwrap())
+            Some(decode_hash(&h, &inf)?)
         } else {
             None
         };
@@ -84,7 +95,8 @@ impl Change {
                 path: f.to_path_buf(),
                 file: if lh.is_some() { lb } else { ib },
                 content: match (lh, ih) {
-                    (None, _) => ChangeContent::Addition(ih.unwrap()),
+                    (None, Some(ih)) => ChangeContent::Addition(ih),
+                    (None, None) => unreachable!("lh != ih"),
                     (_, None) => ChangeContent::Deletion,
                     (Some(lh), Some(ih)) => {
                         let dt = get_diff_type(state, &laf, &inf)?;
@@ -103,7 +115,7 @@ impl Change {
         }
     }
 
-    fn serialise_entry(&self) -> Vec<u8> {
+    pub(crate) fn serialise_entry(&self) -> Vec<u8> {
         let mut base = Vec::new();
 
         match &self.content {
@@ -130,32 +142,36 @@ impl Change {
     }
 
     fn deserialise_entry(v: &[u8]) -> Result<(Self, usize)> {
-        let t = v[0];
+        let bytes = |r: std::ops::Range<usize>| v.get(r).ok_or(DcgError::InvalidCommit);
+        let hash = |r: std::ops::Range<usize>| -> Result<[u8; 32]> { Ok(bytes(r)?.try_into()?) };
+
         let mut off = 1;
 
-        let t = match t {
+        let t = match bytes(0..1)?[0] {
             b'd' => ChangeContent::Deletion,
             b'a' => {
-                let h = v[1..33].try_into().unwrap();
+                let h = hash(1..33)?;
                 off = 33;
 
                 ChangeContent::Addition(h)
             }
             b'm' => {
-                let (dt, to) = DiffType::deserialise(&v[1..]);
-                let h = v[1 + to..33 + to].try_into().unwrap();
+                let (dt, to) = DiffType::deserialise(bytes(1..v.len())?)?;
+                let h = hash(1 + to..33 + to)?;
 
                 off = 33 + to;
 
                 ChangeContent::Modification(dt, h, vec![])
             }
-            _ => unreachable!("invalid change type: {}", t),
+            _ => return Err(DcgError::InvalidCommit.into()),
         };
 
-        let path_l = usize::from_le_bytes(v[off..off + ADDR_BYTES].try_into().unwrap());
+        let path_l = usize::from_le_bytes(bytes(off..off + ADDR_BYTES)?.try_into()?);
         off += ADDR_BYTES;
 
-        let path = PathBuf::from(OsStr::from_bytes(&v[off..off + path_l]));
+        let path = PathBuf::from(OsStr::from_bytes(bytes(
+            off..off.checked_add(path_l).ok_or(DcgError::InvalidCommit)?,
+        )?));
 
         Ok((
             Self {
@@ -168,63 +184,229 @@ impl Change {
     }
 }
 
+/// Who authored or committed a commit, and when.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Signature {
+    pub(crate) name: String,
+    pub(crate) email: String,
+    /// seconds since the unix epoch
+    pub(crate) time: i64,
+    /// offset of the time zone from UTC, in seconds
+    pub(crate) offset: i32,
+}
+
+impl Signature {
+    /// The author or the committer (`role` being `AUTHOR` or
+    /// `COMMITTER`) of a commit made now by the user of `cfg`.  The
+    /// `DCG_<role>_NAME`, `DCG_<role>_EMAIL` and `DCG_<role>_DATE`
+    /// environment variables take precedence over the configuration
+    /// and the current time.
+    pub(crate) fn now(role: &str, cfg: &Config) -> Result<Self> {
+        let user = cfg.user.clone().unwrap_or_default();
+        let var = |field: &str| env::var(format!("DCG_{}_{}", role, field)).ok();
+
+        let (Some(name), Some(email)) = (var("NAME").or(user.name), var("EMAIL").or(user.email))
+        else {
+            return Err(DcgError::NoAuthor.into());
+        };
+
+        let (time, offset) = match var("DATE") {
+            Some(d) => parse_commit_date(&d)?,
+            None => {
+                let t = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+
+                (t, local_offset(t))
+            }
+        };
+
+        Ok(Self {
+            name,
+            email,
+            time,
+            offset,
+        })
+    }
+
+    /// The time of the signature, in its time zone.
+    pub(crate) fn datetime(&self) -> Result<OffsetDateTime> {
+        Ok(OffsetDateTime::from_unix_timestamp(self.time)?
+            .to_offset(UtcOffset::from_whole_seconds(self.offset)?))
+    }
+
+    /// Parse the name and email of `Name <email>`.
+    pub(crate) fn parse_identity(s: &str) -> Result<(String, String)> {
+        match s.trim().strip_suffix('>').and_then(|s| s.split_once('<')) {
+            Some((name, email)) if !name.trim().is_empty() => {
+                Ok((name.trim().to_string(), email.trim().to_string()))
+            }
+            _ => Err(DcgError::InvalidIdentity(s.to_string()).into()),
+        }
+    }
+
+    /// Read a signature stored as its name, email, and time followed
+    /// by the offset, each on its own line.  Commits made before
+    /// offsets were recorded only store the name and email; their
+    /// time is `date`, in UTC.
+    fn deserialise(s: &str, date: Option<i64>) -> Result<Self> {
+        let mut lines = s.lines();
+
+        let (Some(name), Some(email)) = (lines.next(), lines.next()) else {
+            return Err(DcgError::InvalidCommit.into());
+        };
+
+        let (time, offset) = match lines.next().and_then(|l| l.split_once(' ')) {
+            Some((t, o)) => (t.parse()?, parse_offset(o)?),
+            None => (date.ok_or(DcgError::InvalidCommit)?, 0),
+        };
+
+        Ok(Self {
+            name: name.to_string(),
+            email: email.to_string(),
+            time,
+            offset,
+        })
+    }
+
+    fn serialise(&self) -> String {
+        format!(
+            "{}\n{}\n{} {}",
+            self.name,
+            self.email,
+            self.time,
+            format_offset(self.offset)
+        )
+    }
+}
+
 #[derive(Debug, Clone)]
 pub(crate) struct CommitObject {
-    pub(crate) author: User,
+    pub(crate) author: Signature,
+    pub(crate) committer: Signature,
     pub(crate) message: String,
     pub(crate) changes: Vec<Change>,
-    pub(crate) date: u64,
+    /// the commit this one was cherry-picked from
+    pub(crate) origin: Option<[u8; 32]>,
 }
 
 impl CommitObject {
-    pub(crate) fn new(author: User, message: String, changes: Vec<Change>) -> Result<Self> {
-        Ok(Self {
+    pub(crate) fn new(
+        author: Signature,
+        committer: Signature,
+        message: String,
+        changes: Vec<Change>,
+    ) -> Self {
+        Self {
             author,
+            committer,
             message,
             changes,
-            date: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
-        })
+            origin: None,
+        }
     }
 
-    fn hash(&self) -> Result<[u8; 32]> {
-        if let User {
-            name: Some(name),
-            email: Some(email),
-        } = &self.author
-        {
-            let k = format!("{}{}{}", self.date, name, email);
+    /// The hash of the commit, given its parents.  It covers everything
+    /// the commit records, so that commits made within the same second
+    /// by the same author and committer are told apart by their parents,
+    /// message, changes or origin.
+    pub(crate) fn hash(&self, parents: &[[u8; 32]]) -> [u8; 32] {
+        let parents = parents.iter().map(hex::encode).collect::<Vec<_>>();
+        let k = format!(
+            "{}{}{}{}",
+            self.author.serialise(),
+            self.committer.serialise(),
+            parents.join(" "),
+            self.message
+        );
+
+        let mut hasher = Sha256::new();
+
+        hasher.update(k.as_bytes());
+        hasher.update(self.changes.len().to_le_bytes());
 
-            Ok(Sha256::digest(k.as_bytes()).into())
-        } else {
-            Err(DcgError::NoAuthor.into())
+        for change in &self.changes {
+            hasher.update(change.serialise_entry());
         }
+
+        if let Some(origin) = self.origin {
+            hasher.update(b"origin");
+            hasher.update(origin);
+        }
+
+        hasher.finalize().into()
+    }
+
+    /// The hashes earlier versions of dcg gave the commit, given its
+    /// parents, oldest first.  They covered less of it: the author date
+    /// and identity, then its first parent and message, then its whole
+    /// author and committer.  Commits keep the name they were made with.
+    pub(crate) fn legacy_hashes(&self, parents: &[[u8; 32]]) -> [[u8; 32]; 3] {
+        let a = &self.author;
+        let parent = parents.first().map(hex::encode).unwrap_or_default();
+
+        [
+            format!("{}{}{}", a.time, a.name, a.email),
+            format!("{}{}{}{}{}", a.time, a.name, a.email, parent, self.message),
+            format!(
+                "{}{}{}{}",
+                a.serialise(),
+                self.committer.serialise(),
+                parent,
+                self.message
+            ),
+        ]
+        .map(|k| Sha256::digest(k.as_bytes()).into())
     }
 
     pub(crate) fn read<P: AsRef<Path>>(dd: P, h: [u8; 32]) -> Result<Self> {
-        let cf = combine_paths!(dd.as_ref(), DCG_DIR, TREE_DIR, hash_to_commit_path(h));
+        let dd = dd.as_ref();
+
+        let read = |name: &str| read_object(dd, &commit_object(h, name));
+        let read_string = |name: &str| Ok::<_, anyhow::Error>(String::from_utf8(read(name)?)?);
+        let has = |name: &str| has_object(dd, &commit_object(h, name));
 
-        let mp = combine_paths!(&cf, "message");
-        let mut message = String::new();
-        File::open(&mp)?.read_to_string(&mut message)?;
+        let message = read_string("message")?;
 
-        let dp = combine_paths!(&cf, "date");
-        let mut date_bytes = [0; 8];
-        File::open(&dp)?.read(&mut date_bytes)?;
+        /* only written by older versions */
+        let date = if has("date") {
+            let date_bytes = read("date")?;
 
-        let ap = combine_paths!(&cf, "author");
-        let mut author = String::new();
-        File::open(&ap)?.read_to_string(&mut author)?;
+            Some(u64::from_le_bytes(
+                date_bytes
+                    .get(..8)
+                    .and_then(|b| b.try_into().ok())
+                    .ok_or(DcgError::InvalidCommit)?,
+            ) as i64)
+        } else {
+            None
+        };
 
-        let mut al = author.lines();
-        let name = al.next().map(str::to_string);
-        let email = al.next().map(str::to_string);
+        let author = Signature::deserialise(&read_string("author")?, date)?;
 
-        let mut dir = Vec::new();
-        File::open(combine_paths!(&cf, "directory"))?.read_to_end(&mut dir)?;
+        let committer = if has("committer") {
+            Signature::deserialise(&read_string("committer")?, date)?
+        } else {
+            author.clone()
+        };
+
+        let origin = if has("origin") {
+            Some(
+                hex::decode(read_string("origin")?.trim())?
+                    .try_into()
+                    .map_err(|_| DcgError::InvalidCommit)?,
+            )
+        } else {
+            None
+        };
+
+        let dir = read("directory")?;
         let mut i = ADDR_BYTES;
-        let changes_l = usize::from_le_bytes(dir[0..ADDR_BYTES].try_into().unwrap());
+        let changes_l = usize::from_le_bytes(
+            dir.get(0..ADDR_BYTES)
+                .ok_or(DcgError::InvalidCommit)?
+                .try_into()?,
+        );
 
-        let mut changes = Vec::with_capacity(changes_l);
+        let mut changes = Vec::with_capacity(changes_l.min(dir.len()));
         while i < dir.len() {
             let (entry, off) = Change::deserialise_entry(&dir[i..])?;
             i += off;
@@ -234,37 +416,99 @@ impl CommitObject {
         }
 
         Ok(Self {
-            author: User { name, email },
+            author,
+            committer,
             message,
             changes,
-            date: u64::from_le_bytes(date_bytes),
+            origin,
         })
     }
 
-    pub(crate) fn write<P: AsRef<Path>>(&self, dd: P) -> Result<[u8; 32]> {
+    /// Write the commit on top of the current branch, and make its
+    /// files the ones of the last commit.  The move of the branch is
+    /// recorded in the reflog as made by `action`, such as `commit`.
+    pub(crate) fn write<P: AsRef<Path>>(&self, dd: P, action: &str) -> Result<[u8; 32]> {
         let dd = dd.as_ref();
+        let parent = fetch_head(dd, &get_branch(dd)?)?;
 
-        let h = self.hash()?;
+        self.write_on(dd, &Vec::from_iter(parent), action)
+    }
 
-        let cf = combine_paths!(dd, DCG_DIR, TREE_DIR, hash_to_commit_path(h));
+    /// Write the commit with `parents` and make it the head of the
+    /// current branch, as `write` does.  This replaces the head when
+    /// `parents` are not the head itself.
+    pub(crate) fn write_on<P: AsRef<Path>>(
+        &self,
+        dd: P,
+        parents: &[[u8; 32]],
+        action: &str,
+    ) -> Result<[u8; 32]> {
+        let dd = dd.as_ref();
 
-        fs::create_dir_all(&cf)?;
+        let branch = get_branch(dd)?;
+        let h = self.hash(parents);
+        let subject = self.message.lines().next().unwrap_or("");
 
-        let mp = combine_paths!(&cf, "message");
-        File::create(&mp)?.write_all(self.message.as_bytes())?;
+        /* an interrupted commit is finished or dropped on the next run */
+        journal::begin(dd, branch.trim(), fetch_head(dd, &branch)?, h)?;
 
-        let dp = combine_paths!(&cf, "date");
-        File::create(&dp)?.write_all(&self.date.to_le_bytes())?;
+        self.store(dd, h, parents)?;
+        update_branch(dd, &branch, h, &format!("{}: {}", action, subject))?;
 
-        /* should have been previously checked */
-        let name = self.author.name.as_ref().unwrap();
-        let email = self.author.email.as_ref().unwrap();
+        let idp = combine_paths!(dd, DCG_DIR, INDEX_DIR);
+        let ltp = combine_paths!(dd, DCG_DIR, LAST_DIR);
 
-        let ap = combine_paths!(&cf, "author");
-        File::create(&ap)?.write_all(format!("{}\n{}", name, email).as_bytes())?;
+        /* move index to last */
+        remove_dir_all(&ltp)?;
+        copy_dir_all(&idp, ltp)?;
 
-        let branch = get_branch(dd)?;
-        let parent = fetch_head(dd, &branch)?;
+        /* free index */
+        remove_dir_all(&idp)?;
+        create_dir_all(idp)?;
+
+        journal::end(dd)?;
+
+        Ok(h)
+    }
+
+    /// Write the commit with `parents`, without moving any branch.  The
+    /// changes must be relative to the files of the last commit, which
+    /// is expected to be the first parent.
+    pub(crate) fn write_detached<P: AsRef<Path>>(
+        &self,
+        dd: P,
+        parents: &[[u8; 32]],
+    ) -> Result<[u8; 32]> {
+        let dd = dd.as_ref();
+        let h = self.hash(parents);
+
+        self.store(dd, h, parents)?;
+
+        Ok(h)
+    }
+
+    /// Write the commit as the commit `h`, which parents are not
+    /// stored: no parent is recorded, and the changes must add all the
+    /// files of the commit.  This is the base of a history cut short.
+    pub(crate) fn write_graft<P: AsRef<Path>>(&self, dd: P, h: [u8; 32]) -> Result<()> {
+        self.store(dd.as_ref(), h, &[])
+    }
+
+    /// Store the files of the commit `h`.  Its directory is written
+    /// aside, then moved in place, so that it is never seen incomplete.
+    /// A commit already stored is kept as it is: the hash covers all of
+    /// it, so the stored commit is this one.
+    fn store(&self, dd: &Path, h: [u8; 32], parents: &[[u8; 32]]) -> Result<()> {
+        let cf = partial_dir(dd, h)?;
+
+        fs::create_dir_all(&cf)?;
+
+        write_synced(&combine_paths!(&cf, "message"), &self.message)?;
+        write_synced(&combine_paths!(&cf, "author"), self.author.serialise())?;
+        write_synced(
+            &combine_paths!(&cf, "committer"),
+            self.committer.serialise(),
+        )?;
 
         let mut dir = BufWriter::new(File::create(combine_paths!(&cf, "directory"))?);
         dir.write_all(&self.changes.len().to_le_bytes())?;
@@ -273,47 +517,45 @@ impl CommitObject {
 
             match &change.content {
                 ChangeContent::Addition(ch) => {
-                    make_base_file(&change.path, &change.file, h, *ch, dd)?
+                    make_base_file(&change.path, &change.file, *ch, h, dd)?
                 }
                 ChangeContent::Modification(dt, ch, d) => {
-                    handle_modification(*dt, &cf, h, *ch, d, &change.file, &change.path, dd)?
+                    handle_modification(*dt, &cf, *ch, d, &change.path, dd)?
                 }
                 ChangeContent::Deletion => {}
             }
         }
 
-        /* write parent if applicable */
-        if let Some(parent) = parent {
-            File::create(combine_paths!(&cf, "parent"))?
-                .write_all(hex::encode(parent).as_bytes())?;
+        /* write parents if applicable */
+        if !parents.is_empty() {
+            let parents = parents.iter().map(hex::encode).collect::<Vec<_>>();
+
+            write_synced(&combine_paths!(&cf, "parent"), parents.join(" "))?;
         }
 
-        /* update branch head */
-        File::create(combine_paths!(&dd, DCG_DIR, BRANCHES_DIR, branch))?
-            .write_all(hex::encode(h).as_bytes())?;
+        if let Some(origin) = self.origin {
+            write_synced(&combine_paths!(&cf, "origin"), hex::encode(origin))?;
+        }
 
-        let idp = combine_paths!(dd, DCG_DIR, INDEX_DIR);
-        let ltp = combine_paths!(dd, DCG_DIR, LAST_DIR);
+        dir.flush()?;
+        dir.get_ref().sync_all()?;
+        drop(dir);
 
-        /* move index to last */
-        remove_dir_all(&ltp)?;
-        copy_dir_all(&idp, ltp)?;
+        if has_commit(dd, h) {
+            fs::remove_dir_all(&cf)?;
 
-        /* free index */
-        remove_dir_all(&idp)?;
-        create_dir_all(idp)?;
+            return Ok(());
+        }
 
-        Ok(h)
+        finish_commit(dd, h, &cf)
     }
 }
 
 fn handle_modification<P: AsRef<Path>>(
     dt: DiffType,
     commit: P,
-    commit_h: [u8; 32],
     h: [u8; 32],
     d: &[u8],
-    contents: &[u8],
     from: P,
     dd: &Path,
 ) -> Result<()> {
@@ -327,8 +569,9 @@ fn handle_modification<P: AsRef<Path>>(
     let hs = hex::encode(h);
 
     match dt {
+        /* binary diffs are the whole new file */
         DiffType::Binary => {
-            make_blob_from_bytes(contents, &hs, dd)?;
+            make_blob_from_bytes(d, &hs, dd)?;
         }
         DiffType::FromBinary(_) | DiffType::Tree(_) | DiffType::Linear(_, _) => {
             if !virtual_parent.exists() {
@@ -337,13 +580,34 @@ fn handle_modification<P: AsRef<Path>>(
 
             let df = combine_paths!(&virtual_parent, get_fname(from.as_ref()));
 
-            File::create(df)?.write_all(&contents)?;
+            write_synced(&df, d)?;
         }
     }
 
     Ok(())
 }
 
+/// Read the diff stored for the modification of `path` in commit `commit_h`,
+/// `h` being the hash of the modified file.
+pub(crate) fn read_stored_diff<P: AsRef<Path>>(
+    dd: P,
+    dt: DiffType,
+    commit_h: [u8; 32],
+    h: [u8; 32],
+    path: &Path,
+) -> Result<Vec<u8>> {
+    let dd = dd.as_ref();
+
+    let df = match dt {
+        DiffType::Binary => combine_paths!(BLOBS_DIR, hex::encode(h)),
+        DiffType::FromBinary(_) | DiffType::Tree(_) | DiffType::Linear(_, _) => {
+            commit_object(commit_h, path)
+        }
+    };
+
+    read_object(dd, &df)
+}
+
 fn make_base_file<P: AsRef<Path>>(
     p: P,
     contents: &[u8],
@@ -373,43 +637,62 @@ fn make_base_file<P: AsRef<Path>>(
         format!("{}-{}", get_fname(p.as_ref()), hex::encode(commit_h))
     );
 
-    File::create(symlink)?.write_all(&h)?;
-
-    let mut writer = Vec::new();
-    let mut decoder = GzDecoder::new(writer);
-    decoder.write_all(contents)?;
-    writer = decoder.finish()?;
-
     let hs = hex::encode(h);
+    let plain = gz_decode(contents)?;
 
     /* if the file is binary, it is placed in the blobs instead of the base/ directory */
-    if !matches!(content_inspector::inspect(&writer), ContentType::BINARY) {
-        let virtual_file = combine_paths!(&virtual_parent, hs);
+    if !matches!(content_inspector::inspect(&plain), ContentType::BINARY) {
+        let virtual_file = combine_paths!(&virtual_parent, &hs);
 
-        File::create(virtual_file)?.write_all(contents)?;
+        write_atomic(dd, &virtual_file, contents)?;
     } else {
-        let full_path = combine_paths!(dd, DCG_DIR, INDEX_DIR, &p);
-        make_blob(full_path, &hs, dd)?;
+        make_blob_from_bytes(contents, &hs, dd)?;
     }
 
+    /* the contents are written first, so that the pointer never
+     * designates a missing file
+     */
+    write_atomic(dd, &symlink, hs)?;
+
     Ok(())
 }
 
-fn make_blob<P: AsRef<Path>>(from: P, hs: &str, dd: &Path) -> Result<PathBuf> {
-    let bf = combine_paths!(dd, DCG_DIR, BLOBS_DIR, hs);
+/// Read the plain contents of `p` as it was added by commit `commit_h`.
+pub(crate) fn read_base_file<P: AsRef<Path>>(
+    dd: P,
+    p: &Path,
+    commit_h: [u8; 32],
+) -> Result<Vec<u8>> {
+    let dd = dd.as_ref();
 
-    if !bf.exists() {
-        copy(from, &bf)?;
-    }
+    let virtual_parent = combine_paths!(
+        BASE_DIR,
+        p.parent().map(Path::to_path_buf).unwrap_or_default()
+    );
 
-    Ok(bf)
+    let symlink = combine_paths!(
+        &virtual_parent,
+        format!("{}-{}", get_fname(p), hex::encode(commit_h))
+    );
+
+    let hs = String::from_utf8(read_object(dd, &symlink)?)?;
+    let hs = hs.trim();
+
+    let virtual_file = combine_paths!(&virtual_parent, hs);
+    let bf = if has_object(dd, &virtual_file) {
+        virtual_file
+    } else {
+        combine_paths!(BLOBS_DIR, hs)
+    };
+
+    Ok(gz_decode(&read_object(dd, &bf)?)?)
 }
 
 fn make_blob_from_bytes(bytes: &[u8], hs: &str, dd: &Path) -> Result<PathBuf> {
     let bf = combine_paths!(dd, DCG_DIR, BLOBS_DIR, hs);
 
-    if !bf.exists() {
-        File::create(&bf)?.write_all(bytes)?;
+    if !has_object(dd, &combine_paths!(BLOBS_DIR, hs)) {
+        write_atomic(dd, &bf, bytes)?;
     }
 
     Ok(bf)
@@ -422,6 +705,11 @@ pub(crate) fn hash_to_commit_path(h: [u8; 32]) -> String {
     format!("{:02x}/{}/", ph, hex::encode(sh))
 }
 
+/// The object `name` of the commit `h`, relative to `.dcg/`.
+pub(crate) fn commit_object<P: AsRef<Path>>(h: [u8; 32], name: P) -> PathBuf {
+    combine_paths!(TREE_DIR, hash_to_commit_path(h), name)
+}
+
 pub(crate) fn get_branch<P: AsRef<Path>>(dd: P) -> Result<String> {
     let refs = combine_paths!(dd.as_ref(), DCG_DIR, REFS_DIR);
 
@@ -432,23 +720,29 @@ pub(crate) fn get_branch<P: AsRef<Path>>(dd: P) -> Result<String> {
     Ok(branch)
 }
 
-pub(crate) fn get_parent<P: AsRef<Path>>(dd: P, h: [u8; 32]) -> Result<Option<[u8; 32]>> {
-    let cp = hash_to_commit_path(h);
-    let parent_p = combine_paths!(dd.as_ref(), DCG_DIR, TREE_DIR, &cp, "parent");
-
-    if parent_p.exists() {
-        let mut s = String::new();
-
-        File::open(&parent_p)?.read_to_string(&mut s)?;
-
-        let h = hex::decode(s)?.try_into().unwrap();
-
-        Ok(Some(h))
+/// Get the parents of a commit.  The first parent is the one the
+/// changes of the commit are relative to.
+pub(crate) fn get_parents<P: AsRef<Path>>(dd: P, h: [u8; 32]) -> Result<Vec<[u8; 32]>> {
+    let dd = dd.as_ref();
+    let parent_p = commit_object(h, "parent");
+
+    if has_object(dd, &parent_p) {
+        String::from_utf8(read_object(dd, &parent_p)?)?
+            .split_whitespace()
+            .map(|x| match hex::decode(x)?.try_into() {
+                Ok(h) => Ok(h),
+                _ => Err(DcgError::InvalidCommit.into()),
+            })
+            .collect()
     } else {
-        Ok(None)
+        Ok(vec![])
     }
 }
 
+pub(crate) fn get_parent<P: AsRef<Path>>(dd: P, h: [u8; 32]) -> Result<Option<[u8; 32]>> {
+    Ok(get_parents(dd, h)?.first().copied())
+}
+
 pub(crate) fn fetch_head<P: AsRef<Path>>(dd: P, branch: &str) -> Result<Option<[u8; 32]>> {
     let branches = combine_paths!(dd.as_ref(), DCG_DIR, BRANCHES_DIR);
     let mut ch = String::new();
@@ -460,10 +754,31 @@ pub(crate) fn fetch_head<P: AsRef<Path>>(dd: P, branch: &str) -> Result<Option<[
     if ch.is_empty() {
         Ok(None)
     } else {
-        Ok(hex::decode(ch).map(|x| x.try_into().unwrap()).ok())
+        Ok(hex::decode(ch).ok().and_then(|x| x.try_into().ok()))
     }
 }
 
+/// Make `h` the head of `branch`, recording the move and its `reason`
+/// in the reflog.
+pub(crate) fn update_branch<P: AsRef<Path>>(
+    dd: P,
+    branch: &str,
+    h: [u8; 32],
+    reason: &str,
+) -> Result<()> {
+    let dd = dd.as_ref();
+    let branch = branch.trim();
+    let old = fetch_head(dd, branch).ok().flatten();
+
+    write_atomic(
+        dd,
+        &combine_paths!(dd, DCG_DIR, BRANCHES_DIR, branch),
+        hex::encode(h),
+    )?;
+
+    reflog::append(dd, branch, old, h, reason)
+}
+
 fn copy_dir_all(src: impl AsRef<Path>, dst: impl AsRef<Path>) -> io::Result<()> {
     fs::create_dir_all(&dst)?;
     for entry in fs::read_dir(src)? {
@@ -481,7 +796,124 @@ fn copy_dir_all(src: impl AsRef<Path>, dst: impl AsRef<Path>) -> io::Result<()>
 pub(crate) fn make_tag<P: AsRef<Path>>(dd: P, commit: [u8; 32], tag: &str) -> Result<()> {
     let tf = combine_paths!(dd.as_ref(), DCG_DIR, TAGS_DIR, tag);
 
-    File::create(&tf)?.write_all(hex::encode(commit).as_bytes())?;
+    write_atomic(dd.as_ref(), &tf, hex::encode(commit))?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use super::{Change, ChangeContent, CommitObject, Signature};
+    use crate::vcs::{
+        gz_encode,
+        index::Object,
+        snapshot::Snapshot,
+        testing::{commit, repo},
+    };
+
+    fn signature() -> Signature {
+        Signature {
+            name: "A U Thor".to_string(),
+            email: "a@u.th".to_string(),
+            time: 1_700_000_000,
+            offset: 0,
+        }
+    }
+
+    fn adding(path: &str, contents: &str) -> CommitObject {
+        let path = Path::new(path);
+        let change = Change {
+            content: ChangeContent::Addition(Object::new(path, contents.into()).hash()),
+            file: gz_encode(contents.as_bytes()).unwrap(),
+            path: path.to_path_buf(),
+        };
+
+        CommitObject::new(signature(), signature(), "Add".to_string(), vec![change])
+    }
+
+    #[test]
+    fn hash_covers_the_commit() {
+        let a = adding("a.txt", "a\n");
+        let h = a.hash(&[[1; 32]]);
+
+        assert_ne!(adding("a.txt", "b\n").hash(&[[1; 32]]), h);
+        assert_ne!(adding("b.txt", "a\n").hash(&[[1; 32]]), h);
+        assert_ne!(a.hash(&[[2; 32]]), h);
+        assert_ne!(a.hash(&[[1; 32], [2; 32]]), h);
+        assert_ne!(
+            CommitObject {
+                origin: Some([3; 32]),
+                ..a.clone()
+            }
+            .hash(&[[1; 32]]),
+            h
+        );
+    }
+
+    #[test]
+    fn stored_commits_are_kept() {
+        let dd = repo();
+        let parent = commit(&dd, "master", &[("b.txt", "b\n")]);
+
+        let h = adding("a.txt", "a\n")
+            .write_detached(&dd, &[parent])
+            .unwrap();
+        let again = adding("a.txt", "a\n")
+            .write_detached(&dd, &[parent])
+            .unwrap();
+        let other = adding("a.txt", "A\n")
+            .write_detached(&dd, &[parent])
+            .unwrap();
+
+        assert_eq!(again, h);
+        assert_ne!(other, h);
+
+        for (h, contents) in [(h, "a\n"), (other, "A\n")] {
+            let snapshot = Snapshot::at(&dd, Some(h)).unwrap();
+
+            assert_eq!(
+                snapshot.read(Path::new("a.txt")).unwrap(),
+                Some(contents.as_bytes().to_vec())
+            );
+        }
+    }
+
+    #[test]
+    fn signatures() {
+        let signature = Signature {
+            offset: -19800,
+            ..signature()
+        };
+        let s = signature.serialise();
+
+        assert_eq!(s, "A U Thor\na@u.th\n1700000000 -0530");
+        assert_eq!(Signature::deserialise(&s, None).unwrap(), signature);
+        assert_eq!(Signature::deserialise(&s, Some(1)).unwrap(), signature);
+
+        /* written before times and offsets were recorded */
+        assert_eq!(
+            Signature::deserialise("A U Thor\na@u.th", Some(1_700_000_000)).unwrap(),
+            Signature {
+                offset: 0,
+                ..signature
+            }
+        );
+        assert!(Signature::deserialise("A U Thor\na@u.th", None).is_err());
+        assert!(Signature::deserialise("A U Thor", Some(1_700_000_000)).is_err());
+        assert!(Signature::deserialise("A U Thor\na@u.th\nnoon +0000", None).is_err());
+    }
+
+    #[test]
+    fn identities() {
+        assert_eq!(
+            Signature::parse_identity(" A U Thor <a@u.th> ").unwrap(),
+            ("A U Thor".to_string(), "a@u.th".to_string())
+        );
+
+        for s in ["A U Thor", "<a@u.th>", "A U Thor a@u.th>"] {
+            assert!(Signature::parse_identity(s).is_err(), "{:?}", s);
+        }
+    }
+}
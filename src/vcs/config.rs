@@ -2,17 +2,26 @@ use anyhow::Result;
 use config_derive::Merge;
 use serde::Deserialize;
 use std::{
+    collections::BTreeMap,
     env::{self},
     fs::File,
     io::Read,
     path::Path,
 };
 
+use crate::combine_paths;
+
+use super::{lock::write_atomic, DCG_DIR, REPO_CONFIG};
+
 #[derive(Deserialize, Merge, Clone, Debug, Default)]
 pub(crate) struct Config {
     pub(crate) user: Option<User>,
     pub(crate) init: Option<Init>,
     pub(crate) commit: Option<Commit>,
+    pub(crate) log: Option<Log>,
+    /// the remote repositories, by name
+    pub(crate) remote: Option<BTreeMap<String, Remote>>,
+    pub(crate) serve: Option<Serve>,
 }
 
 #[derive(Deserialize, Merge, Clone, Debug, Default)]
@@ -33,6 +42,32 @@ pub(crate) struct Commit {
     pub(crate) editor: Option<String>,
 }
 
+#[derive(Deserialize, Merge, Clone, Debug)]
+pub(crate) struct Log {
+    /// the format used when `--format` is not given
+    pub(crate) format: Option<String>,
+    /// named formats usable with `--format=<name>`
+    pub(crate) formats: Option<BTreeMap<String, String>>,
+    /// whether to colour the output of `dcg log` when it goes to a
+    /// terminal
+    pub(crate) color: Option<bool>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub(crate) struct Remote {
+    /// the path of the repository, possibly as a `file://` URL, or a
+    /// `dcg://` or `ssh://` URL
+    pub(crate) url: String,
+    /// the token authenticating to `dcg serve`
+    pub(crate) token: Option<String>,
+}
+
+#[derive(Deserialize, Merge, Clone, Debug)]
+pub(crate) struct Serve {
+    /// the token clients of `dcg serve` must authenticate with
+    pub(crate) token: Option<String>,
+}
+
 impl Default for Init {
     fn default() -> Self {
         Self {
@@ -94,3 +129,39 @@ pub(crate) fn read_config() -> Result<Config> {
 
     Ok(config)
 }
+
+/// `cfg` completed with the configuration of the repository `dd`,
+/// which is only read by `read_config` from the root of the
+/// repository.
+pub(crate) fn repo_config(dd: &Path, cfg: &Config) -> Result<Config> {
+    let p = combine_paths!(dd, DCG_DIR, REPO_CONFIG);
+
+    if p.is_file() {
+        extract_config(&p, cfg.clone())
+    } else {
+        Ok(cfg.clone())
+    }
+}
+
+/// Change the configuration file of the repository `dd` with `f`.
+pub(crate) fn edit_repo_config<F: FnOnce(&mut toml::Table) -> Result<()>>(
+    dd: &Path,
+    f: F,
+) -> Result<()> {
+    let p = combine_paths!(dd, DCG_DIR, REPO_CONFIG);
+    let mut table = if p.is_file() {
+        let mut s = String::new();
+
+        File::open(&p)?.read_to_string(&mut s)?;
+
+        s.parse::<toml::Table>()?
+    } else {
+        toml::Table::new()
+    };
+
+    f(&mut table)?;
+
+    write_atomic(dd, &p, toml::to_string(&table)?)?;
+
+    Ok(())
+}
@@ -0,0 +1,252 @@
+//! Dates and time zones given on the command line or recorded by
+//! commits
+use anyhow::Result;
+use regex::Regex;
+use time::{format_description::parse, Date, OffsetDateTime, PrimitiveDateTime};
+
+use super::DcgError;
+
+const DATE_FORMATS: [&str; 3] = [
+    "[year]-[month]-[day] [hour]:[minute]:[second]",
+    "[year]-[month]-[day]T[hour]:[minute]:[second]",
+    "[year]-[month]-[day] [hour]:[minute]",
+];
+
+/// Parse a date given on the command line to a unix timestamp.
+/// Accepted dates are unix timestamps (optionally prefixed with
+/// '@'), `YYYY-MM-DD[ HH:MM[:SS]]` in UTC, `now`, `yesterday` and
+/// relative dates such as `3 weeks ago`.
+pub(crate) fn parse_date(s: &str) -> Result<i64> {
+    let s = s.trim();
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+
+    /* timestamps and counts are never negative */
+    let count = |n: &str| n.parse::<u64>().ok().and_then(|n| i64::try_from(n).ok());
+
+    if let Some(t) = count(s.strip_prefix('@').unwrap_or(s)) {
+        return Ok(t);
+    }
+
+    match s {
+        "now" => return Ok(now),
+        "yesterday" => return Ok(now - 86400),
+        _ => {}
+    }
+
+    if let Some(rel) = s.strip_suffix("ago") {
+        let mut words = rel.split_whitespace();
+
+        if let (Some(n), Some(unit), None) = (words.next(), words.next(), words.next()) {
+            let seconds = match unit.trim_end_matches('s') {
+                "second" => 1,
+                "minute" => 60,
+                "hour" => 3600,
+                "day" => 86400,
+                "week" => 7 * 86400,
+                "month" => 30 * 86400,
+                "year" => 365 * 86400,
+                _ => return Err(DcgError::InvalidDate(s.to_string()).into()),
+            };
+
+            return count(n)
+                .and_then(|n| n.checked_mul(seconds))
+                .and_then(|d| now.checked_sub(d))
+                .ok_or(DcgError::InvalidDate(s.to_string()).into());
+        }
+    }
+
+    for fmt in DATE_FORMATS {
+        if let Ok(dt) = PrimitiveDateTime::parse(s, &parse(fmt)?) {
+            return Ok(dt.assume_utc().unix_timestamp());
+        }
+    }
+
+    match Date::parse(s, &parse("[year]-[month]-[day]")?) {
+        Ok(d) => Ok(d.midnight().assume_utc().unix_timestamp()),
+        Err(_) => Err(DcgError::InvalidDate(s.to_string()).into()),
+    }
+}
+
+/// The offset from UTC of the local time zone at the unix timestamp
+/// `t`, in seconds.
+pub(crate) fn local_offset(t: i64) -> i32 {
+    let t = t as libc::time_t;
+    // SAFETY: `tm` is plain data, which `localtime_r` fills in
+    let mut tm = unsafe { std::mem::zeroed::<libc::tm>() };
+
+    // SAFETY: both pointers are valid for the duration of the call
+    if unsafe { libc::localtime_r(&t, &mut tm) }.is_null() {
+        0
+    } else {
+        tm.tm_gmtoff as i32
+    }
+}
+
+/// Parse a date given for a commit to a unix timestamp and an offset
+/// from UTC in seconds.  Dates are the ones accepted by `parse_date`,
+/// optionally followed by an offset such as `+0200`, `-05:30` or `Z`.
+/// Without an offset, the local time zone is used and dates such as
+/// `YYYY-MM-DD HH:MM` are local times.
+pub(crate) fn parse_commit_date(s: &str) -> Result<(i64, i32)> {
+    let re = Regex::new(r"^(.*?)\s*(?:([+-])(\d\d):?(\d\d)|Z)$")?;
+    let s = s.trim();
+
+    let (date, offset) = match re.captures(s) {
+        Some(c) if !c[1].is_empty() => {
+            let offset = match (c.get(2), c.get(3), c.get(4)) {
+                (Some(sign), Some(h), Some(m)) => {
+                    let o = h.as_str().parse::<i32>()? * 3600 + m.as_str().parse::<i32>()? * 60;
+
+                    if sign.as_str() == "-" {
+                        -o
+                    } else {
+                        o
+                    }
+                }
+                _ => 0,
+            };
+
+            (c.get(1).unwrap().as_str(), Some(offset))
+        }
+        _ => (s, None),
+    };
+
+    let t = parse_date(date)?;
+
+    /* timestamps and relative dates do not depend on the time zone */
+    let absolute = date
+        .strip_prefix('@')
+        .unwrap_or(date)
+        .parse::<i64>()
+        .is_ok()
+        || matches!(date, "now" | "yesterday")
+        || date.ends_with("ago");
+
+    Ok(match (offset, absolute) {
+        (Some(o), true) => (t, o),
+        (Some(o), false) => (t - o as i64, o),
+        (None, true) => (t, local_offset(t)),
+        (None, false) => {
+            let o = local_offset(t);
+
+            (t - o as i64, o)
+        }
+    })
+}
+
+/// `+hhmm` or `-hhmm`.
+pub(crate) fn format_offset(offset: i32) -> String {
+    format!(
+        "{}{:02}{:02}",
+        if offset < 0 { '-' } else { '+' },
+        offset.abs() / 3600,
+        offset.abs() % 3600 / 60
+    )
+}
+
+pub(crate) fn parse_offset(s: &str) -> Result<i32> {
+    let (sign, hm) = s.split_at_checked(1).ok_or(DcgError::InvalidCommit)?;
+    let (h, m) = hm.split_at_checked(2).ok_or(DcgError::InvalidCommit)?;
+    let o = h.parse::<i32>()? * 3600 + m.parse::<i32>()? * 60;
+
+    Ok(if sign == "-" { -o } else { o })
+}
+
+#[cfg(test)]
+mod test {
+    use time::OffsetDateTime;
+
+    use super::{format_offset, local_offset, parse_commit_date, parse_date, parse_offset};
+
+    #[test]
+    fn absolute_dates() {
+        assert_eq!(parse_date("1700000000").unwrap(), 1_700_000_000);
+        assert_eq!(parse_date("@1700000000").unwrap(), 1_700_000_000);
+        assert_eq!(parse_date("2023-11-14").unwrap(), 1_699_920_000);
+        assert_eq!(parse_date("2023-11-14 22:13").unwrap(), 1_699_999_980);
+        assert_eq!(parse_date("2023-11-14 22:13:20").unwrap(), 1_700_000_000);
+        assert_eq!(parse_date(" 2023-11-14T22:13:20 ").unwrap(), 1_700_000_000);
+    }
+
+    #[test]
+    fn relative_dates() {
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        let near = |s: &str, t: i64| (parse_date(s).unwrap() - t).abs() < 60;
+
+        assert!(near("now", now));
+        assert!(near("yesterday", now - 86400));
+        assert!(near("1 second ago", now - 1));
+        assert!(near("3 weeks ago", now - 21 * 86400));
+        assert!(near("2 months ago", now - 60 * 86400));
+        assert!(near("1 year ago", now - 365 * 86400));
+    }
+
+    #[test]
+    fn invalid_dates() {
+        for s in [
+            "",
+            "tomorrow",
+            "3 fortnights ago",
+            "three days ago",
+            "3 days",
+            "2023-13-01",
+            "-5",
+            "@-5",
+            "-5 days ago",
+            "-9223372036854775807 seconds ago",
+            "9223372036854775807 years ago",
+            "-9223372036854775807 years ago",
+        ] {
+            assert!(parse_date(s).is_err(), "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn offsets() {
+        for (s, o) in [
+            ("+0000", 0),
+            ("+0200", 7200),
+            ("-0530", -19800),
+            ("+1400", 50400),
+        ] {
+            assert_eq!(parse_offset(s).unwrap(), o);
+            assert_eq!(format_offset(o), s);
+        }
+
+        for s in ["", "+", "+02", "0200x", "+ab00"] {
+            assert!(parse_offset(s).is_err(), "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn commit_dates() {
+        let t = 1_700_000_000;
+
+        assert_eq!(parse_commit_date("@1700000000 +0200").unwrap(), (t, 7200));
+        assert_eq!(parse_commit_date("1700000000 -05:30").unwrap(), (t, -19800));
+        assert_eq!(parse_commit_date("@1700000000Z").unwrap(), (t, 0));
+        assert_eq!(
+            parse_commit_date("@1700000000").unwrap(),
+            (t, local_offset(t))
+        );
+
+        /* local times are converted from their offset */
+        assert_eq!(
+            parse_commit_date("2023-11-15 00:13:20 +0200").unwrap(),
+            (t, 7200)
+        );
+        assert_eq!(
+            parse_commit_date("2023-11-14T17:43:20-04:30").unwrap(),
+            (t, -16200)
+        );
+        assert_eq!(parse_commit_date("2023-11-14 22:13:20 Z").unwrap(), (t, 0));
+
+        let (local, offset) = parse_commit_date("2023-11-14 22:13:20").unwrap();
+
+        assert_eq!(local + offset as i64, t);
+
+        for s in ["", "Z", "yesterday at noon", "2023-11-14 25:00 +0200"] {
+            assert!(parse_commit_date(s).is_err(), "{:?}", s);
+        }
+    }
+}
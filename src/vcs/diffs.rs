@@ -1,23 +1,20 @@
-use std::{
-    fs::File,
-    io::{Read, Write},
-    path::Path,
-    rc::Rc,
-};
-
-use crate::backend::{
-    bcst::{diff_wrapper, BCSTree},
-    diff::{ered, Diff},
-    languages::Languages,
-    linear,
-    linguist::{get_ts_language, guess_language, LinguistState},
-    rcst::RCSTree,
-    serde::{deserialise, serialise, Ranges, TextRanges},
-    ADDR_BYTES,
+use std::{fs::File, io::Read, path::Path, rc::Rc};
+
+use crate::{
+    backend::{
+        bcst::{bcst_to_code, diff_wrapper, patch, BCSTree, Twh},
+        diff::{ered, Diff},
+        languages::Languages,
+        linear,
+        linguist::{get_ts_language, guess_language, LinguistState},
+        rcst::RCSTree,
+        serde::{deserialise, serialise, Ranges, TextRanges},
+        ADDR_BYTES,
+    },
+    vcs::{gz_decode, DcgError},
 };
 
 use anyhow::Result;
-use flate2::write::GzDecoder;
 use tree_sitter::Parser;
 
 #[derive(Debug, Copy, Clone)]
@@ -50,20 +47,20 @@ impl DiffType {
             }
         }
     }
-    pub(crate) fn deserialise(v: &[u8]) -> (Self, usize) {
-        match v[0] {
-            0 => (Self::Binary, 1),
-            1 => (Self::FromBinary(Languages::try_from(v[1]).unwrap()), 2),
-            2 => (Self::Tree(Languages::try_from(v[1]).unwrap()), 2),
-            3 => (
-                Self::Linear(
-                    Languages::try_from(v[1]).unwrap(),
-                    Languages::try_from(v[2]).unwrap(),
-                ),
-                3,
-            ),
-            _ => unreachable!(),
-        }
+    pub(crate) fn deserialise(v: &[u8]) -> Result<(Self, usize)> {
+        let lang = |i: usize| -> Result<Languages> {
+            v.get(i)
+                .and_then(|l| Languages::try_from(*l).ok())
+                .ok_or(DcgError::InvalidDiff.into())
+        };
+
+        Ok(match v.first() {
+            Some(0) => (Self::Binary, 1),
+            Some(1) => (Self::FromBinary(lang(1)?), 2),
+            Some(2) => (Self::Tree(lang(1)?), 2),
+            Some(3) => (Self::Linear(lang(1)?, lang(2)?), 3),
+            _ => return Err(DcgError::InvalidDiff.into()),
+        })
     }
 }
 
@@ -84,56 +81,92 @@ pub(crate) fn get_diff_type<P: AsRef<Path>>(
     })
 }
 
-fn read_usize(i: &mut usize, v: &[u8]) -> usize {
-    let x = usize::from_le_bytes(v[*i..*i + ADDR_BYTES].try_into().unwrap());
+fn read_usize(i: &mut usize, v: &[u8]) -> Result<usize> {
+    let x = v
+        .get(*i..*i + ADDR_BYTES)
+        .ok_or(DcgError::InvalidDiff)?
+        .try_into()
+        .map(usize::from_le_bytes)?;
     *i += ADDR_BYTES;
 
-    x
+    Ok(x)
+}
+
+/// Read the count of entries of `size` bytes each starting at `i`,
+/// checking that they fit in `v`.
+fn read_count(i: &mut usize, v: &[u8], size: usize) -> Result<usize> {
+    let n = read_usize(i, v)?;
+
+    if n > (v.len() - *i) / size {
+        return Err(DcgError::InvalidDiff.into());
+    }
+
+    Ok(n)
 }
 
 /// Deserialise the diff from its serialised form alone.
 pub(crate) fn deserialise_everything<'a>(v: &'a [u8], left: &'a str) -> Result<Diff<'a>> {
     let mut i = 0;
-    let rl = read_usize(&mut i, v);
+    let rl = read_count(&mut i, v, 7 * ADDR_BYTES)?;
 
     let mut vr = vec![((0, 0)..(0, 0), 0..0); rl];
 
     for _ in 0..rl {
-        let r1s0 = read_usize(&mut i, v);
-        let r1s1 = read_usize(&mut i, v);
-        let r1e0 = read_usize(&mut i, v);
-        let r1e1 = read_usize(&mut i, v);
+        let r1s0 = read_usize(&mut i, v)?;
+        let r1s1 = read_usize(&mut i, v)?;
+        let r1e0 = read_usize(&mut i, v)?;
+        let r1e1 = read_usize(&mut i, v)?;
 
-        let r2s = read_usize(&mut i, v);
-        let r2e = read_usize(&mut i, v);
+        let r2s = read_usize(&mut i, v)?;
+        let r2e = read_usize(&mut i, v)?;
 
-        let x = read_usize(&mut i, v);
+        let x = read_usize(&mut i, v)?;
 
-        vr[x] = ((r1s0, r1s1)..(r1e0, r1e1), r2s..r2e);
+        *vr.get_mut(x).ok_or(DcgError::InvalidDiff)? = ((r1s0, r1s1)..(r1e0, r1e1), r2s..r2e);
     }
 
-    let trl = read_usize(&mut i, v);
+    let trl = read_count(&mut i, v, 8 * ADDR_BYTES)?;
     let mut vtr = vec![((0, 0)..(0, 0), 0..0, ""); trl];
 
     for _ in 0..trl {
-        let r1s0 = read_usize(&mut i, v);
-        let r1s1 = read_usize(&mut i, v);
-        let r1e0 = read_usize(&mut i, v);
-        let r1e1 = read_usize(&mut i, v);
+        let r1s0 = read_usize(&mut i, v)?;
+        let r1s1 = read_usize(&mut i, v)?;
+        let r1e0 = read_usize(&mut i, v)?;
+        let r1e1 = read_usize(&mut i, v)?;
+
+        let r2s = read_usize(&mut i, v)?;
+        let r2e = read_usize(&mut i, v)?;
+
+        let sl = read_usize(&mut i, v)?;
+        let s = str::from_utf8(
+            i.checked_add(sl)
+                .and_then(|end| v.get(i..end))
+                .ok_or(DcgError::InvalidDiff)?,
+        )?;
+        i += sl;
 
-        let r2s = read_usize(&mut i, v);
-        let r2e = read_usize(&mut i, v);
+        let x = read_usize(&mut i, v)?;
 
-        let sl = read_usize(&mut i, v);
-        let s = str::from_utf8(&v[i..i + sl])?;
-        i += sl;
+        *vtr.get_mut(x).ok_or(DcgError::InvalidDiff)? = ((r1s0, r1s1)..(r1e0, r1e1), r2s..r2e, s);
+    }
 
-        let x = read_usize(&mut i, v);
+    Ok(deserialise(&v[i..], left, &vr, &vtr)
+        .ok_or(DcgError::InvalidDiff)?
+        .0)
+}
+
+/// Split a tree diff as returned by `do_diff` into the serialised
+/// diff proper and the linear diff restoring the formatting that
+/// rendering the patched tree loses.
+pub(crate) fn split_tree_diff(v: &[u8]) -> Result<(&[u8], &[u8])> {
+    let mut i = 0;
+    let l = read_usize(&mut i, v)?;
 
-        vtr[x] = ((r1s0, r1s1)..(r1e0, r1e1), r2s..r2e, s);
+    if l > v.len() - i {
+        return Err(DcgError::InvalidDiff.into());
     }
 
-    Ok(deserialise(&v[i..], left, &vr, &vtr).0)
+    Ok((&v[i..i + l], &v[i + l..]))
 }
 
 /// Serialise the diff along with the ranges.  All numbers are
@@ -189,14 +222,72 @@ fn do_diff_linear(mut s1: String, mut s2: String) -> Result<Vec<u8>> {
     s1.push('\n');
     s2.push('\n');
 
-    let l1 = s1.lines().collect::<Vec<&str>>();
-    let l2 = s2.lines().collect::<Vec<&str>>();
+    let l1 = s1.split_terminator('\n').collect::<Vec<&str>>();
+    let l2 = s2.split_terminator('\n').collect::<Vec<&str>>();
 
     let diff = linear::diff(&s1, &s2, &l1, &l2);
 
     Ok(linear::serialise(&diff))
 }
 
+/// Inverse of `do_diff_linear`.
+fn patch_linear(mut left: String, d: &[u8]) -> Result<String> {
+    left.push('\n');
+
+    let ll = left.split_terminator('\n').collect::<Vec<&str>>();
+
+    let d = linear::deserialise(d).ok_or(DcgError::InvalidDiff)?;
+
+    match linear::patch(&ll, &d) {
+        Ok(right) => Ok(right.join("\n")),
+        Err(_) => Err(DcgError::InvalidDiff.into()),
+    }
+}
+
+/// Render the tree obtained by applying `d` to `left`.  When the
+/// diff does not apply, the left tree is rendered instead: either way,
+/// the formatting fixup stored along with the diff yields the right
+/// file.
+fn render_patched<'a>(left: Twh<'a>, d: Rc<Diff<'a>>) -> String {
+    match patch(left.clone(), d) {
+        Ok((t, _)) => bcst_to_code(t),
+        Err(_) => bcst_to_code(left.0),
+    }
+}
+
+/// Rebuild the right file of a diff from its left file and the diff
+/// as stored by `CommitObject::write`.  Both files are plain.
+pub(crate) fn apply_diff(difft: DiffType, left: &[u8], d: &[u8]) -> Result<Vec<u8>> {
+    match difft {
+        DiffType::Binary | DiffType::FromBinary(_) => Ok(gz_decode(d)?),
+        DiffType::Linear(_, _) => {
+            Ok(patch_linear(String::from_utf8(left.to_vec())?, d)?.into_bytes())
+        }
+        DiffType::Tree(lang) => {
+            let left = str::from_utf8(left)?;
+            let (td, fixup) = split_tree_diff(d)?;
+
+            let ts_language = get_ts_language(lang).ok_or(DcgError::InvalidDiff)?;
+            let mut parser = Parser::new();
+
+            parser.set_language(&ts_language)?;
+
+            let rendered = if let Some(t) = parser.parse(left, None) {
+                let r = RCSTree::from(t.root_node(), left);
+                let (b, bn): (BCSTree, usize) = r.into();
+
+                let diff = deserialise_everything(td, left)?;
+
+                render_patched((Rc::new(b), bn), Rc::new(diff))
+            } else {
+                left.to_string()
+            };
+
+            Ok(patch_linear(rendered, fixup)?.into_bytes())
+        }
+    }
+}
+
 pub(crate) fn do_diff<P: AsRef<Path>>(
     difft: DiffType,
     file1: P,
@@ -223,21 +314,8 @@ pub(crate) fn do_diff<P: AsRef<Path>>(
             f1.read_to_end(&mut b1)?;
             f2.read_to_end(&mut b2)?;
 
-            let mut writer = Vec::new();
-            let mut decoder = GzDecoder::new(writer);
-
-            decoder.write_all(&b1)?;
-            writer = decoder.finish()?;
-
-            s1 = String::from_utf8(writer)?;
-
-            let mut writer = Vec::new();
-            let mut decoder = GzDecoder::new(writer);
-
-            decoder.write_all(&b2)?;
-            writer = decoder.finish()?;
-
-            s2 = String::from_utf8(writer)?;
+            s1 = String::from_utf8(gz_decode(&b1)?)?;
+            s2 = String::from_utf8(gz_decode(&b2)?)?;
         } else {
             f1.read_to_string(&mut s1)?;
             f2.read_to_string(&mut s2)?;
@@ -261,9 +339,23 @@ pub(crate) fn do_diff<P: AsRef<Path>>(
                 let (b1, bn1): (BCSTree, usize) = r1.into();
                 let (b2, bn2): (BCSTree, usize) = r2.into();
 
-                let diff = ered(diff_wrapper((Rc::new(b1), bn1), (Rc::new(b2), bn2)));
+                let left = (Rc::new(b1), bn1);
+                let diff = ered(diff_wrapper(left.clone(), (Rc::new(b2), bn2)));
+
+                /* trees only keep the position of leaves, not the
+                 * whitespace around them: the formatting is restored
+                 * by a linear diff against the rendered tree.
+                 */
+                let rendered = render_patched(left, diff.clone());
+                let ser = serialise_everything(diff);
+
+                let mut v = Vec::with_capacity(ADDR_BYTES + ser.len());
+
+                v.extend(ser.len().to_le_bytes());
+                v.extend(ser);
+                v.extend(do_diff_linear(rendered, s2)?);
 
-                Ok(serialise_everything(diff))
+                Ok(v)
             } else {
                 do_diff_linear(s1, s2)
             }
@@ -272,3 +364,82 @@ pub(crate) fn do_diff<P: AsRef<Path>>(
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use mktemp::Temp;
+
+    use super::{apply_diff, do_diff, DiffType};
+    use crate::backend::languages::Languages;
+
+    fn round_trip(dt: DiffType, left: &str, right: &str) {
+        let lf = Temp::new_file().unwrap();
+        let rf = Temp::new_file().unwrap();
+
+        fs::write(&lf, left).unwrap();
+        fs::write(&rf, right).unwrap();
+
+        let d = do_diff(dt, &lf, &rf, false).unwrap();
+
+        assert_eq!(
+            right.as_bytes(),
+            apply_diff(dt, left.as_bytes(), &d).unwrap()
+        );
+    }
+
+    #[test]
+    fn tree_formatting() {
+        let left = "fn foo() {\n\t1  \n}\n";
+        let right = "fn foo() {\n\tbar(1,  2);\t\n\n\t3\n}\n\n";
+
+        round_trip(DiffType::Tree(Languages::Rust), left, right);
+    }
+
+    #[test]
+    fn corrupt_diffs() {
+        let left = "fn foo() {\n\t1\n}\n";
+        let right = "fn foo() {\n\tbar(1, 2);\n}\n";
+        let lf = Temp::new_file().unwrap();
+        let rf = Temp::new_file().unwrap();
+
+        fs::write(&lf, left).unwrap();
+        fs::write(&rf, right).unwrap();
+
+        for dt in [
+            DiffType::Tree(Languages::Rust),
+            DiffType::Linear(Languages::Rust, Languages::Rust),
+        ] {
+            let d = do_diff(dt, &lf, &rf, false).unwrap();
+
+            for end in 0..d.len() {
+                assert!(apply_diff(dt, left.as_bytes(), &d[..end]).is_err());
+            }
+
+            /* counts and lengths beyond the end of the diff */
+            let mut huge = d.clone();
+
+            huge[..8].copy_from_slice(&u64::MAX.to_le_bytes());
+
+            assert!(apply_diff(dt, left.as_bytes(), &huge).is_err());
+        }
+
+        /* a language without a tree-sitter grammar */
+        let d = do_diff(DiffType::Tree(Languages::Rust), &lf, &rf, false).unwrap();
+
+        assert!(apply_diff(DiffType::Tree(Languages::PlainText), left.as_bytes(), &d).is_err());
+    }
+
+    #[test]
+    fn linear_line_endings() {
+        let left = "first\r\nsecond\n";
+        let right = "first\r\nnew\r\nsecond";
+
+        round_trip(
+            DiffType::Linear(Languages::PlainText, Languages::PlainText),
+            left,
+            right,
+        );
+    }
+}
@@ -0,0 +1,620 @@
+//! Version of the layout of a repository, and upgrade of repositories
+//! written by older versions of dcg
+//!
+//! `.dcg/format` holds the version of the layout.  Repositories without
+//! it were written before it was recorded: most follow the current
+//! layout, but those first written by the original version of dcg store
+//! commits differently.  Their base files are named after the hash of
+//! the file rather than of the commit, and point to the commit in raw
+//! bytes; a changed file is stored whole, as it was before the change,
+//! instead of as a diff.  Such repositories are upgraded by rewriting
+//! every commit in the current layout, from the contents of its files
+//! rebuilt from whatever the older layout kept of them.  The objects
+//! they had are kept in `.dcg/legacy/`.
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use sha2::{Digest, Sha256};
+
+use crate::{backend::linguist::LinguistState, combine_paths, commands::visit_dirs};
+
+use super::{
+    commit::{
+        commit_object, get_parents, read_base_file, read_stored_diff, Change, ChangeContent,
+        CommitObject,
+    },
+    decode_hash,
+    diffs::apply_diff,
+    fsck::stored_commits,
+    gz_decode,
+    index::{clear_index, get_fname, Object},
+    lock::write_atomic,
+    pack::{has_object, list_objects, read_object},
+    transfer::has_commit,
+    DcgError, BASE_DIR, BISECT_DIR, BLOBS_DIR, CHERRY_PICK_DIR, DCG_DIR, FORMAT_FILE, INDEX_DIR,
+    LAST_DIR, LEGACY_DIR, LOGS_DIR, MERGE_DIR, PACKS_DIR, REBASE_DIR, REFS_DIR, SHALLOW_FILE,
+    TREE_DIR,
+};
+
+/// The version of the layout written by this version of dcg.
+pub(crate) const FORMAT_VERSION: u32 = 2;
+
+/// The directories holding the objects of a repository.
+const OBJECT_DIRS: [&str; 4] = [TREE_DIR, BASE_DIR, BLOBS_DIR, PACKS_DIR];
+
+/// The files and directories of `.dcg/` naming commits.
+const REF_PATHS: [&str; 7] = [
+    REFS_DIR,
+    LOGS_DIR,
+    SHALLOW_FILE,
+    BISECT_DIR,
+    CHERRY_PICK_DIR,
+    REBASE_DIR,
+    MERGE_DIR,
+];
+
+/// Record that the repository `dd` follows the current layout.
+pub(crate) fn write_format(dd: &Path) -> Result<()> {
+    write_atomic(
+        dd,
+        &combine_paths!(dd, DCG_DIR, FORMAT_FILE),
+        format!("{}\n", FORMAT_VERSION),
+    )?;
+
+    Ok(())
+}
+
+/// Whether the repository `dd` may need to be upgraded by `upgrade`,
+/// its layout not being recorded yet.  Fails if it was written by a
+/// newer version of dcg.
+pub(crate) fn outdated(dd: &Path) -> Result<bool> {
+    let p = combine_paths!(dd, DCG_DIR, FORMAT_FILE);
+
+    if !p.is_file() {
+        return Ok(true);
+    }
+
+    let version = fs::read_to_string(&p)?
+        .trim()
+        .parse::<u32>()
+        .map_err(|_| DcgError::CorruptObject(PathBuf::from(FORMAT_FILE)))?;
+
+    if version > FORMAT_VERSION {
+        return Err(DcgError::UnsupportedFormat(version).into());
+    }
+
+    Ok(version < FORMAT_VERSION)
+}
+
+/// Whether the commit `h` of the repository `dd` was written by the
+/// original version of dcg: it records its date apart, and the base
+/// files of the files it adds are not named after it.
+fn is_original(dd: &Path, h: [u8; 32], commit: &CommitObject) -> bool {
+    has_object(dd, &commit_object(h, "date"))
+        && commit.changes.iter().any(|c| {
+            matches!(c.content, ChangeContent::Addition(_))
+                && !has_object(dd, &base_pointer(&c.path, h))
+        })
+}
+
+/// The pointer to the base file of `path` added by commit `h`.
+fn base_pointer(path: &Path, h: [u8; 32]) -> PathBuf {
+    combine_paths!(
+        BASE_DIR,
+        path.parent().map(Path::to_path_buf).unwrap_or_default(),
+        format!("{}-{}", get_fname(path), hex::encode(h))
+    )
+}
+
+/// The hash of the contents of a file whose object hash is `h`, the
+/// path of the file being mixed in the object hash.
+fn contents_hash(path: &Path, h: [u8; 32]) -> [u8; 32] {
+    let mut h = h;
+
+    for (i, byte) in path.as_os_str().as_encoded_bytes().iter().enumerate() {
+        h[i % 32] ^= byte;
+    }
+
+    h
+}
+
+/// Contents of files found in a repository, by hash.
+#[derive(Default)]
+struct Contents(HashMap<[u8; 32], Vec<u8>>);
+
+impl Contents {
+    /// Add `bytes`, and what they decompress to if they can be.
+    fn add(&mut self, bytes: Vec<u8>) {
+        if let Ok(plain) = gz_decode(&bytes) {
+            self.0.insert(Sha256::digest(&plain).into(), plain);
+        }
+
+        self.0.insert(Sha256::digest(&bytes).into(), bytes);
+    }
+
+    fn get(&self, path: &Path, h: [u8; 32]) -> Option<&Vec<u8>> {
+        self.0.get(&contents_hash(path, h))
+    }
+
+    /// Gather the files which may hold the contents of the files of
+    /// `commits`: the objects of the repository `dd`, the files of the
+    /// last commit and of the index, and the working tree.
+    fn gather(dd: &Path, commits: &[([u8; 32], CommitObject)]) -> Result<Self> {
+        let mut contents = Self::default();
+
+        for dir in [TREE_DIR, BASE_DIR, BLOBS_DIR] {
+            for object in list_objects(dd, Path::new(dir))? {
+                contents.add(read_object(dd, &object)?);
+            }
+        }
+
+        for dir in [LAST_DIR, INDEX_DIR] {
+            visit_dirs(&combine_paths!(dd, DCG_DIR, dir), &mut |p| {
+                contents.add(fs::read(p)?);
+
+                Ok(())
+            })?;
+        }
+
+        let paths = commits
+            .iter()
+            .flat_map(|(_, c)| c.changes.iter().map(|x| x.path.clone()))
+            .collect::<BTreeSet<_>>();
+
+        for path in paths {
+            if let Ok(bytes) = fs::read(dd.join(&path)) {
+                contents.add(bytes);
+            }
+        }
+
+        Ok(contents)
+    }
+}
+
+/// The commits of the repository `dd`, parents first.
+fn sorted_commits(dd: &Path) -> Result<Vec<([u8; 32], CommitObject)>> {
+    let stored = stored_commits(dd)?;
+    let mut sorted = Vec::with_capacity(stored.len());
+    let mut visited = BTreeSet::new();
+
+    for h in stored {
+        let mut stack = vec![(h, false)];
+
+        while let Some((h, expanded)) = stack.pop() {
+            if expanded {
+                sorted.push((h, CommitObject::read(dd, h)?));
+            } else if visited.insert(h) {
+                stack.push((h, true));
+                stack.extend(
+                    get_parents(dd, h)?
+                        .into_iter()
+                        .filter(|p| has_commit(dd, *p))
+                        .map(|p| (p, false)),
+                );
+            }
+        }
+    }
+
+    Ok(sorted)
+}
+
+/// The contents of `path` as changed to `h` by the commit `commit_h` of
+/// the repository `dd`, from `contents` or the objects of the commit,
+/// `before` being its contents before the change.
+fn resolve(
+    dd: &Path,
+    contents: &Contents,
+    commit_h: [u8; 32],
+    change: &Change,
+    before: Option<&Vec<u8>>,
+) -> Result<Vec<u8>> {
+    let (path, h) = match change.content {
+        ChangeContent::Addition(h) | ChangeContent::Modification(_, h, _) => (&change.path, h),
+        ChangeContent::Deletion => return Err(DcgError::InvalidCommit.into()),
+    };
+
+    if let Some(c) = contents.get(path, h) {
+        return Ok(c.clone());
+    }
+
+    let stored = match (change.content.clone(), before) {
+        (ChangeContent::Addition(_), _) => read_base_file(dd, path, commit_h).ok(),
+        (ChangeContent::Modification(dt, _, _), Some(before)) => {
+            read_stored_diff(dd, dt, commit_h, h, path)
+                .and_then(|d| apply_diff(dt, before, &d))
+                .ok()
+        }
+        _ => None,
+    };
+
+    stored
+        .filter(|c| Object::new(path, c.clone()).hash() == h)
+        .ok_or(DcgError::LostContents(path.clone(), hex::encode(commit_h)).into())
+}
+
+/// Rewrite the commits of the repository `dd` to the repository
+/// `new`, in the current layout.  Returns the hash of each rewritten
+/// commit.
+fn rewrite(dd: &Path, new: &Path, state: LinguistState) -> Result<BTreeMap<[u8; 32], [u8; 32]>> {
+    let commits = sorted_commits(dd)?;
+    let mut contents = Contents::gather(dd, &commits)?;
+
+    let mut files: HashMap<[u8; 32], BTreeMap<PathBuf, [u8; 32]>> = HashMap::new();
+    let mut hashes = BTreeMap::new();
+
+    for dir in [INDEX_DIR, LAST_DIR] {
+        fs::create_dir_all(combine_paths!(new, DCG_DIR, dir))?;
+    }
+
+    for (h, commit) in commits {
+        /* a commit made in the same second as its parent by the
+         * original layout replaced it, and names itself as parent
+         */
+        let parents = get_parents(dd, h)?
+            .into_iter()
+            .filter(|p| *p != h)
+            .collect::<Vec<_>>();
+        let before = parents
+            .first()
+            .and_then(|p| files.get(p))
+            .cloned()
+            .unwrap_or_default();
+        let mut after = before.clone();
+
+        /* the files of the commit are staged on top of those of its
+         * parent, to be committed as `dcg commit` would
+         */
+        clear_index(new)?;
+        fs::remove_dir_all(combine_paths!(new, DCG_DIR, LAST_DIR))?;
+        fs::create_dir_all(combine_paths!(new, DCG_DIR, LAST_DIR))?;
+
+        for change in &commit.changes {
+            let path = &change.path;
+            let old = before
+                .get(path)
+                .and_then(|x| contents.get(path, *x))
+                .cloned();
+
+            if let Some(old) = &old {
+                Object::new(path, old.clone()).write_in(new, LAST_DIR)?;
+            }
+
+            match change.content {
+                ChangeContent::Deletion => {
+                    after.remove(path);
+                }
+                ChangeContent::Addition(x) | ChangeContent::Modification(_, x, _) => {
+                    let c = resolve(dd, &contents, h, change, old.as_ref())?;
+
+                    Object::new(path, c.clone()).write(new)?;
+                    contents.add(c);
+                    after.insert(path.clone(), x);
+                }
+            }
+        }
+
+        let mut changes = Vec::new();
+
+        for path in commit.changes.iter().map(|c| &c.path) {
+            if !changes.iter().any(|c: &Change| &c.path == path) {
+                changes.extend(Change::from(state, path.as_path(), new)?);
+            }
+        }
+
+        let mut rewritten = CommitObject::new(
+            commit.author.clone(),
+            commit.committer.clone(),
+            commit.message.clone(),
+            changes,
+        );
+        rewritten.origin = commit.origin.map(|o| *hashes.get(&o).unwrap_or(&o));
+
+        let new_parents = parents
+            .iter()
+            .map(|p| *hashes.get(p).unwrap_or(p))
+            .collect::<Vec<_>>();
+
+        let nh = match parents.first() {
+            /* the base of a history cut short */
+            Some(p) if !has_commit(dd, *p) => {
+                let nh = rewritten.hash(&[*p]);
+
+                rewritten.write_graft(new, nh)?;
+
+                nh
+            }
+            _ => rewritten.write_detached(new, &new_parents)?,
+        };
+
+        files.insert(h, after);
+        hashes.insert(h, nh);
+    }
+
+    Ok(hashes)
+}
+
+/// Replace the hashes of `hashes` in the refs of the repository `dd`.
+fn rewrite_refs(dd: &Path, hashes: &BTreeMap<[u8; 32], [u8; 32]>) -> Result<()> {
+    let store = combine_paths!(dd, DCG_DIR);
+    let mut paths = Vec::new();
+
+    for p in REF_PATHS {
+        let p = store.join(p);
+
+        if p.is_file() {
+            paths.push(p);
+        } else {
+            visit_dirs(&p, &mut |p| {
+                paths.push(p.to_path_buf());
+
+                Ok(())
+            })?;
+        }
+    }
+
+    for p in paths {
+        let Ok(mut s) = fs::read_to_string(&p) else {
+            continue;
+        };
+
+        for (old, new) in hashes {
+            s = s.replace(&hex::encode(old), &hex::encode(new));
+        }
+
+        write_atomic(dd, &p, s)?;
+    }
+
+    Ok(())
+}
+
+/// Upgrade the repository `dd` to the current layout, which must be
+/// locked.  Returns whether any commit had to be rewritten.
+///
+/// The commits are rewritten to `.dcg/legacy/new/`, then the list of
+/// the new hashes is written to `.dcg/legacy/map`: from then on, the
+/// upgrade is finished by the next run if it is interrupted.  The
+/// objects of the repository are moved to `.dcg/legacy/`, those of
+/// `new` take their place, and the refs are rewritten.
+pub(crate) fn upgrade(dd: &Path, state: LinguistState) -> Result<bool> {
+    let legacy = combine_paths!(dd, DCG_DIR, LEGACY_DIR);
+    let new = legacy.join("new");
+    let map = legacy.join("map");
+
+    let hashes = if map.is_file() {
+        fs::read_to_string(&map)?
+            .lines()
+            .filter_map(|l| l.split_once(' '))
+            .map(|(o, n)| Ok((decode_hash(o, &map)?, decode_hash(n, &map)?)))
+            .collect::<Result<BTreeMap<_, _>>>()?
+    } else {
+        let original = stored_commits(dd)?
+            .into_iter()
+            .any(|h| CommitObject::read(dd, h).is_ok_and(|c| is_original(dd, h, &c)));
+
+        if !original {
+            write_format(dd)?;
+
+            return Ok(false);
+        }
+
+        if new.exists() {
+            fs::remove_dir_all(&new)?;
+        }
+
+        let hashes = rewrite(dd, &new, state)?;
+
+        write_atomic(
+            dd,
+            &map,
+            hashes
+                .iter()
+                .map(|(o, n)| format!("{} {}\n", hex::encode(o), hex::encode(n)))
+                .collect::<String>(),
+        )?;
+
+        hashes
+    };
+
+    for dir in OBJECT_DIRS {
+        let current = combine_paths!(dd, DCG_DIR, dir);
+        let kept = combine_paths!(&legacy, DCG_DIR, dir);
+        let rewritten = combine_paths!(&new, DCG_DIR, dir);
+
+        if current.exists() && !kept.exists() {
+            fs::create_dir_all(combine_paths!(&legacy, DCG_DIR))?;
+            fs::rename(&current, &kept)?;
+        }
+
+        if rewritten.exists() {
+            fs::rename(&rewritten, &current)?;
+        } else if dir != PACKS_DIR {
+            fs::create_dir_all(&current)?;
+        }
+    }
+
+    rewrite_refs(dd, &hashes)?;
+
+    fs::remove_dir_all(&new)?;
+    fs::remove_file(&map)?;
+    write_format(dd)?;
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::BTreeMap, fs, path::Path};
+
+    use sha2::{Digest, Sha256};
+
+    use super::{outdated, upgrade, FORMAT_VERSION};
+    use crate::{
+        backend::languages::Languages,
+        vcs::{
+            commit::{fetch_head, get_parent, hash_to_commit_path, Change, ChangeContent},
+            diffs::DiffType,
+            fsck, gz_encode,
+            index::Object,
+            snapshot::Snapshot,
+            testing::{commit, repo},
+            DCG_DIR, FORMAT_FILE, INDEX_DIR, LAST_DIR, LEGACY_DIR,
+        },
+    };
+
+    /// Write a commit as the original version of dcg did, on top of
+    /// `parent`, `files` being the files of the commit and `before`
+    /// those of its parent.
+    fn original_commit(
+        dd: &Path,
+        date: u64,
+        parent: Option<[u8; 32]>,
+        before: &[(&str, &str)],
+        files: &[(&str, &str)],
+    ) -> [u8; 32] {
+        let h: [u8; 32] = Sha256::digest(format!("{}A U Thora@u.th", date).as_bytes()).into();
+        let cf = dd.join(DCG_DIR).join("tree").join(hash_to_commit_path(h));
+
+        fs::create_dir_all(&cf).unwrap();
+        fs::write(cf.join("message"), format!("commit {}", date)).unwrap();
+        fs::write(cf.join("date"), date.to_le_bytes()).unwrap();
+        fs::write(cf.join("author"), "A U Thor\na@u.th").unwrap();
+
+        if let Some(p) = parent {
+            fs::write(cf.join("parent"), hex::encode(p)).unwrap();
+        }
+
+        let before = before.iter().copied().collect::<BTreeMap<_, _>>();
+        let mut entries = Vec::new();
+
+        for (path, contents) in files {
+            let fh = Object::new(Path::new(path), contents.as_bytes().to_vec()).hash();
+
+            match before.get(path) {
+                None => {
+                    let base = dd.join(DCG_DIR).join("base");
+
+                    fs::create_dir_all(&base).unwrap();
+                    fs::write(base.join(format!("{}-{}", path, hex::encode(fh))), h).unwrap();
+                    fs::write(
+                        base.join(hex::encode(h)),
+                        gz_encode(contents.as_bytes()).unwrap(),
+                    )
+                    .unwrap();
+
+                    entries.push(Change {
+                        content: ChangeContent::Addition(fh),
+                        file: vec![],
+                        path: path.into(),
+                    });
+                }
+                Some(old) if old != contents => {
+                    /* the file as it was before the change */
+                    fs::write(cf.join(path), gz_encode(old.as_bytes()).unwrap()).unwrap();
+
+                    entries.push(Change {
+                        content: ChangeContent::Modification(
+                            DiffType::Linear(Languages::PlainText, Languages::PlainText),
+                            fh,
+                            vec![],
+                        ),
+                        file: vec![],
+                        path: path.into(),
+                    });
+                }
+                Some(_) => {}
+            }
+        }
+
+        let mut directory = entries.len().to_le_bytes().to_vec();
+
+        for entry in &entries {
+            directory.extend(entry.serialise_entry());
+        }
+
+        fs::write(cf.join("directory"), directory).unwrap();
+
+        h
+    }
+
+    #[test]
+    fn upgrade_original_layout() {
+        let dd = repo();
+        let empty = BTreeMap::new();
+        let state = (&empty, &empty, &empty, &empty);
+
+        fs::remove_file(dd.join(DCG_DIR).join(FORMAT_FILE)).unwrap();
+
+        let first = original_commit(&dd, 1, None, &[], &[("a.txt", "a\n"), ("b.txt", "b\n")]);
+        let second = original_commit(
+            &dd,
+            2,
+            Some(first),
+            &[("a.txt", "a\n"), ("b.txt", "b\n")],
+            &[("a.txt", "a\nA\n"), ("b.txt", "b\n")],
+        );
+
+        /* the files of the last commit, and the head */
+        for (path, contents) in [("a.txt", "a\nA\n"), ("b.txt", "b\n")] {
+            Object::new(Path::new(path), contents.as_bytes().to_vec())
+                .write_in(&dd, LAST_DIR)
+                .unwrap();
+        }
+
+        fs::write(
+            dd.join(DCG_DIR).join("refs/branches/master"),
+            hex::encode(second),
+        )
+        .unwrap();
+
+        assert!(outdated(&dd).unwrap());
+        assert!(upgrade(&dd, state).unwrap());
+        assert!(!outdated(&dd).unwrap());
+
+        let head = fetch_head(&dd, "master").unwrap().unwrap();
+        let snapshot = Snapshot::at(&dd, Some(head)).unwrap();
+
+        assert_ne!(head, second);
+        assert_eq!(
+            snapshot.read(Path::new("a.txt")).unwrap().unwrap(),
+            b"a\nA\n"
+        );
+        assert_eq!(snapshot.read(Path::new("b.txt")).unwrap().unwrap(), b"b\n");
+
+        let parent = get_parent(&dd, head).unwrap().unwrap();
+        let snapshot = Snapshot::at(&dd, Some(parent)).unwrap();
+
+        assert_eq!(snapshot.read(Path::new("a.txt")).unwrap().unwrap(), b"a\n");
+        assert!(fsck::fsck(&dd).unwrap().iter().all(|p| !p.is_error()));
+
+        /* the objects of the original layout are kept */
+        assert!(dd.join(DCG_DIR).join(LEGACY_DIR).join(DCG_DIR).is_dir());
+        assert!(dd.join(DCG_DIR).join(INDEX_DIR).is_dir());
+    }
+
+    #[test]
+    fn record_current_layout() {
+        let dd = repo();
+        let empty = BTreeMap::new();
+        let state = (&empty, &empty, &empty, &empty);
+
+        let h = commit(&dd, "master", &[("a.txt", "a\n")]);
+
+        fs::remove_file(dd.join(DCG_DIR).join(FORMAT_FILE)).unwrap();
+
+        assert!(outdated(&dd).unwrap());
+        assert!(!upgrade(&dd, state).unwrap());
+        assert_eq!(fetch_head(&dd, "master").unwrap(), Some(h));
+
+        fs::write(
+            dd.join(DCG_DIR).join(FORMAT_FILE),
+            (FORMAT_VERSION + 1).to_string(),
+        )
+        .unwrap();
+
+        assert!(outdated(&dd).is_err());
+    }
+}
@@ -0,0 +1,457 @@
+//! Verification of the integrity of a repository
+//!
+//! Every ref must name a stored commit, every commit must be readable,
+//! hash to its name and have its parents, and every file it adds or
+//! changes must be rebuilt, by replaying the diffs of its history, into
+//! contents matching the hash recorded for it.
+use std::{
+    cell::OnceCell,
+    collections::BTreeSet,
+    fmt::{self, Display, Formatter},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+
+use crate::{combine_paths, commands::visit_dirs};
+
+use super::{
+    commit::{
+        commit_object, get_branch, get_parents, read_base_file, read_stored_diff, ChangeContent,
+        CommitObject,
+    },
+    decode_hash,
+    gc::reachable_commits,
+    index::{get_fname, Object},
+    pack::{list_children, loose_objects, packs},
+    snapshot::Snapshot,
+    transfer::{commit_objects, has_commit},
+    DcgError, BASE_DIR, BRANCHES_DIR, DCG_DIR, PACKS_DIR, REFS_DIR, SHALLOW_FILE, STASH_REF,
+    TREE_DIR,
+};
+
+/// Something wrong with a repository.  Paths are relative to `.dcg/`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Problem {
+    /// a ref or an object which cannot be read or whose contents are
+    /// wrong, and why
+    Corrupt(PathBuf, String),
+    /// an object a ref or a commit needs which is not stored
+    Missing(PathBuf),
+    /// a commit no ref leads to
+    DanglingCommit([u8; 32]),
+    /// an object no stored commit refers to
+    DanglingObject(PathBuf),
+}
+
+impl Problem {
+    /// Whether the repository is damaged, rather than holding what
+    /// `dcg gc` would remove.
+    pub(crate) fn is_error(&self) -> bool {
+        matches!(self, Self::Corrupt(_, _) | Self::Missing(_))
+    }
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Corrupt(p, e) => write!(f, "corrupt {}: {}", p.display(), e),
+            Self::Missing(p) => write!(f, "missing {}", p.display()),
+            Self::DanglingCommit(h) => write!(f, "dangling commit {}", hex::encode(h)),
+            Self::DanglingObject(p) => write!(f, "dangling {}", p.display()),
+        }
+    }
+}
+
+/// Run `f`, recording its error as a problem with `object`.
+fn check<F: FnOnce() -> Result<()>>(problems: &mut Vec<Problem>, object: &Path, f: F) {
+    let Err(e) = f() else {
+        return;
+    };
+
+    problems.push(match e.downcast_ref::<DcgError>() {
+        Some(DcgError::MissingObject(p)) => Problem::Missing(p.clone()),
+        _ => Problem::Corrupt(object.to_path_buf(), e.to_string()),
+    });
+}
+
+/// The commits stored in the repository `dd`, loose or packed.  Commits
+/// being received are left out.
+pub(crate) fn stored_commits(dd: &Path) -> Result<Vec<[u8; 32]>> {
+    let mut commits = Vec::new();
+
+    for prefix in list_children(dd, Path::new(TREE_DIR))? {
+        let prefix = prefix.to_string_lossy();
+
+        if prefix.len() != 2 {
+            continue;
+        }
+
+        for rest in list_children(dd, &combine_paths!(TREE_DIR, prefix.as_ref()))? {
+            let name = format!("{}{}", prefix, rest.to_string_lossy());
+
+            commits.extend(
+                hex::decode(&name)
+                    .ok()
+                    .and_then(|h| <[u8; 32]>::try_from(h).ok()),
+            );
+        }
+    }
+
+    Ok(commits)
+}
+
+/// The commits listed in the file `name` of `.dcg/`, if it exists.
+fn listed_commits(dd: &Path, name: &str) -> Result<Vec<[u8; 32]>> {
+    let p = combine_paths!(dd, DCG_DIR, name);
+
+    if !p.is_file() {
+        return Ok(Vec::new());
+    }
+
+    fs::read_to_string(&p)?
+        .split_whitespace()
+        .map(|h| decode_hash(h, Path::new(name)))
+        .collect()
+}
+
+/// Check that the refs, the stash entries and the grafts of the
+/// repository `dd` name stored commits.
+fn check_refs(dd: &Path, problems: &mut Vec<Problem>) -> Result<()> {
+    let store = combine_paths!(dd, DCG_DIR);
+    let mut refs = Vec::new();
+
+    visit_dirs(&store.join(REFS_DIR), &mut |p| {
+        refs.push(p.strip_prefix(&store)?.to_path_buf());
+
+        Ok(())
+    })?;
+
+    refs.push(PathBuf::from(SHALLOW_FILE));
+
+    /* the head names the branch checked out */
+    let head = combine_paths!(REFS_DIR, "HEAD");
+
+    check(problems, &head, || {
+        let branch = get_branch(dd)?;
+
+        if store.join(BRANCHES_DIR).join(branch.trim()).is_file() {
+            Ok(())
+        } else {
+            Err(DcgError::CorruptObject(head.clone()).into())
+        }
+    });
+
+    for r in refs.into_iter().filter(|r| *r != head) {
+        let name = r.to_string_lossy().to_string();
+
+        check(problems, &r, || {
+            let commits = listed_commits(dd, &name)?;
+
+            /* a branch without any commit is empty, other refs are not */
+            if commits.is_empty() && !r.starts_with(BRANCHES_DIR) && r != Path::new(SHALLOW_FILE) {
+                return Err(DcgError::CorruptObject(r.clone()).into());
+            }
+
+            if commits.len() > 1 && name != STASH_REF && name != SHALLOW_FILE {
+                return Err(DcgError::CorruptObject(r.clone()).into());
+            }
+
+            match commits.into_iter().find(|h| !has_commit(dd, *h)) {
+                Some(h) => Err(DcgError::MissingObject(commit_object(h, "")).into()),
+                None => Ok(()),
+            }
+        });
+    }
+
+    Ok(())
+}
+
+/// Check that the commit `h` of the repository `dd` hashes to its name,
+/// as it is hashed now or was by earlier versions, unless it is a graft,
+/// and that its parents are stored.
+fn check_header(dd: &Path, h: [u8; 32], commit: &CommitObject, graft: bool) -> Result<()> {
+    let parents = get_parents(dd, h)?;
+
+    if !graft && commit.hash(&parents) != h && !commit.legacy_hashes(&parents).contains(&h) {
+        return Err(DcgError::HashMismatch.into());
+    }
+
+    match parents.into_iter().find(|p| !has_commit(dd, *p)) {
+        Some(p) => Err(DcgError::MissingObject(commit_object(p, "")).into()),
+        None => Ok(()),
+    }
+}
+
+/// Check that `contents`, the contents of `path`, match `fh`.
+fn check_contents(path: &Path, contents: Vec<u8>, fh: [u8; 32]) -> Result<()> {
+    if Object::new(path, contents).hash() == fh {
+        Ok(())
+    } else {
+        Err(DcgError::HashMismatch.into())
+    }
+}
+
+/// Check the commit `h` of the repository `dd` and the files it adds or
+/// changes.  Returns the commit, if it can be read.
+fn check_commit(
+    dd: &Path,
+    h: [u8; 32],
+    graft: bool,
+    problems: &mut Vec<Problem>,
+) -> Option<CommitObject> {
+    let mut commit = None;
+
+    check(problems, &commit_object(h, ""), || {
+        let c = CommitObject::read(dd, h)?;
+        let header = check_header(dd, h, &c, graft);
+
+        /* its files are checked all the same */
+        commit = Some(c);
+
+        header
+    });
+
+    let commit = commit?;
+
+    /* built once, for the first file the commit changes */
+    let snapshot = OnceCell::new();
+
+    for change in &commit.changes {
+        let path = &change.path;
+
+        match change.content {
+            ChangeContent::Addition(fh) => {
+                let base = combine_paths!(
+                    BASE_DIR,
+                    path.parent().map(Path::to_path_buf).unwrap_or_default(),
+                    format!("{}-{}", get_fname(path), hex::encode(h))
+                );
+
+                check(problems, &base, || {
+                    check_contents(path, read_base_file(dd, path, h)?, fh)
+                });
+            }
+            ChangeContent::Modification(dt, fh, _) => {
+                check(problems, &commit_object(h, path), || {
+                    read_stored_diff(dd, dt, h, fh, path)?;
+
+                    let snapshot = match snapshot.get() {
+                        Some(s) => s,
+                        None => {
+                            let s = Snapshot::at(dd, Some(h))?;
+
+                            snapshot.get_or_init(|| s)
+                        }
+                    };
+
+                    let contents = snapshot.read(path)?.ok_or(DcgError::InvalidCommit)?;
+
+                    check_contents(path, contents, fh)
+                });
+            }
+            ChangeContent::Deletion => {}
+        }
+    }
+
+    Some(commit)
+}
+
+/// Check that the objects listed by the index of each pack of the
+/// repository `dd` lie within the pack.
+fn check_packs(dd: &Path, problems: &mut Vec<Problem>) {
+    let store = combine_paths!(dd, DCG_DIR);
+
+    let mut outside = Vec::new();
+
+    check(problems, Path::new(PACKS_DIR), || {
+        for pack in packs(dd)?.iter() {
+            let size = fs::metadata(&pack.path)?.len();
+            let name = pack.path.strip_prefix(&store)?.to_path_buf();
+
+            for (object, (offset, length)) in &pack.objects {
+                if offset.checked_add(*length).is_none_or(|end| end > size) {
+                    outside.push(Problem::Corrupt(
+                        name.clone(),
+                        format!("{} lies past the end of the pack", object.display()),
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    });
+
+    problems.extend(outside);
+}
+
+/// Check every ref, commit, base file and blob of the repository `dd`,
+/// and list what is wrong with them.
+pub(crate) fn fsck(dd: &Path) -> Result<Vec<Problem>> {
+    let mut problems = Vec::new();
+
+    check_packs(dd, &mut problems);
+    check_refs(dd, &mut problems)?;
+
+    let grafts = listed_commits(dd, SHALLOW_FILE)
+        .unwrap_or_default()
+        .into_iter()
+        .collect::<BTreeSet<_>>();
+
+    let commits = stored_commits(dd)?;
+    let mut referenced = BTreeSet::new();
+
+    for h in &commits {
+        if check_commit(dd, *h, grafts.contains(h), &mut problems).is_some() {
+            referenced.extend(commit_objects(dd, *h).unwrap_or_default());
+        }
+    }
+
+    /* broken refs were reported above, and hide which commits are dangling */
+    if let Ok(reachable) = reachable_commits(dd) {
+        problems.extend(
+            commits
+                .iter()
+                .filter(|h| !reachable.contains(*h))
+                .map(|h| Problem::DanglingCommit(*h)),
+        );
+    }
+
+    let mut objects = loose_objects(dd)?;
+
+    for pack in packs(dd).unwrap_or_default().iter() {
+        objects.extend(pack.objects.keys().cloned());
+    }
+
+    problems.extend(
+        objects
+            .into_iter()
+            .filter(|o| !o.starts_with(TREE_DIR) && !referenced.contains(o))
+            .map(Problem::DanglingObject),
+    );
+
+    Ok(problems)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, path::Path};
+
+    use super::{fsck, Problem};
+    use crate::vcs::{
+        commit::{commit_object, update_branch, CommitObject, Signature},
+        gz_encode,
+        pack::repack,
+        testing::{commit, repo},
+        DcgError, DCG_DIR,
+    };
+
+    #[test]
+    fn sound() {
+        let dd = repo();
+
+        commit(&dd, "master", &[("a.txt", "a\n")]);
+        commit(&dd, "master", &[("a.txt", "b\n"), ("c.txt", "c\n")]);
+
+        assert_eq!(fsck(&dd).unwrap(), Vec::new());
+
+        repack(&dd).unwrap();
+
+        assert_eq!(fsck(&dd).unwrap(), Vec::new());
+    }
+
+    #[test]
+    fn corrupt() {
+        let dd = repo();
+
+        let first = commit(&dd, "master", &[("a.txt", "a\n")]);
+        let second = commit(&dd, "master", &[("a.txt", "b\n")]);
+        let store = dd.join(DCG_DIR);
+
+        /* a garbled change entry */
+        fs::write(
+            store.join(commit_object(second, "directory")),
+            [1, 0, 0, 0, 0, 0, 0, 0, b'x'],
+        )
+        .unwrap();
+
+        /* a base file with other contents */
+        let base = format!("base/a.txt-{}", hex::encode(first));
+        let fh = fs::read_to_string(store.join(&base)).unwrap();
+        fs::write(
+            store.join("base").join(fh.trim()),
+            gz_encode(b"c\n").unwrap(),
+        )
+        .unwrap();
+
+        let problems = fsck(&dd).unwrap();
+
+        assert!(problems.contains(&Problem::Corrupt(
+            commit_object(second, ""),
+            "invalid commit".to_string()
+        )));
+        assert!(problems
+            .iter()
+            .any(|p| matches!(p, Problem::Corrupt(p, _) if p == Path::new(&base))));
+    }
+
+    #[test]
+    fn legacy_hashes() {
+        let dd = repo();
+        let signature = Signature {
+            name: "A U Thor".to_string(),
+            email: "a@u.th".to_string(),
+            time: 1_600_000_000,
+            offset: 3600,
+        };
+        let c = CommitObject::new(signature.clone(), signature, "old".to_string(), vec![]);
+
+        /* commits keep the names earlier versions gave them */
+        let mut names = c.legacy_hashes(&[]).to_vec();
+        names.push([7; 32]);
+
+        for (i, h) in names.iter().enumerate() {
+            c.write_graft(&dd, *h).unwrap();
+            update_branch(&dd, &format!("b{}", i), *h, "branch: created").unwrap();
+        }
+
+        assert_eq!(
+            fsck(&dd).unwrap(),
+            vec![Problem::Corrupt(
+                commit_object([7; 32], ""),
+                DcgError::HashMismatch.to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn missing_and_dangling() {
+        let dd = repo();
+
+        let first = commit(&dd, "master", &[("a.txt", "a\n")]);
+        update_branch(&dd, "topic", first, "branch: created").unwrap();
+        let dropped = commit(&dd, "topic", &[("b.txt", "b\n")]);
+        fs::remove_file(dd.join(".dcg/refs/branches/topic")).unwrap();
+        fs::remove_file(dd.join(".dcg/logs/refs/branches/topic")).unwrap();
+
+        let base = Path::new("base/b.txt").to_path_buf();
+        let fh = fs::read_to_string(
+            dd.join(DCG_DIR)
+                .join(format!("base/b.txt-{}", hex::encode(dropped))),
+        )
+        .unwrap();
+
+        fs::remove_file(dd.join(DCG_DIR).join("base").join(fh.trim())).unwrap();
+        fs::remove_file(dd.join(DCG_DIR).join(commit_object(first, "message"))).unwrap();
+
+        let problems = fsck(&dd).unwrap();
+
+        assert!(problems.contains(&Problem::DanglingCommit(dropped)));
+        assert!(problems.contains(&Problem::Missing(commit_object(first, "message"))));
+        assert!(problems.iter().any(|p| match p {
+            Problem::Missing(p) => p.starts_with("blobs") || p.starts_with(&base),
+            _ => false,
+        }));
+    }
+}
@@ -0,0 +1,307 @@
+//! Removal of the objects no ref leads to
+use std::{
+    collections::BTreeSet,
+    fs,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use anyhow::Result;
+use regex::Regex;
+
+use crate::{combine_paths, commands::visit_dirs};
+
+use super::{
+    index::get_fname,
+    pack::{loose_objects, packs, write_pack},
+    remote::local_heads,
+    transfer::{commit_objects, has_commit, missing_commits},
+    BISECT_DIR, CHERRY_PICK_DIR, DCG_DIR, INDEX_DIR, LAST_DIR, LOGS_DIR, MERGE_DIR, REBASE_DIR,
+    STASH_REF,
+};
+
+/// Files and directories of `.dcg/` which may name commits besides the
+/// branches and tags: the stash, the reflogs, and the state of the
+/// operations in progress.
+const STATE: [&str; 6] = [
+    STASH_REF,
+    LOGS_DIR,
+    BISECT_DIR,
+    CHERRY_PICK_DIR,
+    MERGE_DIR,
+    REBASE_DIR,
+];
+
+/// The commits named in the files of `STATE`.
+fn state_commits(dd: &Path) -> Result<Vec<[u8; 32]>> {
+    let re = Regex::new(r"\b[0-9a-f]{64}\b")?;
+    let mut commits = Vec::new();
+
+    for s in STATE {
+        let p = combine_paths!(dd, DCG_DIR, s);
+        let mut files = Vec::new();
+
+        if p.is_file() {
+            files.push(p);
+        } else {
+            visit_dirs(&p, &mut |f| {
+                files.push(f.to_path_buf());
+
+                Ok(())
+            })?;
+        }
+
+        for f in files {
+            let contents = String::from_utf8_lossy(&fs::read(f)?).to_string();
+
+            for m in re.find_iter(&contents) {
+                commits.extend(<[u8; 32]>::try_from(hex::decode(m.as_str())?).ok());
+            }
+        }
+    }
+
+    Ok(commits)
+}
+
+/// The commits reachable from the branches, tags, branches of remotes,
+/// stash entries, reflogs and operations in progress of the repository
+/// `dd`.
+pub(crate) fn reachable_commits(dd: &Path) -> Result<BTreeSet<[u8; 32]>> {
+    let mut roots = local_heads(dd)?;
+    roots.extend(state_commits(dd)?);
+    roots.retain(|h| has_commit(dd, *h));
+
+    /* the parents of the oldest commits of a shallow history are missing */
+    Ok(missing_commits(dd, &roots, |_| false)?
+        .into_iter()
+        .filter(|h| has_commit(dd, *h))
+        .collect())
+}
+
+/// The objects of the reachable commits, relative to `.dcg/`.
+pub(crate) fn reachable_objects(dd: &Path) -> Result<BTreeSet<PathBuf>> {
+    let mut objects = BTreeSet::new();
+
+    for h in reachable_commits(dd)? {
+        objects.extend(commit_objects(dd, h)?);
+    }
+
+    Ok(objects)
+}
+
+/// When the file at `p` was last modified, as a unix timestamp.
+fn modified(p: &Path) -> Result<i64> {
+    Ok(fs::metadata(p)?
+        .modified()?
+        .duration_since(UNIX_EPOCH)?
+        .as_secs() as i64)
+}
+
+/// The contents stored in the index and in the files of the last
+/// commit which no file refers to anymore, relative to `.dcg/`.  They
+/// are left behind when a file is added again with other contents.
+fn stale_contents(dd: &Path) -> Result<Vec<PathBuf>> {
+    let store = combine_paths!(dd, DCG_DIR);
+    let mut stale = Vec::new();
+
+    for dir in [INDEX_DIR, LAST_DIR] {
+        let mut contents = BTreeSet::new();
+        let mut referenced = BTreeSet::new();
+
+        visit_dirs(&store.join(dir), &mut |p| {
+            let name = get_fname(p);
+
+            if name.len() == 64 && hex::decode(name).is_ok() {
+                contents.insert(p.to_path_buf());
+            } else {
+                let h = String::from_utf8_lossy(&fs::read(p)?).trim().to_string();
+
+                referenced.insert(p.with_file_name(h));
+            }
+
+            Ok(())
+        })?;
+
+        for p in contents.difference(&referenced) {
+            stale.push(p.strip_prefix(&store)?.to_path_buf());
+        }
+    }
+
+    Ok(stale)
+}
+
+/// What can be removed from a repository.  Paths are relative to
+/// `.dcg/`.
+#[derive(Default)]
+pub(crate) struct Garbage {
+    /// unreachable loose objects
+    pub(crate) loose: Vec<PathBuf>,
+    /// unreachable packed objects
+    pub(crate) packed: Vec<PathBuf>,
+    /// contents of the index and of the last commit no file refers to
+    pub(crate) stale: Vec<PathBuf>,
+}
+
+/// What can be removed from the repository `dd`: the objects which are
+/// unreachable, and the stale contents of the index, which were last
+/// modified before `expire`, a unix timestamp.  Packed objects are as
+/// old as their pack.
+pub(crate) fn find_garbage(dd: &Path, expire: i64) -> Result<Garbage> {
+    let store = combine_paths!(dd, DCG_DIR);
+    let reachable = reachable_objects(dd)?;
+
+    let mut garbage = Garbage::default();
+
+    for object in loose_objects(dd)? {
+        if !reachable.contains(&object) && modified(&store.join(&object))? < expire {
+            garbage.loose.push(object);
+        }
+    }
+
+    for pack in packs(dd)?.iter() {
+        if modified(&pack.path)? < expire {
+            garbage.packed.extend(
+                pack.objects
+                    .keys()
+                    .filter(|o| !reachable.contains(*o) && !store.join(o).is_file())
+                    .cloned(),
+            );
+        }
+    }
+
+    for p in stale_contents(dd)? {
+        if modified(&store.join(&p))? < expire {
+            garbage.stale.push(p);
+        }
+    }
+
+    Ok(garbage)
+}
+
+/// Delete the loose objects and the stale contents of `garbage` from
+/// the repository `dd`.
+pub(crate) fn prune(dd: &Path, garbage: &Garbage) -> Result<()> {
+    let store = combine_paths!(dd, DCG_DIR);
+
+    for p in garbage.loose.iter().chain(&garbage.stale) {
+        fs::remove_file(store.join(p))?;
+
+        /* directories left empty, up to `tree/`, `base/` and the like */
+        let mut dir = store.join(p);
+
+        while dir.pop()
+            && dir.parent() != Some(store.as_path())
+            && fs::read_dir(&dir)?.next().is_none()
+        {
+            fs::remove_dir(&dir)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Prune `garbage` from the repository `dd`, then pack all the other
+/// objects.  Returns the number of objects packed.
+pub(crate) fn collect(dd: &Path, garbage: &Garbage) -> Result<usize> {
+    prune(dd, garbage)?;
+
+    let packed = garbage.packed.iter().collect::<BTreeSet<_>>();
+
+    write_pack(dd, |o| !packed.contains(&o.to_path_buf()))
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, path::Path};
+
+    use super::{collect, find_garbage, prune, reachable_commits};
+    use crate::vcs::{
+        commit::update_branch,
+        pack::{has_object, loose_objects, repack},
+        snapshot::Snapshot,
+        testing::{commit, repo},
+        transfer::has_commit,
+        DCG_DIR, STASH_REF,
+    };
+
+    /// A timestamp after any file of the tests was written.
+    const LATER: i64 = i64::MAX;
+
+    #[test]
+    fn prune_unreachable() {
+        let dd = repo();
+
+        let first = commit(&dd, "master", &[("a.txt", "a\n")]);
+        let second = commit(&dd, "master", &[("b.txt", "b\n")]);
+
+        /* a branch made and deleted */
+        update_branch(&dd, "topic", second, "branch: created").unwrap();
+        let dropped = commit(&dd, "topic", &[("c.txt", "c\n")]);
+        fs::remove_file(dd.join(".dcg/refs/branches/topic")).unwrap();
+        fs::remove_file(dd.join(".dcg/logs/refs/branches/topic")).unwrap();
+
+        assert_eq!(
+            reachable_commits(&dd).unwrap(),
+            [first, second].into_iter().collect()
+        );
+
+        /* nothing is old enough */
+        let garbage = find_garbage(&dd, 0).unwrap();
+        assert!(garbage.loose.is_empty());
+
+        let garbage = find_garbage(&dd, LATER).unwrap();
+        assert!(!garbage.loose.is_empty());
+
+        prune(&dd, &garbage).unwrap();
+
+        assert!(!has_commit(&dd, dropped));
+        assert!(!has_object(
+            &dd,
+            Path::new(&format!("base/c.txt-{}", hex::encode(dropped)))
+        ));
+        assert!(find_garbage(&dd, LATER).unwrap().loose.is_empty());
+        assert_eq!(
+            Snapshot::at(&dd, Some(second))
+                .unwrap()
+                .read(Path::new("a.txt"))
+                .unwrap(),
+            Some(b"a\n".to_vec())
+        );
+    }
+
+    #[test]
+    fn stash_is_reachable() {
+        let dd = repo();
+
+        let first = commit(&dd, "master", &[("a.txt", "a\n")]);
+        update_branch(&dd, "topic", first, "branch: created").unwrap();
+        let stashed = commit(&dd, "topic", &[("b.txt", "b\n")]);
+        fs::remove_file(dd.join(".dcg/refs/branches/topic")).unwrap();
+        fs::remove_file(dd.join(".dcg/logs/refs/branches/topic")).unwrap();
+        fs::write(dd.join(DCG_DIR).join(STASH_REF), hex::encode(stashed)).unwrap();
+
+        assert!(find_garbage(&dd, LATER).unwrap().loose.is_empty());
+    }
+
+    #[test]
+    fn collect_packed() {
+        let dd = repo();
+
+        let first = commit(&dd, "master", &[("a.txt", "a\n")]);
+        update_branch(&dd, "topic", first, "branch: created").unwrap();
+        let dropped = commit(&dd, "topic", &[("b.txt", "b\n")]);
+
+        repack(&dd).unwrap();
+        fs::remove_file(dd.join(".dcg/refs/branches/topic")).unwrap();
+        fs::remove_file(dd.join(".dcg/logs/refs/branches/topic")).unwrap();
+
+        let garbage = find_garbage(&dd, LATER).unwrap();
+
+        assert!(garbage.loose.is_empty() && !garbage.packed.is_empty());
+
+        collect(&dd, &garbage).unwrap();
+
+        assert!(loose_objects(&dd).unwrap().is_empty());
+        assert!(has_commit(&dd, first) && !has_commit(&dd, dropped));
+    }
+}
@@ -0,0 +1,235 @@
+//! The dcg protocol over HTTP, for `http://` and `https://` remotes and
+//! `dcg serve --http`
+//!
+//! The refs are read with `GET <url>/refs`, and each request of the
+//! protocol is the body of a `POST` to `<url>/fetch` or `<url>/push`,
+//! the response holding its answer.  Clients authenticate with a bearer
+//! token.
+use std::{io::Read, path::Path};
+
+use anyhow::Result;
+use tiny_http::{Method, Request, Response};
+
+use super::{
+    protocol::{
+        advertise_refs, read_refs, read_status, serve_request, token_matches, write_fetch,
+        write_push,
+    },
+    remote::{Refs, RemoteRepository},
+    transfer::{read_commits, read_line},
+    DcgError,
+};
+
+/// A repository served over HTTP.
+pub(crate) struct HttpRepository {
+    url: String,
+    token: Option<String>,
+    agent: ureq::Agent,
+    refs: Refs,
+}
+
+impl HttpRepository {
+    pub(crate) fn open(url: &str, token: Option<&str>) -> Result<Self> {
+        let mut repository = Self {
+            url: url.trim_end_matches('/').to_string(),
+            token: token.map(str::to_string),
+            agent: ureq::agent(),
+            refs: Refs::default(),
+        };
+
+        repository.refs = read_refs(&mut repository.send("GET", "refs", None)?)?;
+
+        Ok(repository)
+    }
+
+    fn send(
+        &self,
+        method: &str,
+        endpoint: &str,
+        body: Option<&[u8]>,
+    ) -> Result<Box<dyn Read + Send + Sync>> {
+        let mut request = self
+            .agent
+            .request(method, &format!("{}/{}", self.url, endpoint));
+
+        if let Some(token) = &self.token {
+            request = request.set("Authorization", &format!("Bearer {}", token));
+        }
+
+        let response = match body {
+            Some(body) => request.send_bytes(body),
+            None => request.call(),
+        };
+
+        match response {
+            Ok(response) => Ok(response.into_reader()),
+            Err(ureq::Error::Status(401, _)) => Err(DcgError::AuthenticationFailed.into()),
+            Err(ureq::Error::Status(code, response)) => Err(DcgError::RemoteError(format!(
+                "{} {}",
+                code,
+                response.into_string().unwrap_or_default().trim()
+            ))
+            .into()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl RemoteRepository for HttpRepository {
+    fn refs(&mut self) -> Result<Refs> {
+        Ok(self.refs.clone())
+    }
+
+    fn fetch(&mut self, dd: &Path, wants: &[[u8; 32]], depth: Option<usize>) -> Result<()> {
+        let mut body = Vec::new();
+
+        write_fetch(dd, wants, depth, &mut body)?;
+        read_commits(dd, &mut self.send("POST", "fetch", Some(&body))?)?;
+
+        Ok(())
+    }
+
+    fn push(&mut self, dd: &Path, branch: &str, h: [u8; 32], force: bool) -> Result<()> {
+        let mut body = Vec::new();
+
+        write_push(dd, &self.refs, (branch, h, force), &mut body)?;
+        read_status(&mut self.send("POST", "push", Some(&body))?)?;
+
+        self.refs.branches.insert(branch.to_string(), h);
+
+        Ok(())
+    }
+}
+
+/// Answer `request` on the repository `dd`.  Requests must carry
+/// `token` as a bearer token.
+pub(crate) fn serve_http(dd: &Path, mut request: Request, token: &str) -> Result<()> {
+    let authorized = request
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv("Authorization"))
+        .and_then(|h| h.value.as_str().strip_prefix("Bearer "))
+        .is_some_and(|sent| token_matches(sent, token));
+
+    if !authorized {
+        request.respond(Response::from_string("authentication failed").with_status_code(401))?;
+
+        return Err(DcgError::AuthenticationFailed.into());
+    }
+
+    /* the repository may be served under any prefix */
+    let path = request.url().split('?').next().unwrap_or_default();
+    let endpoint = path.rsplit('/').next().unwrap_or_default().to_string();
+
+    let mut body = Vec::new();
+    let answered = match (request.method(), endpoint.as_str()) {
+        (Method::Get, "refs") => advertise_refs(dd, &mut body),
+        (Method::Post, "fetch" | "push") => {
+            let mut r = request.as_reader();
+
+            read_line(&mut r).and_then(|command| {
+                if command.starts_with(&endpoint) {
+                    serve_request(dd, &command, &mut r, &mut body)
+                } else {
+                    Err(DcgError::InvalidPacket.into())
+                }
+            })
+        }
+        _ => {
+            request.respond(Response::from_string("not found").with_status_code(404))?;
+
+            return Ok(());
+        }
+    };
+
+    match answered {
+        Ok(()) => request.respond(Response::from_data(body))?,
+        Err(e) => {
+            request.respond(Response::from_string(e.to_string()).with_status_code(500))?;
+
+            return Err(e);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        path::Path,
+        thread::{self, JoinHandle},
+    };
+
+    use tiny_http::Server;
+
+    use super::{serve_http, HttpRepository};
+    use crate::vcs::{
+        commit::{fetch_head, update_branch},
+        remote::RemoteRepository,
+        snapshot::Snapshot,
+        testing::{commit, repo},
+        transfer::has_commit,
+    };
+
+    /// Serve `dd` on localhost for `requests` requests.
+    fn serve(dd: &Path, requests: usize) -> (String, JoinHandle<()>) {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/repo", server.server_addr());
+        let dd = dd.to_path_buf();
+
+        let handle = thread::spawn(move || {
+            for _ in 0..requests {
+                let _ = serve_http(&dd, server.recv().unwrap(), "secret");
+            }
+        });
+
+        (url, handle)
+    }
+
+    #[test]
+    fn fetch_and_push() {
+        let server_repo = repo();
+        let client_repo = repo();
+        let (sd, cd) = (server_repo.as_path(), client_repo.as_path());
+
+        let first = commit(sd, "master", &[("a.txt", "a\n")]);
+        let second = commit(sd, "master", &[("b.txt", "b\n")]);
+
+        /* the refs, a fetch and a push */
+        let (url, server) = serve(sd, 3);
+        let mut remote = HttpRepository::open(&url, Some("secret")).unwrap();
+
+        assert_eq!(remote.refs().unwrap().branches.get("master"), Some(&second));
+
+        remote.fetch(cd, &[second], None).unwrap();
+
+        assert!(has_commit(cd, first) && has_commit(cd, second));
+        assert_eq!(
+            Snapshot::at(cd, Some(second))
+                .unwrap()
+                .read(Path::new("a.txt"))
+                .unwrap(),
+            Some(b"a\n".to_vec())
+        );
+
+        update_branch(cd, "feature", second, "branch: created").unwrap();
+        let third = commit(cd, "feature", &[("c.txt", "c\n")]);
+
+        remote.push(cd, "feature", third, false).unwrap();
+        server.join().unwrap();
+
+        assert_eq!(fetch_head(sd, "feature").unwrap(), Some(third));
+    }
+
+    #[test]
+    fn wrong_token() {
+        let server_repo = repo();
+        let (url, server) = serve(server_repo.as_path(), 2);
+
+        assert!(HttpRepository::open(&url, Some("guess")).is_err());
+        assert!(HttpRepository::open(&url, None).is_err());
+
+        server.join().unwrap();
+    }
+}
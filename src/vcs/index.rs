@@ -1,23 +1,18 @@
 use std::{
     collections::BTreeMap,
-    env,
     ffi::OsStr,
     fmt::{self, Formatter},
     fs::{self, File},
-    io::{self, Read, Write},
+    io::{self, Read},
     path::{Path, PathBuf},
 };
 
 use anyhow::Result;
-use flate2::{
-    write::{GzDecoder, GzEncoder},
-    Compression,
-};
 use sha2::{Digest, Sha256};
 
 use crate::{combine_paths, commands::visit_dirs};
 
-use super::{find_repo, DCG_DIR, INDEX_DIR, LAST_DIR};
+use super::{decode_hash, gz_decode, gz_encode, lock::write_atomic, DCG_DIR, INDEX_DIR, LAST_DIR};
 
 #[derive(Clone, Debug)]
 pub(crate) struct Object<'a> {
@@ -36,50 +31,65 @@ impl<'a> Object<'a> {
         let mut contents = Vec::with_capacity(size);
         f.read_to_end(&mut contents)?;
 
-        let mut hash: [u8; 32] = Sha256::digest(&contents).into();
+        Ok(Self::new(path.strip_prefix(wd).unwrap_or(path), contents))
+    }
 
-        let fname = path.strip_prefix(wd).unwrap_or(path);
+    /// The object of the file `path`, relative to the repository, with
+    /// `contents`.
+    pub(crate) fn new(path: &'a Path, contents: Vec<u8>) -> Self {
+        let mut hash: [u8; 32] = Sha256::digest(&contents).into();
 
-        for (i, byte) in fname.as_os_str().as_encoded_bytes().iter().enumerate() {
+        for (i, byte) in path.as_os_str().as_encoded_bytes().iter().enumerate() {
             hash[i % 32] ^= byte;
         }
 
-        Ok(Self {
+        Self {
             path,
             hash,
             contents,
-        })
+        }
+    }
+
+    pub(crate) fn hash(&self) -> [u8; 32] {
+        self.hash
     }
 
-    pub(crate) fn read(wd: &'a Path, path: &'a Path) -> io::Result<Option<([u8; 32], Vec<u8>)>> {
-        let index = combine_paths!(wd, DCG_DIR, INDEX_DIR);
+    /// The hash and the contents of `path` in `dir`, the index or the
+    /// files of the last commit.
+    pub(crate) fn read(
+        wd: &'a Path,
+        dir: &str,
+        path: &'a Path,
+    ) -> io::Result<Option<([u8; 32], Vec<u8>)>> {
+        let virtual_parent = combine_paths!(
+            wd,
+            DCG_DIR,
+            dir,
+            path.parent().map(Path::to_path_buf).unwrap_or_default()
+        );
 
-        let hash_p = combine_paths!(&index, path);
+        let hash_p = combine_paths!(&virtual_parent, get_fname(path));
         let mut hash_s = String::new();
 
-        if !hash_p.exists() {
+        if !hash_p.is_file() {
             return Ok(None);
         }
 
         File::open(hash_p)?.read_to_string(&mut hash_s)?;
 
-        let hash = hex::decode(hash_s).unwrap_or(vec![0; 32]);
+        let Ok(Ok(hash)) = hex::decode(hash_s.trim()).map(<[u8; 32]>::try_from) else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid object hash",
+            ));
+        };
 
         let mut gz_contents = Vec::new();
 
-        File::open(combine_paths!(
-            &index,
-            path.parent().map(Path::to_path_buf).unwrap_or_default()
-        ))?
-        .read_to_end(&mut gz_contents)?;
-
-        let contents = Vec::new();
-        let mut decoder = GzDecoder::new(contents);
-        decoder.write_all(&gz_contents)?;
+        File::open(combine_paths!(&virtual_parent, hash_s.trim()))?
+            .read_to_end(&mut gz_contents)?;
 
-        decoder
-            .finish()
-            .map(|x| Some((hash.try_into().unwrap(), x)))
+        gz_decode(&gz_contents).map(|x| Some((hash, x)))
     }
 
     pub(crate) fn delete(wd: &'a Path, path: &'a Path) -> io::Result<()> {
@@ -112,13 +122,27 @@ impl<'a> Object<'a> {
     }
 
     pub(crate) fn write(&self, wd: &'a Path) -> io::Result<usize> {
+        self.write_in(wd, INDEX_DIR)
+    }
+
+    /// Write the object in `dir`, the index or the files of the last
+    /// commit.
+    pub(crate) fn write_in(&self, wd: &'a Path, dir: &str) -> io::Result<usize> {
         let fname = get_fname(self.path);
 
         if fname.is_empty() {
             return Ok(0);
         }
 
-        let virtual_parent = get_virtual_parent(wd, self.path);
+        let virtual_parent = combine_paths!(
+            wd,
+            DCG_DIR,
+            dir,
+            self.path
+                .parent()
+                .map(Path::to_path_buf)
+                .unwrap_or_default()
+        );
 
         if !virtual_parent.exists() {
             fs::create_dir_all(&virtual_parent)?;
@@ -129,14 +153,11 @@ impl<'a> Object<'a> {
         let hash_s = hex::encode(self.hash);
         let virtual_file = combine_paths!(&virtual_parent, &hash_s);
 
-        File::create(symlink)?.write_all(hash_s.as_bytes())?;
-
-        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
-        encoder.write_all(&self.contents)?;
+        let gz_contents = gz_encode(&self.contents)?;
 
-        let gz_contents = encoder.finish()?;
-
-        File::create(virtual_file)?.write_all(&gz_contents)?;
+        /* contents first, so that the file is never missing */
+        write_atomic(wd, &virtual_file, &gz_contents)?;
+        write_atomic(wd, &symlink, hash_s)?;
 
         Ok(gz_contents.len())
     }
@@ -179,7 +200,7 @@ fn archive_one(p: &Path, pref: &Path, m: &mut BTreeMap<PathBuf, [u8; 32]>) -> Re
 
         File::open(p)?.read_to_string(&mut h)?;
 
-        let h = hex::decode(h)?.try_into().unwrap();
+        let h = decode_hash(&h, p)?;
         m.insert(p.strip_prefix(pref)?.to_path_buf(), h);
     }
 
@@ -208,9 +229,31 @@ pub(crate) fn get_indexed_files<P: AsRef<Path>>(dd: P) -> Result<Vec<PathBuf>> {
     Ok(paths)
 }
 
-pub(crate) fn compute_status() -> Result<Vec<(PathBuf, ObjStatus)>> {
-    let wd = env::current_dir().map(fs::canonicalize)??.into_boxed_path();
-    let dd = find_repo(&wd)?;
+/// Add the working tree version of each of `paths` to the index.
+pub(crate) fn stage<P: AsRef<Path>>(dd: P, paths: &[PathBuf]) -> Result<()> {
+    let dd = dd.as_ref();
+
+    for path in paths {
+        Object::construct(dd, path)?.write(dd)?;
+    }
+
+    Ok(())
+}
+
+/// Remove every file from the index.
+pub(crate) fn clear_index<P: AsRef<Path>>(dd: P) -> Result<()> {
+    let idx = combine_paths!(dd.as_ref(), DCG_DIR, INDEX_DIR);
+
+    fs::remove_dir_all(&idx)?;
+    fs::create_dir_all(&idx)?;
+
+    Ok(())
+}
+
+/// The status of the files of the index of the repository `dd`,
+/// compared to the ones of the last commit.
+pub(crate) fn compute_status<P: AsRef<Path>>(dd: P) -> Result<Vec<(PathBuf, ObjStatus)>> {
+    let dd = dd.as_ref();
 
     /* hashmap file -> hash for index/ and last/ and then it's a diff */
     let mut last = BTreeMap::new();
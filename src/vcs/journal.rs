@@ -0,0 +1,200 @@
+//! Journal of the commit being written
+//!
+//! Before writing a commit on top of a branch, `.dcg/journal` records
+//! the branch, its head and the hash of the new commit.  It is removed
+//! once the commit is stored, the branch moved and the index made the
+//! files of the last commit.  If it is still there when the repository
+//! is next locked, the process writing the commit was interrupted: the
+//! commit is finished if all of it was stored, and dropped otherwise.
+use std::{fs, path::Path};
+
+use anyhow::Result;
+
+use crate::combine_paths;
+
+use super::{
+    checkout::set_last,
+    commit::{fetch_head, update_branch},
+    decode_hash,
+    index::clear_index,
+    lock::write_atomic,
+    snapshot::Snapshot,
+    transfer::{has_commit, partial_dir},
+    DCG_DIR, JOURNAL_FILE,
+};
+
+/// What was done with an interrupted commit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Recovery {
+    /// the commit was complete, and was made the head of its branch
+    Forward([u8; 32]),
+    /// the commit was incomplete, and was dropped
+    Back([u8; 32]),
+}
+
+/// Record that the commit `new` is being written on top of `branch`,
+/// whose head is `old`, in the repository `dd`.
+pub(crate) fn begin(dd: &Path, branch: &str, old: Option<[u8; 32]>, new: [u8; 32]) -> Result<()> {
+    let old = old.map(hex::encode).unwrap_or("-".to_string());
+
+    write_atomic(
+        dd,
+        &combine_paths!(dd, DCG_DIR, JOURNAL_FILE),
+        format!("{} {} {}\n", branch, old, hex::encode(new)),
+    )?;
+
+    Ok(())
+}
+
+/// Record that the commit being written is done with.
+pub(crate) fn end(dd: &Path) -> Result<()> {
+    fs::remove_file(combine_paths!(dd, DCG_DIR, JOURNAL_FILE))?;
+
+    Ok(())
+}
+
+/// Whether a commit was being written in the repository `dd` by a
+/// process which may have been interrupted.
+pub(crate) fn pending(dd: &Path) -> bool {
+    combine_paths!(dd, DCG_DIR, JOURNAL_FILE).is_file()
+}
+
+/// Finish or drop the commit an interrupted process was writing in the
+/// repository `dd`, if any.  The lock of the repository must be held.
+pub(crate) fn recover(dd: &Path) -> Result<Option<Recovery>> {
+    if !pending(dd) {
+        return Ok(None);
+    }
+
+    let p = combine_paths!(dd, DCG_DIR, JOURNAL_FILE);
+
+    let journal = fs::read_to_string(&p)?;
+    let (branch, new) = match journal.split_whitespace().collect::<Vec<_>>()[..] {
+        [branch, _, new] => (branch.to_string(), decode_hash(new, &p)?),
+        /* interrupted before anything else was written */
+        _ => {
+            fs::remove_file(p)?;
+
+            return Ok(None);
+        }
+    };
+
+    let recovery = if has_commit(dd, new) {
+        if fetch_head(dd, &branch)? != Some(new) {
+            update_branch(dd, &branch, new, "commit (recovered)")?;
+        }
+
+        /* the index was committed, whether or not it was moved */
+        set_last(dd, &Snapshot::at(dd, Some(new))?)?;
+        clear_index(dd)?;
+
+        Recovery::Forward(new)
+    } else {
+        /* the files of the commit, and the branch, are left as they were */
+        partial_dir(dd, new)?;
+
+        Recovery::Back(new)
+    };
+
+    end(dd)?;
+
+    Ok(Some(recovery))
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, path::Path};
+
+    use super::{begin, recover, Recovery};
+    use crate::vcs::{
+        commit::{fetch_head, update_branch, Change, ChangeContent, CommitObject, Signature},
+        gz_encode,
+        index::{get_indexed_files, Object},
+        lock::Lock,
+        testing::{commit, repo},
+        DCG_DIR, JOURNAL_FILE, TREE_DIR,
+    };
+
+    /// A commit adding `a.txt` on top of `parent`, as `dcg commit`
+    /// would make it from the index.
+    fn new_commit(dd: &Path, parent: [u8; 32]) -> (CommitObject, [u8; 32]) {
+        let signature = Signature {
+            name: "A U Thor".to_string(),
+            email: "a@u.th".to_string(),
+            time: 1_700_000_001,
+            offset: 0,
+        };
+        let path = Path::new("a.txt");
+
+        Object::new(path, b"a\n".to_vec()).write(dd).unwrap();
+
+        let commit = CommitObject::new(
+            signature.clone(),
+            signature,
+            "Add a.txt".to_string(),
+            vec![Change {
+                content: ChangeContent::Addition(Object::new(path, b"a\n".to_vec()).hash()),
+                file: gz_encode(b"a\n").unwrap(),
+                path: path.to_path_buf(),
+            }],
+        );
+        let h = commit.hash(&[parent]);
+
+        (commit, h)
+    }
+
+    #[test]
+    fn roll_forward() {
+        let dd = repo();
+
+        let first = commit(&dd, "master", &[("b.txt", "b\n")]);
+        let (c, h) = new_commit(&dd, first);
+
+        /* interrupted once the commit was stored */
+        begin(&dd, "master", Some(first), h).unwrap();
+        c.write_detached(&dd, &[first]).unwrap();
+
+        drop(Lock::acquire(&dd).unwrap());
+
+        assert_eq!(fetch_head(&dd, "master").unwrap(), Some(h));
+        assert!(get_indexed_files(&dd).unwrap().is_empty());
+        assert!(!dd.join(DCG_DIR).join(JOURNAL_FILE).exists());
+    }
+
+    #[test]
+    fn roll_back() {
+        let dd = repo();
+
+        let first = commit(&dd, "master", &[("b.txt", "b\n")]);
+        let (_, h) = new_commit(&dd, first);
+
+        /* interrupted while the commit was being stored */
+        begin(&dd, "master", Some(first), h).unwrap();
+
+        let partial = dd
+            .join(DCG_DIR)
+            .join(TREE_DIR)
+            .join(format!("{}.partial", hex::encode(h)));
+
+        fs::create_dir_all(&partial).unwrap();
+        fs::write(partial.join("message"), "Add a.txt").unwrap();
+
+        assert_eq!(recover(&dd).unwrap(), Some(Recovery::Back(h)));
+        assert_eq!(fetch_head(&dd, "master").unwrap(), Some(first));
+        assert!(!partial.exists());
+        assert_eq!(
+            get_indexed_files(&dd).unwrap(),
+            vec![Path::new("a.txt").to_path_buf()]
+        );
+
+        /* the branch moved, but the index was not emptied */
+        let (c, h) = new_commit(&dd, first);
+
+        begin(&dd, "master", Some(first), h).unwrap();
+        c.write_detached(&dd, &[first]).unwrap();
+        update_branch(&dd, "master", h, "commit: Add a.txt").unwrap();
+
+        assert_eq!(recover(&dd).unwrap(), Some(Recovery::Forward(h)));
+        assert!(get_indexed_files(&dd).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,335 @@
+//! Exclusive access to a repository, and writes which are never seen
+//! half done
+//!
+//! Commands changing a repository hold `.dcg/lock` until they return:
+//! they take an `flock(2)` on it, and write their process in it.  The
+//! system releases the lock of a process which is not running anymore,
+//! killed or interrupted, so that it is never held by two processes.
+//! Objects and refs are first written to `.dcg/tmp/`, then moved in
+//! place, so that they are either absent or complete.
+use std::{
+    fs::{self, File},
+    io::{self, ErrorKind, Write},
+    os::unix::{fs::MetadataExt, io::AsRawFd},
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+    time::Duration,
+};
+
+use anyhow::Result;
+
+use crate::combine_paths;
+
+use super::{journal, DcgError, DCG_DIR, LOCK_FILE, TMP_DIR};
+
+/// The lock of a repository, released when dropped.
+pub(crate) struct Lock {
+    path: PathBuf,
+    /// the locked file, if the lock was taken by this value rather than
+    /// already held by the process
+    file: Option<File>,
+}
+
+/// Whether the process `pid` is running.
+fn is_running(pid: i32) -> bool {
+    // SAFETY: signal 0 only checks that the process exists
+    pid > 0
+        && (unsafe { libc::kill(pid, 0) } == 0
+            || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM))
+}
+
+/// The process holding the lock at `path`, or `None` if the lock does
+/// not name one.  Fails with `NotFound` if the lock was released.
+fn holder(path: &Path) -> io::Result<Option<i32>> {
+    Ok(String::from_utf8_lossy(&fs::read(path)?)
+        .trim()
+        .parse()
+        .ok())
+}
+
+impl Lock {
+    /// Lock the repository `dd`, then finish the commit a process may
+    /// have been interrupted in (see `journal::recover`).  Fails if
+    /// another running process holds the lock.
+    pub(crate) fn acquire(dd: &Path) -> Result<Self> {
+        let path = combine_paths!(dd, DCG_DIR, LOCK_FILE);
+        let pid = process::id() as i32;
+
+        let mut file = loop {
+            let file = File::options()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&path)?;
+
+            // SAFETY: the descriptor is open for as long as `file`
+            if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+                let e = io::Error::last_os_error();
+
+                if e.kind() != ErrorKind::WouldBlock {
+                    return Err(e.into());
+                }
+
+                match holder(&path) {
+                    Ok(Some(h)) if h == pid => return Ok(Self { path, file: None }),
+                    Ok(Some(h)) if is_running(h) => {
+                        return Err(DcgError::Locked(path, h).into());
+                    }
+                    Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+                    /* the holder has yet to write its process, or released
+                     * the lock in the meantime
+                     */
+                    _ => {
+                        thread::sleep(Duration::from_millis(1));
+
+                        continue;
+                    }
+                }
+            }
+
+            /* the holder removes the lock before releasing it: the file
+             * locked must still be the one in place
+             */
+            let locked = file.metadata()?.ino();
+
+            if fs::metadata(&path).is_ok_and(|m| m.ino() == locked) {
+                break file;
+            }
+        };
+
+        file.set_len(0)?;
+        file.write_all(pid.to_string().as_bytes())?;
+
+        let lock = Self {
+            path,
+            file: Some(file),
+        };
+
+        remove_stale_temps(dd)?;
+        journal::recover(dd)?;
+
+        Ok(lock)
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        /* removed while still locked, see `acquire` */
+        if let Some(file) = self.file.take() {
+            let _ = fs::remove_file(&self.path);
+
+            drop(file);
+        }
+    }
+}
+
+static TEMPS: AtomicUsize = AtomicUsize::new(0);
+
+/// A new path in `.dcg/tmp/` of the repository `dd`, named after the
+/// process.
+fn temp_path(dd: &Path) -> io::Result<PathBuf> {
+    let dir = combine_paths!(dd, DCG_DIR, TMP_DIR);
+
+    fs::create_dir_all(&dir)?;
+
+    Ok(dir.join(format!(
+        "{}-{}",
+        process::id(),
+        TEMPS.fetch_add(1, Ordering::Relaxed)
+    )))
+}
+
+/// Remove the temporary files left by processes which are not running
+/// anymore.
+fn remove_stale_temps(dd: &Path) -> io::Result<()> {
+    let dir = combine_paths!(dd, DCG_DIR, TMP_DIR);
+
+    if !dir.is_dir() {
+        return Ok(());
+    }
+
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let pid = path
+            .file_name()
+            .and_then(|n| n.to_str()?.split('-').next()?.parse().ok());
+
+        if !pid.is_some_and(is_running) {
+            fs::remove_file(path)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Write `contents` to `path`, a file of the repository `dd`, at once:
+/// they are written to a temporary file, which then replaces `path`.
+/// Both the file and its directory are synced, so that a crash leaves
+/// either the old contents or the new ones.
+pub(crate) fn write_atomic<C: AsRef<[u8]>>(dd: &Path, path: &Path, contents: C) -> io::Result<()> {
+    let tmp = temp_path(dd)?;
+    let mut f = File::create(&tmp)?;
+
+    f.write_all(contents.as_ref())?;
+    f.sync_all()?;
+
+    let parent = path.parent().unwrap_or(Path::new("."));
+
+    fs::create_dir_all(parent)?;
+    fs::rename(&tmp, path)?;
+
+    File::open(parent)?.sync_all()
+}
+
+/// Write `contents` to the new file `path`, and sync it.  Used for the
+/// files of a commit gathered in its partial directory, which is moved
+/// in place at once by `transfer::finish_commit`.
+pub(crate) fn write_synced<C: AsRef<[u8]>>(path: &Path, contents: C) -> io::Result<()> {
+    let mut f = File::create(path)?;
+
+    f.write_all(contents.as_ref())?;
+    f.sync_all()
+}
+
+/// Sync the directory `dir` and the ones below it, so that the files
+/// they list survive a crash.
+pub(crate) fn sync_dirs(dir: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+
+        if entry.file_type()?.is_dir() {
+            sync_dirs(&entry.path())?;
+        }
+    }
+
+    File::open(dir)?.sync_all()
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        fs::{self, File},
+        os::unix::io::AsRawFd,
+        path::Path,
+        sync::Barrier,
+        thread,
+    };
+
+    use super::{write_atomic, Lock};
+    use crate::vcs::{testing::repo, DCG_DIR, LOCK_FILE, TMP_DIR};
+
+    /// Lock `path` as another process named `pid` would.
+    fn hold(path: &Path, pid: &str) -> File {
+        let file = File::create(path).unwrap();
+
+        // SAFETY: the descriptor is open for as long as `file`
+        assert_eq!(
+            unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) },
+            0
+        );
+        fs::write(path, pid).unwrap();
+
+        file
+    }
+
+    #[test]
+    fn exclusive() {
+        let dd = repo();
+        let path = dd.join(DCG_DIR).join(LOCK_FILE);
+
+        {
+            let _lock = Lock::acquire(&dd).unwrap();
+
+            /* the process may lock the repository again */
+            drop(Lock::acquire(&dd).unwrap());
+            assert!(path.is_file());
+        }
+
+        assert!(!path.exists());
+
+        /* held by a running process, init */
+        let held = hold(&path, "1");
+        assert!(Lock::acquire(&dd).is_err());
+        drop(held);
+
+        /* left by a process which is gone, or naming none */
+        for stale in [i32::MAX.to_string(), "".to_string(), "x".to_string()] {
+            fs::write(&path, stale).unwrap();
+
+            let lock = Lock::acquire(&dd).unwrap();
+
+            assert_eq!(
+                fs::read_to_string(&path).unwrap(),
+                std::process::id().to_string()
+            );
+
+            drop(lock);
+            assert!(!path.exists());
+        }
+    }
+
+    #[test]
+    fn stale_lock_race() {
+        let dd = repo();
+        let path = dd.join(DCG_DIR).join(LOCK_FILE);
+        let threads = 8;
+
+        for _ in 0..20 {
+            fs::write(&path, i32::MAX.to_string()).unwrap();
+
+            let barrier = Barrier::new(threads);
+            let owners = thread::scope(|s| {
+                let handles = (0..threads)
+                    .map(|_| {
+                        s.spawn(|| {
+                            barrier.wait();
+
+                            let lock = Lock::acquire(&dd).unwrap();
+                            let owned = lock.file.is_some();
+
+                            /* held until every thread has tried */
+                            barrier.wait();
+
+                            owned
+                        })
+                    })
+                    .collect::<Vec<_>>();
+
+                handles
+                    .into_iter()
+                    .map(|h| h.join().unwrap())
+                    .filter(|&owned| owned)
+                    .count()
+            });
+
+            assert_eq!(owners, 1);
+            assert!(!path.exists());
+        }
+    }
+
+    #[test]
+    fn atomic_writes() {
+        let dd = repo();
+        let p = dd.join(DCG_DIR).join("refs/branches/topic");
+
+        /* left by a process which is gone */
+        fs::create_dir_all(dd.join(DCG_DIR).join(TMP_DIR)).unwrap();
+        fs::write(dd.join(DCG_DIR).join(TMP_DIR).join("2147483647-0"), "").unwrap();
+
+        write_atomic(&dd, &p, "contents").unwrap();
+
+        assert_eq!(fs::read_to_string(&p).unwrap(), "contents");
+
+        drop(Lock::acquire(&dd).unwrap());
+
+        assert_eq!(
+            fs::read_dir(dd.join(DCG_DIR).join(TMP_DIR))
+                .unwrap()
+                .count(),
+            0
+        );
+    }
+}
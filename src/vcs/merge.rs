@@ -0,0 +1,570 @@
+//! Application of the change made by a commit to other versions of
+//! the files it touched
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{self, File},
+    io::Write,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+use anyhow::Result;
+use tree_sitter::{Language, Parser, Tree};
+
+use crate::{
+    backend::{
+        bcst::{bcst_to_code, diff_wrapper, patch, to_absolute, to_relative, BCSTree, Twh},
+        diff::{ered, invert},
+        linear::{self, LinDiff},
+        linguist::{get_ts_language, LinguistState},
+        merge::merge,
+        rcst::RCSTree,
+    },
+    commands::guess_contents_language,
+    vcs::{
+        checkout::remove_file,
+        commit::{get_parent, CommitObject},
+        snapshot::Snapshot,
+    },
+};
+
+/// The result of applying a change to a file.
+pub(crate) enum Merged {
+    Contents(Vec<u8>),
+    Deleted,
+    /// the change conflicts with the changes made to the file
+    Conflict,
+}
+
+pub(crate) fn relative<'a>(tree: &'a Tree, src: &'a str) -> Twh<'a> {
+    let (t, th): (BCSTree, usize) = RCSTree::from(tree.root_node(), src).into();
+
+    to_relative(&(Rc::new(t), th))
+}
+
+/// Rendering a tree turns the whitespace before each leaf into spaces
+/// and drops the one at the end of lines: take back the lines of the
+/// original files which only differ from the rendered ones by their
+/// whitespace.
+pub(crate) fn restore_layout(rendered: &str, originals: &[&str]) -> String {
+    let mut lines = HashMap::new();
+
+    for l in originals.iter().flat_map(|s| s.lines()) {
+        let indent = l.len() - l.trim_start().len();
+
+        lines.entry((l.trim(), indent)).or_insert(l);
+    }
+
+    rendered
+        .lines()
+        .map(|l| {
+            let indent = l.len() - l.trim_start().len();
+
+            lines.get(&(l.trim(), indent)).copied().unwrap_or(l)
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn apply_tree_change(
+    language: &Language,
+    from: &str,
+    to: &str,
+    onto: &str,
+    reverse: bool,
+) -> Result<Option<String>> {
+    let mut parser = Parser::new();
+
+    parser.set_language(language)?;
+
+    let (Some(ft), Some(tt), Some(ot)) = (
+        parser.parse(from, None),
+        parser.parse(to, None),
+        parser.parse(onto, None),
+    ) else {
+        return Ok(None);
+    };
+
+    if [&ft, &tt, &ot].iter().any(|t| t.root_node().has_error()) {
+        return Ok(None);
+    }
+
+    let (f, t, o) = (relative(&ft, from), relative(&tt, to), relative(&ot, onto));
+
+    let change = ered(diff_wrapper(f.clone(), t.clone()));
+
+    let (base, left, target) = if reverse {
+        match invert(f, change) {
+            Some(inverse) => (t, inverse, from),
+            None => return Ok(None),
+        }
+    } else {
+        (f, change, to)
+    };
+
+    let right = ered(diff_wrapper(base.clone(), o));
+
+    let mut conflicts = Vec::new();
+    let merged = merge(left, right, &mut conflicts);
+
+    if !conflicts.is_empty() {
+        return Ok(None);
+    }
+
+    let Ok(patched) = patch(base, merged) else {
+        return Ok(None);
+    };
+
+    let mut contents = restore_layout(&bcst_to_code(to_absolute(&patched).0), &[onto, target]);
+
+    if onto.ends_with('\n') {
+        contents.push('\n');
+    }
+
+    /* changes to neighbouring nodes may merge into invalid code */
+    match parser.parse(&contents, None) {
+        Some(t) if !t.root_node().has_error() => Ok(Some(contents)),
+        _ => Ok(None),
+    }
+}
+
+fn apply_linear_change(from: &str, to: &str, onto: &str, reverse: bool) -> Option<String> {
+    /* see `do_diff_linear` */
+    let (from, to, onto) = (
+        format!("{}\n", from),
+        format!("{}\n", to),
+        format!("{}\n", onto),
+    );
+
+    let fl = from.split_terminator('\n').collect::<Vec<_>>();
+    let tl = to.split_terminator('\n').collect::<Vec<_>>();
+    let ol = onto.split_terminator('\n').collect::<Vec<_>>();
+
+    let change = linear::diff(&from, &to, &fl, &tl);
+
+    let (base, base_lines, left) = if reverse {
+        (&to, &tl, linear::invert(&fl, &change)?)
+    } else {
+        (&from, &fl, change)
+    };
+
+    let right = linear::diff(base, &onto, base_lines, &ol);
+
+    let mut conflicts = Vec::new();
+    let merged = linear::merge(&left, &right, &mut conflicts);
+
+    if !conflicts.is_empty() {
+        return None;
+    }
+
+    linear::patch(base_lines, &merged)
+        .ok()
+        .map(|lines| lines.join("\n"))
+}
+
+/// Apply the change of a file from `from` to `to` (or, if `reverse`,
+/// from `to` to `from`) to its version `onto`.  `None` stands for a
+/// missing file.
+///
+/// Files in a language dcg can parse are merged with their syntax
+/// trees, so that changes to the same lines do not conflict as long as
+/// they change different nodes.  Other files are merged by lines.
+pub(crate) fn apply_change(
+    state: LinguistState,
+    path: &Path,
+    from: Option<&[u8]>,
+    to: Option<&[u8]>,
+    onto: Option<&[u8]>,
+    reverse: bool,
+) -> Result<Merged> {
+    let (base, target) = if reverse { (to, from) } else { (from, to) };
+
+    let result = if onto == base || onto == target {
+        target
+    } else if target == base {
+        onto
+    } else {
+        let (Some(from), Some(to), Some(onto)) = (from, to, onto) else {
+            return Ok(Merged::Conflict);
+        };
+
+        let (Ok(from), Ok(to), Ok(onto)) = (
+            str::from_utf8(from),
+            str::from_utf8(to),
+            str::from_utf8(onto),
+        ) else {
+            return Ok(Merged::Conflict);
+        };
+
+        let language = get_ts_language(guess_contents_language(state, path, onto.as_bytes())?);
+
+        let tree_merge = match &language {
+            Some(l) => apply_tree_change(l, from, to, onto, reverse)?,
+            None => None,
+        };
+
+        return Ok(
+            match tree_merge.or_else(|| apply_linear_change(from, to, onto, reverse)) {
+                Some(s) => Merged::Contents(s.into_bytes()),
+                None => Merged::Conflict,
+            },
+        );
+    };
+
+    Ok(match result {
+        Some(c) => Merged::Contents(c.to_vec()),
+        None => Merged::Deleted,
+    })
+}
+
+/// A run of changed lines: the lines `start..end` of the base file
+/// are replaced with `lines`.
+pub(crate) struct Hunk<'a> {
+    pub(crate) start: usize,
+    pub(crate) end: usize,
+    pub(crate) lines: Vec<&'a str>,
+}
+
+pub(crate) fn hunks<'a>(d: &[LinDiff<'a>]) -> Vec<Hunk<'a>> {
+    let mut hunks: Vec<Hunk<'a>> = Vec::new();
+    let mut i = 0;
+    let mut open = false;
+
+    for x in d {
+        if !open && *x != LinDiff::Eps {
+            hunks.push(Hunk {
+                start: i,
+                end: i,
+                lines: Vec::new(),
+            });
+        }
+
+        open = *x != LinDiff::Eps;
+
+        match x {
+            LinDiff::Eps => i += 1,
+            LinDiff::Del => {
+                i += 1;
+                hunks.last_mut().unwrap().end = i;
+            }
+            LinDiff::Add(l) => hunks.last_mut().unwrap().lines.push(l),
+        }
+    }
+
+    hunks
+}
+
+/// The lines `start..end` of `base` with `hunks` applied.
+pub(crate) fn apply_hunks<'a>(
+    base: &[&'a str],
+    hunks: &[&Hunk<'a>],
+    start: usize,
+    end: usize,
+) -> Vec<&'a str> {
+    let mut out = Vec::new();
+    let mut i = start;
+
+    for h in hunks {
+        out.extend_from_slice(&base[i..h.start]);
+        out.extend_from_slice(&h.lines);
+        i = h.end;
+    }
+
+    out.extend_from_slice(&base[i..end]);
+
+    out
+}
+
+/// Merge `ours` and `theirs` by lines, surrounding the changes which
+/// overlap with conflict markers.
+fn merge_with_markers(base: &str, ours: &str, theirs: &str, label: &str) -> String {
+    let (base, ours, theirs) = (
+        format!("{}\n", base),
+        format!("{}\n", ours),
+        format!("{}\n", theirs),
+    );
+
+    let bl = base.split_terminator('\n').collect::<Vec<_>>();
+    let ol = ours.split_terminator('\n').collect::<Vec<_>>();
+    let tl = theirs.split_terminator('\n').collect::<Vec<_>>();
+
+    let mut hs = hunks(&linear::diff(&base, &ours, &bl, &ol))
+        .into_iter()
+        .map(|h| (true, h))
+        .chain(
+            hunks(&linear::diff(&base, &theirs, &bl, &tl))
+                .into_iter()
+                .map(|h| (false, h)),
+        )
+        .collect::<Vec<_>>();
+
+    hs.sort_by_key(|(_, h)| (h.start, h.end));
+
+    let mut out = Vec::new();
+    let mut i = 0;
+    let mut k = 0;
+
+    while k < hs.len() {
+        /* the hunks overlapping the first one, from both sides */
+        let (start, mut end) = (hs[k].1.start, hs[k].1.end);
+        let mut cluster = vec![&hs[k]];
+
+        k += 1;
+
+        while k < hs.len() && (hs[k].1.start < end || hs[k].1.start == start) {
+            end = end.max(hs[k].1.end);
+            cluster.push(&hs[k]);
+            k += 1;
+        }
+
+        out.extend_from_slice(&bl[i..start]);
+        i = end;
+
+        let side = |ours: bool| {
+            let hunks = cluster
+                .iter()
+                .filter(|(o, _)| *o == ours)
+                .map(|(_, h)| h)
+                .collect::<Vec<_>>();
+
+            apply_hunks(&bl, &hunks, start, end)
+        };
+
+        let (o, t) = (side(true), side(false));
+
+        if cluster.iter().all(|(o, _)| *o) || o == t {
+            out.extend(o);
+        } else if cluster.iter().all(|(o, _)| !*o) {
+            out.extend(t);
+        } else {
+            out.push("<<<<<<< HEAD");
+            out.extend(o);
+            out.push("=======");
+            out.extend(t);
+            out.push(label);
+        }
+    }
+
+    out.extend_from_slice(&bl[i..]);
+
+    out.join("\n")
+}
+
+/// The working tree once the changes of a commit were applied to the
+/// files of another one.
+pub(crate) struct Replay {
+    /// the files tracked once the changes are applied
+    pub(crate) files: Vec<PathBuf>,
+    /// the files which changes conflict
+    pub(crate) conflicts: Vec<PathBuf>,
+}
+
+/// Apply the changes made by commit `h` (or undo them, if `reverse`)
+/// to the files of `tip`, which are those of the working tree.
+///
+/// When changes conflict, the files are written with conflict markers
+/// if `label` is given, naming the side of `h`.  Otherwise, the working
+/// tree is left untouched.
+pub(crate) fn replay<P: AsRef<Path>>(
+    dd: P,
+    state: LinguistState,
+    h: [u8; 32],
+    tip: &Snapshot,
+    reverse: bool,
+    label: Option<&str>,
+) -> Result<Replay> {
+    let dd = dd.as_ref();
+
+    /* a merge is replayed relative to its first parent */
+    let before = Snapshot::at(dd, get_parent(dd, h)?)?;
+    let after = Snapshot::at(dd, Some(h))?;
+    let paths = CommitObject::read(dd, h)?
+        .changes
+        .into_iter()
+        .map(|c| c.path)
+        .collect::<Vec<_>>();
+
+    merge_changes(dd, state, (&before, &after), &paths, tip, reverse, label)
+}
+
+/// The files which differ between `before` and `after`.
+pub(crate) fn changed_paths(before: &Snapshot, after: &Snapshot) -> Vec<PathBuf> {
+    let mut paths = before
+        .files
+        .iter()
+        .filter(|(p, h)| after.files.get(*p) != Some(h))
+        .map(|(p, _)| p.clone())
+        .collect::<Vec<_>>();
+
+    paths.extend(
+        after
+            .files
+            .keys()
+            .filter(|p| !before.files.contains_key(*p))
+            .cloned(),
+    );
+
+    paths
+}
+
+/// Apply the changes made to `paths` between the two snapshots of
+/// `change` (or undo them, if `reverse`) to the files of `tip`, as
+/// `replay` does.
+pub(crate) fn merge_changes(
+    dd: &Path,
+    state: LinguistState,
+    (before, after): (&Snapshot, &Snapshot),
+    paths: &[PathBuf],
+    tip: &Snapshot,
+    reverse: bool,
+    label: Option<&str>,
+) -> Result<Replay> {
+    let mut results = BTreeMap::new();
+    let mut conflicts = Vec::new();
+
+    for path in paths {
+        let (from, to, onto) = (before.read(path)?, after.read(path)?, tip.read(path)?);
+
+        let result = match apply_change(
+            state,
+            path,
+            from.as_deref(),
+            to.as_deref(),
+            onto.as_deref(),
+            reverse,
+        )? {
+            Merged::Contents(c) => Some(c),
+            Merged::Deleted => None,
+            Merged::Conflict => {
+                conflicts.push(path.clone());
+
+                let (base, target) = if reverse { (&to, &from) } else { (&from, &to) };
+
+                /* files deleted on one side or binary are kept as
+                 * they are in the working tree
+                 */
+                match (label, base, target, &onto) {
+                    (Some(label), Some(b), Some(t), Some(o)) => {
+                        match (str::from_utf8(b), str::from_utf8(t), str::from_utf8(o)) {
+                            (Ok(b), Ok(t), Ok(o)) => Some(
+                                merge_with_markers(b, o, t, &format!(">>>>>>> {}", label))
+                                    .into_bytes(),
+                            ),
+                            _ => onto.clone(),
+                        }
+                    }
+                    _ => onto.clone().or(target.clone()),
+                }
+            }
+        };
+
+        results.insert(path.clone(), result);
+    }
+
+    let mut files = tip.files.keys().cloned().collect::<Vec<_>>();
+
+    if !conflicts.is_empty() && label.is_none() {
+        return Ok(Replay { files, conflicts });
+    }
+
+    for (path, contents) in results {
+        match contents {
+            Some(c) => {
+                let full_path = dd.join(&path);
+
+                if let Some(parent) = full_path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+
+                File::create(full_path)?.write_all(&c)?;
+
+                if !files.contains(&path) {
+                    files.push(path);
+                }
+            }
+            None => {
+                remove_file(dd, &path)?;
+                files.retain(|p| *p != path);
+            }
+        }
+    }
+
+    Ok(Replay { files, conflicts })
+}
+
+/// The files among `paths` still holding conflict markers.
+pub(crate) fn unresolved<P: AsRef<Path>>(dd: P, paths: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let dd = dd.as_ref();
+    let mut left = Vec::new();
+
+    for path in paths {
+        let full_path = dd.join(path);
+
+        if !full_path.is_file() {
+            continue;
+        }
+
+        let contents = fs::read(full_path)?;
+
+        if contents
+            .split(|c| *c == b'\n')
+            .any(|l| l.starts_with(b"<<<<<<< ") || l.starts_with(b">>>>>>> "))
+        {
+            left.push(path.clone());
+        }
+    }
+
+    Ok(left)
+}
+
+#[cfg(test)]
+mod test {
+    use tree_sitter::Language;
+
+    use super::{apply_linear_change, apply_tree_change, merge_with_markers};
+
+    #[test]
+    fn revert_tree() {
+        let language: Language = tree_sitter_rust::LANGUAGE.into();
+
+        let from = "fn foo() {\n    1\n}\n\nfn bar() {\n    2\n}\n";
+        let to = "fn foo() {\n    1\n}\n\nfn bar() {\n    let y = 5;\n    2 + y\n}\n";
+        let onto =
+            "fn foo() {\n\tlet x = 0;\n\t1 + x\n}\n\nfn bar() {\n    let y = 5;\n    2 + y\n}\n";
+
+        assert_eq!(
+            apply_tree_change(&language, from, to, onto, true)
+                .unwrap()
+                .unwrap(),
+            "fn foo() {\n\tlet x = 0;\n\t1 + x\n}\n\nfn bar() {\n    2\n}\n"
+        );
+    }
+
+    #[test]
+    fn conflict() {
+        let language: Language = tree_sitter_rust::LANGUAGE.into();
+
+        let from = "fn foo() {\n    1\n}\n";
+        let to = "fn foo() {\n    10\n}\n";
+        let onto = "fn foo() {\n    11\n}\n";
+
+        for reverse in [false, true] {
+            assert!(apply_tree_change(&language, from, to, onto, reverse)
+                .unwrap()
+                .is_none());
+            assert!(apply_linear_change(from, to, onto, reverse).is_none());
+        }
+    }
+
+    #[test]
+    fn markers() {
+        let base = "a\nb\nc\nd\n";
+        let ours = "a\nB\nc\nd\n";
+        let theirs = "a\nb2\nc\nD\n";
+
+        assert_eq!(
+            merge_with_markers(base, ours, theirs, ">>>>>>> x"),
+            "a\n<<<<<<< HEAD\nB\n=======\nb2\n>>>>>>> x\nc\nD\n"
+        );
+    }
+}
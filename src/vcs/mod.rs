@@ -1,13 +1,42 @@
 use core::fmt;
-use std::{error::Error, fmt::Formatter, path::Path};
+use std::{
+    error::Error,
+    fmt::Formatter,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
 
 use anyhow::Result;
 use const_format::concatcp;
+use flate2::{
+    write::{GzDecoder, GzEncoder},
+    Compression,
+};
 
+pub(crate) mod bundle;
+pub(crate) mod checkout;
 pub(crate) mod commit;
 pub(crate) mod config;
+pub(crate) mod date;
 pub(crate) mod diffs;
+pub(crate) mod format;
+pub(crate) mod fsck;
+pub(crate) mod gc;
+pub(crate) mod http;
 pub(crate) mod index;
+pub(crate) mod journal;
+pub(crate) mod lock;
+pub(crate) mod merge;
+pub(crate) mod pack;
+pub(crate) mod pending;
+pub(crate) mod protocol;
+pub(crate) mod reflog;
+pub(crate) mod remote;
+pub(crate) mod revision;
+pub(crate) mod snapshot;
+#[cfg(test)]
+pub(crate) mod testing;
+pub(crate) mod transfer;
 
 pub(crate) const DCG_DIR: &str = ".dcg/";
 pub(crate) const INDEX_DIR: &str = "index/";
@@ -15,9 +44,24 @@ pub(crate) const TREE_DIR: &str = "tree/";
 pub(crate) const LAST_DIR: &str = "last/";
 pub(crate) const BASE_DIR: &str = "base/";
 pub(crate) const BLOBS_DIR: &str = "blobs/";
+pub(crate) const PACKS_DIR: &str = "packs/";
+pub(crate) const LOGS_DIR: &str = "logs/";
 pub(crate) const REFS_DIR: &str = "refs/";
 pub(crate) const BRANCHES_DIR: &str = concatcp!(REFS_DIR, "branches/");
 pub(crate) const TAGS_DIR: &str = concatcp!(REFS_DIR, "tags/");
+pub(crate) const REMOTES_DIR: &str = concatcp!(REFS_DIR, "remotes/");
+pub(crate) const STASH_REF: &str = concatcp!(REFS_DIR, "stash");
+pub(crate) const BISECT_DIR: &str = "bisect/";
+pub(crate) const CHERRY_PICK_DIR: &str = "cherry-pick/";
+pub(crate) const REBASE_DIR: &str = "rebase/";
+pub(crate) const MERGE_DIR: &str = "merge/";
+pub(crate) const REPO_CONFIG: &str = "config.toml";
+pub(crate) const SHALLOW_FILE: &str = "shallow";
+pub(crate) const LOCK_FILE: &str = "lock";
+pub(crate) const JOURNAL_FILE: &str = "journal";
+pub(crate) const TMP_DIR: &str = "tmp/";
+pub(crate) const FORMAT_FILE: &str = "format";
+pub(crate) const LEGACY_DIR: &str = "legacy/";
 
 #[macro_export]
 macro_rules! combine_paths {
@@ -32,6 +76,32 @@ macro_rules! combine_paths {
     }}
 }
 
+/// Decompress the contents of a file stored in the index or in the
+/// revision tree
+pub(crate) fn gz_decode(bytes: &[u8]) -> io::Result<Vec<u8>> {
+    let mut decoder = GzDecoder::new(Vec::new());
+
+    decoder.write_all(bytes)?;
+    decoder.finish()
+}
+
+/// Compress the contents of a file as they are stored in the index or
+/// in the revision tree
+pub(crate) fn gz_encode(bytes: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+
+    encoder.write_all(bytes)?;
+    encoder.finish()
+}
+
+/// Parse the hash stored in the file at `p`, as hexadecimal.
+pub(crate) fn decode_hash(h: &str, p: &Path) -> Result<[u8; 32]> {
+    hex::decode(h.trim())
+        .ok()
+        .and_then(|h| h.try_into().ok())
+        .ok_or_else(|| DcgError::CorruptObject(p.to_path_buf()).into())
+}
+
 /// Find a dcg repository in the file hierarchy
 pub(crate) fn find_repo(start: &Path) -> Result<&Path> {
     if start.join(Path::new(DCG_DIR)).exists() {
@@ -48,11 +118,54 @@ pub(crate) fn find_repo(start: &Path) -> Result<&Path> {
 pub(crate) enum DcgError {
     NoRepository,
     NoAuthor,
+    InvalidIdentity(String),
     NoEditor,
     FailedToWriteMessage,
     NoChanges,
     EmptyTree,
     InvalidCommit,
+    UnknownRevision(String),
+    AmbiguousRevision(String),
+    InvalidDiff,
+    InvalidDate(String),
+    UnknownFormat(String),
+    UnknownSymbol(String),
+    NoSyntaxTree(String),
+    UntrackedFile(String),
+    UncommittedChanges(Vec<PathBuf>),
+    NoBisection,
+    BisectRunFailed(String),
+    MergeConflict(Vec<PathBuf>),
+    InProgress(String),
+    NotInProgress(String),
+    UnresolvedConflicts(Vec<PathBuf>),
+    InvalidTodo(String),
+    NoLocalChanges,
+    NoStashEntry(String),
+    UnknownRemote(String),
+    RemoteExists(String),
+    InvalidRemoteName(String),
+    InvalidRemote(String),
+    NonFastForward(String),
+    CheckedOutBranch(String),
+    DestinationExists(String),
+    InvalidPacket,
+    RemoteError(String),
+    AuthenticationFailed,
+    NoServeToken,
+    NotARef(String),
+    InvalidBundle(String),
+    CorruptBundle(String),
+    MissingPrerequisites(Vec<String>),
+    NotSupported(String),
+    InvalidPack(String),
+    MissingObject(PathBuf),
+    CorruptObject(PathBuf),
+    HashMismatch,
+    DamagedRepository(usize),
+    Locked(PathBuf, i32),
+    UnsupportedFormat(u32),
+    LostContents(PathBuf, String),
 }
 
 impl fmt::Display for DcgError {
@@ -60,11 +173,118 @@ impl fmt::Display for DcgError {
         match self {
             Self::NoRepository => write!(f, "no dcg repository found in the file hierarchy"),
             Self::NoAuthor => write!(f, "no author for commits in configuration"),
+            Self::InvalidIdentity(s) => {
+                write!(f, "invalid identity '{}'. expected 'Name <email>'", s)
+            }
             Self::NoEditor => write!(f, "no commit message and no edit command defined"),
             Self::FailedToWriteMessage => write!(f, "failed to write commit message"),
             Self::NoChanges => write!(f, "no changes to commit. add changes to the index first"),
             Self::EmptyTree => write!(f, "no last commit: commit tree is empty"),
             Self::InvalidCommit => write!(f, "invalid commit"),
+            Self::UnknownRevision(r) => write!(f, "unknown revision '{}'", r),
+            Self::AmbiguousRevision(r) => write!(f, "ambiguous revision '{}'", r),
+            Self::InvalidDiff => write!(f, "stored diff does not apply to its base file"),
+            Self::InvalidDate(d) => write!(f, "invalid date '{}'", d),
+            Self::UnknownFormat(s) => write!(f, "unknown log format '{}'", s),
+            Self::UnknownSymbol(s) => write!(f, "no definition found for '{}'", s),
+            Self::NoSyntaxTree(p) => write!(f, "'{}' is not in a language dcg can parse", p),
+            Self::UntrackedFile(p) => write!(f, "'{}' is not tracked at this revision", p),
+            Self::UncommittedChanges(ps) => write!(
+                f,
+                "uncommitted changes would be overwritten: {}",
+                ps.iter()
+                    .map(|p| p.display().to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            Self::NoBisection => write!(f, "no bisection in progress. use 'dcg bisect start'"),
+            Self::BisectRunFailed(s) => write!(f, "bisect run failed: {}", s),
+            Self::MergeConflict(ps) => write!(
+                f,
+                "conflicting changes to {}",
+                ps.iter()
+                    .map(|p| p.display().to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            Self::InProgress(op) => write!(
+                f,
+                "a {} is in progress. use 'dcg {} --continue' or 'dcg {} --abort'",
+                op, op, op
+            ),
+            Self::NotInProgress(op) => write!(f, "no {} in progress", op),
+            Self::UnresolvedConflicts(ps) => write!(
+                f,
+                "conflict markers left in {}",
+                ps.iter()
+                    .map(|p| p.display().to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            Self::InvalidTodo(s) => write!(f, "invalid rebase todo list: {}", s),
+            Self::NoLocalChanges => write!(f, "no local changes to save"),
+            Self::NoStashEntry(s) => write!(f, "no stash entry '{}'", s),
+            Self::UnknownRemote(r) => write!(f, "no remote named '{}'", r),
+            Self::RemoteExists(r) => write!(f, "remote '{}' already exists", r),
+            Self::InvalidRemoteName(r) => write!(f, "invalid remote name '{}'", r),
+            Self::InvalidRemote(u) => write!(f, "'{}' is not a dcg repository or bundle", u),
+            Self::NonFastForward(b) => write!(
+                f,
+                "the remote branch '{}' has commits missing from the local one. pull them first, or push with '--force'",
+                b
+            ),
+            Self::CheckedOutBranch(b) => write!(
+                f,
+                "'{}' is checked out in the remote repository, which has uncommitted changes",
+                b
+            ),
+            Self::DestinationExists(d) => {
+                write!(f, "destination '{}' already exists and is not empty", d)
+            }
+            Self::InvalidPacket => write!(f, "invalid data received from the remote repository"),
+            Self::RemoteError(e) => write!(f, "remote: {}", e),
+            Self::AuthenticationFailed => write!(f, "authentication failed"),
+            Self::NoServeToken => write!(
+                f,
+                "no token to authenticate clients. use '--token' or set 'serve.token'"
+            ),
+            Self::NotARef(r) => write!(f, "'{}' is not a branch or a tag", r),
+            Self::InvalidBundle(p) => write!(f, "'{}' is not a dcg bundle", p),
+            Self::CorruptBundle(p) => {
+                write!(f, "bundle '{}' is corrupt: its checksum does not match", p)
+            }
+            Self::MissingPrerequisites(hs) => write!(
+                f,
+                "the bundle needs commits missing from the repository: {}",
+                hs.join(", ")
+            ),
+            Self::NotSupported(s) => write!(f, "{} is not supported", s),
+            Self::InvalidPack(p) => write!(f, "invalid pack '{}'", p),
+            Self::MissingObject(p) => {
+                write!(f, "object '{}' is missing from the repository", p.display())
+            }
+            Self::CorruptObject(p) => write!(f, "object '{}' is corrupt", p.display()),
+            Self::HashMismatch => write!(f, "the contents do not match their hash"),
+            Self::DamagedRepository(n) => {
+                write!(f, "{} corrupt or missing objects in the repository", n)
+            }
+            Self::Locked(p, pid) => write!(
+                f,
+                "the repository is locked by process {}: remove '{}' if it is not running",
+                pid,
+                p.display()
+            ),
+            Self::UnsupportedFormat(v) => write!(
+                f,
+                "the repository was written by a newer version of dcg (format {})",
+                v
+            ),
+            Self::LostContents(p, h) => write!(
+                f,
+                "the contents of '{}' at commit {} were not kept by the version of dcg which wrote it",
+                p.display(),
+                h
+            ),
         }
     }
 }
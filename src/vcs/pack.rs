@@ -0,0 +1,459 @@
+//! Pack files: the objects of a repository gathered in a single file
+//!
+//! Objects are the files of `tree/`, `base/` and `blobs/`, named by
+//! their path relative to `.dcg/`, which holds the hash of their commit
+//! or of their contents.  `dcg repack` moves them to
+//! `packs/pack-<hash>.pack`, where their contents are laid one after
+//! the other, and lists them in `packs/pack-<hash>.idx`, which maps
+//! their names to their offset and length in the pack.  Objects are
+//! looked up in the loose files first, then in the packs.
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ffi::{OsStr, OsString},
+    fs::{self, File},
+    io::{self, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write},
+    os::unix::ffi::OsStrExt,
+    path::{Component, Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use anyhow::Result;
+use sha2::{Digest, Sha256};
+
+use crate::{combine_paths, commands::visit_dirs};
+
+use super::{DcgError, BASE_DIR, BLOBS_DIR, DCG_DIR, PACKS_DIR, TREE_DIR};
+
+const PACK_MAGIC: &[u8] = b"dcg pack 1\n";
+const INDEX_MAGIC: &[u8] = b"dcg pack index 1\n";
+
+/// A pack, with the offset and the length of each of its objects.
+pub(crate) struct Pack {
+    pub(crate) path: PathBuf,
+    pub(crate) objects: BTreeMap<PathBuf, (u64, u64)>,
+}
+
+fn read_u64(r: &mut &[u8]) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    r.read_exact(&mut bytes)?;
+
+    Ok(u64::from_le_bytes(bytes))
+}
+
+/// The objects listed in an index, without its header.
+fn read_index(mut r: &[u8]) -> io::Result<BTreeMap<PathBuf, (u64, u64)>> {
+    let mut objects = BTreeMap::new();
+
+    for _ in 0..read_u64(&mut r)? {
+        let mut len = [0; 4];
+        r.read_exact(&mut len)?;
+
+        let len = u32::from_le_bytes(len) as usize;
+
+        if len > r.len() {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+
+        let (name, rest) = r.split_at(len);
+        r = rest;
+
+        let name = PathBuf::from(OsStr::from_bytes(name));
+        let offset = read_u64(&mut r)?;
+        let length = read_u64(&mut r)?;
+
+        objects.insert(name, (offset, length));
+    }
+
+    Ok(objects)
+}
+
+impl Pack {
+    /// Read the index of the pack at `path`.
+    fn open(path: &Path) -> Result<Self> {
+        let index = fs::read(path.with_extension("idx"))?;
+
+        let objects = index
+            .strip_prefix(INDEX_MAGIC)
+            .and_then(|r| read_index(r).ok())
+            .ok_or(DcgError::InvalidPack(path.display().to_string()))?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            objects,
+        })
+    }
+
+    /// Read the contents of `object`, which must be in the pack and lie
+    /// within it.
+    pub(crate) fn read(&self, object: &Path) -> Result<Vec<u8>> {
+        let &(offset, length) = self
+            .objects
+            .get(object)
+            .ok_or_else(|| DcgError::MissingObject(object.to_path_buf()))?;
+
+        let mut f = File::open(&self.path)?;
+        let size = f.metadata()?.len();
+
+        /* the index may be corrupt: the length is not trusted as is */
+        if offset.checked_add(length).is_none_or(|end| end > size) {
+            return Err(DcgError::InvalidPack(self.path.display().to_string()).into());
+        }
+
+        let mut contents = vec![0; length as usize];
+
+        f.seek(SeekFrom::Start(offset))?;
+        f.read_exact(&mut contents)?;
+
+        Ok(contents)
+    }
+}
+
+/// The packs of each repository, with the time their directory was
+/// last modified when they were read.
+type PackCache = BTreeMap<PathBuf, (SystemTime, Arc<Vec<Pack>>)>;
+
+static PACKS: Mutex<PackCache> = Mutex::new(BTreeMap::new());
+
+/// The packs of the repository `dd`.  They are read once, until the
+/// packs directory changes.
+pub(crate) fn packs(dd: &Path) -> Result<Arc<Vec<Pack>>> {
+    let dir = combine_paths!(dd, DCG_DIR, PACKS_DIR);
+
+    let Ok(modified) = fs::metadata(&dir).and_then(|m| m.modified()) else {
+        return Ok(Arc::new(Vec::new()));
+    };
+
+    let mut cache = PACKS
+        .lock()
+        .map_err(|_| DcgError::InvalidPack(dir.display().to_string()))?;
+
+    if let Some((time, packs)) = cache.get(&dir) {
+        if *time == modified {
+            return Ok(packs.clone());
+        }
+    }
+
+    let mut packs = Vec::new();
+
+    for entry in fs::read_dir(&dir)? {
+        let path = entry?.path();
+
+        if path.extension() == Some(OsStr::new("pack")) {
+            packs.push(Pack::open(&path)?);
+        }
+    }
+
+    let packs = Arc::new(packs);
+    cache.insert(dir, (modified, packs.clone()));
+
+    Ok(packs)
+}
+
+/// Whether `object`, relative to `.dcg/`, is stored in the repository
+/// `dd`, loose or packed.
+pub(crate) fn has_object(dd: &Path, object: &Path) -> bool {
+    combine_paths!(dd, DCG_DIR, object).is_file()
+        || packs(dd).is_ok_and(|packs| packs.iter().any(|p| p.objects.contains_key(object)))
+}
+
+/// Read `object`, relative to `.dcg/`, from the loose files or the
+/// packs of the repository `dd`.
+pub(crate) fn read_object(dd: &Path, object: &Path) -> Result<Vec<u8>> {
+    let loose = combine_paths!(dd, DCG_DIR, object);
+
+    if loose.is_file() {
+        return Ok(fs::read(loose)?);
+    }
+
+    for pack in packs(dd)?.iter() {
+        if pack.objects.contains_key(object) {
+            return pack.read(object);
+        }
+    }
+
+    Err(DcgError::MissingObject(object.to_path_buf()).into())
+}
+
+/// The objects under `dir`, relative to `.dcg/`, loose or packed.
+pub(crate) fn list_objects(dd: &Path, dir: &Path) -> Result<BTreeSet<PathBuf>> {
+    let store = combine_paths!(dd, DCG_DIR);
+    let mut objects = BTreeSet::new();
+
+    visit_dirs(&store.join(dir), &mut |p| {
+        objects.insert(p.strip_prefix(&store)?.to_path_buf());
+
+        Ok(())
+    })?;
+
+    for pack in packs(dd)?.iter() {
+        objects.extend(
+            pack.objects
+                .range(dir.to_path_buf()..)
+                .take_while(|(o, _)| o.starts_with(dir))
+                .map(|(o, _)| o.clone()),
+        );
+    }
+
+    Ok(objects)
+}
+
+/// The names of the files and directories in `dir`, relative to
+/// `.dcg/`, loose or packed.
+pub(crate) fn list_children(dd: &Path, dir: &Path) -> Result<BTreeSet<OsString>> {
+    let mut children = BTreeSet::new();
+
+    if let Ok(entries) = fs::read_dir(combine_paths!(dd, DCG_DIR, dir)) {
+        for entry in entries {
+            children.insert(entry?.file_name());
+        }
+    }
+
+    for pack in packs(dd)?.iter() {
+        children.extend(
+            pack.objects
+                .range(dir.to_path_buf()..)
+                .take_while(|(o, _)| o.starts_with(dir))
+                .filter_map(|(o, _)| o.strip_prefix(dir).ok()?.iter().next())
+                .map(OsStr::to_os_string),
+        );
+    }
+
+    Ok(children)
+}
+
+/// The loose objects of the repository `dd`, relative to `.dcg/`.
+/// Commits being received are left out.
+pub(crate) fn loose_objects(dd: &Path) -> Result<BTreeSet<PathBuf>> {
+    let store = combine_paths!(dd, DCG_DIR);
+    let mut objects = BTreeSet::new();
+
+    for dir in [TREE_DIR, BASE_DIR, BLOBS_DIR] {
+        visit_dirs(&store.join(dir), &mut |p| {
+            let object = p.strip_prefix(&store)?;
+
+            let partial = object.starts_with(TREE_DIR)
+                && matches!(
+                    object.components().nth(1),
+                    Some(Component::Normal(c)) if c.len() != 2
+                );
+
+            if !partial {
+                objects.insert(object.to_path_buf());
+            }
+
+            Ok(())
+        })?;
+    }
+
+    Ok(objects)
+}
+
+/// Remove the directories left empty under `dir`, but not `dir` itself.
+fn remove_empty_dirs(dir: &Path) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+
+        if path.is_dir() {
+            remove_empty_dirs(&path)?;
+
+            if fs::read_dir(&path)?.next().is_none() {
+                fs::remove_dir(&path)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Write the objects of the repository `dd` for which `keep` is true
+/// to a single pack, replacing all the existing packs and the loose
+/// objects, which are deleted.  Returns the number of objects packed.
+pub(crate) fn write_pack<F: Fn(&Path) -> bool>(dd: &Path, keep: F) -> Result<usize> {
+    let store = combine_paths!(dd, DCG_DIR);
+    let dir = store.join(PACKS_DIR);
+
+    fs::create_dir_all(&dir)?;
+
+    let loose = loose_objects(dd)?;
+    let old = packs(dd)?;
+
+    let mut names = loose.iter().cloned().collect::<BTreeSet<_>>();
+
+    for pack in old.iter() {
+        names.extend(pack.objects.keys().cloned());
+    }
+
+    names.retain(|o| keep(o));
+
+    /* the pack is written aside, and named after its contents */
+    let tmp = dir.join("tmp-pack");
+    let mut w = BufWriter::new(File::create(&tmp)?);
+    let mut hasher = Sha256::new();
+    let mut objects = BTreeMap::new();
+    let mut offset = PACK_MAGIC.len() as u64;
+
+    w.write_all(PACK_MAGIC)?;
+    hasher.update(PACK_MAGIC);
+
+    for name in &names {
+        let contents = read_object(dd, name)?;
+
+        w.write_all(&contents)?;
+        hasher.update(&contents);
+
+        objects.insert(name.clone(), (offset, contents.len() as u64));
+        offset += contents.len() as u64;
+    }
+
+    w.into_inner()?.sync_all()?;
+
+    let mut index = INDEX_MAGIC.to_vec();
+
+    index.extend((objects.len() as u64).to_le_bytes());
+
+    for (name, (offset, length)) in &objects {
+        let name = name.as_os_str().as_bytes();
+
+        index.extend((name.len() as u32).to_le_bytes());
+        index.extend(name);
+        index.extend(offset.to_le_bytes());
+        index.extend(length.to_le_bytes());
+    }
+
+    let pack = dir.join(format!("pack-{}.pack", hex::encode(hasher.finalize())));
+
+    /* a pack is only looked up once its index is in place */
+    fs::write(dir.join("tmp-idx"), index)?;
+    fs::rename(&tmp, &pack)?;
+    fs::rename(dir.join("tmp-idx"), pack.with_extension("idx"))?;
+
+    for p in old.iter().filter(|p| p.path != pack) {
+        fs::remove_file(&p.path)?;
+        fs::remove_file(p.path.with_extension("idx"))?;
+    }
+
+    for object in &loose {
+        fs::remove_file(store.join(object))?;
+    }
+
+    for d in [TREE_DIR, BASE_DIR, BLOBS_DIR] {
+        remove_empty_dirs(&store.join(d))?;
+    }
+
+    Ok(objects.len())
+}
+
+/// Pack all the objects of the repository `dd`, as `write_pack` does.
+pub(crate) fn repack(dd: &Path) -> Result<usize> {
+    write_pack(dd, |_| true)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::BTreeMap,
+        ffi::OsStr,
+        fs,
+        path::{Path, PathBuf},
+    };
+
+    use mktemp::Temp;
+
+    use super::{has_object, list_children, loose_objects, repack, Pack};
+    use crate::vcs::{
+        commit::{get_parents, CommitObject},
+        revision::resolve_revision,
+        snapshot::Snapshot,
+        testing::{commit, repo},
+        transfer::has_commit,
+    };
+
+    #[test]
+    fn read_packed() {
+        let dd = repo();
+
+        let first = commit(&dd, "master", &[("a.txt", "a\n"), ("b.bin", "\0\x01\x02")]);
+        let second = commit(&dd, "master", &[("src/c.txt", "c\n")]);
+
+        assert!(repack(&dd).unwrap() > 0);
+        assert!(loose_objects(&dd).unwrap().is_empty());
+
+        assert!(has_commit(&dd, first) && has_commit(&dd, second));
+        assert_eq!(get_parents(&dd, second).unwrap(), vec![first]);
+        assert_eq!(CommitObject::read(&dd, second).unwrap().changes.len(), 1);
+        assert_eq!(
+            resolve_revision(&dd, &hex::encode(second)[..8]).unwrap(),
+            second
+        );
+        assert!(list_children(&dd, Path::new("tree"))
+            .unwrap()
+            .contains(OsStr::new(&hex::encode(&second[..1]))));
+
+        let snapshot = Snapshot::at(&dd, Some(second)).unwrap();
+
+        assert_eq!(
+            snapshot.read(Path::new("b.bin")).unwrap(),
+            Some(b"\0\x01\x02".to_vec())
+        );
+        assert_eq!(
+            snapshot.read(Path::new("src/c.txt")).unwrap(),
+            Some(b"c\n".to_vec())
+        );
+    }
+
+    #[test]
+    fn read_corrupt_index() {
+        let file = Temp::new_file().unwrap();
+
+        fs::write(&file, b"pack").unwrap();
+
+        let pack = Pack {
+            path: file.to_path_buf(),
+            objects: BTreeMap::from([
+                (PathBuf::from("inside"), (1, 3)),
+                (PathBuf::from("past-the-end"), (2, 3)),
+                (PathBuf::from("huge"), (0, u64::MAX)),
+                (PathBuf::from("overflowing"), (u64::MAX, 1)),
+            ]),
+        };
+
+        assert_eq!(pack.read(Path::new("inside")).unwrap(), b"ack");
+
+        for object in ["past-the-end", "huge", "overflowing", "unlisted"] {
+            assert!(pack.read(Path::new(object)).is_err());
+        }
+    }
+
+    #[test]
+    fn repack_twice() {
+        let dd = repo();
+
+        let first = commit(&dd, "master", &[("a.txt", "a\n")]);
+        repack(&dd).unwrap();
+
+        /* the new commit is loose, the first one packed */
+        let second = commit(&dd, "master", &[("b.txt", "b\n")]);
+
+        assert!(!loose_objects(&dd).unwrap().is_empty());
+        assert_eq!(get_parents(&dd, second).unwrap(), vec![first]);
+
+        repack(&dd).unwrap();
+
+        assert!(loose_objects(&dd).unwrap().is_empty());
+        assert!(has_object(
+            &dd,
+            &Path::new("tree")
+                .join(hex::encode(&first[..1]))
+                .join(hex::encode(&first[1..]))
+                .join("message")
+        ));
+        assert_eq!(
+            Snapshot::at(&dd, Some(second))
+                .unwrap()
+                .read(Path::new("a.txt"))
+                .unwrap(),
+            Some(b"a\n".to_vec())
+        );
+    }
+}
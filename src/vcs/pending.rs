@@ -0,0 +1,199 @@
+//! Operations stopped by conflicts
+//!
+//! A cherry-pick, a pull or a rebase which changes conflict stops, and
+//! keeps what it needs to go on in `.dcg/<operation>/`: the files to
+//! commit, the ones with conflicts, and fields of its own.  It is
+//! resumed once the conflicts are resolved, or aborted.
+use std::{
+    ffi::OsStr,
+    fs::{self, File},
+    io::Write,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+
+use crate::combine_paths;
+
+use super::{
+    checkout::restore,
+    commit::{fetch_head, get_branch},
+    merge::unresolved,
+    revision::read_hash,
+    snapshot::Snapshot,
+    DcgError, DCG_DIR,
+};
+
+pub(crate) struct Pending {
+    /// the operation, such as `cherry-pick`
+    name: &'static str,
+    dir: PathBuf,
+    /// the files to commit once the conflicts are resolved
+    pub(crate) files: Vec<PathBuf>,
+    pub(crate) conflicts: Vec<PathBuf>,
+}
+
+impl Pending {
+    /// The operation `name`, kept in `.dcg/<dir>/`, with no file to
+    /// commit yet.  Nothing is written until `write`.
+    pub(crate) fn new(dd: &Path, name: &'static str, dir: &str) -> Self {
+        Self {
+            name,
+            dir: combine_paths!(dd, DCG_DIR, dir),
+            files: Vec::new(),
+            conflicts: Vec::new(),
+        }
+    }
+
+    /// Fail if the operation `name`, kept in `.dcg/<dir>/`, is stopped.
+    pub(crate) fn ensure_none(dd: &Path, name: &'static str, dir: &str) -> Result<()> {
+        let pending = Self::new(dd, name, dir);
+
+        if pending.dir.is_dir() {
+            return Err(DcgError::InProgress(pending.name.to_string()).into());
+        }
+
+        Ok(())
+    }
+
+    /// Read the operation `name` kept in `.dcg/<dir>/`.
+    pub(crate) fn read(dd: &Path, name: &'static str, dir: &str) -> Result<Self> {
+        let mut pending = Self::new(dd, name, dir);
+
+        if !pending.dir.is_dir() {
+            return Err(DcgError::NotInProgress(pending.name.to_string()).into());
+        }
+
+        pending.files = pending.read_paths("files")?;
+        pending.conflicts = pending.read_paths("conflicts")?;
+
+        Ok(pending)
+    }
+
+    /// Write the files to commit and the ones with conflicts.
+    pub(crate) fn write(&self) -> Result<()> {
+        fs::create_dir_all(&self.dir)?;
+
+        self.write_paths("files", &self.files)?;
+        self.write_paths("conflicts", &self.conflicts)
+    }
+
+    pub(crate) fn read_hash(&self, field: &str) -> Result<[u8; 32]> {
+        read_hash(&self.dir.join(field))
+    }
+
+    pub(crate) fn read_string(&self, field: &str) -> Result<String> {
+        Ok(fs::read_to_string(self.dir.join(field))?)
+    }
+
+    pub(crate) fn read_paths(&self, field: &str) -> Result<Vec<PathBuf>> {
+        Ok(fs::read(self.dir.join(field))?
+            .split(|c| *c == b'\n')
+            .filter(|l| !l.is_empty())
+            .map(|l| PathBuf::from(OsStr::from_bytes(l)))
+            .collect())
+    }
+
+    pub(crate) fn write_field<C: AsRef<[u8]>>(&self, field: &str, contents: C) -> Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        fs::write(self.dir.join(field), contents)?;
+
+        Ok(())
+    }
+
+    pub(crate) fn write_paths(&self, field: &str, paths: &[PathBuf]) -> Result<()> {
+        fs::create_dir_all(&self.dir)?;
+
+        let mut f = File::create(self.dir.join(field))?;
+
+        for path in paths {
+            f.write_all(path.as_os_str().as_bytes())?;
+            f.write_all(b"\n")?;
+        }
+
+        Ok(())
+    }
+
+    /// The files to commit, once every conflict is resolved.
+    pub(crate) fn resolved_files(&self, dd: &Path) -> Result<Vec<PathBuf>> {
+        let left = unresolved(dd, &self.conflicts)?;
+
+        if !left.is_empty() {
+            return Err(DcgError::UnresolvedConflicts(left).into());
+        }
+
+        /* conflicting files may have been resolved by removing them */
+        Ok(self
+            .files
+            .iter()
+            .filter(|p| dd.join(p).is_file())
+            .cloned()
+            .collect())
+    }
+
+    /// Forget the operation, once it is done with.
+    pub(crate) fn finish(self) -> Result<()> {
+        fs::remove_dir_all(&self.dir)?;
+
+        Ok(())
+    }
+
+    /// Give up the operation, restoring `paths` as they are at the head
+    /// of the current branch.
+    pub(crate) fn abort(self, dd: &Path, paths: &[PathBuf]) -> Result<()> {
+        let tip = Snapshot::at(dd, fetch_head(dd, &get_branch(dd)?)?)?;
+
+        restore(dd, &tip, paths)?;
+
+        self.finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, path::PathBuf};
+
+    use super::Pending;
+    use crate::vcs::{testing::repo, DcgError, CHERRY_PICK_DIR};
+
+    #[test]
+    fn stop_and_resume() {
+        let dd = repo();
+        let paths = |s: &[&str]| s.iter().map(PathBuf::from).collect::<Vec<_>>();
+
+        assert!(Pending::read(&dd, "cherry-pick", CHERRY_PICK_DIR).is_err());
+
+        let mut pending = Pending::new(&dd, "cherry-pick", CHERRY_PICK_DIR);
+
+        pending.files = paths(&["a.txt", "b.txt", "c.txt"]);
+        pending.conflicts = paths(&["a.txt", "b.txt"]);
+        pending.write().unwrap();
+        pending.write_field("commit", hex::encode([7; 32])).unwrap();
+
+        assert!(Pending::ensure_none(&dd, "cherry-pick", CHERRY_PICK_DIR).is_err());
+
+        fs::write(dd.join("a.txt"), "<<<<<<< HEAD\na\n=======\nA\n>>>>>>> x\n").unwrap();
+        fs::write(dd.join("c.txt"), "c\n").unwrap();
+
+        let pending = Pending::read(&dd, "cherry-pick", CHERRY_PICK_DIR).unwrap();
+
+        assert_eq!(pending.read_hash("commit").unwrap(), [7; 32]);
+        assert!(matches!(
+            pending.resolved_files(&dd).unwrap_err().downcast_ref::<DcgError>(),
+            Some(DcgError::UnresolvedConflicts(ps)) if ps == &paths(&["a.txt"])
+        ));
+
+        /* b.txt was resolved by removing it */
+        fs::write(dd.join("a.txt"), "a\nA\n").unwrap();
+
+        assert_eq!(
+            pending.resolved_files(&dd).unwrap(),
+            paths(&["a.txt", "c.txt"])
+        );
+
+        pending.finish().unwrap();
+
+        assert!(Pending::ensure_none(&dd, "cherry-pick", CHERRY_PICK_DIR).is_ok());
+    }
+}
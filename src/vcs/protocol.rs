@@ -0,0 +1,563 @@
+//! The dcg protocol, exchanging history over a stream: a TCP connection
+//! to `dcg serve`, or the standard input and output of `dcg serve-pack`
+//!
+//! Both sides exchange packets (see `transfer::write_packet`), most of
+//! them holding a line of text.  Lists of lines end with an empty
+//! packet.  The client opens with the protocol version and a token,
+//! which the server answers with `ok` and the refs of the repository,
+//! or with `error <message>`.  Then the client sends commands:
+//!
+//! - `fetch <depth>`, followed by the lists of the commits it wants and
+//!   of the heads of its own history.  The server answers with the
+//!   missing commits, as written by `transfer::write_commits`.  A depth
+//!   of 0 stands for the whole history.
+//! - `push <branch> <commit> <force>`, followed by the commits the
+//!   server misses.  The server answers with `ok` once the branch is
+//!   updated, or with `error <message>`.
+//! - `quit`, which ends the session, as does the end of the stream.
+use std::{
+    collections::BTreeSet,
+    env,
+    io::{BufReader, BufWriter, Read, Write},
+    net::TcpStream,
+    path::Path,
+    process::{Child, Command, Stdio},
+};
+
+use anyhow::Result;
+use sha2::{Digest, Sha256};
+
+use super::{
+    lock::Lock,
+    remote::{local_heads, update_remote_branch, Refs, RemoteRepository},
+    transfer::{
+        commits_to_send, has_commit, missing_commits, read_commits, read_line, write_commits,
+        write_packet,
+    },
+    DcgError,
+};
+
+/// The port `dcg serve` listens on by default.
+pub(crate) const DEFAULT_PORT: u16 = 9419;
+
+const VERSION: &str = "dcg-protocol 1";
+
+/// Whether `sent` is `token`, in a time which does not depend on how
+/// much of it is right.  Both are hashed first, so that neither does
+/// the time depend on their lengths.
+pub(crate) fn token_matches(sent: &str, token: &str) -> bool {
+    let (sent, token) = (Sha256::digest(sent), Sha256::digest(token));
+
+    sent.iter()
+        .zip(token.iter())
+        .fold(0, |acc, (a, b)| acc | (a ^ b))
+        == 0
+}
+
+fn write_line<W: Write>(w: &mut W, line: &str) -> Result<()> {
+    write_packet(w, line.as_bytes())
+}
+
+pub(crate) fn write_list<W: Write>(w: &mut W, lines: &[String]) -> Result<()> {
+    for line in lines {
+        write_line(w, line)?;
+    }
+
+    write_packet(w, &[])
+}
+
+pub(crate) fn read_list<R: Read>(r: &mut R) -> Result<Vec<String>> {
+    let mut lines = Vec::new();
+
+    loop {
+        match read_line(r)? {
+            line if line.is_empty() => return Ok(lines),
+            line => lines.push(line),
+        }
+    }
+}
+
+pub(crate) fn parse_hash(s: &str) -> Result<[u8; 32]> {
+    hex::decode(s)
+        .ok()
+        .and_then(|h| h.try_into().ok())
+        .ok_or(DcgError::InvalidPacket.into())
+}
+
+/// Whether `name` can name a branch or a tag: it must be usable as a
+/// file name.
+fn valid_ref_name(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".." && !name.contains('/')
+}
+
+/// An `ok` line, or the error sent instead.
+pub(crate) fn read_status<R: Read>(r: &mut R) -> Result<()> {
+    let line = read_line(r)?;
+
+    match line.strip_prefix("error ") {
+        Some(e) => Err(DcgError::RemoteError(e.to_string()).into()),
+        None if line == "ok" => Ok(()),
+        None => Err(DcgError::InvalidPacket.into()),
+    }
+}
+
+/// The commits of the repository `dd` reachable from the ones of
+/// `heads` it has.
+fn common_history(dd: &Path, heads: &[[u8; 32]]) -> Result<BTreeSet<[u8; 32]>> {
+    let known = heads
+        .iter()
+        .copied()
+        .filter(|h| has_commit(dd, *h))
+        .collect::<Vec<_>>();
+
+    Ok(missing_commits(dd, &known, |_| false)?
+        .into_iter()
+        .collect())
+}
+
+/// Write `refs`, as a list.  A repository without a current branch,
+/// such as a bundle of tags, has an empty `head`.
+pub(crate) fn write_refs<W: Write>(w: &mut W, refs: &Refs) -> Result<()> {
+    let mut advertised = Vec::new();
+
+    if !refs.head.is_empty() {
+        advertised.push(format!("head {}", refs.head));
+    }
+
+    advertised.extend(
+        refs.branches
+            .iter()
+            .map(|(b, h)| format!("branch {} {}", b, hex::encode(h))),
+    );
+    advertised.extend(
+        refs.tags
+            .iter()
+            .map(|(t, h)| format!("tag {} {}", t, hex::encode(h))),
+    );
+
+    write_list(w, &advertised)
+}
+
+/// Write the refs of the repository `dd`, as a list.
+pub(crate) fn advertise_refs<W: Write>(dd: &Path, w: &mut W) -> Result<()> {
+    write_refs(w, &Refs::of(dd)?)
+}
+
+/// Read the refs written by `write_refs`.
+pub(crate) fn read_refs<R: Read>(r: &mut R) -> Result<Refs> {
+    let mut refs = Refs::default();
+
+    for line in read_list(r)? {
+        match line.split(' ').collect::<Vec<_>>()[..] {
+            ["head", b] if valid_ref_name(b) => refs.head = b.to_string(),
+            ["branch", b, h] if valid_ref_name(b) => {
+                refs.branches.insert(b.to_string(), parse_hash(h)?);
+            }
+            ["tag", t, h] if valid_ref_name(t) => {
+                refs.tags.insert(t.to_string(), parse_hash(h)?);
+            }
+            _ => return Err(DcgError::InvalidPacket.into()),
+        }
+    }
+
+    Ok(refs)
+}
+
+/// Answer the request `command`, a `fetch` or a `push` whose first line
+/// was read from `r`, on the repository `dd`.
+pub(crate) fn serve_request<R: Read, W: Write>(
+    dd: &Path,
+    command: &str,
+    r: &mut R,
+    w: &mut W,
+) -> Result<()> {
+    match command.split(' ').collect::<Vec<_>>()[..] {
+        ["fetch", depth] => {
+            let depth = depth
+                .parse::<usize>()
+                .map_err(|_| DcgError::InvalidPacket)?;
+            let wants = read_list(r)?
+                .iter()
+                .map(|h| parse_hash(h))
+                .collect::<Result<Vec<_>>>()?;
+            let haves = read_list(r)?
+                .iter()
+                .map(|h| parse_hash(h))
+                .collect::<Result<Vec<_>>>()?;
+
+            if let Some(h) = wants.iter().find(|h| !has_commit(dd, **h)) {
+                return write_line(w, &format!("error unknown commit {}", hex::encode(h)));
+            }
+
+            let common = common_history(dd, &haves)?;
+            let history = commits_to_send(dd, &wants, Some(depth).filter(|d| *d > 0), |h| {
+                common.contains(&h)
+            })?;
+
+            write_commits(dd, &history, w)
+        }
+        ["push", branch, h, force] => {
+            let h = parse_hash(h)?;
+
+            if !valid_ref_name(branch) {
+                return Err(DcgError::InvalidPacket.into());
+            }
+
+            let _lock = Lock::acquire(dd)?;
+
+            read_commits(dd, r)?;
+
+            match update_remote_branch(dd, branch, h, force == "1") {
+                Ok(()) => write_line(w, "ok"),
+                Err(e) => write_line(w, &format!("error {}", e)),
+            }
+        }
+        _ => Err(DcgError::InvalidPacket.into()),
+    }
+}
+
+/// Write a request for the commits reachable from `wants` which the
+/// repository `dd` misses, to be answered by `serve_request`.
+pub(crate) fn write_fetch<W: Write>(
+    dd: &Path,
+    wants: &[[u8; 32]],
+    depth: Option<usize>,
+    w: &mut W,
+) -> Result<()> {
+    write_line(w, &format!("fetch {}", depth.unwrap_or(0)))?;
+    write_list(w, &wants.iter().map(hex::encode).collect::<Vec<_>>())?;
+    write_list(
+        w,
+        &local_heads(dd)?.iter().map(hex::encode).collect::<Vec<_>>(),
+    )
+}
+
+/// Write a request making `h` the head of `branch` on a repository
+/// with `refs`, with the commits of the repository `dd` it misses, to
+/// be answered by `serve_request`.
+pub(crate) fn write_push<W: Write>(
+    dd: &Path,
+    refs: &Refs,
+    (branch, h, force): (&str, [u8; 32], bool),
+    w: &mut W,
+) -> Result<()> {
+    let heads = refs
+        .branches
+        .values()
+        .chain(refs.tags.values())
+        .copied()
+        .collect::<Vec<_>>();
+    let common = common_history(dd, &heads)?;
+
+    write_line(
+        w,
+        &format!(
+            "push {} {} {}",
+            branch,
+            hex::encode(h),
+            if force { 1 } else { 0 }
+        ),
+    )?;
+    write_commits(
+        dd,
+        &(
+            missing_commits(dd, &[h], |c| common.contains(&c))?,
+            Vec::new(),
+        ),
+        w,
+    )
+}
+
+/// The server side of a session on the repository `dd`.  If `token` is
+/// given, clients must send the same one.
+pub(crate) fn serve_session<R: Read, W: Write>(
+    dd: &Path,
+    r: &mut R,
+    w: &mut W,
+    token: Option<&str>,
+) -> Result<()> {
+    if read_line(r)? != VERSION {
+        write_line(w, "error unsupported protocol version")?;
+        w.flush()?;
+
+        return Err(DcgError::InvalidPacket.into());
+    }
+
+    let sent = read_line(r)?;
+
+    if token.is_some_and(|t| !token_matches(sent.strip_prefix("token ").unwrap_or_default(), t)) {
+        write_line(w, "error authentication failed")?;
+        w.flush()?;
+
+        return Err(DcgError::AuthenticationFailed.into());
+    }
+
+    write_line(w, "ok")?;
+    advertise_refs(dd, w)?;
+    w.flush()?;
+
+    loop {
+        /* the client may leave without a word */
+        let Ok(command) = read_line(r) else {
+            return Ok(());
+        };
+
+        if command == "quit" {
+            return Ok(());
+        }
+
+        serve_request(dd, &command, r, w)?;
+        w.flush()?;
+    }
+}
+
+/// A session with a repository served by `dcg serve` or `dcg
+/// serve-pack`.
+pub(crate) struct Connection {
+    reader: BufReader<Box<dyn Read>>,
+    writer: BufWriter<Box<dyn Write>>,
+    /// the process running `dcg serve-pack`, if any
+    child: Option<Child>,
+    refs: Refs,
+}
+
+impl Connection {
+    fn open(
+        reader: Box<dyn Read>,
+        writer: Box<dyn Write>,
+        child: Option<Child>,
+        token: Option<&str>,
+    ) -> Result<Self> {
+        let mut reader = BufReader::new(reader);
+        let mut writer = BufWriter::new(writer);
+
+        write_line(&mut writer, VERSION)?;
+        write_line(&mut writer, &format!("token {}", token.unwrap_or_default()))?;
+        writer.flush()?;
+
+        read_status(&mut reader)?;
+
+        let refs = read_refs(&mut reader)?;
+
+        Ok(Self {
+            reader,
+            writer,
+            child,
+            refs,
+        })
+    }
+
+    /// Connect to `dcg serve` at `address`, as `host:port`.
+    pub(crate) fn tcp(address: &str, token: Option<&str>) -> Result<Self> {
+        let stream = TcpStream::connect(address)?;
+
+        Self::open(Box::new(stream.try_clone()?), Box::new(stream), None, token)
+    }
+
+    /// Run `dcg serve-pack` on the repository at `path` on `host`,
+    /// through `ssh` or the command given by `DCG_SSH`.  `host` may end
+    /// with a port.
+    pub(crate) fn ssh(host: &str, path: &str) -> Result<Self> {
+        let ssh = env::var("DCG_SSH").unwrap_or("ssh".to_string());
+        let mut words = ssh.split_whitespace();
+        let mut command = Command::new(words.next().unwrap_or("ssh"));
+
+        command.args(words);
+
+        /* `--` keeps a host starting with `-` from being read as an option */
+        match host.rsplit_once(':') {
+            Some((host, port)) => command.args(["-p", port, "--", host]),
+            None => command.args(["--", host]),
+        };
+
+        /* the remote shell splits the command, so the path is quoted */
+        let mut child = command
+            .arg(format!("dcg serve-pack {}", shell_quote(path)))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+
+        let reader = child.stdout.take().ok_or(DcgError::InvalidPacket)?;
+        let writer = child.stdin.take().ok_or(DcgError::InvalidPacket)?;
+
+        Self::open(Box::new(reader), Box::new(writer), Some(child), None)
+    }
+}
+
+/// `word` quoted for a POSIX shell.
+fn shell_quote(word: &str) -> String {
+    format!("'{}'", word.replace('\'', "'\\''"))
+}
+
+impl RemoteRepository for Connection {
+    fn refs(&mut self) -> Result<Refs> {
+        Ok(self.refs.clone())
+    }
+
+    fn fetch(&mut self, dd: &Path, wants: &[[u8; 32]], depth: Option<usize>) -> Result<()> {
+        write_fetch(dd, wants, depth, &mut self.writer)?;
+        self.writer.flush()?;
+
+        read_commits(dd, &mut self.reader)?;
+
+        Ok(())
+    }
+
+    fn push(&mut self, dd: &Path, branch: &str, h: [u8; 32], force: bool) -> Result<()> {
+        write_push(dd, &self.refs, (branch, h, force), &mut self.writer)?;
+        self.writer.flush()?;
+
+        read_status(&mut self.reader)?;
+
+        self.refs.branches.insert(branch.to_string(), h);
+
+        Ok(())
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let _ = write_line(&mut self.writer, "quit").and_then(|_| Ok(self.writer.flush()?));
+
+        if let Some(child) = &mut self.child {
+            let _ = child.wait();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        fs,
+        io::{BufReader, BufWriter},
+        net::TcpListener,
+        path::Path,
+        process::Command,
+        thread::{self, JoinHandle},
+    };
+
+    use super::{serve_session, shell_quote, token_matches, Connection};
+    use crate::vcs::{
+        commit::{fetch_head, update_branch},
+        remote::RemoteRepository,
+        snapshot::Snapshot,
+        testing::{commit, repo},
+        transfer::has_commit,
+        DCG_DIR, SHALLOW_FILE,
+    };
+
+    /// Serve `dd` to a single client on localhost.
+    fn serve(dd: &Path, token: &'static str) -> (String, JoinHandle<anyhow::Result<()>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let dd = dd.to_path_buf();
+
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept()?;
+            let mut r = BufReader::new(stream.try_clone()?);
+            let mut w = BufWriter::new(stream);
+
+            serve_session(&dd, &mut r, &mut w, Some(token))
+        });
+
+        (address, server)
+    }
+
+    #[test]
+    fn fetch_and_push() {
+        let server_repo = repo();
+        let client_repo = repo();
+        let (sd, cd) = (server_repo.as_path(), client_repo.as_path());
+
+        let first = commit(sd, "master", &[("a.txt", "a\n")]);
+        let second = commit(sd, "master", &[("dir/b.txt", "b\n")]);
+
+        let (address, server) = serve(sd, "secret");
+        let mut remote = Connection::tcp(&address, Some("secret")).unwrap();
+        let refs = remote.refs().unwrap();
+
+        assert_eq!(refs.head, "master");
+        assert_eq!(refs.branches.get("master"), Some(&second));
+
+        remote.fetch(cd, &[second], None).unwrap();
+
+        assert!(has_commit(cd, first) && has_commit(cd, second));
+        assert_eq!(
+            Snapshot::at(cd, Some(second))
+                .unwrap()
+                .read(Path::new("dir/b.txt"))
+                .unwrap(),
+            Some(b"b\n".to_vec())
+        );
+
+        update_branch(cd, "feature", second, "branch: created").unwrap();
+        let third = commit(cd, "feature", &[("c.txt", "c\n")]);
+
+        remote.push(cd, "feature", third, false).unwrap();
+
+        drop(remote);
+        server.join().unwrap().unwrap();
+
+        assert_eq!(fetch_head(sd, "feature").unwrap(), Some(third));
+    }
+
+    #[test]
+    fn shallow_fetch() {
+        let server_repo = repo();
+        let client_repo = repo();
+        let (sd, cd) = (server_repo.as_path(), client_repo.as_path());
+
+        let first = commit(sd, "master", &[("a.txt", "a\n")]);
+        let second = commit(sd, "master", &[("b.txt", "b\n")]);
+
+        let (address, server) = serve(sd, "secret");
+        let mut remote = Connection::tcp(&address, Some("secret")).unwrap();
+
+        remote.fetch(cd, &[second], Some(1)).unwrap();
+
+        drop(remote);
+        server.join().unwrap().unwrap();
+
+        assert!(!has_commit(cd, first) && has_commit(cd, second));
+        assert_eq!(
+            Snapshot::at(cd, Some(second))
+                .unwrap()
+                .read(Path::new("a.txt"))
+                .unwrap(),
+            Some(b"a\n".to_vec())
+        );
+        assert_eq!(
+            fs::read_to_string(cd.join(DCG_DIR).join(SHALLOW_FILE)).unwrap(),
+            format!("{}\n", hex::encode(second))
+        );
+    }
+
+    #[test]
+    fn wrong_token() {
+        let server_repo = repo();
+        let (address, server) = serve(server_repo.as_path(), "secret");
+
+        assert!(Connection::tcp(&address, Some("guess")).is_err());
+        assert!(server.join().unwrap().is_err());
+    }
+
+    #[test]
+    fn quote_paths() {
+        for path in ["/srv/repo", "my repo", "it's; rm -rf ~", "$(id)`id`\"\\"] {
+            let out = Command::new("sh")
+                .args(["-c", &format!("printf %s {}", shell_quote(path))])
+                .output()
+                .unwrap();
+
+            assert_eq!(String::from_utf8(out.stdout).unwrap(), path);
+        }
+    }
+
+    #[test]
+    fn match_tokens() {
+        assert!(token_matches("secret", "secret"));
+        assert!(!token_matches("secreT", "secret"));
+        assert!(!token_matches("secret2", "secret"));
+        assert!(!token_matches("", "secret"));
+    }
+}
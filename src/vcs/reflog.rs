@@ -0,0 +1,229 @@
+//! Reflogs: the successive heads of the branches
+//!
+//! Each branch has a log in `logs/refs/branches/<branch>`, to which
+//! every move of the branch appends a line: the previous head (all
+//! zeros for a new branch), the new one, the identity of the user and
+//! the time of the move, then a tab and the reason for the move.  The
+//! moves of the branch checked out are also appended to `logs/HEAD`.
+//! Logs are never rewritten, so that the commits a branch was moved
+//! away from can be found again.
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+
+use crate::combine_paths;
+
+use super::{
+    commit::{get_branch, Signature},
+    config::{read_config, repo_config},
+    date::{format_offset, local_offset, parse_offset},
+    DcgError, BRANCHES_DIR, DCG_DIR, LOGS_DIR,
+};
+
+/// A move of a branch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Entry {
+    /// the previous head, if the branch had any commit
+    pub(crate) old: Option<[u8; 32]>,
+    pub(crate) new: [u8; 32],
+    /// who moved the branch, and when
+    pub(crate) identity: Signature,
+    pub(crate) reason: String,
+}
+
+impl Entry {
+    fn serialise(&self) -> String {
+        format!(
+            "{} {} {} <{}> {} {}\t{}\n",
+            hex::encode(self.old.unwrap_or_default()),
+            hex::encode(self.new),
+            self.identity.name,
+            self.identity.email,
+            self.identity.time,
+            format_offset(self.identity.offset),
+            self.reason.lines().next().unwrap_or("")
+        )
+    }
+
+    fn deserialise(line: &str) -> Option<Self> {
+        let (head, reason) = line.split_once('\t')?;
+        let mut fields = head.splitn(3, ' ');
+
+        let mut hash = || -> Option<[u8; 32]> { hex::decode(fields.next()?).ok()?.try_into().ok() };
+        let (old, new) = (hash()?, hash()?);
+
+        let mut rest = fields.next()?.rsplitn(3, ' ');
+        let offset = parse_offset(rest.next()?).ok()?;
+        let time = rest.next()?.parse().ok()?;
+        let (name, email) = Signature::parse_identity(rest.next()?).ok()?;
+
+        Some(Self {
+            old: Some(old).filter(|h| *h != [0; 32]),
+            new,
+            identity: Signature {
+                name,
+                email,
+                time,
+                offset,
+            },
+            reason: reason.to_string(),
+        })
+    }
+}
+
+/// The log of `name`, a branch or `HEAD`.
+fn log_path(dd: &Path, name: &str) -> PathBuf {
+    match name {
+        "HEAD" => combine_paths!(dd, DCG_DIR, LOGS_DIR, "HEAD"),
+        _ => combine_paths!(dd, DCG_DIR, LOGS_DIR, BRANCHES_DIR, name),
+    }
+}
+
+/// The user moving a branch of the repository `dd` now, as the
+/// committer of a commit would be.  Moves made without any identity
+/// configured are recorded as made by `unknown`.
+fn identity(dd: &Path) -> Signature {
+    read_config()
+        .and_then(|cfg| repo_config(dd, &cfg))
+        .and_then(|cfg| Signature::now("COMMITTER", &cfg))
+        .unwrap_or_else(|_| {
+            let time = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs() as i64);
+
+            Signature {
+                name: "unknown".to_string(),
+                email: "unknown".to_string(),
+                time,
+                offset: local_offset(time),
+            }
+        })
+}
+
+/// Record in the reflogs of the repository `dd` that `branch` was moved
+/// from `old` to `new` for `reason`.
+pub(crate) fn append(
+    dd: &Path,
+    branch: &str,
+    old: Option<[u8; 32]>,
+    new: [u8; 32],
+    reason: &str,
+) -> Result<()> {
+    let line = Entry {
+        old,
+        new,
+        identity: identity(dd),
+        reason: reason.to_string(),
+    }
+    .serialise();
+
+    let mut logs = vec![log_path(dd, branch)];
+
+    if get_branch(dd)?.trim() == branch {
+        logs.push(log_path(dd, "HEAD"));
+    }
+
+    for log in logs {
+        if let Some(parent) = log.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(log)?
+            .write_all(line.as_bytes())?;
+    }
+
+    Ok(())
+}
+
+/// The moves of `name`, a branch or `HEAD`, most recent first.  Lines
+/// which cannot be read are skipped.
+pub(crate) fn read_reflog(dd: &Path, name: &str) -> Result<Vec<Entry>> {
+    let log = log_path(dd, name);
+
+    if !log.is_file() {
+        return Ok(Vec::new());
+    }
+
+    Ok(fs::read_to_string(log)?
+        .lines()
+        .rev()
+        .filter_map(Entry::deserialise)
+        .collect())
+}
+
+/// The commit `name@{n}` designates: the head of `name`, a branch or
+/// `HEAD`, before its `n` latest moves.
+pub(crate) fn nth_head(dd: &Path, name: &str, n: usize) -> Result<[u8; 32]> {
+    read_reflog(dd, name)?
+        .get(n)
+        .map(|e| e.new)
+        .ok_or(DcgError::UnknownRevision(format!("{}@{{{}}}", name, n)).into())
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use super::{nth_head, read_reflog};
+    use crate::vcs::{
+        commit::update_branch,
+        gc::reachable_commits,
+        revision::resolve_revision,
+        testing::{commit, repo},
+    };
+
+    #[test]
+    fn record_moves() {
+        let dd = repo();
+
+        let first = commit(&dd, "master", &[("a.txt", "a\n")]);
+        let second = commit(&dd, "master", &[("b.txt", "b\n")]);
+
+        update_branch(&dd, "topic", first, "branch: created").unwrap();
+
+        let log = read_reflog(&dd, "master").unwrap();
+
+        assert_eq!(log.len(), 2);
+        assert_eq!((log[0].old, log[0].new), (Some(first), second));
+        assert_eq!((log[1].old, log[1].new), (None, first));
+
+        /* only the branch checked out moves the head */
+        assert_eq!(read_reflog(&dd, "HEAD").unwrap(), log);
+        assert_eq!(
+            read_reflog(&dd, "topic").unwrap()[0].reason,
+            "branch: created"
+        );
+
+        assert_eq!(nth_head(&dd, "master", 1).unwrap(), first);
+        assert_eq!(resolve_revision(&dd, "master@{0}").unwrap(), second);
+        assert_eq!(resolve_revision(&dd, "HEAD@{1}").unwrap(), first);
+        assert_eq!(resolve_revision(&dd, "@{0}~1").unwrap(), first);
+        assert!(resolve_revision(&dd, "master@{2}").is_err());
+    }
+
+    #[test]
+    fn logged_commits_are_reachable() {
+        let dd = repo();
+
+        let first = commit(&dd, "master", &[("a.txt", "a\n")]);
+        let second = commit(&dd, "master", &[("b.txt", "b\n")]);
+
+        /* the second commit is undone */
+        update_branch(&dd, "master", first, "reset: moving to master~1").unwrap();
+
+        assert_eq!(resolve_revision(&dd, "master@{1}").unwrap(), second);
+        assert!(reachable_commits(&dd).unwrap().contains(&second));
+
+        fs::remove_dir_all(dd.join(".dcg/logs")).unwrap();
+
+        assert!(!reachable_commits(&dd).unwrap().contains(&second));
+    }
+}
@@ -0,0 +1,347 @@
+//! Repositories which history is exchanged with
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+
+use crate::combine_paths;
+
+use super::{
+    bundle::{is_bundle, Bundle},
+    checkout::{modified_files, reset},
+    commit::{fetch_head, get_branch, update_branch},
+    config::{edit_repo_config, repo_config, Config},
+    http::HttpRepository,
+    index::get_indexed_files,
+    lock::{write_atomic, Lock},
+    protocol::{Connection, DEFAULT_PORT},
+    revision::{ancestors, read_hash},
+    snapshot::Snapshot,
+    transfer::{commits_to_send, copy_commits, copy_graft, has_commit, missing_commits},
+    DcgError, BRANCHES_DIR, DCG_DIR, REMOTES_DIR, TAGS_DIR,
+};
+
+/// The URL of the remote `name`.
+pub(crate) fn remote_url(dd: &Path, name: &str, cfg: &Config) -> Result<String> {
+    repo_config(dd, cfg)?
+        .remote
+        .and_then(|r| r.get(name).map(|r| r.url.clone()))
+        .ok_or(DcgError::UnknownRemote(name.to_string()).into())
+}
+
+/// The token to authenticate to the remote `name` with: `DCG_TOKEN`,
+/// or the one set in its configuration.
+pub(crate) fn remote_token(dd: &Path, name: &str, cfg: &Config) -> Result<Option<String>> {
+    if let Ok(token) = env::var("DCG_TOKEN") {
+        return Ok(Some(token));
+    }
+
+    Ok(repo_config(dd, cfg)?
+        .remote
+        .and_then(|r| r.get(name).and_then(|r| r.token.clone())))
+}
+
+/// Record the remote `name` at `url` in the configuration of the
+/// repository `dd`.
+pub(crate) fn add_remote(dd: &Path, name: &str, url: &str) -> Result<()> {
+    edit_repo_config(dd, |t| {
+        let remote = t
+            .entry("remote")
+            .or_insert_with(|| toml::Table::new().into());
+
+        if let Some(remote) = remote.as_table_mut() {
+            let mut r = toml::Table::new();
+
+            r.insert("url".to_string(), url.into());
+            remote.insert(name.to_string(), r.into());
+        }
+
+        Ok(())
+    })
+}
+
+/// How a repository is reached.
+enum Location {
+    Path(PathBuf),
+    Bundle(PathBuf),
+    /// the address of a `dcg serve` daemon
+    Daemon(String),
+    /// the host and the path of a repository reached through `ssh`
+    Ssh(String, String),
+    /// the URL of a repository served over HTTP
+    Http(String),
+}
+
+fn parse_url(dd: &Path, url: &str) -> Result<Location> {
+    if let Some(rest) = url.strip_prefix("dcg://") {
+        let host = rest.split('/').next().unwrap_or_default();
+
+        if host.is_empty() {
+            return Err(DcgError::InvalidRemote(url.to_string()).into());
+        }
+
+        Ok(Location::Daemon(if host.contains(':') {
+            host.to_string()
+        } else {
+            format!("{}:{}", host, DEFAULT_PORT)
+        }))
+    } else if let Some(rest) = url.strip_prefix("ssh://") {
+        match rest.split_once('/') {
+            Some((host, path)) if !host.is_empty() => {
+                Ok(Location::Ssh(host.to_string(), format!("/{}", path)))
+            }
+            _ => Err(DcgError::InvalidRemote(url.to_string()).into()),
+        }
+    } else if url.starts_with("http://") || url.starts_with("https://") {
+        Ok(Location::Http(url.to_string()))
+    } else if url.contains("://") && !url.starts_with("file://") {
+        Err(DcgError::InvalidRemote(url.to_string()).into())
+    } else {
+        let path = local_path(dd, url)?;
+
+        Ok(if path.is_file() {
+            Location::Bundle(path)
+        } else {
+            Location::Path(path)
+        })
+    }
+}
+
+/// Check that `url` names a repository: local ones must exist, while
+/// the others are only checked for their form.
+pub(crate) fn check_url(dd: &Path, url: &str) -> Result<()> {
+    parse_url(dd, url).map(|_| ())
+}
+
+/// Whether `url` is the path of a repository or of a bundle, rather
+/// than an address to connect to.
+pub(crate) fn is_local(url: &str) -> bool {
+    !url.contains("://") || url.starts_with("file://")
+}
+
+/// The root of the repository, or the bundle, at `url`, a path or a
+/// `file://` URL.  Relative paths are relative to the root of the
+/// repository `dd`.
+pub(crate) fn local_path(dd: &Path, url: &str) -> Result<PathBuf> {
+    let path = dd.join(url.strip_prefix("file://").unwrap_or(url));
+
+    if combine_paths!(&path, DCG_DIR).is_dir() || is_bundle(&path) {
+        Ok(fs::canonicalize(path)?)
+    } else {
+        Err(DcgError::InvalidRemote(url.to_string()).into())
+    }
+}
+
+/// The heads of the branches of the repository `dd`.  Branches without
+/// any commit are left out.
+pub(crate) fn branches<P: AsRef<Path>>(dd: P) -> Result<BTreeMap<String, [u8; 32]>> {
+    let dd = dd.as_ref();
+    let mut heads = BTreeMap::new();
+
+    for entry in fs::read_dir(combine_paths!(dd, DCG_DIR, BRANCHES_DIR))? {
+        let name = entry?.file_name().to_string_lossy().to_string();
+
+        if let Some(h) = fetch_head(dd, &name)? {
+            heads.insert(name, h);
+        }
+    }
+
+    Ok(heads)
+}
+
+/// The commits referenced by the tags of the repository `dd`.
+pub(crate) fn tags<P: AsRef<Path>>(dd: P) -> Result<BTreeMap<String, [u8; 32]>> {
+    let mut tags = BTreeMap::new();
+
+    for entry in fs::read_dir(combine_paths!(dd.as_ref(), DCG_DIR, TAGS_DIR))? {
+        let entry = entry?;
+
+        tags.insert(
+            entry.file_name().to_string_lossy().to_string(),
+            read_hash(&entry.path())?,
+        );
+    }
+
+    Ok(tags)
+}
+
+/// The branches of the remote `name` as they were last fetched.
+pub(crate) fn tracking_branches<P: AsRef<Path>>(
+    dd: P,
+    name: &str,
+) -> Result<BTreeMap<String, [u8; 32]>> {
+    let dir = combine_paths!(dd.as_ref(), DCG_DIR, REMOTES_DIR, name);
+    let mut heads = BTreeMap::new();
+
+    if dir.is_dir() {
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+
+            heads.insert(
+                entry.file_name().to_string_lossy().to_string(),
+                read_hash(&entry.path())?,
+            );
+        }
+    }
+
+    Ok(heads)
+}
+
+/// Record `h` as the head of `branch` on the remote `name`, or forget
+/// the branch if `h` is `None`.
+pub(crate) fn update_tracking<P: AsRef<Path>>(
+    dd: P,
+    name: &str,
+    branch: &str,
+    h: Option<[u8; 32]>,
+) -> Result<()> {
+    let dir = combine_paths!(dd.as_ref(), DCG_DIR, REMOTES_DIR, name);
+
+    match h {
+        Some(h) => {
+            write_atomic(dd.as_ref(), &dir.join(branch), hex::encode(h))?;
+        }
+        None if dir.join(branch).is_file() => fs::remove_file(dir.join(branch))?,
+        None => {}
+    }
+
+    Ok(())
+}
+
+/// The heads of the branches, tags and branches of remotes of the
+/// repository `dd`: all its history is reachable from them.
+pub(crate) fn local_heads(dd: &Path) -> Result<Vec<[u8; 32]>> {
+    let mut heads = branches(dd)?
+        .into_values()
+        .chain(tags(dd)?.into_values())
+        .collect::<Vec<_>>();
+
+    let remotes = combine_paths!(dd, DCG_DIR, REMOTES_DIR);
+
+    if remotes.is_dir() {
+        for entry in fs::read_dir(remotes)? {
+            let name = entry?.file_name().to_string_lossy().to_string();
+
+            heads.extend(tracking_branches(dd, &name)?.into_values());
+        }
+    }
+
+    Ok(heads)
+}
+
+/// Make `h` the head of `branch` in the repository `dd`, which is
+/// refused if the branch has commits `h` does not have, unless `force`
+/// is set.  If `branch` is checked out, its working tree is updated
+/// too, which is refused if it holds changes.
+pub(crate) fn update_remote_branch(
+    dd: &Path,
+    branch: &str,
+    h: [u8; 32],
+    force: bool,
+) -> Result<()> {
+    let old = branches(dd)?.get(branch).copied();
+
+    if let Some(old) = old {
+        if !force && !ancestors(dd, h)?.contains(&old) {
+            return Err(DcgError::NonFastForward(branch.to_string()).into());
+        }
+    }
+
+    if get_branch(dd)?.trim() != branch {
+        return update_branch(dd, branch, h, "push");
+    }
+
+    let tip = Snapshot::at(dd, old)?;
+
+    if !modified_files(dd, &tip)?.is_empty() || !get_indexed_files(dd)?.is_empty() {
+        return Err(DcgError::CheckedOutBranch(branch.to_string()).into());
+    }
+
+    reset(dd, &tip, h, "push")?;
+
+    Ok(())
+}
+
+/// The refs of a repository.
+#[derive(Clone, Default)]
+pub(crate) struct Refs {
+    /// the current branch
+    pub(crate) head: String,
+    pub(crate) branches: BTreeMap<String, [u8; 32]>,
+    pub(crate) tags: BTreeMap<String, [u8; 32]>,
+}
+
+impl Refs {
+    pub(crate) fn of(dd: &Path) -> Result<Self> {
+        Ok(Self {
+            head: get_branch(dd)?.trim().to_string(),
+            branches: branches(dd)?,
+            tags: tags(dd)?,
+        })
+    }
+}
+
+/// A repository history is exchanged with, whichever way it is reached.
+pub(crate) trait RemoteRepository {
+    fn refs(&mut self) -> Result<Refs>;
+
+    /// Copy the commits reachable from `wants` which are missing from
+    /// the repository `dd`.  With `depth`, only the commits within
+    /// `depth` generations of `wants` are copied, the oldest ones
+    /// without their parents.
+    fn fetch(&mut self, dd: &Path, wants: &[[u8; 32]], depth: Option<usize>) -> Result<()>;
+
+    /// Copy the commits of the repository `dd` reachable from `h`, and
+    /// make `h` the head of `branch`, as `update_remote_branch` does.
+    fn push(&mut self, dd: &Path, branch: &str, h: [u8; 32], force: bool) -> Result<()>;
+}
+
+/// A repository on the same machine.
+struct LocalRepository {
+    root: PathBuf,
+}
+
+impl RemoteRepository for LocalRepository {
+    fn refs(&mut self) -> Result<Refs> {
+        Refs::of(&self.root)
+    }
+
+    fn fetch(&mut self, dd: &Path, wants: &[[u8; 32]], depth: Option<usize>) -> Result<()> {
+        let (commits, grafts) = commits_to_send(&self.root, wants, depth, |h| has_commit(dd, h))?;
+
+        for h in grafts {
+            copy_graft(&self.root, dd, h)?;
+        }
+
+        copy_commits(&self.root, dd, &commits)?;
+
+        Ok(())
+    }
+
+    fn push(&mut self, dd: &Path, branch: &str, h: [u8; 32], force: bool) -> Result<()> {
+        let _lock = Lock::acquire(&self.root)?;
+        let missing = missing_commits(dd, &[h], |c| has_commit(&self.root, c))?;
+
+        copy_commits(dd, &self.root, &missing)?;
+        update_remote_branch(&self.root, branch, h, force)
+    }
+}
+
+/// Reach the repository at `url`.  Relative paths are relative to the
+/// root of the repository `dd`.
+pub(crate) fn connect(
+    dd: &Path,
+    url: &str,
+    token: Option<&str>,
+) -> Result<Box<dyn RemoteRepository>> {
+    Ok(match parse_url(dd, url)? {
+        Location::Path(root) => Box::new(LocalRepository { root }),
+        Location::Bundle(path) => Box::new(Bundle::open(&path)?),
+        Location::Daemon(address) => Box::new(Connection::tcp(&address, token)?),
+        Location::Ssh(host, path) => Box::new(Connection::ssh(&host, &path)?),
+        Location::Http(url) => Box::new(HttpRepository::open(&url, token)?),
+    })
+}
@@ -0,0 +1,356 @@
+//! Resolution of user-supplied revisions to commit hashes
+use std::{
+    collections::{BTreeMap, BTreeSet, BinaryHeap, VecDeque},
+    fs,
+    path::Path,
+};
+
+use anyhow::Result;
+
+use crate::combine_paths;
+
+use super::{
+    commit::{fetch_head, get_branch, get_parents, CommitObject},
+    decode_hash,
+    pack::list_children,
+    reflog::nth_head,
+    remote::{branches, tags, tracking_branches},
+    DcgError, BRANCHES_DIR, DCG_DIR, REMOTES_DIR, TAGS_DIR, TREE_DIR,
+};
+
+/// Resolve a revision to the commit it designates.
+///
+/// A revision is `HEAD`, a branch name, a tag name, a branch of a
+/// remote (`<remote>/<branch>`), a (possibly abbreviated) commit hash or
+/// `<branch>@{<n>}` (the head of a branch, or of `HEAD` if the branch is
+/// left out, before its n latest moves), followed by any number of
+/// `~<n>` (n-th first-parent ancestor) and `^[<n>]` (n-th parent)
+/// suffixes.
+pub(crate) fn resolve_revision<P: AsRef<Path>>(dd: P, rev: &str) -> Result<[u8; 32]> {
+    let dd = dd.as_ref();
+
+    let base_end = rev.find(['~', '^']).unwrap_or(rev.len());
+    let (base, mut suffixes) = rev.split_at(base_end);
+
+    let mut h = resolve_base(dd, base).ok_or(DcgError::UnknownRevision(rev.to_string()))??;
+
+    while let Some(c) = suffixes.chars().next() {
+        suffixes = &suffixes[1..];
+
+        let n_end = suffixes
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(suffixes.len());
+        let (n, rest) = suffixes.split_at(n_end);
+        suffixes = rest;
+
+        let n = if n.is_empty() {
+            1
+        } else {
+            n.parse::<usize>()
+                .map_err(|_| DcgError::UnknownRevision(rev.to_string()))?
+        };
+
+        h = match c {
+            '~' => {
+                for _ in 0..n {
+                    h = *get_parents(dd, h)?
+                        .first()
+                        .ok_or(DcgError::UnknownRevision(rev.to_string()))?;
+                }
+
+                h
+            }
+            _ if n == 0 => h,
+            _ => *get_parents(dd, h)?
+                .get(n - 1)
+                .ok_or(DcgError::UnknownRevision(rev.to_string()))?,
+        };
+    }
+
+    Ok(h)
+}
+
+/// Resolve a revision without suffixes.  Returns `None` if nothing
+/// matches the revision.
+fn resolve_base(dd: &Path, base: &str) -> Option<Result<[u8; 32]>> {
+    if let Some((name, n)) = base
+        .strip_suffix('}')
+        .and_then(|b| b.split_once("@{"))
+        .and_then(|(name, n)| Some((name, n.parse::<usize>().ok()?)))
+    {
+        let name = if name.is_empty() { "HEAD" } else { name };
+
+        return Some(nth_head(dd, name, n));
+    }
+
+    if base == "HEAD" {
+        return Some(
+            get_branch(dd)
+                .and_then(|b| fetch_head(dd, &b))
+                .and_then(|h| h.ok_or(DcgError::EmptyTree.into())),
+        );
+    }
+
+    let branch = combine_paths!(dd, DCG_DIR, BRANCHES_DIR, base);
+
+    if branch.is_file() {
+        return Some(fetch_head(dd, base).and_then(|h| h.ok_or(DcgError::EmptyTree.into())));
+    }
+
+    let tag = combine_paths!(dd, DCG_DIR, TAGS_DIR, base);
+
+    if tag.is_file() {
+        return Some(read_hash(&tag));
+    }
+
+    let tracking = combine_paths!(dd, DCG_DIR, REMOTES_DIR, base);
+
+    if tracking.is_file() {
+        return Some(read_hash(&tracking));
+    }
+
+    resolve_hash(dd, base)
+}
+
+/// Read the commit hash stored in the file `p`, such as a branch.
+pub(crate) fn read_hash(p: &Path) -> Result<[u8; 32]> {
+    decode_hash(&fs::read_to_string(p)?, p)
+}
+
+/// Resolve a full or abbreviated commit hash.  Abbreviated hashes
+/// must be at least 4 characters long.
+fn resolve_hash(dd: &Path, s: &str) -> Option<Result<[u8; 32]>> {
+    if s.len() < 4 || s.len() > 64 || !s.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    let s = s.to_ascii_lowercase();
+    let (ph, sh) = s.split_at(2);
+
+    let mut candidates = Vec::new();
+
+    for name in list_children(dd, &combine_paths!(TREE_DIR, ph)).unwrap_or_default() {
+        let name = name.to_str().unwrap_or("");
+
+        if name.starts_with(sh) {
+            candidates.push(format!("{}{}", ph, name));
+        }
+    }
+
+    match candidates.len() {
+        0 => None,
+        1 => Some(match hex::decode(&candidates[0]).map(|x| x.try_into()) {
+            Ok(Ok(h)) => Ok(h),
+            _ => Err(DcgError::InvalidCommit.into()),
+        }),
+        _ => Some(Err(DcgError::AmbiguousRevision(s).into())),
+    }
+}
+
+/// Find a best common ancestor of two commits.
+pub(crate) fn merge_base<P: AsRef<Path>>(
+    dd: P,
+    a: [u8; 32],
+    b: [u8; 32],
+) -> Result<Option<[u8; 32]>> {
+    let dd = dd.as_ref();
+
+    let ancestors = ancestors(dd, a)?;
+
+    let mut seen = BTreeSet::new();
+    let mut queue = VecDeque::from([b]);
+
+    while let Some(h) = queue.pop_front() {
+        if ancestors.contains(&h) {
+            return Ok(Some(h));
+        }
+
+        if seen.insert(h) {
+            queue.extend(get_parents(dd, h)?);
+        }
+    }
+
+    Ok(None)
+}
+
+/// All the commits reachable from `h`, `h` included.
+pub(crate) fn ancestors<P: AsRef<Path>>(dd: P, h: [u8; 32]) -> Result<BTreeSet<[u8; 32]>> {
+    let dd = dd.as_ref();
+
+    let mut seen = BTreeSet::new();
+    let mut stack = vec![h];
+
+    while let Some(h) = stack.pop() {
+        if seen.insert(h) {
+            stack.extend(get_parents(dd, h)?);
+        }
+    }
+
+    Ok(seen)
+}
+
+/// A name given to a commit.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Ref {
+    Branch(String),
+    Tag(String),
+    /// a branch of a remote, as `<remote>/<branch>`
+    Remote(String),
+}
+
+/// All the branches, tags and branches of remotes, with the commit they
+/// point to.  Branches without any commit are left out.
+pub(crate) fn list_refs<P: AsRef<Path>>(dd: P) -> Result<Vec<(Ref, [u8; 32])>> {
+    let dd = dd.as_ref();
+
+    let mut refs = Vec::new();
+
+    for (name, h) in branches(dd)? {
+        refs.push((Ref::Branch(name), h));
+    }
+
+    for (name, h) in tags(dd)? {
+        refs.push((Ref::Tag(name), h));
+    }
+
+    let remotes = combine_paths!(dd, DCG_DIR, REMOTES_DIR);
+
+    if remotes.is_dir() {
+        for entry in fs::read_dir(remotes)? {
+            let remote = entry?.file_name().to_string_lossy().to_string();
+
+            for (name, h) in tracking_branches(dd, &remote)? {
+                refs.push((Ref::Remote(format!("{}/{}", remote, name)), h));
+            }
+        }
+    }
+
+    refs.sort();
+
+    Ok(refs)
+}
+
+/// Iterator over the commits reachable from a set of commits, most
+/// recent first.
+pub(crate) struct RevWalk<'a> {
+    dd: &'a Path,
+    queue: BinaryHeap<(i64, [u8; 32])>,
+    pending: BTreeMap<[u8; 32], CommitObject>,
+    seen: BTreeSet<[u8; 32]>,
+    hidden: BTreeSet<[u8; 32]>,
+    /// in topological order, the number of children of each commit
+    /// which were not listed yet
+    children: Option<BTreeMap<[u8; 32], usize>>,
+}
+
+impl<'a> RevWalk<'a> {
+    pub(crate) fn new(dd: &'a Path, start: &[[u8; 32]]) -> Result<Self> {
+        let mut walk = Self {
+            dd,
+            queue: BinaryHeap::new(),
+            pending: BTreeMap::new(),
+            seen: BTreeSet::new(),
+            hidden: BTreeSet::new(),
+            children: None,
+        };
+
+        for h in start {
+            walk.push(*h)?;
+        }
+
+        Ok(walk)
+    }
+
+    /// Do not list the commits reachable from `h`.
+    pub(crate) fn hide(&mut self, h: [u8; 32]) -> Result<()> {
+        let hidden = ancestors(self.dd, h)?;
+
+        self.queue.retain(|(_, h)| !hidden.contains(h));
+        self.pending.retain(|h, _| !hidden.contains(h));
+        self.seen.extend(&hidden);
+        self.hidden.extend(hidden);
+
+        Ok(())
+    }
+
+    /// Never list a commit before one of its children, even if the
+    /// child is older.  Must be called after the commits are hidden.
+    pub(crate) fn topo_order(&mut self) -> Result<()> {
+        let mut children = BTreeMap::new();
+        let mut visited = BTreeSet::new();
+        let mut stack = self.queue.iter().map(|(_, h)| *h).collect::<Vec<_>>();
+
+        while let Some(h) = stack.pop() {
+            if visited.insert(h) {
+                for parent in get_parents(self.dd, h)? {
+                    if !self.hidden.contains(&parent) {
+                        *children.entry(parent).or_insert(0) += 1;
+                        stack.push(parent);
+                    }
+                }
+            }
+        }
+
+        /* starting commits reachable from other ones wait for them */
+        self.queue.retain(|(_, h)| !children.contains_key(h));
+        self.children = Some(children);
+
+        Ok(())
+    }
+
+    /// The parents of `h` which are not hidden.
+    pub(crate) fn parents(&self, h: [u8; 32]) -> Result<Vec<[u8; 32]>> {
+        let mut parents = get_parents(self.dd, h)?;
+
+        parents.retain(|p| !self.hidden.contains(p));
+
+        Ok(parents)
+    }
+
+    fn push(&mut self, h: [u8; 32]) -> Result<()> {
+        if self.seen.insert(h) {
+            let commit = CommitObject::read(self.dd, h)?;
+
+            self.pending.insert(h, commit);
+        } else if self.children.is_none() || !self.pending.contains_key(&h) {
+            return Ok(());
+        }
+
+        if let Some(children) = &mut self.children {
+            match children.get_mut(&h) {
+                Some(n) if *n > 1 => {
+                    *n -= 1;
+
+                    return Ok(());
+                }
+                _ => {
+                    children.remove(&h);
+                }
+            }
+        }
+
+        self.queue.push((self.pending[&h].committer.time, h));
+
+        Ok(())
+    }
+
+    fn next_commit(&mut self) -> Result<Option<([u8; 32], CommitObject)>> {
+        if let Some((_, h)) = self.queue.pop() {
+            for parent in self.parents(h)? {
+                self.push(parent)?;
+            }
+
+            Ok(self.pending.remove(&h).map(|c| (h, c)))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+impl Iterator for RevWalk<'_> {
+    type Item = Result<([u8; 32], CommitObject)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_commit().transpose()
+    }
+}
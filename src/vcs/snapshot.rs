@@ -0,0 +1,104 @@
+//! Reconstruction of the tracked files as they were at a given commit
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+
+use super::{
+    commit::{get_parent, read_base_file, read_stored_diff, ChangeContent, CommitObject},
+    diffs::apply_diff,
+    DcgError,
+};
+
+/// The state of the repository at a commit.  Files are rebuilt from
+/// their base file by replaying the diffs of the first-parent history.
+pub(crate) struct Snapshot {
+    dd: PathBuf,
+    /// commits from the root of the history to the snapshot commit
+    commits: Vec<([u8; 32], CommitObject)>,
+    /// tracked files and their hash
+    pub(crate) files: BTreeMap<PathBuf, [u8; 32]>,
+}
+
+impl Snapshot {
+    /// Build the snapshot of commit `h`.  `None` stands for the empty
+    /// history.
+    pub(crate) fn at<P: AsRef<Path>>(dd: P, h: Option<[u8; 32]>) -> Result<Self> {
+        let dd = dd.as_ref();
+
+        let mut commits = Vec::new();
+        let mut head = h;
+
+        while let Some(h) = head {
+            commits.push((h, CommitObject::read(dd, h)?));
+
+            head = get_parent(dd, h)?;
+        }
+
+        commits.reverse();
+
+        let mut files = BTreeMap::new();
+
+        for (_, commit) in &commits {
+            for change in &commit.changes {
+                match change.content {
+                    ChangeContent::Addition(h) | ChangeContent::Modification(_, h, _) => {
+                        files.insert(change.path.clone(), h);
+                    }
+                    ChangeContent::Deletion => {
+                        files.remove(&change.path);
+                    }
+                }
+            }
+        }
+
+        Ok(Self {
+            dd: dd.to_path_buf(),
+            commits,
+            files,
+        })
+    }
+
+    /// The plain contents of `path`, or `None` if it is not tracked.
+    pub(crate) fn read(&self, path: &Path) -> Result<Option<Vec<u8>>> {
+        Ok(self.history(path)?.pop().map(|(_, contents)| contents))
+    }
+
+    /// The successive contents of `path` since it was last added,
+    /// oldest first, with the commit which made each of them.  Empty
+    /// if `path` is not tracked.
+    pub(crate) fn history(&self, path: &Path) -> Result<Vec<([u8; 32], Vec<u8>)>> {
+        if !self.files.contains_key(path) {
+            return Ok(Vec::new());
+        }
+
+        /* the file is tracked, hence it was added by one of the commits */
+        let start = self
+            .commits
+            .iter()
+            .rposition(|(_, c)| {
+                c.changes
+                    .iter()
+                    .any(|x| x.path == path && matches!(x.content, ChangeContent::Addition(_)))
+            })
+            .ok_or(DcgError::InvalidCommit)?;
+
+        let (sh, _) = self.commits[start];
+        let mut versions = vec![(sh, read_base_file(&self.dd, path, sh)?)];
+
+        for (ch, commit) in &self.commits[start + 1..] {
+            for change in commit.changes.iter().filter(|x| x.path == path) {
+                if let ChangeContent::Modification(dt, h, _) = change.content {
+                    let d = read_stored_diff(&self.dd, dt, *ch, h, path)?;
+                    let contents = apply_diff(dt, &versions[versions.len() - 1].1, &d)?;
+
+                    versions.push((*ch, contents));
+                }
+            }
+        }
+
+        Ok(versions)
+    }
+}
@@ -0,0 +1,71 @@
+//! Repositories and commits for tests
+use std::path::{Path, PathBuf};
+
+use mktemp::Temp;
+
+use crate::{
+    commands::init::init_repo,
+    vcs::{
+        checkout::{checkout, set_last},
+        commit::{fetch_head, update_branch, Change, ChangeContent, CommitObject, Signature},
+        gz_encode,
+        index::Object,
+        snapshot::Snapshot,
+    },
+    NotificationLevel,
+};
+
+/// A new repository, removed when dropped.
+pub(crate) fn repo() -> Temp {
+    let dir = Temp::new_dir().unwrap();
+
+    init_repo(dir.as_path(), "master", NotificationLevel::Errors).unwrap();
+
+    dir
+}
+
+/// Commit the addition of `files` on top of `branch`.
+pub(crate) fn commit(dd: &Path, branch: &str, files: &[(&str, &str)]) -> [u8; 32] {
+    let signature = Signature {
+        name: "A U Thor".to_string(),
+        email: "a@u.th".to_string(),
+        time: 1_700_000_000,
+        offset: 0,
+    };
+    let changes = files
+        .iter()
+        .map(|(p, contents)| Change {
+            content: ChangeContent::Addition(
+                Object::new(Path::new(p), contents.as_bytes().to_vec()).hash(),
+            ),
+            file: gz_encode(contents.as_bytes()).unwrap(),
+            path: PathBuf::from(p),
+        })
+        .collect();
+
+    let parent = fetch_head(dd, branch).unwrap();
+    let h = CommitObject::new(
+        signature.clone(),
+        signature,
+        files[0].0.to_string(),
+        changes,
+    )
+    .write_detached(dd, &Vec::from_iter(parent))
+    .unwrap();
+
+    update_branch(dd, branch, h, "commit").unwrap();
+
+    h
+}
+
+/// Commit `files` on top of `master`, and check the commit out.
+pub(crate) fn commit_and_check_out(dd: &Path, files: &[(&str, &str)]) -> [u8; 32] {
+    let before = Snapshot::at(dd, fetch_head(dd, "master").unwrap()).unwrap();
+    let h = commit(dd, "master", files);
+    let after = Snapshot::at(dd, Some(h)).unwrap();
+
+    checkout(dd, &before, &after).unwrap();
+    set_last(dd, &after).unwrap();
+
+    h
+}
@@ -0,0 +1,455 @@
+//! Copy of history between repositories
+use std::{
+    collections::{BTreeSet, VecDeque},
+    ffi::OsStr,
+    fs::{self, File},
+    io::{Read, Write},
+    os::unix::ffi::OsStrExt,
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::Result;
+use mktemp::Temp;
+
+use crate::combine_paths;
+
+use super::{
+    commit::{
+        commit_object, get_parents, hash_to_commit_path, Change, ChangeContent, CommitObject,
+    },
+    diffs::DiffType,
+    gz_encode,
+    index::get_fname,
+    lock::{sync_dirs, write_atomic},
+    pack::{has_object, list_objects, read_object},
+    snapshot::Snapshot,
+    DcgError, BASE_DIR, BLOBS_DIR, DCG_DIR, SHALLOW_FILE, TREE_DIR,
+};
+
+/// Whether the commit `h` is stored in the repository `dd`.
+pub(crate) fn has_commit<P: AsRef<Path>>(dd: P, h: [u8; 32]) -> bool {
+    has_object(dd.as_ref(), &commit_object(h, "directory"))
+}
+
+/// The files making up the commit `h`, relative to `.dcg/`: the
+/// directory of the commit, and the base files and blobs of the files
+/// it adds or changes.  The files of the directory of the commit come
+/// last.
+pub(crate) fn commit_objects<P: AsRef<Path>>(dd: P, h: [u8; 32]) -> Result<Vec<PathBuf>> {
+    let dd = dd.as_ref();
+
+    let mut objects = Vec::new();
+
+    for change in CommitObject::read(dd, h)?.changes {
+        let parent = change
+            .path
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+
+        match change.content {
+            ChangeContent::Addition(fh) => {
+                let base = combine_paths!(BASE_DIR, &parent);
+
+                objects.push(base.join(format!("{}-{}", get_fname(&change.path), hex::encode(h))));
+
+                /* binary files are stored in the blobs */
+                let text = base.join(hex::encode(fh));
+
+                if has_object(dd, &text) {
+                    objects.push(text);
+                } else {
+                    objects.push(combine_paths!(BLOBS_DIR, hex::encode(fh)));
+                }
+            }
+            ChangeContent::Modification(DiffType::Binary, fh, _) => {
+                objects.push(combine_paths!(BLOBS_DIR, hex::encode(fh)))
+            }
+            ChangeContent::Modification(_, _, _) | ChangeContent::Deletion => {}
+        }
+    }
+
+    objects.extend(list_objects(dd, &commit_object(h, ""))?);
+
+    Ok(objects)
+}
+
+/// The commits reachable from `heads` in the repository `dd` for which
+/// `has` is false, parents first.  The history of a commit for which
+/// `has` is true is assumed to be complete.
+pub(crate) fn missing_commits<P: AsRef<Path>, F: Fn([u8; 32]) -> bool>(
+    dd: P,
+    heads: &[[u8; 32]],
+    has: F,
+) -> Result<Vec<[u8; 32]>> {
+    let dd = dd.as_ref();
+
+    let mut missing = Vec::new();
+    let mut seen = BTreeSet::new();
+    let mut stack = heads.iter().map(|h| (*h, false)).collect::<Vec<_>>();
+
+    while let Some((h, visited)) = stack.pop() {
+        if visited {
+            missing.push(h);
+        } else if !has(h) && seen.insert(h) {
+            stack.push((h, true));
+            stack.extend(get_parents(dd, h)?.into_iter().map(|p| (p, false)));
+        }
+    }
+
+    Ok(missing)
+}
+
+/// The directory the files of commit `h` are gathered in while it is
+/// copied to the repository `dst`.  The commit is considered stored
+/// once this directory is moved in place by `finish_commit`.
+pub(crate) fn partial_dir(dst: &Path, h: [u8; 32]) -> Result<PathBuf> {
+    let partial = combine_paths!(
+        dst,
+        DCG_DIR,
+        TREE_DIR,
+        format!("{}.partial", hex::encode(h))
+    );
+
+    /* left by an interrupted copy */
+    if partial.exists() {
+        fs::remove_dir_all(&partial)?;
+    }
+
+    Ok(partial)
+}
+
+/// Where the file `object` of commit `h`, relative to `.dcg/`, is
+/// copied in the repository `dst`.
+fn destination(dst: &Path, h: [u8; 32], object: &Path, partial: &Path) -> PathBuf {
+    match object.strip_prefix(combine_paths!(TREE_DIR, hash_to_commit_path(h))) {
+        Ok(rest) => partial.join(rest),
+        Err(_) => combine_paths!(dst, DCG_DIR, object),
+    }
+}
+
+/// Move the files of the commit `h`, gathered and synced in `partial`,
+/// in place in the repository `dst`.  The directories are synced before
+/// and after the move, so that a crash leaves either no commit or all
+/// of it.
+pub(crate) fn finish_commit(dst: &Path, h: [u8; 32], partial: &Path) -> Result<()> {
+    let to = combine_paths!(dst, DCG_DIR, TREE_DIR, hash_to_commit_path(h));
+    let parent = to.parent().unwrap_or(Path::new("."));
+
+    fs::create_dir_all(parent)?;
+    sync_dirs(partial)?;
+    fs::rename(partial, &to)?;
+    File::open(parent)?.sync_all()?;
+
+    Ok(())
+}
+
+/// Copy `commits`, ordered parents first, from the repository `src`
+/// to `dst`.  Returns the number of files copied.
+pub(crate) fn copy_commits(src: &Path, dst: &Path, commits: &[[u8; 32]]) -> Result<usize> {
+    let mut copied = 0;
+
+    for h in commits {
+        if has_commit(dst, *h) {
+            continue;
+        }
+
+        let partial = partial_dir(dst, *h)?;
+
+        for object in commit_objects(src, *h)? {
+            let to = destination(dst, *h, &object, &partial);
+
+            if to.is_file() || has_object(dst, &object) {
+                continue;
+            }
+
+            write_atomic(dst, &to, read_object(src, &object)?)?;
+            copied += 1;
+        }
+
+        finish_commit(dst, *h, &partial)?;
+    }
+
+    Ok(copied)
+}
+
+/// Commits to copy, parents first, and the ones among them to copy
+/// without their parents.
+pub(crate) type ShallowHistory = (Vec<[u8; 32]>, Vec<[u8; 32]>);
+
+/// The commits within `depth` generations of `heads` in the repository
+/// `dd`, parents first, and the ones of them at the last generation,
+/// whose parents are left out.
+pub(crate) fn cut_history<P: AsRef<Path>>(
+    dd: P,
+    heads: &[[u8; 32]],
+    depth: usize,
+) -> Result<ShallowHistory> {
+    let dd = dd.as_ref();
+
+    let mut cutoffs = Vec::new();
+    let mut seen = heads.iter().copied().collect::<BTreeSet<_>>();
+    let mut queue = heads.iter().map(|h| (*h, 1)).collect::<VecDeque<_>>();
+
+    /* breadth first, so that commits are met at their lowest generation */
+    while let Some((h, generation)) = queue.pop_front() {
+        let parents = get_parents(dd, h)?;
+
+        if generation == depth {
+            if !parents.is_empty() {
+                cutoffs.push(h);
+            }
+
+            continue;
+        }
+
+        for p in parents {
+            if seen.insert(p) {
+                queue.push_back((p, generation + 1));
+            }
+        }
+    }
+
+    let kept = missing_commits(dd, heads, |h| cutoffs.contains(&h))?;
+
+    Ok((kept, cutoffs))
+}
+
+/// The commits of the repository `dd` to send for `wants` to a
+/// repository which has the commits for which `has` is true, as
+/// `cut_history` gives them.  Without `depth`, the whole history is
+/// sent, and no commit is sent without its parents.
+pub(crate) fn commits_to_send<P: AsRef<Path>, F: Fn([u8; 32]) -> bool>(
+    dd: P,
+    wants: &[[u8; 32]],
+    depth: Option<usize>,
+    has: F,
+) -> Result<ShallowHistory> {
+    let dd = dd.as_ref();
+
+    Ok(match depth {
+        Some(depth) => {
+            let (kept, cutoffs) = cut_history(dd, wants, depth)?;
+
+            (
+                kept.into_iter().filter(|h| !has(*h)).collect(),
+                cutoffs.into_iter().filter(|h| !has(*h)).collect(),
+            )
+        }
+        None => (missing_commits(dd, wants, has)?, Vec::new()),
+    })
+}
+
+/// Write the commit `h` of the repository `src` to `dst` without its
+/// parents.  Its changes are replaced by the addition of all the files
+/// of its snapshot, which the following commits apply to.
+fn write_graft(src: &Path, dst: &Path, h: [u8; 32]) -> Result<()> {
+    let snapshot = Snapshot::at(src, Some(h))?;
+    let mut changes = Vec::new();
+
+    for (path, fh) in &snapshot.files {
+        let contents = snapshot.read(path)?.ok_or(DcgError::InvalidCommit)?;
+
+        changes.push(Change {
+            content: ChangeContent::Addition(*fh),
+            file: gz_encode(&contents)?,
+            path: path.clone(),
+        });
+    }
+
+    let graft = CommitObject {
+        changes,
+        ..CommitObject::read(src, h)?
+    };
+
+    graft.write_graft(dst, h)
+}
+
+/// List `h` in `.dcg/shallow`, as a commit whose parents are missing.
+/// The list is written again at once, so that it is never half
+/// written.
+fn mark_shallow(dd: &Path, h: [u8; 32]) -> Result<()> {
+    let p = combine_paths!(dd, DCG_DIR, SHALLOW_FILE);
+
+    let mut listed = if p.is_file() {
+        fs::read_to_string(&p)?
+            .split_whitespace()
+            .map(str::to_string)
+            .collect()
+    } else {
+        BTreeSet::new()
+    };
+
+    listed.insert(hex::encode(h));
+
+    let contents = listed.into_iter().map(|h| h + "\n").collect::<String>();
+
+    write_atomic(dd, &p, contents)?;
+
+    Ok(())
+}
+
+/// Copy the commit `h` from the repository `src` to `dst` without its
+/// parents, as `write_graft` does, and list it in `.dcg/shallow`.
+pub(crate) fn copy_graft(src: &Path, dst: &Path, h: [u8; 32]) -> Result<()> {
+    if has_commit(dst, h) {
+        return Ok(());
+    }
+
+    write_graft(src, dst, h)?;
+    mark_shallow(dst, h)
+}
+
+/// Packets larger than this are refused, as they can only come from a
+/// broken or malicious peer.
+const MAX_PACKET: usize = 1 << 30;
+
+/// Write `data` as a packet: its length, as four big-endian bytes,
+/// then the data itself.
+pub(crate) fn write_packet<W: Write>(w: &mut W, data: &[u8]) -> Result<()> {
+    let len = u32::try_from(data.len()).map_err(|_| DcgError::InvalidPacket)?;
+
+    w.write_all(&len.to_be_bytes())?;
+    w.write_all(data)?;
+
+    Ok(())
+}
+
+/// Read a packet written by `write_packet`.
+pub(crate) fn read_packet<R: Read>(r: &mut R) -> Result<Vec<u8>> {
+    let mut len = [0; 4];
+    r.read_exact(&mut len)?;
+
+    let len = u32::from_be_bytes(len) as usize;
+
+    if len > MAX_PACKET {
+        return Err(DcgError::InvalidPacket.into());
+    }
+
+    let mut data = vec![0; len];
+    r.read_exact(&mut data)?;
+
+    Ok(data)
+}
+
+/// Read a packet holding a line of text.
+pub(crate) fn read_line<R: Read>(r: &mut R) -> Result<String> {
+    String::from_utf8(read_packet(r)?).map_err(|_| DcgError::InvalidPacket.into())
+}
+
+fn write_objects<W: Write>(
+    dd: &Path,
+    kind: &str,
+    h: [u8; 32],
+    objects: &[PathBuf],
+    w: &mut W,
+) -> Result<()> {
+    write_packet(
+        w,
+        format!("{} {} {}", kind, hex::encode(h), objects.len()).as_bytes(),
+    )?;
+
+    for object in objects {
+        write_packet(w, object.as_os_str().as_bytes())?;
+        write_packet(w, &read_object(dd, object)?)?;
+    }
+
+    Ok(())
+}
+
+/// Write the commits of the repository `dd` given by `commits_to_send`
+/// to `w`, with all their files, for `read_commits` to store them.
+pub(crate) fn write_commits<W: Write>(
+    dd: &Path,
+    (commits, grafts): &ShallowHistory,
+    w: &mut W,
+) -> Result<()> {
+    if !grafts.is_empty() {
+        /* grafts are made aside, then sent as they were written */
+        let tmp = Temp::new_dir()?;
+
+        for dir in [BASE_DIR, BLOBS_DIR, TREE_DIR] {
+            fs::create_dir_all(combine_paths!(tmp.as_path(), DCG_DIR, dir))?;
+        }
+
+        for h in grafts {
+            write_graft(dd, &tmp, *h)?;
+            write_objects(&tmp, "graft", *h, &commit_objects(&tmp, *h)?, w)?;
+        }
+    }
+
+    for h in commits {
+        write_objects(dd, "commit", *h, &commit_objects(dd, *h)?, w)?;
+    }
+
+    write_packet(w, b"done")
+}
+
+/// Whether `object` is a path commit `h` may store a file at: in the
+/// base files, in the blobs, or in its own directory.
+fn valid_object(object: &Path, h: [u8; 32]) -> bool {
+    object
+        .components()
+        .all(|c| matches!(c, Component::Normal(_)))
+        && (object.starts_with(BASE_DIR)
+            || object.starts_with(BLOBS_DIR)
+            || object.starts_with(combine_paths!(TREE_DIR, hash_to_commit_path(h))))
+}
+
+/// Store the commits written to `r` by `write_commits` in the
+/// repository `dd`.  Returns the number of commits received.
+pub(crate) fn read_commits<R: Read>(dd: &Path, r: &mut R) -> Result<usize> {
+    let mut received = 0;
+
+    loop {
+        let header = read_line(r)?;
+        let fields = header.split(' ').collect::<Vec<_>>();
+
+        let (kind, h, n) = match fields[..] {
+            ["done"] => return Ok(received),
+            ["error", ..] => {
+                return Err(DcgError::RemoteError(header["error ".len()..].to_string()).into())
+            }
+            [kind @ ("commit" | "graft"), h, n] => (
+                kind,
+                hex::decode(h)
+                    .ok()
+                    .and_then(|h| <[u8; 32]>::try_from(h).ok())
+                    .ok_or(DcgError::InvalidPacket)?,
+                n.parse::<usize>().map_err(|_| DcgError::InvalidPacket)?,
+            ),
+            _ => return Err(DcgError::InvalidPacket.into()),
+        };
+
+        /* the files are read even if the commit is already stored */
+        let known = has_commit(dd, h);
+        let partial = partial_dir(dd, h)?;
+
+        for _ in 0..n {
+            let object = PathBuf::from(OsStr::from_bytes(&read_packet(r)?));
+            let contents = read_packet(r)?;
+
+            if !valid_object(&object, h) {
+                return Err(DcgError::InvalidPacket.into());
+            }
+
+            let to = destination(dd, h, &object, &partial);
+
+            if known || to.is_file() || has_object(dd, &object) {
+                continue;
+            }
+
+            write_atomic(dd, &to, &contents)?;
+        }
+
+        if !known {
+            finish_commit(dd, h, &partial)?;
+
+            if kind == "graft" {
+                mark_shallow(dd, h)?;
+            }
+
+            received += 1;
+        }
+    }
+}